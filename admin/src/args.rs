@@ -0,0 +1,130 @@
+use clap::{
+    crate_authors, crate_description, crate_name, crate_version, App, AppSettings, Arg, SubCommand,
+};
+use std::net::SocketAddr;
+
+#[derive(Debug)]
+pub struct Args {
+    pub endpoint: SocketAddr,
+    pub command: Command,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Chunks,
+    Computations,
+    Keys { computation_id: u32 },
+    Rotate,
+    Retention,
+    TreeHead,
+    Consistency { old_size: u64, new_size: u64 },
+}
+
+impl Args {
+    const ENDPOINT: &'static str = "ENDPOINT";
+    const ENDPOINT_DEFAULT: &'static str = "127.0.0.1:9099";
+    const CHUNKS: &'static str = "chunks";
+    const COMPUTATIONS: &'static str = "computations";
+    const KEYS: &'static str = "keys";
+    const COMPUTATION_ID: &'static str = "COMPUTATION_ID";
+    const ROTATE: &'static str = "rotate";
+    const RETENTION: &'static str = "retention";
+    const TREE_HEAD: &'static str = "tree-head";
+    const CONSISTENCY: &'static str = "consistency";
+    const OLD_SIZE: &'static str = "OLD_SIZE";
+    const NEW_SIZE: &'static str = "NEW_SIZE";
+
+    pub fn new() -> Self {
+        let matches = App::new(crate_name!())
+            .version(crate_version!())
+            .author(crate_authors!())
+            .about(crate_description!())
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .arg(
+                Arg::with_name(Self::ENDPOINT)
+                    .short("e")
+                    .long("endpoint")
+                    .value_name("ADDRESS")
+                    .default_value(Self::ENDPOINT_DEFAULT)
+                    .help("Sets the admin endpoint of the diagnosis server"),
+            )
+            .subcommand(
+                SubCommand::with_name(Self::CHUNKS)
+                    .about("Lists the done chunks and the current chunk"),
+            )
+            .subcommand(
+                SubCommand::with_name(Self::COMPUTATIONS)
+                    .about("Lists the retained computations with their list sizes"),
+            )
+            .subcommand(
+                SubCommand::with_name(Self::KEYS)
+                    .about("Dumps the blacklist and greylist keys of a computation")
+                    .arg(
+                        Arg::with_name(Self::COMPUTATION_ID)
+                            .required(true)
+                            .help("Sets the computation id"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name(Self::ROTATE)
+                    .about("Publishes the current chunk before its interval ends"),
+            )
+            .subcommand(
+                SubCommand::with_name(Self::RETENTION)
+                    .about("Shows which chunks and computations are retained"),
+            )
+            .subcommand(
+                SubCommand::with_name(Self::TREE_HEAD)
+                    .about("Dumps the signed tree head of the transparency log"),
+            )
+            .subcommand(
+                SubCommand::with_name(Self::CONSISTENCY)
+                    .about("Dumps the consistency proof between two sizes of the transparency log")
+                    .arg(
+                        Arg::with_name(Self::OLD_SIZE)
+                            .required(true)
+                            .help("Sets the size of the older tree"),
+                    )
+                    .arg(
+                        Arg::with_name(Self::NEW_SIZE)
+                            .required(true)
+                            .help("Sets the size of the newer tree"),
+                    ),
+            )
+            .get_matches();
+
+        let endpoint = matches
+            .value_of(Self::ENDPOINT)
+            .unwrap()
+            .parse()
+            .expect("Invalid admin endpoint.");
+        let command = match matches.subcommand() {
+            (Self::CHUNKS, _) => Command::Chunks,
+            (Self::COMPUTATIONS, _) => Command::Computations,
+            (Self::KEYS, Some(matches)) => Command::Keys {
+                computation_id: matches
+                    .value_of(Self::COMPUTATION_ID)
+                    .unwrap()
+                    .parse()
+                    .expect("Invalid computation id."),
+            },
+            (Self::ROTATE, _) => Command::Rotate,
+            (Self::RETENTION, _) => Command::Retention,
+            (Self::TREE_HEAD, _) => Command::TreeHead,
+            (Self::CONSISTENCY, Some(matches)) => Command::Consistency {
+                old_size: matches
+                    .value_of(Self::OLD_SIZE)
+                    .unwrap()
+                    .parse()
+                    .expect("Invalid old tree size."),
+                new_size: matches
+                    .value_of(Self::NEW_SIZE)
+                    .unwrap()
+                    .parse()
+                    .expect("Invalid new tree size."),
+            },
+            _ => panic!("Invalid subcommand."),
+        };
+        Args { endpoint, command }
+    }
+}
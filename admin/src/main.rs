@@ -0,0 +1,104 @@
+mod args;
+use anyhow::{Context, Result};
+use args::{Args, Command};
+use exposurelib::diagnosis_server_state::ChunkInfo;
+use exposurelib::primitives::ComputationId;
+use exposurelib::rpcs::{AdminClient, ConsistencyParams};
+use tarpc::tokio_serde::formats;
+use tarpc::{client, context};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::new();
+
+    let transport = tarpc::serde_transport::tcp::connect(args.endpoint, formats::Bincode::default)
+        .await
+        .context(format!(
+            "Could not connect to admin endpoint at {:?}.",
+            args.endpoint
+        ))?;
+    let admin = AdminClient::new(client::Config::default(), transport)
+        .spawn()
+        .context("Could not spawn admin client.")?;
+
+    match args.command {
+        Command::Chunks => {
+            let chunks = admin.chunks(context::current()).await?;
+            // the last one is the current chunk
+            for (index, chunk) in chunks.iter().enumerate() {
+                print_chunk(chunk, index + 1 == chunks.len());
+            }
+        }
+        Command::Computations => {
+            for computation in admin.computations(context::current()).await? {
+                println!(
+                    "{:?} started at {} {:?}: {} blacklisted and {} greylisted keys{}",
+                    computation.computation_id,
+                    computation.started_at,
+                    computation.phase,
+                    computation.blacklisted,
+                    computation.greylisted,
+                    if computation.revoked { ", revoked" } else { "" }
+                );
+            }
+        }
+        Command::Keys { computation_id } => {
+            let keys = admin
+                .computation_keys(context::current(), ComputationId::from(computation_id))
+                .await??;
+            println!(
+                "{}",
+                serde_yaml::to_string(&keys).context("Could not serialize keys.")?
+            );
+        }
+        Command::Rotate => {
+            let chunk_id = admin.rotate(context::current()).await?;
+            println!("Published {:?} ahead of time", chunk_id);
+        }
+        Command::Retention => {
+            let retention = admin.retention(context::current()).await?;
+            println!("Retention period: {:?}", retention.retention_period);
+            println!("Done chunks: {}", retention.done_chunks);
+            if let Some(chunk) = &retention.oldest_chunk {
+                print!("Oldest chunk: ");
+                print_chunk(chunk, false);
+            }
+            if let Some(expires_at) = retention.oldest_chunk_expires_at {
+                println!(
+                    "Oldest chunk is pruned with the first rotation after {}",
+                    expires_at
+                );
+            }
+            println!("Retained computations: {}", retention.computations);
+        }
+        Command::TreeHead => {
+            let tree_head = admin.tree_head(context::current()).await?;
+            println!(
+                "{}",
+                serde_yaml::to_string(&tree_head).context("Could not serialize tree head.")?
+            );
+        }
+        Command::Consistency { old_size, new_size } => {
+            let proof = admin
+                .consistency(context::current(), ConsistencyParams { old_size, new_size })
+                .await??;
+            println!(
+                "{}",
+                serde_yaml::to_string(&proof).context("Could not serialize consistency proof.")?
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_chunk(chunk: &ChunkInfo, current: bool) {
+    println!(
+        "{:?} covering {} to {}: {} keys, {} bytes{}",
+        chunk.id,
+        chunk.covers.from_including(),
+        chunk.covers.to_excluding(),
+        chunk.diagnosis_keys,
+        chunk.size,
+        if current { " (current)" } else { "" }
+    );
+}
@@ -0,0 +1,114 @@
+use clap::{crate_authors, crate_description, crate_name, crate_version, App, Arg, SubCommand};
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum Args {
+    Replay(ReplayArgs),
+    Consistency(ConsistencyArgs),
+}
+
+#[derive(Debug)]
+pub struct ReplayArgs {
+    pub journal_file_path: PathBuf,
+    pub chunks_output_path: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct ConsistencyArgs {
+    pub old_tree_head_path: PathBuf,
+    pub new_tree_head_path: PathBuf,
+    pub proof_path: PathBuf,
+    pub public_key: Option<String>,
+}
+
+impl Args {
+    const REPLAY: &'static str = "replay";
+    const JOURNAL_FILE_PATH: &'static str = "JOURNAL_FILE_PATH";
+    const CHUNKS_OUTPUT_PATH: &'static str = "CHUNKS_OUTPUT_PATH";
+    const CONSISTENCY: &'static str = "consistency";
+    const OLD_TREE_HEAD_PATH: &'static str = "OLD_TREE_HEAD_PATH";
+    const NEW_TREE_HEAD_PATH: &'static str = "NEW_TREE_HEAD_PATH";
+    const PROOF_PATH: &'static str = "PROOF_PATH";
+    const PUBLIC_KEY: &'static str = "PUBLIC_KEY";
+
+    pub fn new() -> Self {
+        let matches = App::new(crate_name!())
+            .version(crate_version!())
+            .author(crate_authors!())
+            .about(crate_description!())
+            .subcommand(
+                SubCommand::with_name(Self::REPLAY)
+                    .about("Verifies the journal of a diagnosis server and rebuilds its chunks")
+                    .arg(
+                        Arg::with_name(Self::JOURNAL_FILE_PATH)
+                            .short("j")
+                            .long("journal")
+                            .value_name("FILE")
+                            .required(true)
+                            .help("Sets the journal file of the diagnosis server"),
+                    )
+                    .arg(
+                        Arg::with_name(Self::CHUNKS_OUTPUT_PATH)
+                            .short("o")
+                            .long("output")
+                            .value_name("FILE")
+                            .help("Sets the yaml file to write the rebuilt chunks to"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name(Self::CONSISTENCY)
+                    .about("Verifies that a newer tree head of the transparency log extends an older one")
+                    .arg(
+                        Arg::with_name(Self::OLD_TREE_HEAD_PATH)
+                            .long("old")
+                            .value_name("FILE")
+                            .required(true)
+                            .help("Sets the yaml file of the older tree head"),
+                    )
+                    .arg(
+                        Arg::with_name(Self::NEW_TREE_HEAD_PATH)
+                            .long("new")
+                            .value_name("FILE")
+                            .required(true)
+                            .help("Sets the yaml file of the newer tree head"),
+                    )
+                    .arg(
+                        Arg::with_name(Self::PROOF_PATH)
+                            .short("p")
+                            .long("proof")
+                            .value_name("FILE")
+                            .required(true)
+                            .help("Sets the yaml file of the consistency proof"),
+                    )
+                    .arg(
+                        Arg::with_name(Self::PUBLIC_KEY)
+                            .short("k")
+                            .long("public-key")
+                            .value_name("HEX")
+                            .help("Sets the public key the tree heads must be signed with"),
+                    ),
+            )
+            .get_matches();
+
+        match matches.subcommand_name() {
+            Some(Self::REPLAY) => {
+                let matches = matches.subcommand_matches(Self::REPLAY).unwrap();
+                Args::Replay(ReplayArgs {
+                    journal_file_path: matches.value_of(Self::JOURNAL_FILE_PATH).unwrap().into(),
+                    chunks_output_path: matches.value_of(Self::CHUNKS_OUTPUT_PATH).map(Into::into),
+                })
+            }
+            Some(Self::CONSISTENCY) => {
+                let matches = matches.subcommand_matches(Self::CONSISTENCY).unwrap();
+                Args::Consistency(ConsistencyArgs {
+                    old_tree_head_path: matches.value_of(Self::OLD_TREE_HEAD_PATH).unwrap().into(),
+                    new_tree_head_path: matches.value_of(Self::NEW_TREE_HEAD_PATH).unwrap().into(),
+                    proof_path: matches.value_of(Self::PROOF_PATH).unwrap().into(),
+                    public_key: matches.value_of(Self::PUBLIC_KEY).map(Into::into),
+                })
+            }
+            None => panic!("Please specify which subcommand to use. See --help for usage."),
+            _ => panic!("Invalid subcommand."),
+        }
+    }
+}
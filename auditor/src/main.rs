@@ -0,0 +1,179 @@
+mod args;
+use anyhow::{anyhow, bail, Context, Result};
+use args::{Args, ConsistencyArgs, ReplayArgs};
+use exposurelib::diagnosis_server_state::ListType;
+use exposurelib::journal::{self, JournalEntry};
+use exposurelib::signing::PublicKey;
+use exposurelib::transparency::{ConsistencyProof, SignedTreeHead};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+const DIVIDER: &str = "-------------------------------------------";
+
+fn main() -> Result<()> {
+    let args = Args::new();
+
+    match args {
+        Args::Replay(args) => handle_replay(args),
+        Args::Consistency(args) => handle_consistency(args),
+    }
+}
+
+fn handle_replay(args: ReplayArgs) -> Result<()> {
+    let journal = journal::read(&args.journal_file_path).context(format!(
+        "Could not verify journal {:?}.",
+        args.journal_file_path
+    ))?;
+    let records = &journal.records;
+    println!(
+        "Verified {} records of journal {:?}",
+        records.len(),
+        args.journal_file_path
+    );
+    if let (Some(first), Some(last)) = (records.first(), records.last()) {
+        println!("Journal spans from {} to {}", first.at, last.at);
+    }
+    let mut peers = HashSet::new();
+    let (mut starts, mut blacklist_uploads, mut greylist_uploads, mut rotations, mut revocations) =
+        (0, 0, 0, 0, 0);
+    let mut imports = BTreeMap::new();
+    for record in records {
+        match &record.entry {
+            JournalEntry::Started { .. } => starts += 1,
+            JournalEntry::Uploaded { list, peer, .. } => {
+                match list {
+                    ListType::Blacklist => blacklist_uploads += 1,
+                    ListType::Greylist => greylist_uploads += 1,
+                }
+                // fake cases have no peer
+                peers.extend(*peer);
+            }
+            JournalEntry::Rotated { .. } => rotations += 1,
+            JournalEntry::Imported { region, .. } => *imports.entry(*region).or_insert(0) += 1,
+            JournalEntry::Revoked { peer, .. } => {
+                revocations += 1;
+                peers.extend(*peer);
+            }
+            JournalEntry::ComputationStarted { .. } => {}
+        }
+    }
+    println!(
+        "{} starts, {} blacklist uploads, {} greylist uploads, {} chunk rotations, {} revocations",
+        starts, blacklist_uploads, greylist_uploads, rotations, revocations
+    );
+    println!(
+        "Uploads and revocations came from {} distinct peers",
+        peers.len()
+    );
+    for (region, count) in imports.iter() {
+        println!(
+            "{} chunks imported from federation peer {:?}",
+            count, region
+        );
+    }
+
+    let replay = journal::replay(records).context("Could not replay journal.")?;
+    let chunks = replay.chunks;
+    println!("{}", DIVIDER);
+    println!(
+        "Rebuilt {} computations, {} of them closed and {} revoked",
+        replay.computations.len(),
+        replay
+            .computations
+            .values()
+            .filter(|computation| computation.closure_published)
+            .count(),
+        replay
+            .computations
+            .values()
+            .filter(|computation| computation.revoked)
+            .count()
+    );
+    println!("Rebuilt {} chunks:", chunks.len());
+    for chunk in chunks.values() {
+        let (blacklisted, greylisted) =
+            chunk
+                .data()
+                .values()
+                .fold((0, 0), |(blacklisted, greylisted), computation| {
+                    (
+                        blacklisted + computation.blacklist().len(),
+                        greylisted + computation.greylist().len(),
+                    )
+                });
+        println!(
+            "{:?} covering {} to {}: {} computations, {} blacklisted and {} greylisted keys, closes {:?}, revokes {:?}",
+            chunk.id(),
+            chunk.covers().from_including(),
+            chunk.covers().to_excluding(),
+            chunk.data().len(),
+            blacklisted,
+            greylisted,
+            chunk.closed(),
+            chunk.revoked()
+        );
+    }
+
+    if let Some(output) = args.chunks_output_path {
+        let chunks: Vec<_> = chunks.values().collect();
+        let chunks = serde_yaml::to_string(&chunks).context("Could not serialize chunks.")?;
+        fs::write(&output, chunks).context(format!("Could not write chunks to {:?}.", output))?;
+        println!("{}", DIVIDER);
+        println!("Wrote rebuilt chunks to {:?}", output);
+    }
+    Ok(())
+}
+
+fn handle_consistency(args: ConsistencyArgs) -> Result<()> {
+    let old: SignedTreeHead = serde_yaml::from_str(&read(&args.old_tree_head_path)?)
+        .context(format!("Could not parse {:?}.", args.old_tree_head_path))?;
+    let new: SignedTreeHead = serde_yaml::from_str(&read(&args.new_tree_head_path)?)
+        .context(format!("Could not parse {:?}.", args.new_tree_head_path))?;
+    let proof: ConsistencyProof = serde_yaml::from_str(&read(&args.proof_path)?)
+        .context(format!("Could not parse {:?}.", args.proof_path))?;
+    match args.public_key {
+        Some(public_key) => {
+            let public_key = PublicKey::try_from(public_key)
+                .map_err(|e| anyhow!("Invalid public key: {}", e))?;
+            old.verify(&public_key)
+                .context("Could not verify signature of old tree head.")?;
+            new.verify(&public_key)
+                .context("Could not verify signature of new tree head.")?;
+            println!("Verified signatures of both tree heads");
+        }
+        None => println!("No public key given, signatures of tree heads are not verified"),
+    }
+    if old.tree_size > new.tree_size {
+        bail!(
+            "Old tree head has size {} which exceeds the size {} of the new one.",
+            old.tree_size,
+            new.tree_size
+        );
+    }
+    if old.conflicts_with(&new) {
+        bail!("Tree heads of the same size have different root hashes, the log forked.");
+    }
+    if proof.old_size != old.tree_size || proof.new_size != new.tree_size {
+        bail!(
+            "Proof is for sizes {} and {} instead of {} and {}.",
+            proof.old_size,
+            proof.new_size,
+            old.tree_size,
+            new.tree_size
+        );
+    }
+    if !proof.verify(&old.root_hash, &new.root_hash) {
+        bail!("Consistency proof does not verify, the log was rewritten.");
+    }
+    println!(
+        "Tree head of size {} from {} extends tree head of size {} from {}",
+        new.tree_size, new.timestamp, old.tree_size, old.timestamp
+    );
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).context(format!("Could not read {:?}.", path))
+}
@@ -0,0 +1,193 @@
+use crate::forwarder::ForwardRouter;
+use anyhow::{Context, Result};
+use exposurelib::config::{CoverTrafficConfig, SystemParams};
+use exposurelib::error::RequestError;
+use exposurelib::logger;
+use exposurelib::primitives::{ComputationId, Seed, TemporaryExposureKey, Validity};
+use exposurelib::rpcs::{
+    self, BlacklistUploadParams, Features, ForwardParams, GreylistUploadParams, VerifyParams,
+};
+use exposurelib::time::{poisson_interval, ExposureTime, ExposureTimeSet};
+use exposurelib::verification::{GreylistSecret, KeyCommitment, VerificationCode};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tarpc::context;
+use tokio::time;
+
+/// Sends fake forwards to random peers and fake uploads to the diagnosis
+/// server which look like the requests of participants involved in a
+/// computation.
+/// Fake forwards carry random TEKs which never match, hence listeners drop
+/// them like any other forward they cannot continue.
+pub struct CoverTraffic {
+    config: CoverTrafficConfig,
+    system_params: SystemParams,
+    // validities of the own TEKs, fake keys mimic them
+    valid_froms: Vec<ExposureTime>,
+    diagnosis_server: Arc<rpcs::DiagnosisServerClient>,
+    // older diagnosis servers would publish fake uploads
+    uploads: bool,
+    router: ForwardRouter,
+    secure_random: SystemRandom,
+    // fake greylist uploads continue the latest fake blacklist upload
+    computation: Option<(ComputationId, GreylistSecret)>,
+}
+
+impl CoverTraffic {
+    pub fn new(
+        config: CoverTrafficConfig,
+        system_params: SystemParams,
+        valid_froms: Vec<ExposureTime>,
+        diagnosis_server: Arc<rpcs::DiagnosisServerClient>,
+        features: Features,
+        router: ForwardRouter,
+    ) -> Self {
+        Self {
+            config,
+            system_params,
+            valid_froms,
+            diagnosis_server,
+            uploads: features.contains(Features::COVER_TRAFFIC),
+            router,
+            secure_random: SystemRandom::new(),
+            computation: None,
+        }
+    }
+    pub async fn run(mut self) -> ! {
+        logger::info!(
+            "Sending cover traffic every {:?} on average",
+            self.config.mean_interval
+        );
+        logger::warn!(
+            "Cover uploads never show up in chunks, they only hide the genuine \
+            ones if the connections to the diagnosis server are encrypted"
+        );
+        loop {
+            time::sleep(poisson_interval(
+                self.config.mean_interval,
+                &self.secure_random,
+            ))
+            .await;
+            let result = match self.random() % 3 {
+                0 => self.cover_forward().await,
+                1 => self.cover_blacklist_upload().await,
+                _ => self.cover_greylist_upload().await,
+            };
+            if let Err(e) = result {
+                logger::debug!("Error sending cover request: {:?}", e);
+            }
+        }
+    }
+    async fn cover_forward(&mut self) -> Result<()> {
+        if self.config.peers.is_empty() {
+            return Ok(());
+        }
+        let endpoint = self.config.peers[self.random() as usize % self.config.peers.len()];
+        let tekrp = self.system_params.tek_rolling_period;
+        let valid_from = self.random_valid_from();
+        let mut shared_encounter_times = ExposureTimeSet::new();
+        shared_encounter_times.insert(ExposureTime::from(
+            u32::from(valid_from) + (self.random() % u64::from(u32::from(tekrp))) as u32,
+        ));
+        let params = ForwardParams::new(
+            self.computation
+                .as_ref()
+                .map(|(computation_id, _)| *computation_id)
+                .unwrap_or_default(),
+            valid_from,
+            tekrp,
+            self.random_tek()?,
+            shared_encounter_times,
+        );
+        logger::debug!("Sending cover forward to {:?}", endpoint);
+        let successor_seed =
+            Seed::new(&self.secure_random).context("Error generating random seed")?;
+        self.router
+            .forward(endpoint, &successor_seed, params)
+            .await
+            .map(|_| ())
+    }
+    async fn cover_blacklist_upload(&mut self) -> Result<()> {
+        if !self.uploads {
+            return Ok(());
+        }
+        let mut diagnosis_keys = self
+            .valid_froms
+            .iter()
+            .map(|valid_from| {
+                self.random_tek().map(|tek| {
+                    Validity::new(*valid_from, self.system_params.tek_rolling_period, tek)
+                })
+            })
+            .collect::<Result<HashSet<_>>>()?;
+        rpcs::pad_diagnosis_keys(
+            &mut diagnosis_keys,
+            &self.system_params,
+            &self.secure_random,
+        )
+        .context("Error padding diagnosis keys with decoys")?;
+        logger::debug!("Sending cover blacklist upload");
+        let verification_token = self
+            .diagnosis_server
+            .verify(
+                context::current(),
+                VerifyParams {
+                    code: VerificationCode::new(&self.secure_random)
+                        .context("Error generating verification code")?,
+                    key_commitment: KeyCommitment::new(&diagnosis_keys),
+                },
+            )
+            .await??;
+        let response = self
+            .diagnosis_server
+            .blacklist_upload(
+                context::current(),
+                BlacklistUploadParams {
+                    diagnosis_keys,
+                    verification_token: Some(verification_token),
+                },
+            )
+            .await??;
+        self.computation = Some((response.computation_id, response.greylist_secret));
+        Ok(())
+    }
+    async fn cover_greylist_upload(&mut self) -> Result<()> {
+        let (computation_id, greylist_secret) = match &self.computation {
+            Some((computation_id, greylist_secret)) if self.uploads => {
+                (*computation_id, greylist_secret)
+            }
+            _ => return self.cover_blacklist_upload().await,
+        };
+        let mut diagnosis_keys = HashSet::with_capacity(1);
+        diagnosis_keys.insert(Validity::new(
+            self.random_valid_from(),
+            self.system_params.tek_rolling_period,
+            self.random_tek()?,
+        ));
+        let params = GreylistUploadParams::new(computation_id, diagnosis_keys, greylist_secret);
+        logger::debug!("Sending cover greylist upload");
+        let result = self
+            .diagnosis_server
+            .greylist_upload(context::current(), params)
+            .await?;
+        // like a genuine one the fake computation closes eventually
+        if let Err(RequestError::ComputationClosed { .. }) = result {
+            self.computation = None;
+        }
+        Ok(result?)
+    }
+    fn random_valid_from(&self) -> ExposureTime {
+        self.valid_froms[self.random() as usize % self.valid_froms.len()]
+    }
+    fn random_tek(&self) -> Result<TemporaryExposureKey> {
+        TemporaryExposureKey::new(&self.secure_random).context("Error generating random TEK")
+    }
+    fn random(&self) -> u64 {
+        let mut random = [0u8; 8];
+        self.secure_random
+            .fill(&mut random)
+            .expect("Generating randomness failed");
+        u64::from_le_bytes(random)
+    }
+}
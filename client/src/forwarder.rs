@@ -0,0 +1,207 @@
+use crate::queue::Outcome;
+use anyhow::{Context, Result};
+use exposurelib::logger;
+use exposurelib::mailbox::MailboxAddress;
+use exposurelib::primitives::{Seed, SystemRandom};
+use exposurelib::rpcs::{self, v1, Deposit, Features, ForwardParams, Handshake, RelayParams};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tarpc::serde_transport::Transport;
+use tarpc::{client, context, tokio_serde::formats};
+use tokio::net::TcpStream;
+
+/// Routes forwards to successors directly or via the relay and deposits
+/// them at the diagnosis server if the successor's listener is unreachable.
+pub struct ForwardRouter {
+    relay: Option<SocketAddr>,
+    max_frame_length: usize,
+    mailbox: Option<Arc<rpcs::DiagnosisServerClient>>,
+}
+
+impl ForwardRouter {
+    pub fn new(
+        relay: Option<SocketAddr>,
+        max_frame_length: usize,
+        mailbox: Option<Arc<rpcs::DiagnosisServerClient>>,
+    ) -> Self {
+        Self {
+            relay,
+            max_frame_length,
+            mailbox,
+        }
+    }
+    /// Deposits at the diagnosis server only if it offers the mailbox.
+    pub fn negotiated(
+        relay: Option<SocketAddr>,
+        max_frame_length: usize,
+        diagnosis_server: &Arc<rpcs::DiagnosisServerClient>,
+        features: Features,
+    ) -> Self {
+        let mailbox = if features.contains(Features::MAILBOX) {
+            Some(Arc::clone(diagnosis_server))
+        } else {
+            None
+        };
+        Self::new(relay, max_frame_length, mailbox)
+    }
+    /// Tells whether the forward reached the successor's listener or was
+    /// only handed to the relay or the mailbox.
+    pub async fn forward(
+        &self,
+        endpoint: SocketAddr,
+        successor_seed: &Seed,
+        params: ForwardParams,
+    ) -> Result<Outcome> {
+        let client =
+            match VersionedForwarder::connect(endpoint, self.relay, self.max_frame_length).await {
+                Ok(client) => client,
+                Err(e) => match &self.mailbox {
+                    Some(mailbox) => {
+                        logger::info!(
+                            "Depositing forward for unreachable successor at {:?}: {:?}",
+                            endpoint,
+                            e
+                        );
+                        return Self::deposit(mailbox, successor_seed, &params)
+                            .await
+                            .map(|_| Outcome::Deposited);
+                    }
+                    None => return Err(e),
+                },
+            };
+        client.forward(params).await
+    }
+    async fn deposit(
+        mailbox: &rpcs::DiagnosisServerClient,
+        successor_seed: &Seed,
+        params: &ForwardParams,
+    ) -> Result<()> {
+        let address =
+            MailboxAddress::new(successor_seed).context("Error deriving mailbox address")?;
+        let sealed = address
+            .seal(params, &SystemRandom::new())
+            .context("Error sealing forward")?;
+        mailbox
+            .deposit(
+                context::current(),
+                Deposit {
+                    token: address.token(),
+                    sealed,
+                },
+            )
+            .await?
+            .context("Diagnosis server rejected deposit")
+    }
+}
+
+/// Client of another participant's listener speaking the newest protocol
+/// version both support or of a relay delivering to that listener.
+pub enum VersionedForwarder {
+    Current(rpcs::ForwarderClient),
+    V1(v1::ForwarderClient),
+    Relayed {
+        relay: rpcs::RelayClient,
+        destination: SocketAddr,
+    },
+}
+
+impl VersionedForwarder {
+    // version 1 listeners drop the connection on the unknown handshake right away
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub async fn connect(
+        endpoint: SocketAddr,
+        relay: Option<SocketAddr>,
+        max_frame_length: usize,
+    ) -> Result<Self> {
+        match relay {
+            Some(relay) => Self::connect_relay(relay, endpoint, max_frame_length).await,
+            None => Self::connect_direct(endpoint, max_frame_length).await,
+        }
+    }
+    async fn connect_relay(
+        relay: SocketAddr,
+        destination: SocketAddr,
+        max_frame_length: usize,
+    ) -> Result<Self> {
+        let transport = Self::transport(relay, max_frame_length).await?;
+        let relay = rpcs::RelayClient::new(client::Config::default(), transport)
+            .spawn()
+            .context("Error spawning relay client")?;
+        Ok(Self::Relayed { relay, destination })
+    }
+    async fn connect_direct(endpoint: SocketAddr, max_frame_length: usize) -> Result<Self> {
+        let transport = Self::transport(endpoint, max_frame_length).await?;
+        let client = rpcs::ForwarderClient::new(client::Config::default(), transport)
+            .spawn()
+            .context("Error spawning forwarder client")?;
+        let mut context = context::current();
+        context.deadline = SystemTime::now() + Self::HANDSHAKE_TIMEOUT;
+        match client
+            .handshake(context, Handshake::new(Features::NONE))
+            .await
+        {
+            Ok(negotiated) => {
+                let negotiated = negotiated
+                    .context(format!("Incompatible protocol of client at {:?}", endpoint))?;
+                logger::debug!("Negotiated {:?} with client at {:?}", negotiated, endpoint);
+                Ok(Self::Current(client))
+            }
+            Err(e) => {
+                logger::debug!(
+                    "Handshake with client at {:?} failed ({}), falling back to protocol version 1",
+                    endpoint,
+                    e
+                );
+                let transport = Self::transport(endpoint, max_frame_length).await?;
+                let client = v1::ForwarderClient::new(client::Config::default(), transport)
+                    .spawn()
+                    .context("Error spawning version 1 forwarder client")?;
+                Ok(Self::V1(client))
+            }
+        }
+    }
+    pub async fn forward(&self, params: ForwardParams) -> Result<Outcome> {
+        match self {
+            Self::Current(client) => client
+                .forward(context::current(), params)
+                .await?
+                .map(|_| Outcome::Delivered)
+                .map_err(anyhow::Error::from),
+            Self::V1(client) => {
+                client.forward(context::current(), params.into()).await?;
+                Ok(Outcome::Delivered)
+            }
+            // the relay only confirms the acceptance, not the delivery
+            Self::Relayed { relay, destination } => relay
+                .relay(
+                    context::current(),
+                    RelayParams {
+                        destination: *destination,
+                        forward: params,
+                    },
+                )
+                .await?
+                .map(|_| Outcome::Relayed)
+                .map_err(anyhow::Error::from),
+        }
+    }
+    async fn transport<Item, SinkItem>(
+        endpoint: SocketAddr,
+        max_frame_length: usize,
+    ) -> Result<Transport<TcpStream, Item, SinkItem, formats::Bincode<Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let mut transport =
+            tarpc::serde_transport::tcp::connect(&endpoint, formats::Bincode::default);
+        transport.config_mut().max_frame_length(max_frame_length);
+        transport.await.context(format!(
+            "Error creating TCP Bincode connect with {:?}",
+            endpoint
+        ))
+    }
+}
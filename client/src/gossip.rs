@@ -0,0 +1,454 @@
+use anyhow::{anyhow, Context, Result};
+use exposurelib::config::{GossipConfig, Limits};
+use exposurelib::logger;
+use exposurelib::rpcs::{self, ConsistencyParams, Gossip};
+use exposurelib::signing::PublicKey;
+use exposurelib::transparency::SignedTreeHead;
+use futures::{future, prelude::*};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tarpc::server::{self, Channel, Incoming};
+use tarpc::tokio_serde::formats;
+use tarpc::{client, context};
+use tokio::sync::Mutex;
+use tokio::task;
+use tokio::time;
+
+/// Keeps the latest tree head of the transparency log the participant
+/// observed and checks every further one, whether downloaded or gossiped,
+/// for consistency with it. Any inconsistency means the diagnosis server
+/// shows different participants different chunks, i.e., a split view.
+pub struct TreeHeadMonitor {
+    diagnosis_server: Arc<rpcs::DiagnosisServerClient>,
+    public_key: Option<PublicKey>,
+    latest: Mutex<Option<SignedTreeHead>>,
+}
+
+impl TreeHeadMonitor {
+    pub fn new(
+        diagnosis_server: Arc<rpcs::DiagnosisServerClient>,
+        public_key: Option<PublicKey>,
+    ) -> Self {
+        Self {
+            diagnosis_server,
+            public_key,
+            latest: Mutex::new(None),
+        }
+    }
+    pub async fn latest(&self) -> Option<SignedTreeHead> {
+        self.latest.lock().await.clone()
+    }
+    /// Fails if the tree head is not signed with the pinned key or if it is
+    /// inconsistent with the latest one, the latter is logged as a security
+    /// event. Fails with the context `ProofUnavailable` if the consistency
+    /// proof could not be retrieved.
+    pub async fn observe(&self, tree_head: SignedTreeHead, source: &str) -> Result<()> {
+        if let Some(public_key) = &self.public_key {
+            tree_head
+                .verify(public_key)
+                .context(format!("Invalid tree head from {}", source))?;
+        }
+        let mut latest = self.latest.lock().await;
+        if let Some(latest) = latest.as_ref() {
+            let (old, new) = if latest.tree_size <= tree_head.tree_size {
+                (latest, &tree_head)
+            } else {
+                (&tree_head, latest)
+            };
+            if old.conflicts_with(new) {
+                return Err(split_view(old, new, source));
+            }
+            if old.tree_size < new.tree_size {
+                let proof = match self
+                    .diagnosis_server
+                    .consistency(
+                        context::current(),
+                        ConsistencyParams {
+                            old_size: old.tree_size,
+                            new_size: new.tree_size,
+                        },
+                    )
+                    .await
+                {
+                    Ok(Ok(proof)) => proof,
+                    Ok(Err(e)) => return Err(anyhow::Error::new(e).context(ProofUnavailable)),
+                    Err(e) => return Err(anyhow::Error::new(e).context(ProofUnavailable)),
+                };
+                if proof.old_size != old.tree_size
+                    || proof.new_size != new.tree_size
+                    || !proof.verify(&old.root_hash, &new.root_hash)
+                {
+                    return Err(split_view(old, new, source));
+                }
+            }
+        }
+        // None is smaller than any tree size
+        if latest.as_ref().map(|latest| latest.tree_size) < Some(tree_head.tree_size) {
+            logger::debug!("Observed new {:?} from {}", tree_head, source);
+            *latest = Some(tree_head);
+        }
+        Ok(())
+    }
+}
+
+/// Retrieving a consistency proof failed, e.g., as the request was rate
+/// limited. Unlike a proof failing verification this is no evidence of a
+/// split view, hence the tree head may be observed again later.
+#[derive(Debug)]
+pub struct ProofUnavailable;
+
+impl fmt::Display for ProofUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Diagnosis server handed out no consistency proof")
+    }
+}
+
+impl ProofUnavailable {
+    pub fn is_cause_of(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<Self>().is_some()
+    }
+}
+
+fn split_view(old: &SignedTreeHead, new: &SignedTreeHead, source: &str) -> anyhow::Error {
+    logger::error!(
+        "Security event: diagnosis server shows a split view, {:?} from {} is inconsistent with {:?}",
+        if source == "diagnosis server" { new } else { old },
+        source,
+        if source == "diagnosis server" { old } else { new }
+    );
+    anyhow!("Split view of the diagnosis server detected")
+}
+
+/// Serves the own latest tree head and periodically exchanges it with a
+/// random peer. Only started with a pinned diagnosis server key, as peers
+/// could otherwise hand out tree heads of their own making.
+pub struct Gossiper {
+    config: GossipConfig,
+    limits: Limits,
+    monitor: Arc<TreeHeadMonitor>,
+    secure_random: SystemRandom,
+}
+
+impl Gossiper {
+    pub fn new(
+        config: GossipConfig,
+        limits: Limits,
+        monitor: Arc<TreeHeadMonitor>,
+    ) -> Self {
+        Self {
+            config,
+            limits,
+            monitor,
+            secure_random: SystemRandom::new(),
+        }
+    }
+    pub async fn run(self) -> ! {
+        if let Some(endpoint) = self.config.endpoint {
+            let limits = self.limits;
+            let monitor = Arc::clone(&self.monitor);
+            task::spawn(async move {
+                if let Err(e) = serve(endpoint, limits, monitor).await {
+                    logger::error!("Gossip endpoint at {} failed: {:?}", endpoint, e);
+                }
+            });
+        }
+        loop {
+            time::sleep(self.config.period).await;
+            if self.config.peers.is_empty() {
+                continue;
+            }
+            let peer = self.config.peers[self.random() as usize % self.config.peers.len()];
+            if let Err(e) = self.exchange(peer).await {
+                logger::warn!("Gossiping with {:?} failed: {:?}", peer, e);
+            }
+        }
+    }
+    async fn exchange(&self, peer: SocketAddr) -> Result<()> {
+        let mut transport = tarpc::serde_transport::tcp::connect(&peer, formats::Bincode::default);
+        transport
+            .config_mut()
+            .max_frame_length(self.limits.max_frame_length);
+        let transport = transport
+            .await
+            .context(format!("Error connecting to gossip peer at {:?}", peer))?;
+        let gossip = rpcs::GossipClient::new(client::Config::default(), transport)
+            .spawn()
+            .context("Error spawning gossip client")?;
+        let tree_head = gossip
+            .exchange(context::current(), self.monitor.latest().await)
+            .await?;
+        if let Some(tree_head) = tree_head {
+            self.monitor
+                .observe(tree_head, &format!("gossip peer {:?}", peer))
+                .await?;
+        }
+        Ok(())
+    }
+    fn random(&self) -> u64 {
+        let mut random = [0u8; 8];
+        self.secure_random
+            .fill(&mut random)
+            .expect("Generating randomness failed");
+        u64::from_le_bytes(random)
+    }
+}
+
+async fn serve(
+    endpoint: SocketAddr,
+    limits: Limits,
+    monitor: Arc<TreeHeadMonitor>,
+) -> Result<()> {
+    let mut listener = tarpc::serde_transport::tcp::listen(&endpoint, formats::Bincode::default)
+        .await
+        .context("Error creating TCP Bincode listener")?;
+    listener
+        .config_mut()
+        .max_frame_length(limits.max_frame_length);
+    logger::info!("Serving tree heads to gossip peers at {:?}", endpoint);
+    listener
+        .filter_map(|r| future::ready(r.ok()))
+        .map(server::BaseChannel::with_defaults)
+        // peers which hung up already have no address and count as one
+        .max_channels_per_key(limits.max_channels_per_ip, |t| {
+            t.as_ref()
+                .peer_addr()
+                .map(|peer_addr| peer_addr.ip())
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        })
+        .filter_map(|channel| {
+            future::ready(match channel.as_ref().as_ref().peer_addr() {
+                Ok(peer_addr) => Some((peer_addr, channel)),
+                Err(e) => {
+                    logger::debug!("Dropping gossip connection without peer address: {}", e);
+                    None
+                }
+            })
+        })
+        .map(|(peer_addr, channel)| {
+            let server = GossipHandler {
+                peer_addr,
+                monitor: Arc::clone(&monitor),
+            };
+            channel.requests().execute(server.serve())
+        })
+        .buffer_unordered(100)
+        .for_each(|_| async {})
+        .await;
+    Ok(())
+}
+
+#[derive(Clone)]
+struct GossipHandler {
+    peer_addr: SocketAddr,
+    monitor: Arc<TreeHeadMonitor>,
+}
+
+#[tarpc::server]
+impl Gossip for GossipHandler {
+    async fn exchange(
+        self,
+        _: tarpc::context::Context,
+        tree_head: Option<SignedTreeHead>,
+    ) -> Option<SignedTreeHead> {
+        logger::trace!("New exchange() RPC from {:?}", self.peer_addr);
+        let latest = self.monitor.latest().await;
+        if let Some(tree_head) = tree_head {
+            // checked in the background as it may involve the diagnosis server
+            let source = format!("gossip peer {:?}", self.peer_addr);
+            let monitor = self.monitor;
+            task::spawn(async move {
+                if let Err(e) = monitor.observe(tree_head, &source).await {
+                    logger::warn!("Tree head from {} not accepted: {:?}", source, e);
+                }
+            });
+        }
+        latest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exposurelib::diagnosis_server_state::{Chunk, ChunkId, ChunkInfo};
+    use exposurelib::error::RequestError;
+    use exposurelib::rpcs::{
+        BlacklistUploadParams, BlacklistUploadResponse, ChunkPage, Deposit, DiagnosisServer,
+        DownloadParams, FetchParams, GreylistUploadParams, Handshake, RevokeParams, VerifyParams,
+    };
+    use exposurelib::signing::SigningKey;
+    use exposurelib::time::TimeInterval;
+    use exposurelib::transparency::{ConsistencyProof, TransparencyLog, TreeHash};
+    use exposurelib::verification::VerificationToken;
+    use tarpc::context::Context;
+
+    /// Diagnosis server which only answers consistency() from its log.
+    #[derive(Clone)]
+    struct LogServer(Arc<TransparencyLog>);
+
+    #[tarpc::server]
+    impl DiagnosisServer for LogServer {
+        async fn blacklist_upload(
+            self,
+            _: Context,
+            _: BlacklistUploadParams,
+        ) -> Result<BlacklistUploadResponse, RequestError> {
+            unreachable!()
+        }
+        async fn greylist_upload(
+            self,
+            _: Context,
+            _: GreylistUploadParams,
+        ) -> Result<(), RequestError> {
+            unreachable!()
+        }
+        async fn download(self, _: Context, _: DownloadParams) -> ChunkPage {
+            unreachable!()
+        }
+        async fn subscribe(self, _: Context, _: DownloadParams) -> ChunkPage {
+            unreachable!()
+        }
+        async fn index(self, _: Context) -> Vec<ChunkInfo> {
+            unreachable!()
+        }
+        async fn handshake(self, _: Context, _: Handshake) -> Result<Handshake, RequestError> {
+            unreachable!()
+        }
+        async fn verify(
+            self,
+            _: Context,
+            _: VerifyParams,
+        ) -> Result<VerificationToken, RequestError> {
+            unreachable!()
+        }
+        async fn deposit(self, _: Context, _: Deposit) -> Result<(), RequestError> {
+            unreachable!()
+        }
+        async fn fetch(self, _: Context, _: FetchParams) -> Result<Vec<Deposit>, RequestError> {
+            unreachable!()
+        }
+        async fn revoke(self, _: Context, _: RevokeParams) -> Result<(), RequestError> {
+            unreachable!()
+        }
+        async fn tree_head(self, _: Context) -> Result<SignedTreeHead, RequestError> {
+            unreachable!()
+        }
+        async fn consistency(
+            self,
+            _: Context,
+            params: ConsistencyParams,
+        ) -> Result<ConsistencyProof, RequestError> {
+            self.0
+                .consistency_proof(params.old_size, params.new_size)
+                .ok_or(RequestError::InvalidTreeSize {
+                    old_size: params.old_size,
+                    new_size: params.new_size,
+                    tree_size: self.0.size(),
+                })
+        }
+    }
+
+    fn leaves(ids: std::ops::Range<u64>, fork: bool) -> Vec<TreeHash> {
+        ids.map(|id| {
+            let mut chunk = Chunk::new(
+                ChunkId::from(id),
+                TimeInterval::with_alignment(chrono::Duration::minutes(1)),
+            );
+            if fork {
+                chunk.revoke(Default::default());
+            }
+            TreeHash::leaf(&chunk)
+        })
+        .collect()
+    }
+
+    fn monitor(log: TransparencyLog, public_key: PublicKey) -> TreeHeadMonitor {
+        let (client_transport, server_transport) = tarpc::transport::channel::unbounded();
+        let server = server::BaseChannel::with_defaults(server_transport)
+            .requests()
+            .execute(LogServer(Arc::new(log)).serve());
+        task::spawn(server);
+        let diagnosis_server =
+            rpcs::DiagnosisServerClient::new(client::Config::default(), client_transport)
+                .spawn()
+                .unwrap();
+        TreeHeadMonitor::new(Arc::new(diagnosis_server), Some(public_key))
+    }
+
+    #[tokio::test]
+    async fn test_split_view() {
+        let signing_key = SigningKey::generate(&SystemRandom::new()).unwrap();
+        let key_pair = signing_key.key_pair().unwrap();
+        let server_log = TransparencyLog::with_leaves(leaves(0..8, false));
+        // shares the first four chunks with the log of the server
+        let forked = TransparencyLog::with_leaves(
+            leaves(0..4, false)
+                .into_iter()
+                .chain(leaves(4..8, true))
+                .collect(),
+        );
+        let head = |log: &TransparencyLog, tree_size| {
+            SignedTreeHead::new(
+                tree_size,
+                log.root_hash(tree_size).unwrap(),
+                Some(&key_pair),
+            )
+        };
+        let monitor = monitor(
+            TransparencyLog::with_leaves(leaves(0..8, false)),
+            signing_key.public_key().unwrap(),
+        );
+
+        monitor.observe(head(&forked, 3), "test").await.unwrap();
+        monitor.observe(head(&forked, 4), "test").await.unwrap();
+        // the server proves the consistency of its own log
+        monitor.observe(head(&server_log, 6), "test").await.unwrap();
+        assert_eq!(monitor.latest().await.unwrap().tree_size, 6);
+        // an older head is checked against the latest one as well
+        monitor.observe(head(&server_log, 5), "test").await.unwrap();
+
+        // same size, different root
+        assert!(monitor.observe(head(&forked, 6), "test").await.is_err());
+        // the forked log does not extend the latest head
+        let e = monitor.observe(head(&forked, 7), "test").await.unwrap_err();
+        assert!(!ProofUnavailable::is_cause_of(&e));
+        assert!(monitor.observe(head(&forked, 5), "test").await.is_err());
+        assert_eq!(monitor.latest().await.unwrap().tree_size, 6);
+
+        // heads not signed with the pinned key are rejected right away
+        let unsigned = SignedTreeHead::new(8, server_log.root_hash(8).unwrap(), None);
+        assert!(monitor.observe(unsigned, "test").await.is_err());
+        monitor.observe(head(&server_log, 8), "test").await.unwrap();
+        assert_eq!(monitor.latest().await.unwrap().tree_size, 8);
+    }
+
+    #[tokio::test]
+    async fn test_proof_unavailable() {
+        let signing_key = SigningKey::generate(&SystemRandom::new()).unwrap();
+        let key_pair = signing_key.key_pair().unwrap();
+        let log = TransparencyLog::with_leaves(leaves(0..8, false));
+        let head = |tree_size| {
+            SignedTreeHead::new(
+                tree_size,
+                log.root_hash(tree_size).unwrap(),
+                Some(&key_pair),
+            )
+        };
+        // the diagnosis server is unreachable
+        let (client_transport, _) = tarpc::transport::channel::unbounded();
+        let diagnosis_server =
+            rpcs::DiagnosisServerClient::new(client::Config::default(), client_transport)
+                .spawn()
+                .unwrap();
+        let monitor = TreeHeadMonitor::new(
+            Arc::new(diagnosis_server),
+            Some(signing_key.public_key().unwrap()),
+        );
+
+        monitor.observe(head(4), "test").await.unwrap();
+        let e = monitor.observe(head(6), "test").await.unwrap_err();
+        assert!(ProofUnavailable::is_cause_of(&e));
+        assert_eq!(monitor.latest().await.unwrap().tree_size, 4);
+    }
+}
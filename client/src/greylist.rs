@@ -0,0 +1,194 @@
+use exposurelib::config::GreylistBatchingConfig;
+use exposurelib::logger;
+use exposurelib::primitives::{ComputationId, TemporaryExposureKey, Validity};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{HashMap, HashSet};
+use tokio::time::Instant;
+
+/// Collects the keys the origin greylists per computation and releases them
+/// for a single upload after a random delay, hence the upload time does not
+/// tell the length of the chains the keys returned through.
+pub struct GreylistBatcher {
+    config: GreylistBatchingConfig,
+    secure_random: SystemRandom,
+    batches: HashMap<ComputationId, Batch>,
+}
+
+struct Batch {
+    diagnosis_keys: HashSet<Validity<TemporaryExposureKey>>,
+    due: Instant,
+    closes_at: Instant,
+}
+
+impl GreylistBatcher {
+    pub fn new(config: GreylistBatchingConfig) -> Self {
+        Self {
+            config,
+            secure_random: SystemRandom::new(),
+            batches: HashMap::new(),
+        }
+    }
+    /// Adds the key to the pending batch of the computation or starts a new
+    /// one which is due at a random point before the computation closes.
+    pub fn add(
+        &mut self,
+        computation_id: ComputationId,
+        diagnosis_key: Validity<TemporaryExposureKey>,
+        closes_at: Instant,
+    ) {
+        if !self.batches.contains_key(&computation_id) {
+            let due = self.random_due(closes_at);
+            logger::debug!(
+                "Delaying greylist upload with {:?} by {:?}",
+                computation_id,
+                due.saturating_duration_since(Instant::now())
+            );
+            self.batches.insert(
+                computation_id,
+                Batch {
+                    diagnosis_keys: HashSet::new(),
+                    due,
+                    closes_at,
+                },
+            );
+        }
+        if let Some(batch) = self.batches.get_mut(&computation_id) {
+            batch.diagnosis_keys.insert(diagnosis_key);
+        }
+    }
+    pub fn next_due(&self) -> Option<Instant> {
+        self.batches.values().map(|batch| batch.due).min()
+    }
+    /// Releases the keys of all due batches. The batch of a computation which
+    /// is still open is kept with a newly drawn due time, such that keys
+    /// returning later are released at a point not depending on their arrival.
+    pub fn take_due(&mut self) -> Vec<(ComputationId, HashSet<Validity<TemporaryExposureKey>>)> {
+        let now = Instant::now();
+        let due: Vec<ComputationId> = self
+            .batches
+            .iter()
+            .filter(|(_, batch)| batch.due <= now)
+            .map(|(computation_id, _)| *computation_id)
+            .collect();
+        let mut taken = Vec::new();
+        for computation_id in due {
+            let batch = match self.batches.remove(&computation_id) {
+                Some(batch) => batch,
+                None => continue,
+            };
+            // an empty batch ends the schedule, a later key draws a new one
+            if batch.diagnosis_keys.is_empty() {
+                continue;
+            }
+            if now < batch.closes_at {
+                self.batches.insert(
+                    computation_id,
+                    Batch {
+                        diagnosis_keys: HashSet::new(),
+                        due: self.random_due(batch.closes_at),
+                        closes_at: batch.closes_at,
+                    },
+                );
+            }
+            taken.push((computation_id, batch.diagnosis_keys));
+        }
+        taken
+    }
+    /// Drops the pending batch of a closed or revoked computation.
+    pub fn discard(&mut self, computation_id: ComputationId) {
+        if let Some(batch) = self.batches.remove(&computation_id) {
+            if !batch.diagnosis_keys.is_empty() {
+                logger::warn!(
+                    "Discarding {} greylist keys of computation with {:?}",
+                    batch.diagnosis_keys.len(),
+                    computation_id
+                );
+            }
+        }
+    }
+    /// Uniformly distributed in [now, closes_at), but at most `max_delay`
+    /// from now.
+    fn random_due(&self, closes_at: Instant) -> Instant {
+        let now = Instant::now();
+        let max_delay = self
+            .config
+            .max_delay
+            .min(closes_at.saturating_duration_since(now));
+        let mut random = [0u8; 8];
+        self.secure_random
+            .fill(&mut random)
+            .expect("Generating randomness failed");
+        let uniform = (u64::from_le_bytes(random) >> 11) as f64 / (1u64 << 53) as f64;
+        now + max_delay.mul_f64(uniform)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exposurelib::primitives::{SystemRandom, TekRollingPeriod};
+    use exposurelib::time::ExposureTime;
+    use std::time::Duration;
+
+    fn diagnosis_key() -> Validity<TemporaryExposureKey> {
+        Validity::new(
+            ExposureTime::from(0),
+            TekRollingPeriod::default(),
+            TemporaryExposureKey::new(&SystemRandom::new()).unwrap(),
+        )
+    }
+
+    fn batcher(max_delay: Duration) -> GreylistBatcher {
+        GreylistBatcher::new(GreylistBatchingConfig { max_delay })
+    }
+
+    #[test]
+    fn test_due_before_closure() {
+        let mut batcher = batcher(Duration::from_secs(3600));
+        let closes_at = Instant::now() + Duration::from_secs(60);
+        let mut dues = Vec::new();
+        for id in 0..64 {
+            batcher.add(ComputationId::from(id), diagnosis_key(), closes_at);
+            dues.push(batcher.batches[&ComputationId::from(id)].due);
+        }
+        assert!(dues.iter().all(|due| *due < closes_at));
+        // not all clustered at the closure
+        dues.sort();
+        dues.dedup();
+        assert!(dues.len() > 1);
+    }
+
+    #[test]
+    fn test_late_keys() {
+        let mut batcher = batcher(Duration::from_secs(0));
+        let computation_id = ComputationId::from(0);
+        let closes_at = Instant::now() + Duration::from_secs(3600);
+        batcher.add(computation_id, diagnosis_key(), closes_at);
+        batcher.add(computation_id, diagnosis_key(), closes_at);
+        let taken = batcher.take_due();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].1.len(), 2);
+
+        // late keys join the kept batch instead of drawing their own due time
+        let due = batcher.next_due().unwrap();
+        batcher.add(computation_id, diagnosis_key(), closes_at);
+        batcher.add(computation_id, diagnosis_key(), closes_at);
+        assert_eq!(batcher.next_due(), Some(due));
+        let taken = batcher.take_due();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].1.len(), 2);
+
+        // nothing returned in the meantime
+        assert!(batcher.take_due().is_empty());
+        assert!(batcher.next_due().is_none());
+    }
+
+    #[test]
+    fn test_closed() {
+        let mut batcher = batcher(Duration::from_secs(3600));
+        let computation_id = ComputationId::from(0);
+        batcher.add(computation_id, diagnosis_key(), Instant::now());
+        assert_eq!(batcher.take_due().len(), 1);
+        assert!(batcher.next_due().is_none());
+    }
+}
@@ -1,7 +1,9 @@
 use crate::state::Event;
 use anyhow::{Context, Result};
+use exposurelib::config::SystemParams;
+use exposurelib::error::RequestError;
 use exposurelib::logger;
-use exposurelib::rpcs::{ForwardParams, Forwarder};
+use exposurelib::rpcs::{v1, Features, ForwardParams, Forwarder, Handshake};
 use futures::{future, prelude::*};
 use std::net::SocketAddr;
 use std::time::Duration;
@@ -9,10 +11,12 @@ use tarpc::server::{self, Channel, Incoming};
 use tarpc::tokio_serde::formats;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::task;
 use tokio::time;
 
 pub struct Listener {
     address: SocketAddr,
+    params: SystemParams,
     requests: mpsc::Receiver<Duration>,
     client_state: mpsc::Sender<Event>,
 }
@@ -20,11 +24,13 @@ pub struct Listener {
 impl Listener {
     pub fn new(
         address: SocketAddr,
+        params: SystemParams,
         requests: mpsc::Receiver<Duration>,
         client_state: mpsc::Sender<Event>,
     ) -> Self {
         Self {
             address,
+            params,
             requests,
             client_state,
         }
@@ -33,10 +39,11 @@ impl Listener {
         loop {
             let duration = self.requests.recv().await.unwrap();
             let address = self.address;
+            let params = self.params;
             let client_state = self.client_state.clone();
             let listener_result = tokio::select! {
                 _ = self.timeout(duration) => Ok(()),
-                result = Self::listen(address, client_state) => result,
+                result = Self::listen(address, params, client_state) => result,
             };
             if let Err(e) = listener_result {
                 logger::warn!("Error spawning listener: {}", e);
@@ -59,18 +66,24 @@ impl Listener {
                     continue;
                 }
                 None => {
+                    // warnings are evaluated once the diagnosis server closes the computations
                     logger::info!("Stopping to listen for forwardable TEKs");
-                    self.client_state.send(Event::ComputationPeriodExpired).await.unwrap();
                     break;
                 }
             }
         }
     }
-    pub async fn listen(address: SocketAddr, client_state: mpsc::Sender<Event>) -> Result<()> {
+    pub async fn listen(
+        address: SocketAddr,
+        params: SystemParams,
+        client_state: mpsc::Sender<Event>,
+    ) -> Result<()> {
         let mut listener = tarpc::serde_transport::tcp::listen(&address, formats::Bincode::default)
             .await
             .context("Error creating TCP Bincode listener")?;
-        listener.config_mut().max_frame_length(usize::MAX);
+        listener
+            .config_mut()
+            .max_frame_length(params.limits.max_frame_length);
 
         logger::info!("Starting to listen for forwardable TEKs at {:?}", address);
 
@@ -78,13 +91,15 @@ impl Listener {
             // ignore accept errors
             .filter_map(|r| future::ready(r.ok()))
             .map(server::BaseChannel::with_defaults)
-            // just one channel per *ip/port combo* (instead of per ip) in our simulation case
-            .max_channels_per_key(1, |t| t.as_ref().peer_addr().unwrap())
+            .max_channels_per_key(params.limits.max_channels_per_ip, |t| {
+                t.as_ref().peer_addr().unwrap().ip()
+            })
             // function serve() is generated by the service attribute
             // it takes as input any type implementing the generated service trait
             .map(|channel| {
                 let server = Handler::new(
                     channel.as_ref().as_ref().peer_addr().unwrap(),
+                    params,
                     client_state.clone(),
                 );
                 channel.requests().execute(server.serve())
@@ -101,13 +116,19 @@ impl Listener {
 #[derive(Clone)]
 struct Handler {
     peer_addr: SocketAddr,
+    params: SystemParams,
     client_state: mpsc::Sender<Event>,
 }
 
 impl Handler {
-    pub fn new(peer_addr: SocketAddr, client_state: mpsc::Sender<Event>) -> Self {
+    pub fn new(
+        peer_addr: SocketAddr,
+        params: SystemParams,
+        client_state: mpsc::Sender<Event>,
+    ) -> Self {
         Self {
             peer_addr,
+            params,
             client_state,
         }
     }
@@ -115,20 +136,55 @@ impl Handler {
 
 #[tarpc::server]
 impl Forwarder for Handler {
-    async fn forward(self, context: tarpc::context::Context, params: ForwardParams) -> () {
+    async fn legacy_forward(self, context: tarpc::context::Context, params: v1::ForwardParams) {
+        // rejections are logged by forward() and cannot be sent to version 1 peers
+        let _ = self.forward(context, params.into()).await;
+    }
+    async fn forward(
+        self,
+        context: tarpc::context::Context,
+        params: ForwardParams,
+    ) -> Result<(), RequestError> {
         logger::trace!(
             "New forward() RPC from {:?} with context {:?} and params {:?}",
             self.peer_addr,
             context,
             params
         );
+        if let Err(e) = params.validate(&self.params) {
+            logger::warn!("Rejecting forward() RPC from {:?}: {}", self.peer_addr, e);
+            return Err(e);
+        }
         let (tx, rx) = oneshot::channel();
         self.client_state
             .send(Event::NewForwardRequest { params, resp: tx })
             .await
             .unwrap();
-        if let Err(e) = rx.await {
-            logger::warn!("Error while forwarding TEK: {:?}", e);
-        }
+        // acknowledged before the forward is processed, hence the response time
+        // does not tell whether the chain continues here or the forward is dropped
+        task::spawn(async move {
+            if let Err(e) = rx.await {
+                logger::warn!("Error while forwarding TEK: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+    async fn handshake(
+        self,
+        context: tarpc::context::Context,
+        params: Handshake,
+    ) -> Result<Handshake, RequestError> {
+        logger::trace!(
+            "New handshake() RPC from {:?} with context {:?} and params {:?}",
+            self.peer_addr,
+            context,
+            params
+        );
+        Handshake::new(Features::NONE)
+            .negotiate(&params)
+            .map_err(|e| {
+                logger::warn!("Rejecting handshake() RPC from {:?}: {}", self.peer_addr, e);
+                e
+            })
     }
 }
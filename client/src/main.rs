@@ -1,21 +1,32 @@
+mod cover;
+mod forwarder;
+mod gossip;
+mod greylist;
 mod listener;
+mod metrics;
+mod queue;
 mod state;
 mod updater;
 use anyhow::{Context, Result};
-use chrono::prelude::*;
+use cover::CoverTraffic;
 use exposurelib::args::{crate_authors, crate_description, crate_name, crate_version, Args};
 use exposurelib::config::ClientConfig;
 use exposurelib::logger;
+use exposurelib::mailbox::MailboxAddress;
 use exposurelib::rpcs;
+use forwarder::ForwardRouter;
+use gossip::{Gossiper, TreeHeadMonitor};
 use listener::Listener;
+use metrics::Metrics;
+use queue::ForwardQueue;
 use serde_yaml;
 use state::ClientState;
 use std::fs;
 use std::sync::Arc;
-use tarpc::{client, tokio_serde::formats};
+use tarpc::{client, context, tokio_serde::formats};
 use tokio::sync::mpsc;
 use tokio::task;
-use updater::Updater;
+use updater::{Updater, UpdaterConfig};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -41,7 +52,9 @@ async fn main() -> Result<()> {
         config.diagnosis_server_endpoint,
         formats::Bincode::default,
     );
-    transport.config_mut().max_frame_length(usize::MAX);
+    transport
+        .config_mut()
+        .max_frame_length(config.params.limits.max_frame_length);
     let transport = transport.await.context(format!(
         "Error creating TCP Bincode connect with diagnosis server at {:?}",
         config.diagnosis_server_endpoint,
@@ -52,28 +65,132 @@ async fn main() -> Result<()> {
             .context("Error spawning diagnosis server client")?,
     );
 
+    let negotiated = diagnosis_server_client
+        .handshake(
+            context::current(),
+            rpcs::Handshake::new(Updater::features()),
+        )
+        .await
+        .context("Error during handshake with diagnosis server")?
+        .context("Incompatible protocol of diagnosis server")?;
+    logger::debug!("Negotiated {:?} with diagnosis server", negotiated);
+
     let (state_tx, state_rx) = mpsc::channel::<state::Event>(100);
     let (listener_tx, listener_rx) = mpsc::channel::<std::time::Duration>(100);
 
-    let initial_from = Utc::now()
-        - config
-            .params
-            .infection_period
-            .as_duration(config.params.tek_rolling_period);
+    // forwards to own TEKRPs are deposited while the listener is offline
+    let mailbox = config
+        .state
+        .keys
+        .all()
+        .iter()
+        .map(|keyring| MailboxAddress::new(keyring.keyring().sd_keyring().sd()))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Error deriving mailbox addresses")?;
+
+    if config.diagnosis_server_public_key.is_none() {
+        logger::warn!("No diagnosis server key pinned, downloaded chunks are not verified");
+    }
+    let monitor = Arc::new(TreeHeadMonitor::new(
+        Arc::clone(&diagnosis_server_client),
+        config.diagnosis_server_public_key,
+    ));
+    let gossiper = if !negotiated.features.contains(rpcs::Features::TRANSPARENCY) {
+        None
+    } else if config.diagnosis_server_public_key.is_none() {
+        logger::warn!("Not gossiping tree heads as no diagnosis server key is pinned");
+        None
+    } else {
+        Some(Gossiper::new(
+            config.gossip.clone(),
+            config.params.limits,
+            Arc::clone(&monitor),
+        ))
+    };
     let updater = Updater::new(
         Arc::clone(&diagnosis_server_client),
-        config.params.refresh_period,
-        initial_from,
+        UpdaterConfig {
+            refresh_period: config.params.refresh_period,
+            chunk_encoding: config.chunk_encoding,
+            max_decoded_length: config.params.limits.max_frame_length,
+            public_key: config.diagnosis_server_public_key,
+            features: negotiated.features,
+            mailbox,
+        },
         state_tx.clone(),
+        monitor,
     );
 
-    let listener = Listener::new(config.client_endpoint, listener_rx, state_tx);
+    let cover_traffic = if config.cover_traffic.enabled {
+        Some(CoverTraffic::new(
+            config.cover_traffic.clone(),
+            config.params,
+            config
+                .state
+                .keys
+                .all_teks()
+                .iter()
+                .map(|tek| tek.valid_from())
+                .collect(),
+            Arc::clone(&diagnosis_server_client),
+            negotiated.features,
+            ForwardRouter::negotiated(
+                config.relay,
+                config.params.limits.max_frame_length,
+                &diagnosis_server_client,
+                negotiated.features,
+            ),
+        ))
+    } else {
+        None
+    };
+
+    let listener = Listener::new(config.client_endpoint, config.params, listener_rx, state_tx);
 
-    let state = ClientState::new(config, diagnosis_server_client, state_rx, listener_tx);
+    let metrics = Arc::new(Metrics::new());
+    if let Some(metrics_endpoint) = config.metrics_endpoint {
+        let registry = metrics.registry().clone();
+        task::spawn(async move {
+            if let Err(e) = exposurelib::metrics::serve(metrics_endpoint, registry).await {
+                logger::error!("Metrics endpoint at {} failed: {:?}", metrics_endpoint, e);
+            }
+        });
+    }
+
+    // undelivered forwards are kept next to the log for later analysis
+    let queue = ForwardQueue::load(
+        args.log_file_path.with_extension("queue.yaml"),
+        args.log_file_path.with_extension("outcomes.csv"),
+        ForwardRouter::negotiated(
+            config.relay,
+            config.params.limits.max_frame_length,
+            &diagnosis_server_client,
+            negotiated.features,
+        ),
+        config.params.computation_period.into(),
+        config.forward_queue.attempt_timeout,
+        Arc::clone(&metrics),
+    )?;
+
+    let state = ClientState::new(
+        config,
+        diagnosis_server_client,
+        negotiated.features,
+        queue,
+        state_rx,
+        listener_tx,
+        metrics,
+    );
 
     let state_handle = task::spawn(async move { state.run().await });
     let updater_handle = task::spawn(async move { updater.run().await });
     let listener_handle = task::spawn(async move { listener.run().await });
+    if let Some(cover_traffic) = cover_traffic {
+        task::spawn(async move { cover_traffic.run().await });
+    }
+    if let Some(gossiper) = gossiper {
+        task::spawn(async move { gossiper.run().await });
+    }
 
     state_handle.await.context("State panicked")?;
     updater_handle.await.context("Updater panicked")?;
@@ -0,0 +1,126 @@
+use crate::queue::Outcome;
+use exposurelib::diagnosis_server_state::ListType;
+use exposurelib::metrics::{self, IntCounter, IntCounterVec, Opts, Registry};
+
+/// Operational metrics of the client, which are collected regardless of
+/// whether the metrics endpoint is enabled.
+pub struct Metrics {
+    registry: Registry,
+    matches: IntCounterVec,
+    forwards_sent: IntCounterVec,
+    forwards_received: IntCounter,
+    forwards_dropped: IntCounterVec,
+    warnings: IntCounterVec,
+}
+
+/// Why a received forward is not passed on.
+#[derive(Debug, Copy, Clone)]
+pub enum DropReason {
+    NoMatch,
+    UnknownComputation,
+    RevokedComputation,
+    NotRedlisted,
+    NoSharedEncounter,
+    MissingGreylistSecret,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Warning {
+    LowRisk,
+    HighRisk,
+    Ssev,
+    Retracted,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("client")), None)
+            .expect("Creating the metrics registry failed");
+        Self {
+            matches: metrics::register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("matches_total", "Matched diagnosis keys by list"),
+                    &["list"],
+                )
+                .unwrap(),
+            ),
+            forwards_sent: metrics::register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("forwards_sent_total", "Outbound forwards by outcome"),
+                    &["outcome"],
+                )
+                .unwrap(),
+            ),
+            forwards_received: metrics::register(
+                &registry,
+                IntCounter::new("forwards_received_total", "Inbound forwards").unwrap(),
+            ),
+            forwards_dropped: metrics::register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "forwards_dropped_total",
+                        "Inbound forwards dropped by reason",
+                    ),
+                    &["reason"],
+                )
+                .unwrap(),
+            ),
+            warnings: metrics::register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("warnings_total", "Warnings shown by kind"),
+                    &["kind"],
+                )
+                .unwrap(),
+            ),
+            registry,
+        }
+    }
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+    pub fn matched(&self, list: ListType) {
+        let list = match list {
+            ListType::Blacklist => "blacklist",
+            ListType::Greylist => "greylist",
+        };
+        self.matches.with_label_values(&[list]).inc();
+    }
+    pub fn forward_sent(&self, outcome: Outcome) {
+        let outcome = match outcome {
+            Outcome::Delivered => "delivered",
+            Outcome::Relayed => "relayed",
+            Outcome::Deposited => "deposited",
+            Outcome::Rejected => "rejected",
+            Outcome::Expired => "expired",
+            Outcome::Revoked => "revoked",
+        };
+        self.forwards_sent.with_label_values(&[outcome]).inc();
+    }
+    pub fn forward_received(&self) {
+        self.forwards_received.inc();
+    }
+    pub fn forward_dropped(&self, reason: DropReason) {
+        let reason = match reason {
+            DropReason::NoMatch => "no_match",
+            DropReason::UnknownComputation => "unknown_computation",
+            DropReason::RevokedComputation => "revoked_computation",
+            DropReason::NotRedlisted => "not_redlisted",
+            DropReason::NoSharedEncounter => "no_shared_encounter",
+            DropReason::MissingGreylistSecret => "missing_greylist_secret",
+        };
+        self.forwards_dropped.with_label_values(&[reason]).inc();
+    }
+    pub fn warned(&self, warning: Warning) {
+        let kind = match warning {
+            Warning::LowRisk => "low_risk",
+            Warning::HighRisk => "high_risk",
+            Warning::Ssev => "ssev",
+            Warning::Retracted => "retracted",
+        };
+        self.warnings.with_label_values(&[kind]).inc();
+    }
+}
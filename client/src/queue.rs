@@ -0,0 +1,528 @@
+use crate::forwarder::ForwardRouter;
+use crate::metrics::Metrics;
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use chrono::Duration;
+use exposurelib::error::RequestError;
+use exposurelib::logger;
+use exposurelib::primitives::{ComputationId, Seed};
+use exposurelib::rpcs::ForwardParams;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::{task, time};
+
+/// Outbound forwards per computation which could not be delivered yet.
+/// Pending forwards survive restarts in `path`, the outcome of each forward
+/// is appended to `outcomes` as a line of comma separated values.
+/// Attempts run in their own tasks, their results are handed back through
+/// `settled()` so that a slow successor never stalls the client state.
+pub struct ForwardQueue {
+    path: PathBuf,
+    outcomes: PathBuf,
+    router: Arc<ForwardRouter>,
+    computation_period: Duration,
+    attempt_timeout: std::time::Duration,
+    // latest possible end of the computations seen in a chunk
+    closes_at: HashMap<ComputationId, DateTime<Utc>>,
+    pending: HashMap<ComputationId, Vec<PendingForward>>,
+    next_id: u64,
+    settled_tx: mpsc::UnboundedSender<Attempt>,
+    settled_rx: mpsc::UnboundedReceiver<Attempt>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingForward {
+    #[serde(skip)]
+    id: u64,
+    endpoint: SocketAddr,
+    successor_seed: Seed,
+    params: ForwardParams,
+    expires_at: DateTime<Utc>,
+    attempts: u32,
+    #[serde(skip)]
+    in_flight: bool,
+}
+
+/// Result of a single attempt, `None` if the forward should be retried.
+#[derive(Debug)]
+pub struct Attempt {
+    id: u64,
+    computation_id: ComputationId,
+    outcome: Option<Outcome>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Accepted by the successor's listener.
+    Delivered,
+    /// Accepted by the relay, which does not report the delivery.
+    Relayed,
+    /// Deposited in the successor's mailbox at the diagnosis server.
+    Deposited,
+    Rejected,
+    Expired,
+    Revoked,
+}
+
+impl ForwardQueue {
+    /// Picks up the forwards left pending by a previous run.
+    pub fn load(
+        path: PathBuf,
+        outcomes: PathBuf,
+        router: ForwardRouter,
+        computation_period: Duration,
+        attempt_timeout: std::time::Duration,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let (settled_tx, settled_rx) = mpsc::unbounded_channel();
+        let mut queue = Self {
+            path,
+            outcomes,
+            router: Arc::new(router),
+            computation_period,
+            attempt_timeout,
+            closes_at: HashMap::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+            settled_tx,
+            settled_rx,
+            metrics,
+        };
+        if queue.path.exists() {
+            let pending = fs::read_to_string(&queue.path)
+                .context(format!("Error reading forward queue {:?}", queue.path))?;
+            let pending: Vec<PendingForward> = serde_yaml::from_str(&pending)
+                .context(format!("Error parsing forward queue {:?}", queue.path))?;
+            logger::info!("Restored {} pending forwards", pending.len());
+            for forward in pending {
+                queue.push(forward);
+            }
+        }
+        Ok(queue)
+    }
+    /// Bounds the end of a computation whose keys are in a chunk covering
+    /// up to `to_excluding`, as the computation started within or before it.
+    pub fn observe(&mut self, computation_id: ComputationId, to_excluding: DateTime<Utc>) {
+        let closes_at = to_excluding + self.computation_period;
+        self.closes_at
+            .entry(computation_id)
+            .and_modify(|known| *known = closes_at.min(*known))
+            .or_insert(closes_at);
+    }
+    /// Keeps the forward until it is delivered and tries it right away.
+    pub fn submit(&mut self, endpoint: SocketAddr, successor_seed: Seed, params: ForwardParams) {
+        let computation_id = params.computation_id();
+        // the computation started before the predecessor forwarded to us
+        let expires_at = Utc::now() + self.computation_period;
+        let expires_at = match self.closes_at.get(&computation_id) {
+            Some(closes_at) => expires_at.min(*closes_at),
+            None => expires_at,
+        };
+        let forward = PendingForward {
+            id: 0,
+            endpoint,
+            successor_seed,
+            params,
+            expires_at,
+            attempts: 0,
+            in_flight: false,
+        };
+        let id = self.push(forward);
+        self.persist();
+        self.dispatch(computation_id, id);
+    }
+    /// Attempts all pending forwards which are not in flight once more and
+    /// drops the expired ones.
+    pub fn retry(&mut self) {
+        let now = Utc::now();
+        let mut expired = Vec::new();
+        let mut due = Vec::new();
+        for (computation_id, forwards) in self.pending.iter_mut() {
+            let (gone, kept): (Vec<_>, Vec<_>) = forwards
+                .drain(..)
+                .partition(|forward| forward.expires_at <= now);
+            *forwards = kept;
+            expired.extend(gone);
+            due.extend(
+                forwards
+                    .iter()
+                    .filter(|forward| !forward.in_flight)
+                    .map(|forward| (*computation_id, forward.id)),
+            );
+        }
+        self.pending.retain(|_, forwards| !forwards.is_empty());
+        for forward in expired.iter() {
+            self.record(forward, Outcome::Expired);
+        }
+        if !expired.is_empty() {
+            self.persist();
+        }
+        for (computation_id, id) in due {
+            self.dispatch(computation_id, id);
+        }
+    }
+    /// Waits for the next attempt to finish.
+    pub async fn settled(&mut self) -> Attempt {
+        // the queue holds a sender itself, hence the channel never closes
+        self.settled_rx.recv().await.unwrap()
+    }
+    /// Records the outcome of a finished attempt or leaves the forward for
+    /// the next retry.
+    pub fn settle(&mut self, attempt: Attempt) {
+        let forwards = match self.pending.get_mut(&attempt.computation_id) {
+            Some(forwards) => forwards,
+            // expired or revoked while in flight
+            None => return,
+        };
+        let index = match forwards.iter().position(|forward| forward.id == attempt.id) {
+            Some(index) => index,
+            None => return,
+        };
+        match attempt.outcome {
+            Some(outcome) => {
+                let forward = forwards.remove(index);
+                if forwards.is_empty() {
+                    self.pending.remove(&attempt.computation_id);
+                }
+                self.record(&forward, outcome);
+                self.persist();
+            }
+            None => forwards[index].in_flight = false,
+        }
+    }
+    /// Gives up on the pending forwards of a closed computation.
+    pub fn expire(&mut self, computation_id: ComputationId) {
+        self.closes_at.remove(&computation_id);
+        self.drop_pending(computation_id, Outcome::Expired);
+    }
+    /// Drops the pending forwards of a revoked computation.
+    pub fn cancel(&mut self, computation_id: ComputationId) {
+        self.drop_pending(computation_id, Outcome::Revoked);
+    }
+    fn drop_pending(&mut self, computation_id: ComputationId, outcome: Outcome) {
+        if let Some(forwards) = self.pending.remove(&computation_id) {
+            for forward in forwards.iter() {
+                self.record(forward, outcome);
+            }
+            self.persist();
+        }
+    }
+    /// Spawns an attempt of the pending forward, which is settled at the
+    /// latest after `attempt_timeout`.
+    fn dispatch(&mut self, computation_id: ComputationId, id: u64) {
+        let forward = match self
+            .pending
+            .get_mut(&computation_id)
+            .and_then(|forwards| forwards.iter_mut().find(|forward| forward.id == id))
+        {
+            Some(forward) => forward,
+            None => return,
+        };
+        forward.in_flight = true;
+        forward.attempts += 1;
+        let router = Arc::clone(&self.router);
+        let settled = self.settled_tx.clone();
+        let timeout = self.attempt_timeout;
+        let endpoint = forward.endpoint;
+        let successor_seed = forward.successor_seed;
+        let params = forward.params.clone();
+        let attempts = forward.attempts;
+        task::spawn(async move {
+            let outcome =
+                match time::timeout(timeout, router.forward(endpoint, &successor_seed, params))
+                    .await
+                {
+                    Ok(result) => Self::outcome(endpoint, attempts, result),
+                    Err(_) => {
+                        logger::warn!(
+                            "Attempt {} to forward to successor at {:?} timed out, retrying later",
+                            attempts,
+                            endpoint
+                        );
+                        None
+                    }
+                };
+            let _ = settled.send(Attempt {
+                id,
+                computation_id,
+                outcome,
+            });
+        });
+    }
+    /// Returns the outcome or `None` if the forward should be retried.
+    fn outcome(endpoint: SocketAddr, attempts: u32, result: Result<Outcome>) -> Option<Outcome> {
+        match result {
+            Ok(outcome) => Some(outcome),
+            // transient rejections, e.g., by a rate limited mailbox, are retried
+            Err(e) if Self::is_final(&e) => {
+                logger::warn!("Forward to successor at {:?} rejected: {:?}", endpoint, e);
+                Some(Outcome::Rejected)
+            }
+            Err(e) => {
+                logger::warn!(
+                    "Attempt {} to forward to successor at {:?} failed, retrying later: {:?}",
+                    attempts,
+                    endpoint,
+                    e
+                );
+                None
+            }
+        }
+    }
+    fn is_final(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<RequestError>()
+            .is_some_and(|e| !e.is_transient())
+    }
+    fn push(&mut self, mut forward: PendingForward) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        forward.id = id;
+        self.pending
+            .entry(forward.params.computation_id())
+            .or_default()
+            .push(forward);
+        id
+    }
+    /// The queue holds the own TEKs, hence only the participant may read it.
+    fn persist(&self) {
+        let pending: Vec<&PendingForward> = self.pending.values().flatten().collect();
+        let staged = self.path.with_extension("yaml.tmp");
+        let result = serde_yaml::to_string(&pending)
+            .context("Error serializing forward queue")
+            .and_then(|pending| {
+                // a leftover file would keep its permissions
+                let _ = fs::remove_file(&staged);
+                private_file(&staged)
+                    .and_then(|mut file| file.write_all(pending.as_bytes()))
+                    .and_then(|_| fs::rename(&staged, &self.path))
+                    .context(format!("Error writing forward queue {:?}", self.path))
+            });
+        if let Err(e) = result {
+            logger::error!("{:?}", e);
+        }
+    }
+    fn record(&self, forward: &PendingForward, outcome: Outcome) {
+        self.metrics.forward_sent(outcome);
+        logger::info!(
+            "Forward of {:?} to successor at {:?} {:?} after {} attempts",
+            forward.params.computation_id(),
+            forward.endpoint,
+            outcome,
+            forward.attempts
+        );
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.outcomes)
+            .and_then(|mut file| {
+                writeln!(
+                    file,
+                    "{},{},{},{:?},{}",
+                    Utc::now().to_rfc3339(),
+                    u32::from(forward.params.computation_id()),
+                    forward.endpoint,
+                    outcome,
+                    forward.attempts
+                )
+            });
+        if let Err(e) = result {
+            logger::error!(
+                "Error recording forward outcome in {:?}: {:?}",
+                self.outcomes,
+                e
+            );
+        }
+    }
+}
+
+fn private_file(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exposurelib::primitives::SystemRandom;
+    use exposurelib::rpcs::{Relay, RelayParams};
+    use exposurelib::testing::forward_params;
+    use futures::{future, prelude::*};
+    use tarpc::server::{self, Channel};
+    use tarpc::tokio_serde::formats;
+    use tokio::net::TcpListener;
+
+    /// Relay which accepts all forwards without ever delivering them.
+    #[derive(Clone)]
+    struct BlackHole;
+
+    #[tarpc::server]
+    impl Relay for BlackHole {
+        async fn relay(
+            self,
+            _: tarpc::context::Context,
+            _: RelayParams,
+        ) -> Result<(), RequestError> {
+            Ok(())
+        }
+    }
+
+    fn queue(name: &str, relay: Option<SocketAddr>) -> ForwardQueue {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(path.with_extension("queue.yaml"));
+        let _ = fs::remove_file(path.with_extension("outcomes.csv"));
+        ForwardQueue::load(
+            path.with_extension("queue.yaml"),
+            path.with_extension("outcomes.csv"),
+            ForwardRouter::new(relay, 1 << 20, None),
+            Duration::minutes(10),
+            std::time::Duration::from_millis(200),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap()
+    }
+
+    fn outcomes(queue: &ForwardQueue) -> String {
+        fs::read_to_string(&queue.outcomes).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let mut queue = queue("test-retry", None);
+        // the successor accepts connections but never answers
+        let successor = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = successor.local_addr().unwrap();
+        let seed = Seed::new(&SystemRandom::new()).unwrap();
+        let computation_id = ComputationId::from(3);
+        queue.submit(endpoint, seed, forward_params(computation_id));
+        let attempt = queue.settled().await;
+        assert!(attempt.outcome.is_none());
+        queue.settle(attempt);
+        assert!(!queue.pending[&computation_id][0].in_flight);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&queue.path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // pending forwards survive a restart
+        let restored = ForwardQueue::load(
+            queue.path.clone(),
+            queue.outcomes.clone(),
+            ForwardRouter::new(None, 1 << 20, None),
+            Duration::minutes(10),
+            std::time::Duration::from_millis(200),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap();
+        assert_eq!(restored.pending[&computation_id].len(), 1);
+
+        // forwards still in flight are not attempted twice
+        queue.retry();
+        queue.retry();
+        assert_eq!(queue.pending[&computation_id][0].attempts, 2);
+        let attempt = queue.settled().await;
+        queue.settle(attempt);
+        assert!(queue.settled_rx.try_recv().is_err());
+        assert_eq!(queue.pending[&computation_id][0].attempts, 2);
+        assert!(outcomes(&queue).is_empty());
+
+        queue.cancel(computation_id);
+        assert!(queue.pending.is_empty());
+        assert!(outcomes(&queue).ends_with(",Revoked,2\n"));
+        fs::remove_file(&queue.path).unwrap();
+        fs::remove_file(&queue.outcomes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let mut queue = queue("test-expiry", None);
+        let unreachable = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = unreachable.local_addr().unwrap();
+        drop(unreachable);
+        let seed = Seed::new(&SystemRandom::new()).unwrap();
+
+        // the computation's keys were in a chunk long gone
+        let computation_id = ComputationId::from(3);
+        queue.observe(computation_id, Utc::now() - Duration::minutes(5));
+        queue.observe(computation_id, Utc::now() - Duration::minutes(20));
+        queue.observe(computation_id, Utc::now());
+        queue.submit(endpoint, seed, forward_params(computation_id));
+        let expires_at = queue.pending[&computation_id][0].expires_at;
+        assert!(expires_at <= Utc::now() - Duration::minutes(10));
+        let attempt = queue.settled().await;
+        queue.settle(attempt);
+        queue.retry();
+        assert!(queue.pending.is_empty());
+        assert!(outcomes(&queue).ends_with(",Expired,1\n"));
+
+        // unseen computations expire a computation period after the forward
+        let computation_id = ComputationId::from(4);
+        queue.submit(endpoint, seed, forward_params(computation_id));
+        let expires_at = queue.pending[&computation_id][0].expires_at;
+        assert!(expires_at > Utc::now() + Duration::minutes(9));
+        // the closure drops the forward even while it is in flight
+        queue.expire(computation_id);
+        assert!(queue.pending.is_empty());
+        let attempt = queue.settled().await;
+        queue.settle(attempt);
+        assert!(queue.pending.is_empty());
+        assert_eq!(outcomes(&queue).lines().count(), 2);
+        fs::remove_file(&queue.path).unwrap();
+        fs::remove_file(&queue.outcomes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relayed() {
+        let mut listener =
+            tarpc::serde_transport::tcp::listen("127.0.0.1:0", formats::Bincode::default)
+                .await
+                .unwrap();
+        listener.config_mut().max_frame_length(1 << 20);
+        let relay = listener.local_addr();
+        task::spawn(
+            listener
+                .filter_map(|r| future::ready(r.ok()))
+                .map(server::BaseChannel::with_defaults)
+                .map(|channel| channel.requests().execute(BlackHole.serve()))
+                .buffer_unordered(10)
+                .for_each(|_| async {}),
+        );
+        let mut queue = queue("test-relayed", Some(relay));
+        // the successor is never contacted by the client itself
+        let endpoint = "127.0.0.1:1".parse().unwrap();
+        let seed = Seed::new(&SystemRandom::new()).unwrap();
+        queue.submit(endpoint, seed, forward_params(ComputationId::from(3)));
+        let attempt = queue.settled().await;
+        assert_eq!(attempt.outcome, Some(Outcome::Relayed));
+        queue.settle(attempt);
+        assert!(queue.pending.is_empty());
+        assert!(outcomes(&queue).ends_with(",Relayed,1\n"));
+        fs::remove_file(&queue.path).unwrap();
+        fs::remove_file(&queue.outcomes).unwrap();
+    }
+
+    #[test]
+    fn test_transient_rejection() {
+        let endpoint = "127.0.0.1:1".parse().unwrap();
+        let outcome =
+            |e: RequestError| ForwardQueue::outcome(endpoint, 1, Err(anyhow::Error::new(e)));
+        assert_eq!(outcome(RequestError::RelayUnavailable), None);
+        assert_eq!(outcome(RequestError::StoreUnavailable), None);
+        assert_eq!(
+            outcome(RequestError::NoEncounterTimes),
+            Some(Outcome::Rejected)
+        );
+    }
+}
@@ -1,38 +1,39 @@
+use crate::greylist::GreylistBatcher;
+use crate::metrics::{DropReason, Metrics, Warning};
+use crate::queue::ForwardQueue;
 use anyhow::{Context, Result};
-use chrono::prelude::*;
 use exposurelib::config::{ClientConfig, Participant, SystemParams};
 use exposurelib::diagnosis_server_state::Chunk;
 use exposurelib::logger;
 use exposurelib::primitives::*;
 use exposurelib::rpcs;
-use exposurelib::rpcs::{BlacklistUploadParams, ForwardParams, GreylistUploadParams};
+use exposurelib::rpcs::{
+    BlacklistUploadParams, Features, ForwardParams, GreylistUploadParams, RevokeParams,
+    VerifyParams,
+};
 use exposurelib::time::ExposureTimeSet;
+use exposurelib::verification::{GreylistSecret, KeyCommitment, VerificationCode};
 use exposurelib::{
     client_state::{BluetoothLayer, Keys, Match},
     diagnosis_server_state::ListType,
 };
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::{
-    collections::{HashMap, HashSet},
-    net::SocketAddr,
-};
 use std::{convert::TryFrom, time::Duration};
-use tarpc::{client, context, tokio_serde::formats};
+use tarpc::context;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::time::{self, Instant};
 
 #[derive(Debug)]
 pub enum Event {
     NewChunks {
-        last_from: DateTime<Utc>,
         chunks: Vec<Chunk>,
-        resp: oneshot::Sender<DateTime<Utc>>,
     },
     NewForwardRequest {
         params: ForwardParams,
         resp: oneshot::Sender<Result<()>>,
     },
-    ComputationPeriodExpired,
 }
 
 pub struct ClientState {
@@ -44,17 +45,41 @@ pub struct ClientState {
     requests: mpsc::Receiver<Event>,
     listener: mpsc::Sender<Duration>,
     diagnosis_server: Arc<rpcs::DiagnosisServerClient>,
-    traced_contact: bool,
-    transitive_contact: bool,
+    verification_code: Option<VerificationCode>,
+    queue: ForwardQueue,
+    retry_interval: Duration,
+    greylist: GreylistBatcher,
+    // code and due time of the revocation of the own upload
+    revocation: Option<(VerificationCode, Duration)>,
+    revocation_due: Option<Instant>,
+    own_computation: Option<ComputationId>,
+    revoked: HashSet<ComputationId>,
+    // computations which warned the participant and may still be revoked
+    traced_contacts: HashSet<ComputationId>,
+    transitive_contacts: HashSet<ComputationId>,
+    metrics: Arc<Metrics>,
 }
 
 impl ClientState {
     pub fn new(
         config: ClientConfig,
         diagnosis_server: Arc<rpcs::DiagnosisServerClient>,
+        features: Features,
+        queue: ForwardQueue,
         requests: mpsc::Receiver<Event>,
         listener: mpsc::Sender<Duration>,
+        metrics: Arc<Metrics>,
     ) -> Self {
+        let revocation = match config.revocation {
+            Some(revocation) if !features.contains(Features::REVOCATION) => {
+                logger::warn!(
+                    "Diagnosis server does not support revoking {:?}",
+                    revocation.code
+                );
+                None
+            }
+            revocation => revocation.map(|revocation| (revocation.code, revocation.after)),
+        };
         Self {
             participant: config.participant,
             system_params: config.params,
@@ -64,60 +89,60 @@ impl ClientState {
             requests,
             listener,
             diagnosis_server,
-            traced_contact: false,
-            transitive_contact: false,
+            verification_code: config.verification_code,
+            queue,
+            retry_interval: config.forward_queue.retry_interval,
+            greylist: GreylistBatcher::new(config.greylist_batching),
+            revocation,
+            revocation_due: None,
+            own_computation: None,
+            revoked: HashSet::new(),
+            traced_contacts: HashSet::new(),
+            transitive_contacts: HashSet::new(),
+            metrics,
         }
     }
     pub async fn run(mut self) -> ! {
         self.init().await.unwrap(); // insert favorite retry strategy here
+        let mut retry = time::interval(self.retry_interval);
         loop {
-            let event = match self.requests.recv().await {
-                Some(event) => event,
-                None => panic!("Client sender all dropped"),
+            let greylist_due = self.greylist.next_due();
+            let event = tokio::select! {
+                event = self.requests.recv() => match event {
+                    Some(event) => event,
+                    None => panic!("Client sender all dropped"),
+                },
+                _ = retry.tick() => {
+                    self.queue.retry();
+                    continue;
+                }
+                attempt = self.queue.settled() => {
+                    self.queue.settle(attempt);
+                    continue;
+                }
+                _ = time::sleep_until(greylist_due.unwrap_or_else(Instant::now)),
+                    if greylist_due.is_some() => {
+                    self.upload_greylist_batches().await;
+                    continue;
+                }
+                _ = time::sleep_until(self.revocation_due.unwrap_or_else(Instant::now)),
+                    if self.revocation_due.is_some() => {
+                    self.revocation_due = None;
+                    if let Err(e) = self.revoke_own_upload().await {
+                        logger::error!("Error during revocation: {:?}", e);
+                    }
+                    continue;
+                }
             };
             match event {
-                Event::NewChunks {
-                    last_from,
-                    chunks,
-                    resp,
-                } => {
-                    let mut next_from = last_from;
+                Event::NewChunks { chunks } => {
                     for chunk in chunks {
-                        if *chunk.covers().to_excluding() > next_from {
-                            next_from = chunk.covers().from_including().clone();
-                        }
                         self.process_chunk(chunk).await;
                     }
-                    resp.send(next_from).unwrap();
                 }
                 Event::NewForwardRequest { params, resp } => {
                     resp.send(self.on_tek_forward(params).await).unwrap();
                 }
-                Event::ComputationPeriodExpired => {
-                    if self.participant.to_be_warned() {
-                        if self.traced_contact
-                            || self.transitive_contact
-                            || self.participant.positively_tested()
-                        {
-                            logger::info!("Computation detected SSEV participant which is correct! :)")
-                        } else {
-                            logger::error!("Computation detected SSEV participant which is incorrect! :(");
-                        }
-                    } else {
-                        if self.traced_contact
-                            || self.transitive_contact
-                            || self.participant.positively_tested()
-                        {
-                            logger::error!(
-                                "Computation did not detect SSEV participant which is incorrect! :("
-                            )
-                        } else {
-                            logger::info!(
-                                "Computation did not detect SSEV participant which is correct! :)"
-                            )
-                        }
-                    }
-                }
             }
         }
     }
@@ -126,19 +151,55 @@ impl ClientState {
             logger::warn!(
                 "Participant is positively tested and announcing its TEKs to the blacklist"
             );
-            let diagnosis_keys = self.keys.all_teks();
+            let mut diagnosis_keys = self.keys.all_teks();
+            rpcs::pad_diagnosis_keys(
+                &mut diagnosis_keys,
+                &self.system_params,
+                &SystemRandom::new(),
+            )
+            .context("Error padding diagnosis keys with decoys")?;
+            let verification_token = match self.verification_code.take() {
+                Some(code) => Some(
+                    self.diagnosis_server
+                        .verify(
+                            context::current(),
+                            VerifyParams {
+                                code,
+                                key_commitment: KeyCommitment::new(&diagnosis_keys),
+                            },
+                        )
+                        .await?
+                        .context("Diagnosis server rejected verification code")?,
+                ),
+                None => {
+                    logger::warn!(
+                        "Uploading without verification due to a missing verification code"
+                    );
+                    None
+                }
+            };
             self.listener
                 .send(Duration::from(self.system_params.computation_period))
                 .await
                 .unwrap();
-            let computation_id = self // insert favorite retry strategy here
+            // the own clock starts before the diagnosis server's
+            let closes_at = Instant::now() + Duration::from(self.system_params.computation_period);
+            let response = self // insert favorite retry strategy here
                 .diagnosis_server
-                .blacklist_upload(context::current(), BlacklistUploadParams { diagnosis_keys })
-                .await?;
-            match self
-                .computations
-                .insert(computation_id, Computation::default())
-            {
+                .blacklist_upload(
+                    context::current(),
+                    BlacklistUploadParams {
+                        diagnosis_keys,
+                        verification_token,
+                    },
+                )
+                .await?
+                .context("Diagnosis server rejected blacklist upload")?;
+            let computation_id = response.computation_id;
+            match self.computations.insert(
+                computation_id,
+                Computation::own(response.greylist_secret, closes_at),
+            ) {
                 Some(old_computation) => logger::error!(
                     "Computation with {:?} already present with old: {:?}",
                     computation_id,
@@ -148,26 +209,85 @@ impl ClientState {
                     logger::info!("Adding new computation with {:?}", computation_id);
                 }
             }
+            self.own_computation = Some(computation_id);
+            if let Some((_, after)) = &self.revocation {
+                self.revocation_due = Some(Instant::now() + *after);
+            }
         }
         Ok(())
     }
+    /// Revokes the own upload once the positive test turned out to be false.
+    async fn revoke_own_upload(&mut self) -> Result<()> {
+        let (code, _) = self
+            .revocation
+            .take()
+            .context("Missing revocation verification code")?;
+        let computation_id = self
+            .own_computation
+            .context("Missing own computation to revoke")?;
+        let greylist_secret = self
+            .computations
+            .get(&computation_id)
+            .and_then(Computation::greylist_secret)
+            .context(format!("Missing greylist secret for {:?}", computation_id))?;
+        logger::warn!(
+            "Positive test turned out to be false, revoking computation with {:?}",
+            computation_id
+        );
+        let verification_token = self
+            .diagnosis_server
+            .verify(
+                context::current(),
+                VerifyParams {
+                    code,
+                    key_commitment: KeyCommitment::revocation(computation_id),
+                },
+            )
+            .await?
+            .context("Diagnosis server rejected revocation verification code")?;
+        self.diagnosis_server // insert favorite retry strategy here
+            .revoke(
+                context::current(),
+                RevokeParams::new(computation_id, Some(verification_token), greylist_secret),
+            )
+            .await?
+            .context(format!(
+                "Diagnosis server rejected revocation of {:?}",
+                computation_id
+            ))
+    }
     async fn process_chunk(&mut self, chunk: Chunk) -> () {
-        for (computation_id, computation_state) in chunk.to_data().into_iter() {
+        let to_excluding = *chunk.covers().to_excluding();
+        let (data, closed, revoked) = chunk.into_parts();
+        // revocations go first as the chunk may also hold keys of the computation
+        for computation_id in revoked.into_iter() {
+            self.on_computation_revoked(computation_id);
+        }
+        for (computation_id, computation_state) in data.into_iter() {
+            self.queue.observe(computation_id, to_excluding);
+            if self.revoked.contains(&computation_id) {
+                logger::info!(
+                    "Skipping keys of revoked computation with {:?}",
+                    computation_id
+                );
+                continue;
+            }
             let (blacklist, greylist) = computation_state.to_data();
             if self.computations.contains_key(&computation_id) {
                 if let Some(_) = greylist.iter().find(|tek| self.keys.is_own_tek(tek)) {
-                    if !self.traced_contact {
+                    if self.traced_contacts.is_empty() {
                         logger::warn!(
                             "WARNING: SSEV alert: participant had a high-risk \
                             transitive contact with another infected participant."
                         );
+                        self.metrics.warned(Warning::Ssev);
                     } else {
                         logger::info!(
                             "Participant is already a traced contact \
                             and therefore her transitive contact warning is omitted"
                         );
                     }
-                    self.transitive_contact = true;
+                    self.transitive_contacts.insert(computation_id);
                 }
             }
             for tek in blacklist.into_iter() {
@@ -187,6 +307,81 @@ impl ClientState {
                 }
             }
         }
+        for computation_id in closed.into_iter() {
+            self.on_computation_closed(computation_id);
+        }
+    }
+    /// Stops taking part in a revoked computation and retracts its warnings.
+    /// The state, including the revocation, is freed once the computation closes.
+    fn on_computation_revoked(&mut self, computation_id: ComputationId) {
+        if !self.revoked.insert(computation_id) {
+            return;
+        }
+        self.queue.cancel(computation_id);
+        self.greylist.discard(computation_id);
+        if let Some(computation) = self.computations.get_mut(&computation_id) {
+            logger::info!("Computation with {:?} is revoked", computation_id);
+            computation.successors_mut().clear();
+            computation.redlist_mut().clear();
+        }
+        if self.traced_contacts.remove(&computation_id) {
+            logger::warn!(
+                "WARNING retracted: positive test of the high-risk traced contact \
+                with {:?} turned out to be false",
+                computation_id
+            );
+            self.metrics.warned(Warning::Retracted);
+        }
+        if self.transitive_contacts.remove(&computation_id) {
+            logger::warn!(
+                "WARNING retracted: SSEV alert with {:?} is revoked",
+                computation_id
+            );
+            self.metrics.warned(Warning::Retracted);
+        }
+    }
+    /// Whether the participant ends up warned or positively tested.
+    fn detected(&self) -> bool {
+        let own_revoked = self
+            .own_computation
+            .is_some_and(|computation_id| self.revoked.contains(&computation_id));
+        (self.participant.positively_tested() && !own_revoked)
+            || !self.traced_contacts.is_empty()
+            || !self.transitive_contacts.is_empty()
+    }
+    /// Frees the state of a closed computation and evaluates the warnings
+    /// once all computations the participant is involved in are closed.
+    fn on_computation_closed(&mut self, computation_id: ComputationId) {
+        self.queue.expire(computation_id);
+        self.greylist.discard(computation_id);
+        // a closed computation publishes no more keys, only the revocation of
+        // the own one is kept for telling whether the participant was detected
+        if self.own_computation != Some(computation_id) {
+            self.revoked.remove(&computation_id);
+        }
+        if self.computations.remove(&computation_id).is_none() {
+            return;
+        }
+        logger::info!(
+            "Freeing state of closed computation with {:?}",
+            computation_id
+        );
+        if !self.computations.is_empty() {
+            return;
+        }
+        if self.participant.to_be_warned() {
+            if self.detected() {
+                logger::info!("Computation detected SSEV participant which is correct! :)")
+            } else {
+                logger::error!("Computation detected SSEV participant which is incorrect! :(");
+            }
+        } else {
+            if self.detected() {
+                logger::error!("Computation did not detect SSEV participant which is incorrect! :(")
+            } else {
+                logger::info!("Computation did not detect SSEV participant which is correct! :)")
+            }
+        }
     }
     async fn on_tek_match(
         &mut self,
@@ -194,22 +389,28 @@ impl ClientState {
         from: ListType,
         computation_id: ComputationId,
     ) -> Result<()> {
+        if self.revoked.contains(&computation_id) {
+            return Ok(());
+        }
         let tek_keyring = Validity::<TekKeyring>::try_from(tek)
             .context(format!("Error deriving RPIK and AEMK from {:?}", tek))?;
         let matched = match self.bluetooth_layer.match_with(tek_keyring) {
             Some(matched) => matched,
             None => return Ok(()),
         };
+        self.metrics.matched(from);
         if from == ListType::Blacklist {
             if !matched.high_risk().is_empty() {
                 logger::warn!(
                     "WARNING: participant had a high-risk traced contact with an infected participant"
                 ); // TODO: when? From<ExposureTime> for DateTime<Utc>
-                self.traced_contact = true;
+                self.traced_contacts.insert(computation_id);
+                self.metrics.warned(Warning::HighRisk);
             } else {
                 logger::warn!(
                     "WARNING: participant had a low-risk traced contact with an infected participant"
                 );
+                self.metrics.warned(Warning::LowRisk);
             }
         }
         if matched.high_risk().is_empty() {
@@ -247,20 +448,17 @@ impl ClientState {
             matched.connection_identifier(),
             own_tek,
         );
-        let client = Self::get_forwarder_client(matched.connection_identifier()).await?;
-        client
-            .forward(
-                context::current(),
-                ForwardParams::new(
-                    computation_id,
-                    valid_from,
-                    tekrp,
-                    own_tek.to_keyring(),
-                    matched.high_risk().clone(),
-                ),
-            )
-            .await
-            .context("Error while sending first forward from origin")?;
+        self.queue.submit(
+            matched.connection_identifier(),
+            *matched.seed(),
+            ForwardParams::new(
+                computation_id,
+                valid_from,
+                tekrp,
+                own_tek.to_keyring(),
+                matched.high_risk().clone(),
+            ),
+        );
         let computation = self
             .computations
             .entry(computation_id)
@@ -272,6 +470,7 @@ impl ClientState {
         let tekrp = self.system_params.tek_rolling_period;
         let origin_tek = params.origin_tek(tekrp);
         logger::info!("New forward request of {:?}", origin_tek);
+        self.metrics.forward_received();
         let predecessor_tek = params.predecessor_tek(tekrp);
         let predecessor_tek_keyring = Validity::<TekKeyring>::try_from(predecessor_tek.clone())
             .context(format!(
@@ -285,10 +484,20 @@ impl ClientState {
                     "Dropping forwarding due to missing match of {:?}",
                     origin_tek
                 );
+                self.metrics.forward_dropped(DropReason::NoMatch);
                 return Ok(());
             }
         };
         let computation_id = params.computation_id();
+        if self.revoked.contains(&computation_id) {
+            logger::info!(
+                "Dropping forwarding due to revoked {:?} of {:?}",
+                computation_id,
+                origin_tek,
+            );
+            self.metrics.forward_dropped(DropReason::RevokedComputation);
+            return Ok(());
+        }
         let computation = match self.computations.get_mut(&computation_id) {
             Some(computation) => computation,
             None => {
@@ -297,6 +506,7 @@ impl ClientState {
                     computation_id,
                     origin_tek,
                 );
+                self.metrics.forward_dropped(DropReason::UnknownComputation);
                 return Ok(());
             }
         };
@@ -308,6 +518,7 @@ impl ClientState {
         }
         if !computation.redlist().contains(&predecessor_tek) {
             logger::info!("Dropping {:?} forwarding due to missing entry of predecessor in the computation's redlist with {:?}", origin_tek, computation_id);
+            self.metrics.forward_dropped(DropReason::NotRedlisted);
             return Ok(());
         }
         let shared_encounter_times: ExposureTimeSet = matched
@@ -320,28 +531,24 @@ impl ClientState {
                 "Dropping forwarding due to a missing shared encounter time of {:?}",
                 origin_tek
             );
+            self.metrics.forward_dropped(DropReason::NoSharedEncounter);
             return Ok(());
         }
         if computation.is_own() {
-            let mut diagnosis_keys = HashSet::with_capacity(1);
-            diagnosis_keys.insert(origin_tek);
-            logger::info!(
-                "Announcing to greylist on diagnosis server {:?}",
-                origin_tek
-            );
-            self.diagnosis_server // insert favorite retry strategy here
-                .greylist_upload(
-                    context::current(),
-                    GreylistUploadParams {
-                        computation_id,
-                        diagnosis_keys,
-                    },
-                )
-                .await
-                .context(format!(
-                    "Pooling node could not upload received {:?} to greylist",
-                    origin_tek
-                ))?;
+            let closes_at = match computation.closes_at() {
+                Some(closes_at) if computation.greylist_secret().is_some() => closes_at,
+                _ => {
+                    logger::error!(
+                        "Dropping forwarding due to a missing greylist secret for {:?}",
+                        computation_id
+                    );
+                    self.metrics
+                        .forward_dropped(DropReason::MissingGreylistSecret);
+                    return Ok(());
+                }
+            };
+            logger::info!("Collecting for greylist upload {:?}", origin_tek);
+            self.greylist.add(computation_id, origin_tek, closes_at);
         } else {
             let valid_from = matched.tek().valid_from();
             let own_tek = match self.keys.exposure_keyring(valid_from, tekrp) {
@@ -364,32 +571,45 @@ impl ClientState {
                         successor.connection_identifier(),
                         origin_tek,
                     );
-                    let client =
-                        Self::get_forwarder_client(successor.connection_identifier()).await?;
-                    client
-                        .forward(context::current(), params)
-                        .await
-                        .context(format!(
-                            "Error while forwarding {:?} to next successor at {:?}",
-                            origin_tek,
-                            successor.connection_identifier()
-                        ))?;
+                    self.queue
+                        .submit(successor.connection_identifier(), *successor.seed(), params);
                 }
             }
         }
         Ok(())
     }
-    async fn get_forwarder_client(endpoint: SocketAddr) -> Result<rpcs::ForwarderClient> {
-        let mut transport =
-            tarpc::serde_transport::tcp::connect(&endpoint, formats::Bincode::default);
-        transport.config_mut().max_frame_length(usize::MAX);
-        let transport = transport.await.context(format!(
-            "Error creating TCP Bincode connect with client at {:?}",
-            endpoint
-        ))?;
-        rpcs::ForwarderClient::new(client::Config::default(), transport)
-            .spawn()
-            .context("Error spawning forwarder client")
+    async fn upload_greylist_batches(&mut self) {
+        for (computation_id, diagnosis_keys) in self.greylist.take_due() {
+            if let Err(e) = self.upload_greylist(computation_id, diagnosis_keys).await {
+                logger::error!("Error during greylist upload: {}", e);
+            }
+        }
+    }
+    async fn upload_greylist(
+        &self,
+        computation_id: ComputationId,
+        diagnosis_keys: HashSet<Validity<TemporaryExposureKey>>,
+    ) -> Result<()> {
+        let greylist_secret = self
+            .computations
+            .get(&computation_id)
+            .and_then(Computation::greylist_secret)
+            .context(format!("Missing greylist secret for {:?}", computation_id))?;
+        logger::info!(
+            "Announcing to greylist on diagnosis server {:?}",
+            diagnosis_keys
+        );
+        let count = diagnosis_keys.len();
+        self.diagnosis_server // insert favorite retry strategy here
+            .greylist_upload(
+                context::current(),
+                GreylistUploadParams::new(computation_id, diagnosis_keys, greylist_secret),
+            )
+            .await?
+            .context(format!(
+                "Pooling node could not upload {} received keys of {:?} to greylist",
+                count, computation_id
+            ))
     }
 }
 
@@ -397,9 +617,18 @@ impl ClientState {
 pub struct Computation {
     successors: HashSet<Match>,
     redlist: HashSet<Validity<TemporaryExposureKey>>,
+    greylist_secret: Option<GreylistSecret>,
+    closes_at: Option<Instant>,
 }
 
 impl Computation {
+    pub fn own(greylist_secret: GreylistSecret, closes_at: Instant) -> Self {
+        Self {
+            greylist_secret: Some(greylist_secret),
+            closes_at: Some(closes_at),
+            ..Self::default()
+        }
+    }
     pub fn is_own(&self) -> bool {
         self.successors.is_empty()
     }
@@ -409,6 +638,14 @@ impl Computation {
     pub fn successors_mut(&mut self) -> &mut HashSet<Match> {
         &mut self.successors
     }
+    /// Only known for the own computation.
+    pub fn greylist_secret(&self) -> Option<&GreylistSecret> {
+        self.greylist_secret.as_ref()
+    }
+    /// End of the computation period, only known for the own computation.
+    pub fn closes_at(&self) -> Option<Instant> {
+        self.closes_at
+    }
     pub fn redlist(&self) -> &HashSet<Validity<TemporaryExposureKey>> {
         &self.redlist
     }
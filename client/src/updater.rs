@@ -1,70 +1,377 @@
+use crate::gossip::{ProofUnavailable, TreeHeadMonitor};
 use crate::state::Event;
-use chrono::prelude::*;
+use anyhow::{bail, Context, Result};
+use exposurelib::chunk_encoding::ChunkEncoding;
 use exposurelib::config::RefreshPeriod;
+use exposurelib::diagnosis_server_state::{Chunk, ChunkId};
+use exposurelib::error::RequestError;
 use exposurelib::logger;
-use exposurelib::rpcs::{self, DownloadParams};
+use exposurelib::mailbox::MailboxAddress;
+use exposurelib::rpcs::{self, ChunkPage, DownloadParams, Features, FetchParams};
+use exposurelib::signing::PublicKey;
+use exposurelib::transparency::TreeHash;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tarpc::context;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot;
 use tokio::time;
 
+/// What the updater downloads and how it checks the downloaded chunks.
+pub struct UpdaterConfig {
+    pub refresh_period: RefreshPeriod,
+    pub chunk_encoding: ChunkEncoding,
+    pub max_decoded_length: usize,
+    /// Downloaded chunks are rejected unless signed with the matching key.
+    pub public_key: Option<PublicKey>,
+    /// Negotiated with the diagnosis server.
+    pub features: Features,
+    /// Addresses of the own mailbox, one per TEK.
+    pub mailbox: Vec<MailboxAddress>,
+}
+
 pub struct Updater {
     diagnosis_server: Arc<rpcs::DiagnosisServerClient>,
-    refresh_period: RefreshPeriod,
+    config: UpdaterConfig,
     client_state: Sender<Event>,
-    from: DateTime<Utc>,
+    monitor: Arc<TreeHeadMonitor>,
+    after: Option<ChunkId>,
 }
 
 impl Updater {
+    // grace time for the diagnosis server to answer a subscription that timed out
+    const SUBSCRIPTION_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+    /// Features the updater makes use of if the diagnosis server supports them.
+    pub fn features() -> Features {
+        Features::CHUNK_ENCODINGS
+            | Features::PAGINATION
+            | Features::SUBSCRIPTIONS
+            | Features::MAILBOX
+            | Features::COVER_TRAFFIC
+            | Features::REVOCATION
+            | Features::TRANSPARENCY
+    }
+
     pub fn new(
         diagnosis_server: Arc<rpcs::DiagnosisServerClient>,
-        refresh_period: RefreshPeriod,
-        from: DateTime<Utc>,
+        config: UpdaterConfig,
         client_state: Sender<Event>,
+        monitor: Arc<TreeHeadMonitor>,
     ) -> Self {
         Self {
             diagnosis_server,
-            refresh_period,
-            from,
+            config,
+            after: None,
             client_state,
+            monitor,
         }
     }
     pub async fn run(mut self) -> ! {
-        let refresh_period = Duration::from(self.refresh_period);
-        let mut interval = time::interval(refresh_period);
+        let refresh_period = Duration::from(self.config.refresh_period);
+        // pages are downloaded right away until the last one, only then we subscribe
+        let mut caught_up = false;
         loop {
-            interval.tick().await;
-            logger::info!(
-                "Downloading latest chunks from {:?} from diagnosis server",
-                self.from
-            );
-            let (tx, rx) = oneshot::channel();
-            let new_chunks_event = match self
-                .diagnosis_server
-                .download(context::current(), DownloadParams { from: self.from })
-                .await
-            {
-                Ok(updates) => {
-                    if updates.len() == 0 {
-                        logger::debug!("No new chunks");
-                        continue;
+            if caught_up {
+                self.fetch_deposits().await;
+            }
+            let encoding = if self.config.features.contains(Features::CHUNK_ENCODINGS) {
+                self.config.chunk_encoding
+            } else {
+                ChunkEncoding::plain()
+            };
+            let params = DownloadParams {
+                after: self.after,
+                encoding,
+            };
+            let subscribe = self.config.features.contains(Features::SUBSCRIPTIONS);
+            if caught_up && !subscribe {
+                time::sleep(refresh_period).await;
+            }
+            let page = if caught_up && subscribe {
+                logger::info!(
+                    "Subscribing to chunks after {:?} at diagnosis server",
+                    self.after
+                );
+                // the diagnosis server answers at the latest after one refresh period
+                let mut context = context::current();
+                context.deadline =
+                    SystemTime::now() + refresh_period + Self::SUBSCRIPTION_GRACE_PERIOD;
+                self.diagnosis_server.subscribe(context, params).await
+            } else {
+                logger::info!(
+                    "Downloading page of chunks after {:?} from diagnosis server",
+                    self.after
+                );
+                self.diagnosis_server
+                    .download(context::current(), params)
+                    .await
+            };
+            let new_chunks_event = match page {
+                Ok(page) => {
+                    logger::debug!("New page: {:?}", page);
+                    if let Some(public_key) = &self.config.public_key {
+                        if let Some((chunk, e)) = page.chunks.iter().find_map(|chunk| {
+                            chunk.verify(public_key).err().map(|e| (chunk, e))
+                        }) {
+                            logger::error!(
+                                "Security event: rejecting page after {:?} as {:?} failed verification against the pinned diagnosis server key: {}",
+                                self.after,
+                                chunk,
+                                e
+                            );
+                            time::sleep(refresh_period).await;
+                            continue;
+                        }
                     }
-                    logger::debug!("New chunks: {:?}", updates);
-                    Event::NewChunks {
-                        last_from: self.from,
-                        chunks: updates,
-                        resp: tx,
+                    let updates = match page
+                        .chunks
+                        .iter()
+                        .map(|chunk| chunk.decode(self.config.max_decoded_length))
+                        .collect::<Result<Vec<_>, _>>()
+                    {
+                        Ok(updates) => updates,
+                        Err(e) => {
+                            logger::error!("Error while decoding chunks: {}", e);
+                            time::sleep(refresh_period).await;
+                            continue;
+                        }
+                    };
+                    if self.config.features.contains(Features::TRANSPARENCY) {
+                        if let Err(e) = self
+                            .check_inclusion_retrying(&page, &updates, refresh_period)
+                            .await
+                        {
+                            logger::error!(
+                                "Security event: rejecting page after {:?} as it is not covered by the transparency log: {:?}",
+                                self.after,
+                                e
+                            );
+                            time::sleep(refresh_period).await;
+                            continue;
+                        }
                     }
+                    caught_up = page.continuation.is_none();
+                    let last = match updates.last() {
+                        Some(last) => last.id(),
+                        None => {
+                            logger::debug!("No new chunks");
+                            continue;
+                        }
+                    };
+                    self.after = Some(last);
+                    Event::NewChunks { chunks: updates }
                 }
                 Err(e) => {
                     logger::error!("Errow while downloading DKs from diagnosis server: {}", e);
+                    time::sleep(refresh_period).await;
                     continue;
                 }
             };
             self.client_state.send(new_chunks_event).await.unwrap();
-            self.from = rx.await.unwrap();
         }
     }
+    /// Checks that every chunk of the page is included in the tree head
+    /// handed out with it and that the tree head is consistent with the
+    /// ones observed before.
+    async fn check_inclusion(&self, page: &ChunkPage, chunks: &[Chunk]) -> Result<()> {
+        let tree_head = page
+            .tree_head
+            .as_ref()
+            .context("Page comes without tree head")?;
+        if page.inclusion_proofs.len() != chunks.len() {
+            bail!(
+                "Page has {} inclusion proofs for {} chunks",
+                page.inclusion_proofs.len(),
+                chunks.len()
+            );
+        }
+        for (chunk, proof) in chunks.iter().zip(page.inclusion_proofs.iter()) {
+            if proof.tree_size != tree_head.tree_size
+                || !proof.verify(&TreeHash::leaf(chunk), &tree_head.root_hash)
+            {
+                bail!("Invalid inclusion proof for {:?}", chunk);
+            }
+        }
+        self.monitor
+            .observe(tree_head.clone(), "diagnosis server")
+            .await
+    }
+    /// Retries check_inclusion() as long as the consistency proof cannot be
+    /// retrieved, which is no reason to reject the page.
+    async fn check_inclusion_retrying(
+        &self,
+        page: &ChunkPage,
+        chunks: &[Chunk],
+        refresh_period: Duration,
+    ) -> Result<()> {
+        loop {
+            match self.check_inclusion(page, chunks).await {
+                Err(e) if ProofUnavailable::is_cause_of(&e) => {
+                    let retry_after = match e.downcast_ref::<RequestError>() {
+                        Some(RequestError::RateLimited { retry_after }) => *retry_after,
+                        _ => refresh_period,
+                    };
+                    logger::warn!(
+                        "Retrying to check the page after {:?} in {:?}: {:?}",
+                        self.after,
+                        retry_after,
+                        e
+                    );
+                    time::sleep(retry_after).await;
+                }
+                result => return result,
+            }
+        }
+    }
+    /// Hands forwards deposited while the listener was offline to the client state.
+    /// All mailboxes are fetched by a single request, as the diagnosis server
+    /// could link separate requests of the same refresh anyway.
+    async fn fetch_deposits(&self) -> () {
+        if !self.config.features.contains(Features::MAILBOX) || self.config.mailbox.is_empty() {
+            return;
+        }
+        let params = FetchParams {
+            secrets: self
+                .config
+                .mailbox
+                .iter()
+                .map(MailboxAddress::secret)
+                .collect(),
+        };
+        let deposits = match self
+            .diagnosis_server
+            .fetch(context::current(), params)
+            .await
+        {
+            Ok(Ok(deposits)) => deposits,
+            Ok(Err(e)) => {
+                logger::error!("Diagnosis server rejected fetching deposits: {}", e);
+                return;
+            }
+            Err(e) => {
+                logger::error!("Error while fetching deposits from diagnosis server: {}", e);
+                return;
+            }
+        };
+        for deposit in deposits {
+            let address = match self
+                .config
+                .mailbox
+                .iter()
+                .find(|address| address.token() == deposit.token)
+            {
+                Some(address) => address,
+                None => {
+                    logger::warn!("Dropping deposit for foreign {:?}", deposit.token);
+                    continue;
+                }
+            };
+            let params = match address.open(deposit.sealed) {
+                Ok(params) => params,
+                Err(e) => {
+                    logger::warn!("Dropping deposit for {:?}: {}", deposit.token, e);
+                    continue;
+                }
+            };
+            logger::info!("Fetched deposited forward for {:?}", deposit.token);
+            let (tx, rx) = oneshot::channel();
+            self.client_state
+                .send(Event::NewForwardRequest { params, resp: tx })
+                .await
+                .unwrap();
+            match rx.await {
+                Ok(Err(e)) => logger::warn!("Error while forwarding deposited TEK: {:?}", e),
+                Err(e) => logger::warn!("Error while forwarding deposited TEK: {:?}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exposurelib::signing::SigningKey;
+    use exposurelib::time::TimeInterval;
+    use exposurelib::transparency::{SignedTreeHead, TransparencyLog};
+    use ring::rand::SystemRandom;
+    use tarpc::client;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_inclusion() {
+        // the diagnosis server is never asked as no tree head was observed before
+        let (transport, _) = tarpc::transport::channel::unbounded();
+        let diagnosis_server = Arc::new(
+            rpcs::DiagnosisServerClient::new(client::Config::default(), transport)
+                .spawn()
+                .unwrap(),
+        );
+        let signing_key = SigningKey::generate(&SystemRandom::new()).unwrap();
+        let public_key = signing_key.public_key().unwrap();
+        let monitor = Arc::new(TreeHeadMonitor::new(
+            Arc::clone(&diagnosis_server),
+            Some(public_key),
+        ));
+        let (client_state, _) = mpsc::channel(1);
+        let updater = Updater::new(
+            diagnosis_server,
+            UpdaterConfig {
+                refresh_period: RefreshPeriod::default(),
+                chunk_encoding: ChunkEncoding::plain(),
+                max_decoded_length: 1 << 20,
+                public_key: Some(public_key),
+                features: Updater::features(),
+                mailbox: Vec::new(),
+            },
+            client_state,
+            Arc::clone(&monitor),
+        );
+
+        let chunks: Vec<Chunk> = (0..3)
+            .map(|id| {
+                Chunk::new(
+                    ChunkId::from(id),
+                    TimeInterval::with_alignment(chrono::Duration::minutes(1)),
+                )
+            })
+            .collect();
+        let log = TransparencyLog::with_leaves(chunks.iter().map(TreeHash::leaf).collect());
+        let page = |proofs: Vec<u64>| ChunkPage {
+            chunks: Vec::new(),
+            continuation: None,
+            tree_head: Some(SignedTreeHead::new(
+                3,
+                log.root_hash(3).unwrap(),
+                Some(&signing_key.key_pair().unwrap()),
+            )),
+            inclusion_proofs: proofs
+                .into_iter()
+                .map(|leaf_index| log.inclusion_proof(leaf_index, 3).unwrap())
+                .collect(),
+        };
+        // the proofs of the first two chunks are swapped
+        assert!(updater
+            .check_inclusion(&page(vec![1, 0, 2]), &chunks)
+            .await
+            .is_err());
+        assert!(updater
+            .check_inclusion(&page(vec![0, 1]), &chunks)
+            .await
+            .is_err());
+        let mut unlogged = chunks.clone();
+        unlogged[2].revoke(Default::default());
+        assert!(updater
+            .check_inclusion(&page(vec![0, 1, 2]), &unlogged)
+            .await
+            .is_err());
+        assert!(monitor.latest().await.is_none());
+
+        updater
+            .check_inclusion(&page(vec![0, 1, 2]), &chunks)
+            .await
+            .unwrap();
+        assert_eq!(monitor.latest().await.unwrap().tree_size, 3);
+    }
 }
@@ -9,6 +9,26 @@ pub struct Config {
     pub host: String,
     pub base_port: u16,
     pub diagnosis_server_endpoint: String,
+    /// Optional HTTP gateway of the diagnosis server
+    #[serde(default)]
+    pub diagnosis_server_http_endpoint: Option<String>,
+    /// Optional metrics endpoint of the diagnosis server
+    #[serde(default)]
+    pub diagnosis_server_metrics_endpoint: Option<String>,
+    /// Enables the metrics endpoints of the clients at ports counting up
+    /// from this one in the same order as their client endpoints
+    #[serde(default)]
+    pub client_metrics_base_port: Option<u16>,
+    /// Enables the gossip endpoints of the clients at ports counting up
+    /// from this one, every client gossips tree heads with all others
+    #[serde(default)]
+    pub client_gossip_base_port: Option<u16>,
+    /// Optional relay all clients route their forwards through
+    #[serde(default)]
+    pub relay_endpoint: Option<String>,
+    /// Enables cover traffic of all clients with this mean interval
+    #[serde(default)]
+    pub cover_traffic_interval: Option<std::time::Duration>,
     pub system_params: SystemParams,
     pub today: DateTime<Utc>,
     /// All dates specified in the graph sould be within
@@ -79,6 +99,12 @@ impl Default for Config {
             host: String::from("127.0.0.1"),
             base_port: 10000,
             diagnosis_server_endpoint: String::from("127.0.0.1:9999"),
+            diagnosis_server_http_endpoint: None,
+            diagnosis_server_metrics_endpoint: None,
+            client_metrics_base_port: None,
+            client_gossip_base_port: None,
+            relay_endpoint: None,
+            cover_traffic_interval: None,
             system_params: SystemParams::default(),
             today,
             social_graph,
@@ -8,8 +8,12 @@ use args::{Args, EmitDefaultConfigArgs, GenerateConfigsArgs};
 use chrono::Duration;
 use config::Config;
 use exposurelib::client_state::{BluetoothLayer, ClientState, Keys, TracedContact};
-use exposurelib::config::{ClientConfig, DiagnosisServerConfig, Participant};
+use exposurelib::config::{
+    ClientConfig, DiagnosisServerConfig, Participant, RelayConfig, RevocationConfig,
+};
 use exposurelib::primitives::{Metadata, SystemRandom};
+use exposurelib::signing::SigningKey;
+use exposurelib::verification::{TestType, VerificationCode};
 use petgraph::dot::Dot;
 use petgraph::visit::IntoNodeReferences;
 use std::collections::HashMap;
@@ -56,13 +60,16 @@ fn handle_generate_configs(args: GenerateConfigsArgs) -> Result<()> {
         args.config_file_path
     ))?;
     let mut graph = config.social_graph;
-    let system_params = config.system_params;
+    let mut system_params = config.system_params;
 
     verification::mark_ssev_group(&mut graph);
 
     let secure_random = SystemRandom::new();
 
     let participant_count = graph.node_references().count();
+    // all participants of the simulation connect from the same host, hence
+    // a server admits one connection per participant and a spare from it
+    system_params.limits.max_channels_per_ip = participant_count as u32 + 1;
 
     let mut client_init: HashMap<&Participant, (Keys, BluetoothLayer, SocketAddr)> =
         HashMap::with_capacity(participant_count);
@@ -95,8 +102,7 @@ fn handle_generate_configs(args: GenerateConfigsArgs) -> Result<()> {
             let encounters = graph.edge_weight(edge_index).unwrap();
             for encounter in encounters.encounters.iter() {
                 let (keys, _, endpoint) = client_init.get(&other_participant).unwrap();
-                let metadata = Metadata::new(encounter.intensity, endpoint.clone());
-                let (rpi, aem) = keys
+                let exposure_keyring = keys
                     .exposure_keyring(encounter.time.into(), tekrp)
                     .ok_or(InvalidConfigError::EncounterOutOfBounds {
                         from: participant.clone(),
@@ -107,7 +113,13 @@ fn handle_generate_configs(args: GenerateConfigsArgs) -> Result<()> {
                             + Duration::from(tekrp),
                         upper: config.today + Duration::from(tekrp),
                     })
-                    .context("Invalid config")?
+                    .context("Invalid config")?;
+                let metadata = Metadata::new(
+                    encounter.intensity,
+                    endpoint.clone(),
+                    *exposure_keyring.sd_keyring().sd(),
+                );
+                let (rpi, aem) = exposure_keyring
                     .tek_keyring()
                     .rpi_and_aem(encounter.time.into(), metadata);
                 let traced_contact = TracedContact::new(encounter.time, rpi, aem);
@@ -118,6 +130,30 @@ fn handle_generate_configs(args: GenerateConfigsArgs) -> Result<()> {
     }
 
     let diagnosis_server_endpoint: SocketAddr = config.diagnosis_server_endpoint.parse()?;
+    let relay_endpoint: Option<SocketAddr> = config
+        .relay_endpoint
+        .as_ref()
+        .map(|relay_endpoint| relay_endpoint.parse())
+        .transpose()?;
+    let cover_traffic_interval = config.cover_traffic_interval;
+    let client_metrics_base_port = config.client_metrics_base_port;
+    let client_gossip_base_port = config.client_gossip_base_port;
+    let base_port = config.base_port;
+
+    let client_endpoints: Vec<SocketAddr> = client_init
+        .values()
+        .map(|(_, _, client_endpoint)| *client_endpoint)
+        .collect();
+
+    // clients pin the public key of the chunk signing key of the diagnosis server
+    let signing_key =
+        SigningKey::generate(&secure_random).context("Error generating chunk signing key")?;
+    let public_key = signing_key
+        .public_key()
+        .context("Error deriving public key of chunk signing key")?;
+
+    // the configurator acts as health authority and pre-issues verification codes
+    let mut verification_codes = HashMap::new();
 
     let client_configs: Vec<ClientConfig> = graph
         .node_references()
@@ -126,15 +162,59 @@ fn handle_generate_configs(args: GenerateConfigsArgs) -> Result<()> {
             let (keys, bluetooth_layer, client_endpoint) =
                 client_init.remove(&participant).unwrap();
             let state = ClientState::new(keys, bluetooth_layer);
-            ClientConfig::new(
+            let positively_tested = participant.positively_tested();
+            let revoked = participant.revoked();
+            let mut client_config = ClientConfig::new(
                 participant,
                 client_endpoint,
                 diagnosis_server_endpoint,
                 system_params,
                 state,
-            )
+            );
+            if positively_tested {
+                let code = VerificationCode::new(&secure_random)?;
+                verification_codes.insert(code.clone(), TestType::Confirmed);
+                client_config.verification_code = Some(code);
+            }
+            // the revised result arrives while the computation is still open
+            if positively_tested && revoked {
+                let code = VerificationCode::new(&secure_random)?;
+                verification_codes.insert(code.clone(), TestType::Revoked);
+                client_config.revocation = Some(RevocationConfig {
+                    code,
+                    after: std::time::Duration::from(system_params.computation_period) / 3,
+                });
+            }
+            client_config.relay = relay_endpoint;
+            client_config.diagnosis_server_public_key = Some(public_key);
+            if let Some(metrics_base_port) = client_metrics_base_port {
+                let offset = client_endpoint.port() - base_port;
+                client_config.metrics_endpoint =
+                    Some(SocketAddr::new(host, metrics_base_port + offset));
+            }
+            if let Some(gossip_base_port) = client_gossip_base_port {
+                let gossip_endpoint = |endpoint: &SocketAddr| {
+                    SocketAddr::new(host, gossip_base_port + endpoint.port() - base_port)
+                };
+                client_config.gossip.endpoint = Some(gossip_endpoint(&client_endpoint));
+                client_config.gossip.peers = client_endpoints
+                    .iter()
+                    .filter(|peer| **peer != client_endpoint)
+                    .map(gossip_endpoint)
+                    .collect();
+            }
+            if let Some(mean_interval) = cover_traffic_interval {
+                client_config.cover_traffic.enabled = true;
+                client_config.cover_traffic.mean_interval = mean_interval;
+                client_config.cover_traffic.peers = client_endpoints
+                    .iter()
+                    .filter(|peer| **peer != client_endpoint)
+                    .cloned()
+                    .collect();
+            }
+            Ok(client_config)
         })
-        .collect();
+        .collect::<Result<_>>()?;
 
     let mut client_config_output_path = args.config_output_path.clone();
     client_config_output_path.push("clients");
@@ -162,8 +242,23 @@ fn handle_generate_configs(args: GenerateConfigsArgs) -> Result<()> {
         .context("Error writing client config")?;
     }
 
-    let diagnosis_server_config =
-        DiagnosisServerConfig::new(diagnosis_server_endpoint, config.system_params);
+    let mut diagnosis_server_config =
+        DiagnosisServerConfig::new(diagnosis_server_endpoint, system_params);
+    // the rate limits of a single host are shared by all participants
+    let participants = participant_count.max(1) as u32;
+    diagnosis_server_config.rate_limits.per_peer.capacity *= participants;
+    diagnosis_server_config.rate_limits.per_peer.refill_period /= participants;
+    diagnosis_server_config.rate_limits.queries.capacity *= participants;
+    diagnosis_server_config.rate_limits.queries.refill_period /= participants;
+    if let Some(http_endpoint) = config.diagnosis_server_http_endpoint {
+        diagnosis_server_config.http_endpoint = Some(http_endpoint.parse()?);
+    }
+    if let Some(metrics_endpoint) = config.diagnosis_server_metrics_endpoint {
+        diagnosis_server_config.metrics_endpoint = Some(metrics_endpoint.parse()?);
+    }
+    diagnosis_server_config.verification.required = true;
+    diagnosis_server_config.verification.codes = verification_codes;
+    diagnosis_server_config.signing_key = Some(signing_key);
     let yaml_diagnosis_server_config =
         serde_yaml::to_string(&diagnosis_server_config).context(format!(
             "Could not serialize diagnosis server config {:?}",
@@ -177,6 +272,26 @@ fn handle_generate_configs(args: GenerateConfigsArgs) -> Result<()> {
     )
     .context("Error writing diagnosis config")?;
 
+    if let Some(relay_endpoint) = relay_endpoint {
+        let relay_config = RelayConfig::new(relay_endpoint, system_params);
+        let yaml_relay_config = serde_yaml::to_string(&relay_config).context(format!(
+            "Could not serialize relay config {:?}",
+            relay_config
+        ))?;
+        write_config(&args.config_output_path, "relay", "yaml", yaml_relay_config)
+            .context("Error writing relay config")?;
+    } else {
+        // the runner spawns a relay whenever its config is present
+        let mut relay_config_path = args.config_output_path.clone();
+        relay_config_path.push("relay.yaml");
+        if relay_config_path.exists() {
+            fs::remove_file(&relay_config_path).context(format!(
+                "Error removing stale relay config {:?}",
+                relay_config_path
+            ))?;
+        }
+    }
+
     let dot_graph = format!("{}", Dot::new(&graph));
     write_config(
         &args.config_output_path,
@@ -10,7 +10,10 @@ const DIVIDER: &'static str = "-------------------------------------------";
 pub fn mark_ssev_group(graph: &mut Graph<Participant, Encounters>) -> () {
     let positively_tested: Vec<_> = graph
         .node_references()
-        .filter(|(_node_index, participant)| participant.positively_tested())
+        // revoked uploads do not warn anyone of an SSEV
+        .filter(|(_node_index, participant)| {
+            participant.positively_tested() && !participant.revoked()
+        })
         .map(|(infected, _participant)| {
             let mut ssev_times = HashSet::new();
             for traced in graph.neighbors(infected) {
@@ -0,0 +1,74 @@
+use crate::state::DiagnosisServerState;
+use exposurelib::diagnosis_server_state::{ChunkId, ChunkInfo};
+use exposurelib::error::RequestError;
+use exposurelib::logger;
+use exposurelib::primitives::ComputationId;
+use exposurelib::rpcs::{
+    Admin, ComputationInfo, ComputationKeys, ConsistencyParams, RetentionStatus,
+};
+use exposurelib::transparency::{ConsistencyProof, SignedTreeHead};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tarpc::context::Context;
+
+/// Serves the admin endpoint which is expected to be reachable by operators
+/// only, hence it neither rate limits nor authenticates.
+#[derive(Clone)]
+pub struct AdminHandler {
+    peer_addr: SocketAddr,
+    state: Arc<DiagnosisServerState>,
+}
+
+impl AdminHandler {
+    pub fn new(peer_addr: SocketAddr, state: Arc<DiagnosisServerState>) -> Self {
+        Self { peer_addr, state }
+    }
+}
+
+#[tarpc::server]
+impl Admin for AdminHandler {
+    async fn chunks(self, _: Context) -> Vec<ChunkInfo> {
+        logger::debug!("New admin chunks() RPC from {:?}", self.peer_addr);
+        self.state.admin_chunks().await
+    }
+    async fn computations(self, _: Context) -> Vec<ComputationInfo> {
+        logger::debug!("New admin computations() RPC from {:?}", self.peer_addr);
+        self.state.admin_computations().await
+    }
+    async fn computation_keys(
+        self,
+        _: Context,
+        computation_id: ComputationId,
+    ) -> Result<ComputationKeys, RequestError> {
+        logger::debug!(
+            "New admin computation_keys() RPC from {:?} for {:?}",
+            self.peer_addr,
+            computation_id
+        );
+        self.state.admin_computation_keys(computation_id).await
+    }
+    async fn rotate(self, _: Context) -> ChunkId {
+        logger::info!("New admin rotate() RPC from {:?}", self.peer_addr);
+        self.state.admin_rotate().await
+    }
+    async fn retention(self, _: Context) -> RetentionStatus {
+        logger::debug!("New admin retention() RPC from {:?}", self.peer_addr);
+        self.state.admin_retention().await
+    }
+    async fn tree_head(self, _: Context) -> SignedTreeHead {
+        logger::debug!("New admin tree_head() RPC from {:?}", self.peer_addr);
+        self.state.tree_head().await
+    }
+    async fn consistency(
+        self,
+        _: Context,
+        params: ConsistencyParams,
+    ) -> Result<ConsistencyProof, RequestError> {
+        logger::debug!(
+            "New admin consistency() RPC from {:?} with {:?}",
+            self.peer_addr,
+            params
+        );
+        self.state.consistency(params).await
+    }
+}
@@ -0,0 +1,71 @@
+use crate::state::DiagnosisServerState;
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use exposurelib::config::{FakeCasesConfig, SystemParams};
+use exposurelib::logger;
+use exposurelib::primitives::{TemporaryExposureKey, Validity};
+use exposurelib::rpcs::{self, BlacklistUploadParams};
+use exposurelib::time::{poisson_interval, ExposureTime};
+use ring::rand::SystemRandom;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::time;
+
+/// Publishes blacklists of random keys which run through the same lifecycle
+/// as the computations of genuine uploads.
+pub struct FakeCases {
+    config: FakeCasesConfig,
+    params: SystemParams,
+    secure_random: SystemRandom,
+    state: Arc<DiagnosisServerState>,
+}
+
+impl FakeCases {
+    pub fn new(
+        config: FakeCasesConfig,
+        params: SystemParams,
+        state: Arc<DiagnosisServerState>,
+    ) -> Self {
+        Self {
+            config,
+            params,
+            secure_random: SystemRandom::new(),
+            state,
+        }
+    }
+    pub async fn run(self) -> ! {
+        loop {
+            time::sleep(poisson_interval(
+                self.config.mean_interval,
+                &self.secure_random,
+            ))
+            .await;
+            if let Err(e) = self.publish().await {
+                logger::error!("Error publishing fake case: {:?}", e);
+            }
+        }
+    }
+    async fn publish(&self) -> Result<()> {
+        let tekrp = self.params.tek_rolling_period;
+        // keys of genuine uploads end with the current TEKRP as well
+        let latest = ExposureTime::from(Utc::now()).floor_tekrp_multiple(tekrp);
+        let mut diagnosis_keys = HashSet::with_capacity(self.params.max_diagnosis_keys());
+        diagnosis_keys.insert(Validity::new(
+            latest,
+            tekrp,
+            TemporaryExposureKey::new(&self.secure_random)?,
+        ));
+        rpcs::pad_diagnosis_keys(&mut diagnosis_keys, &self.params, &self.secure_random)?;
+        self.state
+            .add_to_blacklist(
+                BlacklistUploadParams {
+                    diagnosis_keys,
+                    verification_token: None,
+                },
+                None,
+            )
+            .await
+            .context("Diagnosis server state refused fake case")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+use crate::state::DiagnosisServerState;
+use anyhow::{Context, Result};
+use exposurelib::chunk_encoding::EncodedChunk;
+use exposurelib::config::{FederationConfig, FederationPeer, SystemParams, TokenBucketConfig};
+use exposurelib::diagnosis_server_state::{Chunk, ChunkId};
+use exposurelib::error::RequestError;
+use exposurelib::logger;
+use exposurelib::primitives::Region;
+use exposurelib::rpcs::{self, ChunkPage, Federation, PullParams, PushParams};
+use exposurelib::signing::PublicKey;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tarpc::context::{self, Context as RpcContext};
+use tarpc::{client, tokio_serde::formats};
+use tokio::time;
+
+/// Serves the federation endpoint to the configured peers.
+#[derive(Clone)]
+pub struct FederationHandler {
+    peer_addr: SocketAddr,
+    peers: Arc<HashMap<Region, FederationPeer>>,
+    limits: ImportLimits,
+    state: Arc<DiagnosisServerState>,
+}
+
+impl FederationHandler {
+    pub fn new(
+        peer_addr: SocketAddr,
+        peers: Arc<HashMap<Region, FederationPeer>>,
+        limits: ImportLimits,
+        state: Arc<DiagnosisServerState>,
+    ) -> Self {
+        Self {
+            peer_addr,
+            peers,
+            limits,
+            state,
+        }
+    }
+    /// The claimed region only counts if the request comes from the IP
+    /// address configured for the peer of that region.
+    fn authorize(&self, region: Region) -> Result<&FederationPeer, RequestError> {
+        match self.peers.get(&region) {
+            Some(peer) if peer.endpoint.ip() == self.peer_addr.ip() => Ok(peer),
+            Some(peer) => {
+                logger::error!(
+                    "Security event: {:?} claims to be {:?} configured at {:?}",
+                    self.peer_addr,
+                    region,
+                    peer.endpoint
+                );
+                Err(RequestError::UnknownRegion { region })
+            }
+            None => {
+                logger::warn!(
+                    "Refusing federation request of {:?} from {:?}",
+                    region,
+                    self.peer_addr
+                );
+                Err(RequestError::UnknownRegion { region })
+            }
+        }
+    }
+}
+
+#[tarpc::server]
+impl Federation for FederationHandler {
+    async fn pull(self, _: RpcContext, params: PullParams) -> Result<ChunkPage, RequestError> {
+        logger::debug!(
+            "New federation pull() RPC of {:?} from {:?} after {:?}",
+            params.region,
+            self.peer_addr,
+            params.after
+        );
+        self.authorize(params.region)?;
+        Ok(self.state.federation_pull(params.after).await)
+    }
+    async fn push(self, _: RpcContext, params: PushParams) -> Result<(), RequestError> {
+        logger::debug!(
+            "New federation push() RPC of {:?} from {:?} with {} chunks",
+            params.region,
+            self.peer_addr,
+            params.chunks.len()
+        );
+        let peer = self.authorize(params.region)?;
+        let chunks = self.limits.check(&params.chunks, peer.public_key.as_ref())?;
+        self.state.import(params.region, chunks).await
+    }
+}
+
+/// Imported chunks are held to the same limits as the uploads they were
+/// merged from.
+#[derive(Clone, Copy)]
+pub struct ImportLimits {
+    params: SystemParams,
+    per_computation: TokenBucketConfig,
+}
+
+impl ImportLimits {
+    pub fn new(params: SystemParams, per_computation: TokenBucketConfig) -> Self {
+        Self {
+            params,
+            per_computation,
+        }
+    }
+    pub fn max_frame_length(&self) -> usize {
+        self.params.limits.max_frame_length
+    }
+    /// Verifies the signatures if the peer's key is pinned and decodes the
+    /// chunks, which must hold valid uploads only.
+    fn check(
+        &self,
+        chunks: &[EncodedChunk],
+        public_key: Option<&PublicKey>,
+    ) -> Result<Vec<Chunk>, RequestError> {
+        chunks
+            .iter()
+            .map(|chunk| {
+                if let Some(public_key) = public_key {
+                    chunk
+                        .verify(public_key)
+                        .map_err(|e| RequestError::InvalidChunk {
+                            reason: e.to_string(),
+                        })?;
+                }
+                let chunk = chunk
+                    .decode(self.max_frame_length())
+                    .map_err(|e| RequestError::InvalidChunk {
+                        reason: e.to_string(),
+                    })?;
+                self.validate(&chunk)?;
+                Ok(chunk)
+            })
+            .collect()
+    }
+    /// A blacklist stems from a single upload whereas a greylist gathers the
+    /// uploads of a whole chunk period, which the per computation rate limit
+    /// bounds.
+    fn validate(&self, chunk: &Chunk) -> Result<(), RequestError> {
+        let max_greylist_keys = self.max_greylist_keys();
+        for computation in chunk.data().values() {
+            if !computation.blacklist().is_empty() {
+                rpcs::validate_diagnosis_keys(computation.blacklist(), &self.params)?;
+            }
+            let greylist = computation.greylist();
+            if greylist.len() > max_greylist_keys {
+                return Err(RequestError::TooManyDiagnosisKeys {
+                    count: greylist.len(),
+                    max: max_greylist_keys,
+                });
+            }
+            for diagnosis_key in greylist.iter() {
+                rpcs::validate_alignment(
+                    diagnosis_key.valid_from(),
+                    self.params.tek_rolling_period,
+                )?;
+            }
+        }
+        Ok(())
+    }
+    fn max_greylist_keys(&self) -> usize {
+        let chunk_period = chrono::Duration::from(self.params.chunk_period)
+            .to_std()
+            .unwrap_or_default();
+        let refills = chunk_period.as_secs_f64() / self.per_computation.refill_period.as_secs_f64();
+        (self.per_computation.capacity as usize + refills.ceil() as usize)
+            * self.params.max_diagnosis_keys()
+    }
+}
+
+/// Keeps a peer up to date by pushing each chunk right after it is done and
+/// catches up with the peer by pulling every pull period.
+pub struct Federator {
+    peer: FederationPeer,
+    pull_period: std::time::Duration,
+    limits: ImportLimits,
+    state: Arc<DiagnosisServerState>,
+    client: Option<rpcs::FederationClient>,
+    /// Latest own chunk the peer accepted
+    pushed: Option<ChunkId>,
+}
+
+impl Federator {
+    pub fn new(
+        peer: FederationPeer,
+        config: &FederationConfig,
+        limits: ImportLimits,
+        state: Arc<DiagnosisServerState>,
+    ) -> Self {
+        Self {
+            peer,
+            pull_period: config.pull_period,
+            limits,
+            state,
+            client: None,
+            pushed: None,
+        }
+    }
+    pub async fn run(mut self) -> ! {
+        let mut latest_done_chunk = self.state.latest_done_chunk();
+        let mut pull = time::interval(self.pull_period);
+        loop {
+            tokio::select! {
+                _ = pull.tick() => {
+                    if let Err(e) = self.pull().await {
+                        logger::warn!("Pulling from {:?} failed: {:?}", self.peer.region, e);
+                        self.client = None;
+                    }
+                }
+                Ok(()) = latest_done_chunk.changed() => {}
+            }
+            if let Err(e) = self.push().await {
+                logger::warn!("Pushing to {:?} failed: {:?}", self.peer.region, e);
+                self.client = None;
+            }
+        }
+    }
+    async fn pull(&mut self) -> Result<()> {
+        let region = self.state.region();
+        let mut after = self.state.imported_up_to(self.peer.region).await;
+        loop {
+            let page = self
+                .client()
+                .await?
+                .pull(context::current(), PullParams { region, after })
+                .await??;
+            let chunks = self
+                .limits
+                .check(&page.chunks, self.peer.public_key.as_ref())?;
+            logger::debug!(
+                "Pulled {} chunks after {:?} from {:?}",
+                chunks.len(),
+                after,
+                self.peer.region
+            );
+            after = chunks.last().map(Chunk::id).or(after);
+            self.state.import(self.peer.region, chunks).await?;
+            if page.continuation.is_none() {
+                return Ok(());
+            }
+        }
+    }
+    /// Pushes the own done chunks the peer did not accept yet, page by page.
+    async fn push(&mut self) -> Result<()> {
+        let region = self.state.region();
+        loop {
+            let page = self.state.federation_pull(self.pushed).await;
+            let last = match page.chunks.last() {
+                Some(last) => last.decode(self.max_frame_length())?.id(),
+                None => return Ok(()),
+            };
+            self.client()
+                .await?
+                .push(
+                    context::current(),
+                    PushParams {
+                        region,
+                        chunks: page.chunks,
+                    },
+                )
+                .await??;
+            logger::debug!("Pushed chunks up to {:?} to {:?}", last, self.peer.region);
+            self.pushed = Some(last);
+            if page.continuation.is_none() {
+                return Ok(());
+            }
+        }
+    }
+    fn max_frame_length(&self) -> usize {
+        self.limits.max_frame_length()
+    }
+    async fn client(&mut self) -> Result<&rpcs::FederationClient> {
+        if self.client.is_none() {
+            let mut transport = tarpc::serde_transport::tcp::connect(
+                &self.peer.endpoint,
+                formats::Bincode::default,
+            );
+            transport
+                .config_mut()
+                .max_frame_length(self.max_frame_length());
+            let transport = transport.await.context(format!(
+                "Error connecting to federation peer at {:?}",
+                self.peer.endpoint
+            ))?;
+            self.client = Some(
+                rpcs::FederationClient::new(client::Config::default(), transport)
+                    .spawn()
+                    .context("Error spawning federation client")?,
+            );
+        }
+        Ok(self.client.as_ref().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exposurelib::chunk_encoding::ChunkEncoding;
+    use exposurelib::config::RateLimits;
+    use exposurelib::diagnosis_server_state::ListType;
+    use exposurelib::primitives::{ComputationId, TemporaryExposureKey, Validity};
+    use exposurelib::signing::SigningKey;
+    use exposurelib::time::{ExposureTime, TimeInterval};
+    use ring::rand::SystemRandom;
+
+    fn chunk_with_blacklist(keys: u32) -> Chunk {
+        let secure_random = SystemRandom::new();
+        let params = SystemParams::default();
+        let tekrp = params.tek_rolling_period;
+        let blacklist: Vec<_> = (0..keys)
+            .map(|day| {
+                Validity::new(
+                    ExposureTime::from(2_700_000 + day * u32::from(tekrp)),
+                    tekrp,
+                    TemporaryExposureKey::new(&secure_random).unwrap(),
+                )
+            })
+            .collect();
+        let mut chunk = Chunk::new(
+            ChunkId::default(),
+            TimeInterval::with_alignment(chrono::Duration::from(params.chunk_period)),
+        );
+        chunk.insert(
+            ListType::Blacklist,
+            ComputationId::from(0),
+            blacklist.iter().collect(),
+        );
+        chunk
+    }
+
+    #[test]
+    fn test_import_limits() {
+        let params = SystemParams::default();
+        let limits = ImportLimits::new(params, RateLimits::default().per_computation);
+        let valid = EncodedChunk::encode(&chunk_with_blacklist(3), ChunkEncoding::default());
+        assert!(limits.check(std::slice::from_ref(&valid), None).is_ok());
+        // a blacklist larger than any single upload
+        let too_large = chunk_with_blacklist(params.max_diagnosis_keys() as u32 + 1);
+        let too_large = EncodedChunk::encode(&too_large, ChunkEncoding::default());
+        assert!(matches!(
+            limits.check(&[valid.clone(), too_large], None),
+            Err(RequestError::TooManyDiagnosisKeys { .. })
+        ));
+        // chunks of a peer with a pinned key must be signed with it
+        let signing_key = SigningKey::generate(&SystemRandom::new()).unwrap();
+        let public_key = signing_key.public_key().unwrap();
+        assert!(matches!(
+            limits.check(std::slice::from_ref(&valid), Some(&public_key)),
+            Err(RequestError::InvalidChunk { .. })
+        ));
+        let signed = valid.sign(&signing_key.key_pair().unwrap());
+        assert!(limits.check(&[signed], Some(&public_key)).is_ok());
+    }
+}
@@ -0,0 +1,776 @@
+use crate::handler::ConnectionHandler;
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use exposurelib::chunk_encoding::{ChunkEncoding, ChunkFormat, Compression};
+use exposurelib::diagnosis_server_state::{Chunk, ChunkId, ChunkInfo};
+use exposurelib::error::RequestError;
+use exposurelib::logger;
+use exposurelib::primitives::{
+    ComputationId, Key, TekRollingPeriod, TemporaryExposureKey, Validity,
+};
+use exposurelib::rpcs::{
+    BlacklistUploadParams, BlacklistUploadResponse, ChunkPage, DiagnosisServer, DownloadParams,
+    GreylistUploadParams, VerifyParams,
+};
+use exposurelib::time::ExposureTime;
+use exposurelib::verification::{
+    GreylistAuthorization, KeyCommitment, VerificationCode, VerificationToken,
+};
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderValue};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::convert::{Infallible, TryInto};
+use std::net::SocketAddr;
+use tarpc::context;
+
+/// Serves the diagnosis server RPCs as REST endpoints
+/// with either JSON or Bincode payloads:
+///
+/// * `POST /blacklist` with a blacklist upload
+/// * `POST /greylist` with a greylist upload
+/// * `POST /verify` for redeeming a verification code
+/// * `GET /chunks?after=<chunk id>` for downloading a page of chunks after the given id
+///   or the oldest chunks if omitted, see `continuation` for the next page
+/// * `GET /subscribe?after=<chunk id>` like `/chunks` but waits for newer chunks
+/// * `GET /index` for listing all available chunks
+///
+/// Bincode payloads are identical to the tarpc ones and are chosen via
+/// `Content-Type: application/octet-stream` for requests and
+/// `Accept: application/octet-stream` for responses; JSON is the default.
+/// Bincode chunk downloads additionally accept `format=bincode|columnar`
+/// and `compression=none|gzip` query parameters.
+#[derive(Clone)]
+pub struct Gateway {
+    handler: ConnectionHandler,
+    max_body_length: usize,
+}
+
+impl Gateway {
+    pub fn new(handler: ConnectionHandler, max_body_length: usize) -> Self {
+        Self {
+            handler,
+            max_body_length,
+        }
+    }
+    pub async fn serve(self, endpoint: SocketAddr) -> Result<()> {
+        let make_service = make_service_fn(move |connection: &AddrStream| {
+            let gateway = self.clone();
+            let peer_addr = connection.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    gateway.clone().handle(peer_addr, request)
+                }))
+            }
+        });
+        logger::info!("HTTP gateway listening on {}", endpoint);
+        Server::try_bind(&endpoint)
+            .context(format!("Error binding HTTP gateway to {}", endpoint))?
+            .serve(make_service)
+            .await
+            .context("Error serving HTTP gateway")
+    }
+    async fn handle(
+        self,
+        peer_addr: SocketAddr,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        logger::trace!(
+            "New HTTP {} {} request from {:?}",
+            request.method(),
+            request.uri(),
+            peer_addr
+        );
+        let accept = Encoding::from_header(&request, header::ACCEPT);
+        let handler = self.handler.for_peer(peer_addr);
+        let tekrp = handler.params().tek_rolling_period;
+        let response = match (request.method(), request.uri().path()) {
+            (&Method::POST, "/blacklist") => {
+                match self
+                    .read_body::<JsonBlacklistUpload, _>(request, tekrp)
+                    .await
+                {
+                    Ok(params) => {
+                        match handler.blacklist_upload(context::current(), params).await {
+                            Ok(response) => accept
+                                .encode::<_, JsonBlacklistUploadResponse>(StatusCode::OK, response),
+                            Err(e) => GatewayError::Rejected(e).into_response(accept),
+                        }
+                    }
+                    Err(e) => e.into_response(accept),
+                }
+            }
+            (&Method::POST, "/greylist") => {
+                match self
+                    .read_body::<JsonGreylistUpload, _>(request, tekrp)
+                    .await
+                {
+                    Ok(params) => match handler.greylist_upload(context::current(), params).await {
+                        Ok(()) => empty_response(StatusCode::NO_CONTENT),
+                        Err(e) => GatewayError::Rejected(e).into_response(accept),
+                    },
+                    Err(e) => e.into_response(accept),
+                }
+            }
+            (&Method::POST, "/verify") => {
+                match self.read_body::<JsonVerify, _>(request, tekrp).await {
+                    Ok(params) => match handler.verify(context::current(), params).await {
+                        Ok(token) => {
+                            accept.encode::<_, JsonVerificationToken>(StatusCode::OK, token)
+                        }
+                        Err(e) => GatewayError::Rejected(e).into_response(accept),
+                    },
+                    Err(e) => e.into_response(accept),
+                }
+            }
+            (&Method::GET, "/chunks") => match download_params(&request, accept) {
+                Ok(params) => {
+                    let page = handler.download(context::current(), params).await;
+                    accept.encode::<_, JsonChunkPage>(StatusCode::OK, page)
+                }
+                Err(e) => e.into_response(accept),
+            },
+            (&Method::GET, "/subscribe") => match download_params(&request, accept) {
+                Ok(params) => {
+                    let page = handler.subscribe(context::current(), params).await;
+                    accept.encode::<_, JsonChunkPage>(StatusCode::OK, page)
+                }
+                Err(e) => e.into_response(accept),
+            },
+            (&Method::GET, "/index") => {
+                let index = handler.index(context::current()).await;
+                accept.encode::<_, JsonIndex>(StatusCode::OK, index)
+            }
+            (_, "/blacklist")
+            | (_, "/greylist")
+            | (_, "/verify")
+            | (_, "/chunks")
+            | (_, "/subscribe")
+            | (_, "/index") => empty_response(StatusCode::METHOD_NOT_ALLOWED),
+            _ => empty_response(StatusCode::NOT_FOUND),
+        };
+        Ok(response)
+    }
+    /// Reads and decodes a request body of at most `max_body_length` bytes.
+    async fn read_body<J, T>(
+        &self,
+        mut request: Request<Body>,
+        tekrp: TekRollingPeriod,
+    ) -> Result<T, GatewayError>
+    where
+        J: for<'de> Deserialize<'de> + IntoNative<T>,
+        T: for<'de> Deserialize<'de>,
+    {
+        let encoding = Encoding::from_header(&request, header::CONTENT_TYPE);
+        let mut body = Vec::new();
+        while let Some(data) = request.body_mut().data().await {
+            let data = data.map_err(|e| GatewayError::BadRequest(e.to_string()))?;
+            if body.len() + data.len() > self.max_body_length {
+                return Err(GatewayError::PayloadTooLarge);
+            }
+            body.extend_from_slice(&data);
+        }
+        match encoding {
+            Encoding::Json => serde_json::from_slice::<J>(&body)
+                .map_err(|e| GatewayError::BadRequest(e.to_string()))?
+                .into_native(tekrp),
+            Encoding::Bincode => {
+                bincode::deserialize(&body).map_err(|e| GatewayError::BadRequest(e.to_string()))
+            }
+        }
+    }
+}
+
+fn download_params(
+    request: &Request<Body>,
+    accept: Encoding,
+) -> Result<DownloadParams, GatewayError> {
+    let query = |name: &str| {
+        request
+            .uri()
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+    };
+    let invalid = |name: &str, value: &dyn std::fmt::Display| {
+        GatewayError::BadRequest(format!("Invalid query parameter {}: {}", name, value))
+    };
+    let after = match query("after") {
+        Some(after) => Some(ChunkId::from(
+            after.parse::<u64>().map_err(|e| invalid("after", &e))?,
+        )),
+        None => None,
+    };
+    // JSON responses are built from decoded chunks, hence encode them plainly
+    let mut encoding = ChunkEncoding::plain();
+    if let Encoding::Bincode = accept {
+        encoding.format = match query("format") {
+            None | Some("bincode") => ChunkFormat::Bincode,
+            Some("columnar") => ChunkFormat::Columnar,
+            Some(format) => return Err(invalid("format", &format)),
+        };
+        encoding.compression = match query("compression") {
+            None | Some("none") => Compression::None,
+            Some("gzip") => Compression::Gzip,
+            Some(compression) => return Err(invalid("compression", &compression)),
+        };
+    }
+    Ok(DownloadParams { after, encoding })
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Encoding {
+    Json,
+    Bincode,
+}
+
+impl Encoding {
+    const JSON: &'static str = "application/json";
+    const BINCODE: &'static str = "application/octet-stream";
+
+    fn from_header(request: &Request<Body>, name: header::HeaderName) -> Self {
+        match request.headers().get(name).map(HeaderValue::to_str) {
+            Some(Ok(value)) if value.contains(Self::BINCODE) => Encoding::Bincode,
+            _ => Encoding::Json,
+        }
+    }
+    fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => Self::JSON,
+            Encoding::Bincode => Self::BINCODE,
+        }
+    }
+    /// Encodes `value` as Bincode or its JSON representation `J` as JSON.
+    fn encode<T: Serialize, J: Serialize + From<T>>(
+        &self,
+        status: StatusCode,
+        value: T,
+    ) -> Response<Body> {
+        let body = match self {
+            Encoding::Json => serde_json::to_vec(&J::from(value)).unwrap(),
+            Encoding::Bincode => bincode::serialize(&value).unwrap(),
+        };
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(self.content_type()),
+        );
+        response
+    }
+}
+
+#[derive(Debug)]
+enum GatewayError {
+    BadRequest(String),
+    PayloadTooLarge,
+    Rejected(RequestError),
+}
+
+impl GatewayError {
+    fn into_response(self, encoding: Encoding) -> Response<Body> {
+        let error = match self {
+            GatewayError::BadRequest(message) => {
+                let mut response = empty_response(StatusCode::BAD_REQUEST);
+                *response.body_mut() = Body::from(message);
+                return response;
+            }
+            GatewayError::PayloadTooLarge => {
+                return empty_response(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            GatewayError::Rejected(error) => error,
+        };
+        let status = match error {
+            RequestError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            RequestError::ComputationIdsExhausted | RequestError::StoreUnavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            RequestError::VerificationRequired
+            | RequestError::InvalidVerificationCode
+            | RequestError::InvalidVerificationToken
+            | RequestError::VerificationTokenExpired { .. }
+            | RequestError::KeyCommitmentMismatch
+            | RequestError::InvalidGreylistAuthorization => StatusCode::FORBIDDEN,
+            RequestError::UnknownComputation { .. } => StatusCode::NOT_FOUND,
+            RequestError::ComputationClosed { .. } => StatusCode::GONE,
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        };
+        let retry_after = match &error {
+            RequestError::RateLimited { retry_after } => Some(retry_after.as_secs() + 1),
+            _ => None,
+        };
+        let mut response = encoding.encode::<_, JsonError>(status, error);
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+/// Conversion of a JSON representation into the native RPC type.
+trait IntoNative<T> {
+    fn into_native(self, tekrp: TekRollingPeriod) -> Result<T, GatewayError>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonDiagnosisKey {
+    /// EN interval number at which the key becomes valid
+    valid_from: u32,
+    /// hex encoded temporary exposure key
+    tek: String,
+}
+
+impl JsonDiagnosisKey {
+    fn into_validity(
+        self,
+        tekrp: TekRollingPeriod,
+    ) -> Result<Validity<TemporaryExposureKey>, GatewayError> {
+        let key: [u8; 16] = hex::decode(&self.tek)
+            .map_err(|e| GatewayError::BadRequest(format!("Invalid TEK {}: {}", self.tek, e)))?
+            .try_into()
+            .map_err(|_| GatewayError::BadRequest(format!("Invalid TEK length of {}", self.tek)))?;
+        let valid_from = ExposureTime::from(self.valid_from);
+        let validity = Validity::new(valid_from, tekrp, TemporaryExposureKey::from(key));
+        // Validity::new() silently aligns, but misaligned uploads must be rejected
+        if validity.valid_from() != valid_from {
+            return Err(GatewayError::Rejected(RequestError::MisalignedValidity {
+                valid_from,
+            }));
+        }
+        Ok(validity)
+    }
+}
+
+impl From<&Validity<TemporaryExposureKey>> for JsonDiagnosisKey {
+    fn from(validity: &Validity<TemporaryExposureKey>) -> Self {
+        Self {
+            valid_from: u32::from(validity.valid_from()),
+            tek: hex::encode(validity.keyring().get()),
+        }
+    }
+}
+
+fn from_json_keys(
+    diagnosis_keys: Vec<JsonDiagnosisKey>,
+    tekrp: TekRollingPeriod,
+) -> Result<HashSet<Validity<TemporaryExposureKey>>, GatewayError> {
+    diagnosis_keys
+        .into_iter()
+        .map(|diagnosis_key| diagnosis_key.into_validity(tekrp))
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct JsonBlacklistUpload {
+    diagnosis_keys: Vec<JsonDiagnosisKey>,
+    /// as returned by `/verify`
+    #[serde(default)]
+    verification_token: Option<String>,
+}
+
+impl IntoNative<BlacklistUploadParams> for JsonBlacklistUpload {
+    fn into_native(self, tekrp: TekRollingPeriod) -> Result<BlacklistUploadParams, GatewayError> {
+        let verification_token = match self.verification_token {
+            Some(token) => Some(
+                hex::decode(&token)
+                    .ok()
+                    .and_then(|token| bincode::deserialize(&token).ok())
+                    .ok_or_else(|| {
+                        GatewayError::BadRequest(String::from("Invalid verification token"))
+                    })?,
+            ),
+            None => None,
+        };
+        Ok(BlacklistUploadParams {
+            diagnosis_keys: from_json_keys(self.diagnosis_keys, tekrp)?,
+            verification_token,
+        })
+    }
+}
+
+/// Takes the diagnosis keys to upload instead of their commitment for convenience.
+#[derive(Deserialize)]
+struct JsonVerify {
+    code: String,
+    diagnosis_keys: Vec<JsonDiagnosisKey>,
+}
+
+impl IntoNative<VerifyParams> for JsonVerify {
+    fn into_native(self, tekrp: TekRollingPeriod) -> Result<VerifyParams, GatewayError> {
+        Ok(VerifyParams {
+            code: VerificationCode::from(self.code),
+            key_commitment: KeyCommitment::new(&from_json_keys(self.diagnosis_keys, tekrp)?),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct JsonVerificationToken {
+    /// hex encoded and opaque to the client
+    verification_token: String,
+}
+
+impl From<VerificationToken> for JsonVerificationToken {
+    fn from(token: VerificationToken) -> Self {
+        Self {
+            verification_token: hex::encode(bincode::serialize(&token).unwrap()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonGreylistUpload {
+    computation_id: u32,
+    diagnosis_keys: Vec<JsonDiagnosisKey>,
+    /// hex encoded HMAC-SHA256 keyed by the computation's greylist secret
+    authorization: String,
+}
+
+impl IntoNative<GreylistUploadParams> for JsonGreylistUpload {
+    fn into_native(self, tekrp: TekRollingPeriod) -> Result<GreylistUploadParams, GatewayError> {
+        let authorization = hex::decode(&self.authorization).map_err(|_| {
+            GatewayError::BadRequest(String::from("Invalid greylist authorization"))
+        })?;
+        Ok(GreylistUploadParams {
+            computation_id: ComputationId::from(self.computation_id),
+            diagnosis_keys: from_json_keys(self.diagnosis_keys, tekrp)?,
+            authorization: GreylistAuthorization::from(authorization),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct JsonBlacklistUploadResponse {
+    computation_id: u32,
+    /// hex encoded, keys the MAC authorizing greylist uploads
+    greylist_secret: String,
+}
+
+impl From<BlacklistUploadResponse> for JsonBlacklistUploadResponse {
+    fn from(response: BlacklistUploadResponse) -> Self {
+        Self {
+            computation_id: u32::from(response.computation_id),
+            greylist_secret: hex::encode(bincode::serialize(&response.greylist_secret).unwrap()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    error: String,
+    details: RequestError,
+}
+
+impl From<RequestError> for JsonError {
+    fn from(error: RequestError) -> Self {
+        Self {
+            error: error.to_string(),
+            details: error,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonChunkPage {
+    chunks: Vec<JsonChunk>,
+    continuation: Option<u64>,
+}
+
+impl From<ChunkPage> for JsonChunkPage {
+    fn from(page: ChunkPage) -> Self {
+        Self {
+            chunks: page
+                .chunks
+                .iter()
+                .map(|chunk| {
+                    chunk
+                        .decode(usize::MAX)
+                        .expect("Chunks encoded by this server must decode")
+                })
+                .map(JsonChunk::from)
+                .collect(),
+            continuation: page.continuation.map(u64::from),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonChunk {
+    id: u64,
+    from_including: DateTime<Utc>,
+    to_excluding: DateTime<Utc>,
+    computations: Vec<JsonComputation>,
+    closed_computations: Vec<u32>,
+    revoked_computations: Vec<u32>,
+}
+
+#[derive(Serialize)]
+struct JsonComputation {
+    computation_id: u32,
+    blacklist: Vec<JsonDiagnosisKey>,
+    greylist: Vec<JsonDiagnosisKey>,
+}
+
+impl From<Chunk> for JsonChunk {
+    fn from(chunk: Chunk) -> Self {
+        Self {
+            id: u64::from(chunk.id()),
+            from_including: *chunk.covers().from_including(),
+            to_excluding: *chunk.covers().to_excluding(),
+            computations: chunk
+                .data()
+                .iter()
+                .map(|(computation_id, computation_state)| JsonComputation {
+                    computation_id: u32::from(*computation_id),
+                    blacklist: computation_state
+                        .blacklist()
+                        .iter()
+                        .map(Into::into)
+                        .collect(),
+                    greylist: computation_state
+                        .greylist()
+                        .iter()
+                        .map(Into::into)
+                        .collect(),
+                })
+                .collect(),
+            closed_computations: chunk.closed().iter().copied().map(u32::from).collect(),
+            revoked_computations: chunk.revoked().iter().copied().map(u32::from).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+struct JsonIndex(Vec<JsonChunkInfo>);
+
+#[derive(Serialize)]
+struct JsonChunkInfo {
+    id: u64,
+    from_including: DateTime<Utc>,
+    to_excluding: DateTime<Utc>,
+    size: u64,
+    diagnosis_keys: usize,
+}
+
+impl From<Vec<ChunkInfo>> for JsonIndex {
+    fn from(index: Vec<ChunkInfo>) -> Self {
+        Self(
+            index
+                .into_iter()
+                .map(|info| JsonChunkInfo {
+                    id: u64::from(info.id),
+                    from_including: *info.covers.from_including(),
+                    to_excluding: *info.covers.to_excluding(),
+                    size: info.size,
+                    diagnosis_keys: info.diagnosis_keys,
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exposurelib::config::{DiagnosisServerConfig, SystemParams};
+    use exposurelib::primitives::SystemRandom;
+    use exposurelib::verification::GreylistSecret;
+
+    fn gateway(max_body_length: usize) -> Gateway {
+        let config =
+            DiagnosisServerConfig::new("127.0.0.1:0".parse().unwrap(), SystemParams::default());
+        Gateway::new(ConnectionHandler::in_memory(&config), max_body_length)
+    }
+
+    fn json_key(valid_from: u32) -> serde_json::Value {
+        let tek = TemporaryExposureKey::new(&SystemRandom::new()).unwrap();
+        serde_json::json!({ "valid_from": valid_from, "tek": hex::encode(tek.get()) })
+    }
+
+    async fn request(
+        gateway: &Gateway,
+        method: Method,
+        uri: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> (StatusCode, Vec<u8>) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::ACCEPT, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        let response = gateway
+            .clone()
+            .handle("127.0.0.1:1".parse().unwrap(), request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    async fn post_json(
+        gateway: &Gateway,
+        uri: &str,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let (status, body) = request(
+            gateway,
+            Method::POST,
+            uri,
+            Encoding::JSON,
+            serde_json::to_vec(&body).unwrap(),
+        )
+        .await;
+        (
+            status,
+            serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let gateway = gateway(1 << 20);
+        let (status, response) = post_json(
+            &gateway,
+            "/blacklist",
+            serde_json::json!({ "diagnosis_keys": [json_key(144)] }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let computation_id = response["computation_id"].as_u64().unwrap() as u32;
+        let greylist_secret: GreylistSecret = bincode::deserialize(
+            &hex::decode(response["greylist_secret"].as_str().unwrap()).unwrap(),
+        )
+        .unwrap();
+
+        // Bincode payloads are the RPC parameters themselves
+        let diagnosis_keys: HashSet<_> = vec![Validity::new(
+            ExposureTime::from(144),
+            TekRollingPeriod::default(),
+            TemporaryExposureKey::new(&SystemRandom::new()).unwrap(),
+        )]
+        .into_iter()
+        .collect();
+        let params = GreylistUploadParams::new(
+            ComputationId::from(computation_id),
+            diagnosis_keys,
+            &greylist_secret,
+        );
+        let (status, _) = request(
+            &gateway,
+            Method::POST,
+            "/greylist",
+            Encoding::BINCODE,
+            bincode::serialize(&params).unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, body) =
+            request(&gateway, Method::GET, "/index", Encoding::JSON, Vec::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(serde_json::from_slice::<serde_json::Value>(&body)
+            .unwrap()
+            .is_array());
+        let (status, body) = request(
+            &gateway,
+            Method::GET,
+            "/chunks?format=columnar&compression=gzip",
+            Encoding::BINCODE,
+            Vec::new(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let page: ChunkPage = bincode::deserialize(&body).unwrap();
+        assert!(page.continuation.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rejection() {
+        let gateway = gateway(1024);
+        // Validity::new() would align the key, the gateway must not
+        let (status, response) = post_json(
+            &gateway,
+            "/blacklist",
+            serde_json::json!({ "diagnosis_keys": [json_key(145)] }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(response["details"]["MisalignedValidity"].is_object());
+
+        let (status, response) = post_json(
+            &gateway,
+            "/blacklist",
+            serde_json::json!({ "diagnosis_keys": [json_key(144)] }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = post_json(
+            &gateway,
+            "/greylist",
+            serde_json::json!({
+                "computation_id": response["computation_id"],
+                "diagnosis_keys": [json_key(144)],
+                "authorization": "00",
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) =
+            post_json(&gateway, "/blacklist", serde_json::json!({ "keys": [] })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request(
+            &gateway,
+            Method::POST,
+            "/blacklist",
+            Encoding::JSON,
+            vec![b' '; 1025],
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let (status, _) = request(
+            &gateway,
+            Method::GET,
+            "/chunks?after=x",
+            Encoding::JSON,
+            Vec::new(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request(
+            &gateway,
+            Method::GET,
+            "/blacklist",
+            Encoding::JSON,
+            Vec::new(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, _) = request(
+            &gateway,
+            Method::GET,
+            "/unknown",
+            Encoding::JSON,
+            Vec::new(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
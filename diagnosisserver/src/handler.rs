@@ -1,10 +1,18 @@
+use crate::mailbox::Mailbox;
+use crate::rate_limiter::RateLimiters;
 use crate::state::DiagnosisServerState;
-use exposurelib::diagnosis_server_state::Chunk;
+use crate::verification::Verifier;
+use exposurelib::config::SystemParams;
+use exposurelib::diagnosis_server_state::ChunkInfo;
+use exposurelib::error::RequestError;
 use exposurelib::logger;
-use exposurelib::primitives::ComputationId;
 use exposurelib::rpcs::{
-    BlacklistUploadParams, DiagnosisServer, DownloadParams, GreylistUploadParams,
+    BlacklistUploadParams, BlacklistUploadResponse, ChunkPage, ConsistencyParams, Deposit,
+    DiagnosisServer, DownloadParams, Features, FetchParams, GreylistUploadParams, Handshake,
+    RevokeParams, VerifyParams,
 };
+use exposurelib::transparency::{ConsistencyProof, SignedTreeHead};
+use exposurelib::verification::VerificationToken;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tarpc::context::Context;
@@ -12,12 +20,82 @@ use tarpc::context::Context;
 #[derive(Clone)]
 pub struct ConnectionHandler {
     peer_addr: SocketAddr,
+    params: SystemParams,
+    rate_limiters: Arc<RateLimiters>,
+    verifier: Arc<Verifier>,
+    mailbox: Arc<Mailbox>,
     state: Arc<DiagnosisServerState>,
 }
 
 impl ConnectionHandler {
-    pub fn new(peer_addr: SocketAddr, state: Arc<DiagnosisServerState>) -> Self {
-        Self { peer_addr, state }
+    pub fn new(
+        peer_addr: SocketAddr,
+        params: SystemParams,
+        rate_limiters: Arc<RateLimiters>,
+        verifier: Arc<Verifier>,
+        mailbox: Arc<Mailbox>,
+        state: Arc<DiagnosisServerState>,
+    ) -> Self {
+        Self {
+            peer_addr,
+            params,
+            rate_limiters,
+            verifier,
+            mailbox,
+            state,
+        }
+    }
+    pub fn params(&self) -> &SystemParams {
+        &self.params
+    }
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+    pub fn state(&self) -> &DiagnosisServerState {
+        &self.state
+    }
+    pub fn features() -> Features {
+        Features::CHUNK_ENCODINGS
+            | Features::PAGINATION
+            | Features::SUBSCRIPTIONS
+            | Features::INDEX
+            | Features::MAILBOX
+            | Features::COVER_TRAFFIC
+            | Features::REVOCATION
+            | Features::TRANSPARENCY
+    }
+    /// Serves `config.endpoint` from a fresh in-memory state.
+    #[cfg(test)]
+    pub fn in_memory(config: &exposurelib::config::DiagnosisServerConfig) -> Self {
+        let store = Arc::new(crate::store::InMemoryStore::default());
+        Self::new(
+            config.endpoint,
+            config.params,
+            Arc::new(RateLimiters::new(config.rate_limits)),
+            Arc::new(Verifier::new(&config.verification, store).unwrap()),
+            Arc::new(Mailbox::new(
+                config.mailbox,
+                chrono::Duration::from(config.params.computation_period),
+            )),
+            Arc::new(DiagnosisServerState::in_memory(config)),
+        )
+    }
+    /// Returns a handler sharing all state with this one but serving the given peer.
+    pub fn for_peer(&self, peer_addr: SocketAddr) -> Self {
+        Self {
+            peer_addr,
+            ..self.clone()
+        }
+    }
+    fn reject(&self, rpc: &str, error: RequestError) -> RequestError {
+        self.state.metrics().rejected(rpc);
+        logger::warn!(
+            "Rejecting {}() RPC from {:?}: {}",
+            rpc,
+            self.peer_addr,
+            error
+        );
+        error
     }
 }
 
@@ -27,25 +105,89 @@ impl DiagnosisServer for ConnectionHandler {
         self,
         _context: Context,
         params: BlacklistUploadParams,
-    ) -> ComputationId {
+    ) -> Result<BlacklistUploadResponse, RequestError> {
         logger::trace!(
             "New blacklist_upload() RPC from {:?} with context {:?} and params {:?}",
             self.peer_addr,
             _context,
             params
         );
-        self.state.add_to_blacklist(params).await
+        self.rate_limiters
+            .check_peer(self.peer_addr)
+            .await
+            .map_err(|e| self.reject("blacklist_upload", e))?;
+        params
+            .validate(&self.params)
+            .map_err(|e| self.reject("blacklist_upload", e))?;
+        if self
+            .verifier
+            .is_cover_token(params.verification_token.as_ref())
+        {
+            self.verifier
+                .redeem_cover_token(params.verification_token.as_ref(), &params.diagnosis_keys)
+                .await
+                .map_err(|e| self.reject("blacklist_upload", e))?;
+            return self
+                .state
+                .cover_blacklist(&params)
+                .await
+                .map_err(|e| self.reject("blacklist_upload", e));
+        }
+        self.verifier
+            .redeem_token(params.verification_token.as_ref(), &params.diagnosis_keys)
+            .await
+            .map_err(|e| self.reject("blacklist_upload", e))?;
+        let verification_token = params.verification_token.clone();
+        match self
+            .state
+            .add_to_blacklist(params, Some(self.peer_addr))
+            .await
+        {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                // the token is not used up by an upload which was not stored
+                self.verifier
+                    .release_token(verification_token.as_ref())
+                    .await;
+                Err(self.reject("blacklist_upload", e))
+            }
+        }
     }
-    async fn greylist_upload(self, context: Context, params: GreylistUploadParams) -> () {
+    async fn greylist_upload(
+        self,
+        context: Context,
+        params: GreylistUploadParams,
+    ) -> Result<(), RequestError> {
         logger::trace!(
             "New greylist_upload() RPC from {:?} with context {:?} and params {:?}",
             self.peer_addr,
             context,
             params
         );
-        self.state.add_to_greylist(params).await
+        self.rate_limiters
+            .check_peer(self.peer_addr)
+            .await
+            .map_err(|e| self.reject("greylist_upload", e))?;
+        params
+            .validate(&self.params)
+            .map_err(|e| self.reject("greylist_upload", e))?;
+        self.rate_limiters
+            .check_computation(params.computation_id)
+            .await
+            .map_err(|e| self.reject("greylist_upload", e))?;
+        if self.state.is_cover_computation(params.computation_id).await {
+            return self
+                .state
+                .cover_greylist(&params)
+                .await
+                .map_err(|e| self.reject("greylist_upload", e));
+        }
+        self.state
+            .add_to_greylist(params, Some(self.peer_addr))
+            .await
+            .map_err(|e| self.reject("greylist_upload", e))
     }
-    async fn download(self, context: Context, params: DownloadParams) -> Vec<Chunk> {
+    async fn download(self, context: Context, params: DownloadParams) -> ChunkPage {
         logger::trace!(
             "New download() RPC from {:?} with context {:?} and params {:?}",
             self.peer_addr,
@@ -54,4 +196,159 @@ impl DiagnosisServer for ConnectionHandler {
         );
         self.state.request_chunks(params).await
     }
+    async fn subscribe(self, context: Context, params: DownloadParams) -> ChunkPage {
+        logger::trace!(
+            "New subscribe() RPC from {:?} with context {:?} and params {:?}",
+            self.peer_addr,
+            context,
+            params
+        );
+        self.state.subscribe_chunks(params).await
+    }
+    async fn index(self, context: Context) -> Vec<ChunkInfo> {
+        logger::trace!(
+            "New index() RPC from {:?} with context {:?}",
+            self.peer_addr,
+            context
+        );
+        self.state.request_index().await
+    }
+    async fn handshake(
+        self,
+        context: Context,
+        params: Handshake,
+    ) -> Result<Handshake, RequestError> {
+        logger::trace!(
+            "New handshake() RPC from {:?} with context {:?} and params {:?}",
+            self.peer_addr,
+            context,
+            params
+        );
+        Handshake::new(Self::features())
+            .negotiate(&params)
+            .map_err(|e| self.reject("handshake", e))
+    }
+    async fn verify(
+        self,
+        context: Context,
+        params: VerifyParams,
+    ) -> Result<VerificationToken, RequestError> {
+        logger::trace!(
+            "New verify() RPC from {:?} with context {:?} and params {:?}",
+            self.peer_addr,
+            context,
+            params
+        );
+        // guessing codes is slowed down by the rate limit
+        self.rate_limiters
+            .check_peer(self.peer_addr)
+            .await
+            .map_err(|e| self.reject("verify", e))?;
+        self.verifier
+            .redeem_code(params)
+            .await
+            .map_err(|e| self.reject("verify", e))
+    }
+    async fn deposit(self, context: Context, params: Deposit) -> Result<(), RequestError> {
+        logger::trace!(
+            "New deposit() RPC from {:?} with context {:?} and params {:?}",
+            self.peer_addr,
+            context,
+            params
+        );
+        self.rate_limiters
+            .check_peer(self.peer_addr)
+            .await
+            .map_err(|e| self.reject("deposit", e))?;
+        self.mailbox
+            .deposit(params)
+            .await
+            .map_err(|e| self.reject("deposit", e))
+    }
+    async fn fetch(
+        self,
+        context: Context,
+        params: FetchParams,
+    ) -> Result<Vec<Deposit>, RequestError> {
+        logger::trace!(
+            "New fetch() RPC from {:?} with context {:?} and params {:?}",
+            self.peer_addr,
+            context,
+            params
+        );
+        self.rate_limiters
+            .check_query(self.peer_addr)
+            .await
+            .map_err(|e| self.reject("fetch", e))?;
+        params
+            .validate(&self.params)
+            .map_err(|e| self.reject("fetch", e))?;
+        self.mailbox
+            .fetch(&params.secrets)
+            .await
+            .map_err(|e| self.reject("fetch", e))
+    }
+    async fn revoke(self, context: Context, params: RevokeParams) -> Result<(), RequestError> {
+        logger::trace!(
+            "New revoke() RPC from {:?} with context {:?} and params {:?}",
+            self.peer_addr,
+            context,
+            params
+        );
+        self.rate_limiters
+            .check_peer(self.peer_addr)
+            .await
+            .map_err(|e| self.reject("revoke", e))?;
+        self.state
+            .authorize_revocation(&params)
+            .await
+            .map_err(|e| self.reject("revoke", e))?;
+        self.verifier
+            .redeem_revocation_token(params.verification_token.as_ref(), params.computation_id)
+            .await
+            .map_err(|e| self.reject("revoke", e))?;
+        let verification_token = params.verification_token.clone();
+        match self.state.revoke(params, Some(self.peer_addr)).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // e.g. revoked concurrently, the token is not used up
+                self.verifier
+                    .release_token(verification_token.as_ref())
+                    .await;
+                Err(self.reject("revoke", e))
+            }
+        }
+    }
+    async fn tree_head(self, context: Context) -> Result<SignedTreeHead, RequestError> {
+        logger::trace!(
+            "New tree_head() RPC from {:?} with context {:?}",
+            self.peer_addr,
+            context
+        );
+        self.rate_limiters
+            .check_query(self.peer_addr)
+            .await
+            .map_err(|e| self.reject("tree_head", e))?;
+        Ok(self.state.tree_head().await)
+    }
+    async fn consistency(
+        self,
+        context: Context,
+        params: ConsistencyParams,
+    ) -> Result<ConsistencyProof, RequestError> {
+        logger::trace!(
+            "New consistency() RPC from {:?} with context {:?} and params {:?}",
+            self.peer_addr,
+            context,
+            params
+        );
+        self.rate_limiters
+            .check_query(self.peer_addr)
+            .await
+            .map_err(|e| self.reject("consistency", e))?;
+        self.state
+            .consistency(params)
+            .await
+            .map_err(|e| self.reject("consistency", e))
+    }
 }
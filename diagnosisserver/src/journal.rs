@@ -0,0 +1,259 @@
+use crate::state::ComputationRecord;
+use crate::store::ChunkStore;
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use exposurelib::config::JournalConfig;
+use exposurelib::diagnosis_server_state::{Chunk, ChunkId, ListType};
+use exposurelib::journal::{self, JournalEntry, JournalWriter};
+use exposurelib::logger;
+use exposurelib::primitives::{ComputationId, Region, TemporaryExposureKey, Validity};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+/// Records the mutations of the diagnosis server state if configured.
+/// Callers hold the lock of the current chunk until the record is written,
+/// hence records are in the order the mutations took place.
+pub struct Journal {
+    writer: Option<Arc<Mutex<JournalWriter>>>,
+    anonymise_peers: bool,
+}
+
+impl Journal {
+    pub fn open(config: Option<&JournalConfig>) -> Result<Self> {
+        Ok(match config {
+            Some(config) => {
+                logger::info!("Journaling to {:?}", config.path);
+                Self {
+                    writer: Some(Arc::new(Mutex::new(
+                        JournalWriter::open(&config.path).context("Error opening journal")?,
+                    ))),
+                    anonymise_peers: config.anonymise_peers,
+                }
+            }
+            None => Self::disabled(),
+        })
+    }
+    pub fn disabled() -> Self {
+        Self {
+            writer: None,
+            anonymise_peers: false,
+        }
+    }
+    /// Blocks as it is only called on startup.
+    pub fn started(&self, current_chunk: &Chunk) {
+        if let Some(writer) = &self.writer {
+            let entry = JournalEntry::Started {
+                current_chunk: current_chunk.id(),
+                covers: current_chunk.covers().clone(),
+            };
+            if let Err(e) = writer.lock().unwrap().append(entry) {
+                logger::error!("Error journaling diagnosis server state: {:?}", e);
+            }
+        }
+    }
+    pub async fn computation_started(
+        &self,
+        computation_id: ComputationId,
+        started_at: DateTime<Utc>,
+    ) {
+        self.append(|| JournalEntry::ComputationStarted {
+            computation_id,
+            started_at,
+        })
+        .await;
+    }
+    pub async fn uploaded(
+        &self,
+        list: ListType,
+        peer: Option<SocketAddr>,
+        computation_id: ComputationId,
+        chunk_id: ChunkId,
+        diagnosis_keys: &HashSet<&Validity<TemporaryExposureKey>>,
+    ) {
+        self.append(|| JournalEntry::Uploaded {
+            list,
+            peer: peer.map(|peer| self.peer_ip(peer)),
+            computation_id,
+            chunk_id,
+            diagnosis_keys: diagnosis_keys.iter().map(|key| **key).collect(),
+        })
+        .await;
+    }
+    pub async fn revoked(
+        &self,
+        peer: Option<SocketAddr>,
+        computation_id: ComputationId,
+        chunk_id: ChunkId,
+    ) {
+        self.append(|| JournalEntry::Revoked {
+            peer: peer.map(|peer| self.peer_ip(peer)),
+            computation_id,
+            chunk_id,
+        })
+        .await;
+    }
+    pub async fn imported(&self, region: Region, peer_chunk: ChunkId, chunk_id: ChunkId) {
+        self.append(|| JournalEntry::Imported {
+            region,
+            peer_chunk,
+            chunk_id,
+        })
+        .await;
+    }
+    pub async fn rotated(&self, done_chunk: &Chunk, next_chunk: &Chunk) {
+        self.append(|| JournalEntry::Rotated {
+            done_chunk: done_chunk.id(),
+            closed: done_chunk.closed().clone(),
+            next_chunk: next_chunk.id(),
+            covers: next_chunk.covers().clone(),
+        })
+        .await;
+    }
+    /// Takes about as long as appending `records` records but writes nothing,
+    /// hence cover uploads are not answered faster than genuine ones.
+    pub async fn cover(&self, records: usize) {
+        if let Some(writer) = &self.writer {
+            let writer = Arc::clone(writer);
+            let result = task::spawn_blocking(move || {
+                let mut writer = writer.lock().unwrap();
+                (0..records).try_for_each(|_| writer.sync())
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => logger::error!("Error syncing journal: {:?}", e),
+                Err(e) => logger::error!("Error syncing journal: {:?}", e),
+            }
+        }
+    }
+    fn peer_ip(&self, peer: SocketAddr) -> IpAddr {
+        if self.anonymise_peers {
+            journal::anonymise(peer.ip())
+        } else {
+            peer.ip()
+        }
+    }
+    // entries are only built if journaling is enabled
+    async fn append(&self, entry: impl FnOnce() -> JournalEntry) {
+        if let Some(writer) = &self.writer {
+            let entry = entry();
+            let writer = Arc::clone(writer);
+            // appending syncs the file, which must not block a tokio worker
+            match task::spawn_blocking(move || writer.lock().unwrap().append(entry)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => logger::error!("Error journaling diagnosis server state: {:?}", e),
+                Err(e) => logger::error!("Error journaling diagnosis server state: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Rebuilds an empty store from the journal, e.g., after the store was lost.
+/// The journal must have been kept since the first start of the server.
+/// Greylist secrets are not journaled, hence the origins of the restored
+/// computations can neither greylist nor revoke them anymore.
+pub fn restore(
+    config: Option<&JournalConfig>,
+    store: &dyn ChunkStore,
+    region: Region,
+) -> Result<()> {
+    let config = match config {
+        Some(config) if config.path.exists() => config,
+        _ => return Ok(()),
+    };
+    if !store.chunks()?.is_empty() || !store.computations()?.is_empty() {
+        return Ok(());
+    }
+    let records = journal::read(&config.path)
+        .context("Error reading journal")?
+        .records;
+    let replay = journal::replay(&records).context("Error replaying journal")?;
+    logger::warn!(
+        "Store is empty, restoring {} chunks and {} computations from journal {:?}",
+        replay.chunks.len(),
+        replay.computations.len(),
+        config.path
+    );
+    for chunk in replay.chunks.values() {
+        store.put_chunk(chunk)?;
+    }
+    let mut computation_id_seed = 0;
+    for (computation_id, computation) in replay.computations {
+        if computation_id.region() == region {
+            computation_id_seed = computation_id_seed.max(computation_id.local() + 1);
+        }
+        store.put_computation(
+            computation_id,
+            &ComputationRecord::restore(
+                computation.started_at,
+                computation.closure_published,
+                computation.revoked,
+            )?,
+        )?;
+    }
+    store.put_computation_id_seed(computation_id_seed)?;
+    for (region, peer_chunk) in replay.imported {
+        store.put_imported(region, peer_chunk)?;
+    }
+    store.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::InMemoryStore;
+    use exposurelib::time::TimeInterval;
+
+    #[tokio::test]
+    async fn test_restore() {
+        let path = std::env::temp_dir().join(format!("restore-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = JournalConfig {
+            path: path.clone(),
+            anonymise_peers: false,
+        };
+        let region = Region::from(1);
+        let computation_id = ComputationId::new(region, 4).unwrap();
+        let current_chunk = Chunk::new(
+            ChunkId::default(),
+            TimeInterval::with_alignment(chrono::Duration::minutes(1)),
+        );
+        let mut done_chunk = current_chunk.clone();
+        done_chunk.close(computation_id);
+        {
+            let journal = Journal::open(Some(&config)).unwrap();
+            journal.started(&current_chunk);
+            journal
+                .computation_started(computation_id, Utc::now())
+                .await;
+            journal
+                .imported(Region::from(2), ChunkId::from(7), current_chunk.id())
+                .await;
+            journal
+                .rotated(&done_chunk, &current_chunk.next_chunk())
+                .await;
+        }
+
+        let store = InMemoryStore::default();
+        restore(Some(&config), &store, region).unwrap();
+        assert_eq!(store.chunks().unwrap().len(), 2);
+        assert!(store.chunks().unwrap()[0]
+            .closed()
+            .contains(&computation_id));
+        let computations = store.computations().unwrap();
+        assert_eq!(computations.len(), 1);
+        assert!(computations.contains_key(&computation_id));
+        assert_eq!(store.computation_id_seed().unwrap(), 5);
+        assert_eq!(
+            store.imported().unwrap()[&Region::from(2)],
+            ChunkId::from(7)
+        );
+        // a store which is not empty is left as it is
+        store.remove_chunk(ChunkId::from(1)).unwrap();
+        restore(Some(&config), &store, region).unwrap();
+        assert_eq!(store.chunks().unwrap().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+use crate::handler::ConnectionHandler;
+use chrono::prelude::*;
+use chrono::Duration;
+use exposurelib::error::RequestError;
+use exposurelib::logger;
+use exposurelib::primitives::ComputationId;
+use exposurelib::rpcs::v1::{DiagnosisServerRequest, DiagnosisServerResponse};
+use exposurelib::rpcs::{DiagnosisServer, GreylistUploadParams};
+use exposurelib::verification::GreylistSecret;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tarpc::context::Context;
+use tarpc::server::Channel;
+use tokio::sync::{oneshot, Mutex};
+
+/// Greylist secrets of the computations started by version 1 origins, which
+/// authorize greylist uploads on their behalf. Anyone speaking version 1 may
+/// thus greylist such a computation, and the secrets are lost on a restart.
+#[derive(Default)]
+pub struct LegacySecrets(Mutex<HashMap<ComputationId, (DateTime<Utc>, GreylistSecret)>>);
+
+/// Serves protocol version 1 clients by translating their requests.
+/// Without `secrets` their blacklist uploads are rejected, as they could never
+/// authorize the greylist uploads of the computation.
+#[derive(Clone)]
+pub struct LegacyHandler {
+    handler: ConnectionHandler,
+    secrets: Option<Arc<LegacySecrets>>,
+}
+
+impl LegacyHandler {
+    pub fn new(handler: ConnectionHandler, secrets: Option<Arc<LegacySecrets>>) -> Self {
+        Self { handler, secrets }
+    }
+    /// Answers the requests of the channel one after another. Version 1
+    /// cannot express rejections, hence the first one closes the connection,
+    /// which fails the pending calls of the client instead of letting them
+    /// wait for their deadline.
+    pub async fn serve<C>(self, channel: C)
+    where
+        C: Channel<Req = DiagnosisServerRequest, Resp = DiagnosisServerResponse>,
+    {
+        let requests = channel.requests();
+        futures::pin_mut!(requests);
+        while let Some(request) = requests.next().await {
+            let request = match request {
+                Ok(request) => request,
+                Err(e) => {
+                    logger::debug!(
+                        "Error reading version 1 request from {:?}: {}",
+                        self.handler.peer_addr(),
+                        e
+                    );
+                    return;
+                }
+            };
+            let (rejected_tx, rejected_rx) = oneshot::channel();
+            let handler = self.clone();
+            let served = request.execute(move |context, request| async move {
+                match handler.answer(context, request).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let _ = rejected_tx.send(e);
+                        // dropped together with the connection
+                        future::pending().await
+                    }
+                }
+            });
+            futures::pin_mut!(served);
+            match future::select(served, rejected_rx).await {
+                Either::Left(_) => {}
+                Either::Right((Ok(e), _)) => {
+                    logger::info!(
+                        "Closing version 1 connection of {:?} after rejecting its request: {}",
+                        self.handler.peer_addr(),
+                        e
+                    );
+                    return;
+                }
+                // answered, the response is still being handed to the channel
+                Either::Right((Err(_), served)) => served.await,
+            }
+        }
+    }
+    async fn answer(
+        &self,
+        context: Context,
+        request: DiagnosisServerRequest,
+    ) -> Result<DiagnosisServerResponse, RequestError> {
+        let handler = self.handler.clone();
+        match request {
+            DiagnosisServerRequest::BlacklistUpload { params } => {
+                let secrets = self
+                    .secrets
+                    .as_ref()
+                    .ok_or(RequestError::UnauthorizableLegacyUpload)?;
+                let response = handler.blacklist_upload(context, params.into()).await?;
+                secrets
+                    .insert(
+                        response.computation_id,
+                        response.greylist_secret,
+                        Duration::from(self.handler.params().computation_period),
+                    )
+                    .await;
+                Ok(DiagnosisServerResponse::BlacklistUpload(
+                    response.computation_id,
+                ))
+            }
+            DiagnosisServerRequest::GreylistUpload { params } => {
+                let mut params = GreylistUploadParams::from(params);
+                // unknown computations are left unauthorized and thus rejected
+                if let Some(secrets) = &self.secrets {
+                    if let Some(secret) = secrets.get(params.computation_id).await {
+                        params.authorization =
+                            secret.authorize(params.computation_id, &params.diagnosis_keys);
+                    }
+                }
+                handler
+                    .greylist_upload(context, params)
+                    .await
+                    .map(DiagnosisServerResponse::GreylistUpload)
+            }
+            DiagnosisServerRequest::Download { params } => {
+                logger::trace!(
+                    "New version 1 download() RPC from {:?} with context {:?} and params {:?}",
+                    handler.peer_addr(),
+                    context,
+                    params
+                );
+                Ok(DiagnosisServerResponse::Download(
+                    handler.state().request_chunks_from(params.from).await,
+                ))
+            }
+        }
+    }
+}
+
+impl LegacySecrets {
+    /// Forgets the secrets of computations which closed already.
+    async fn insert(
+        &self,
+        computation_id: ComputationId,
+        secret: GreylistSecret,
+        computation_period: Duration,
+    ) {
+        let now = Utc::now();
+        let mut secrets = self.0.lock().await;
+        secrets.retain(|_, (started_at, _)| *started_at + computation_period > now);
+        secrets.insert(computation_id, (now, secret));
+    }
+    async fn get(&self, computation_id: ComputationId) -> Option<GreylistSecret> {
+        self.0
+            .lock()
+            .await
+            .get(&computation_id)
+            .map(|(_, secret)| secret.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exposurelib::config::DiagnosisServerConfig;
+    use exposurelib::primitives::{SystemRandom, TekRollingPeriod, TemporaryExposureKey, Validity};
+    use exposurelib::rpcs::v1;
+    use exposurelib::time::ExposureTime;
+    use std::time::{Duration, SystemTime};
+    use tarpc::{client, context, server};
+
+    fn diagnosis_keys() -> std::collections::HashSet<v1::DiagnosisKey> {
+        let key = Validity::new(
+            ExposureTime::from(0),
+            TekRollingPeriod::default(),
+            TemporaryExposureKey::new(&SystemRandom::new()).unwrap(),
+        );
+        vec![v1::DiagnosisKey::from(&key)].into_iter().collect()
+    }
+
+    fn connect(secrets: Option<Arc<LegacySecrets>>) -> v1::DiagnosisServerClient {
+        let config = DiagnosisServerConfig::new(
+            "127.0.0.1:0".parse().unwrap(),
+            exposurelib::config::SystemParams::default(),
+        );
+        let handler = LegacyHandler::new(ConnectionHandler::in_memory(&config), secrets);
+        let (client_transport, server_transport) = tarpc::transport::channel::unbounded();
+        tokio::spawn(handler.serve(server::BaseChannel::with_defaults(server_transport)));
+        v1::DiagnosisServerClient::new(client::Config::default(), client_transport)
+            .spawn()
+            .unwrap()
+    }
+
+    fn context() -> Context {
+        let mut context = context::current();
+        context.deadline = SystemTime::now() + Duration::from_secs(60);
+        context
+    }
+
+    #[tokio::test]
+    async fn test_rejection_closes_connection() {
+        let client = connect(None);
+        assert!(client
+            .download(
+                context(),
+                v1::DownloadParams {
+                    from: chrono::Utc::now(),
+                },
+            )
+            .await
+            .unwrap()
+            .is_empty());
+
+        // without greylist uploads a computation is of no use, the rejection
+        // fails the call long before its deadline
+        let rejected = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.blacklist_upload(
+                context(),
+                v1::BlacklistUpload {
+                    diagnosis_keys: diagnosis_keys(),
+                },
+            ),
+        )
+        .await
+        .unwrap();
+        assert!(rejected.is_err());
+        assert!(client
+            .download(
+                context(),
+                v1::DownloadParams {
+                    from: chrono::Utc::now(),
+                },
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_greylist_uploads() {
+        let client = connect(Some(Arc::new(LegacySecrets::default())));
+        let computation_id = client
+            .blacklist_upload(
+                context(),
+                v1::BlacklistUpload {
+                    diagnosis_keys: diagnosis_keys(),
+                },
+            )
+            .await
+            .unwrap();
+        client
+            .greylist_upload(
+                context(),
+                v1::GreylistUpload {
+                    computation_id,
+                    diagnosis_keys: diagnosis_keys(),
+                },
+            )
+            .await
+            .unwrap();
+        // computations started at the main endpoint stay unauthorized
+        assert!(client
+            .greylist_upload(
+                context(),
+                v1::GreylistUpload {
+                    computation_id: ComputationId::from(u32::from(computation_id) + 1),
+                    diagnosis_keys: diagnosis_keys(),
+                },
+            )
+            .await
+            .is_err());
+    }
+}
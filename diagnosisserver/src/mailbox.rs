@@ -0,0 +1,115 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use exposurelib::config::MailboxConfig;
+use exposurelib::error::RequestError;
+use exposurelib::logger;
+use exposurelib::mailbox::SealedForward;
+use exposurelib::primitives::{MailboxSecret, RendezvousToken};
+use exposurelib::rpcs::Deposit;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+
+/// Holds sealed forwards until the addressed successor fetches them.
+pub struct Mailbox {
+    config: MailboxConfig,
+    // forwards are of no use after the computation period anyway
+    retention_period: Duration,
+    deposits: Mutex<HashMap<RendezvousToken, Vec<PendingDeposit>>>,
+}
+
+struct PendingDeposit {
+    deposited_at: DateTime<Utc>,
+    sealed: SealedForward,
+}
+
+impl Mailbox {
+    pub fn new(config: MailboxConfig, retention_period: Duration) -> Self {
+        Self {
+            config,
+            retention_period,
+            deposits: Mutex::new(HashMap::new()),
+        }
+    }
+    pub async fn deposit(&self, deposit: Deposit) -> Result<(), RequestError> {
+        let mut deposits = self.deposits.lock().await;
+        self.prune(&mut deposits);
+        let pending = deposits.entry(deposit.token).or_insert_with(Vec::new);
+        if pending.len() >= self.config.max_deposits_per_token {
+            return Err(RequestError::MailboxFull {
+                token: deposit.token,
+            });
+        }
+        logger::info!("Depositing forward for {:?}", deposit.token);
+        pending.push(PendingDeposit {
+            deposited_at: Utc::now(),
+            sealed: deposit.sealed,
+        });
+        Ok(())
+    }
+    /// The tokens are derived from the secrets, hence the fetcher must hold them.
+    pub async fn fetch(&self, secrets: &[MailboxSecret]) -> Result<Vec<Deposit>, RequestError> {
+        let tokens = secrets
+            .iter()
+            .map(RendezvousToken::new)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RequestError::InvalidMailboxSecret)?;
+        let mut deposits = self.deposits.lock().await;
+        self.prune(&mut deposits);
+        let mut fetched = Vec::new();
+        for token in tokens {
+            let pending = deposits.remove(&token).unwrap_or_default();
+            if !pending.is_empty() {
+                logger::info!("Handing out {} forwards for {:?}", pending.len(), token);
+            }
+            fetched.extend(pending.into_iter().map(|pending| Deposit {
+                token,
+                sealed: pending.sealed,
+            }));
+        }
+        Ok(fetched)
+    }
+    fn prune(&self, deposits: &mut HashMap<RendezvousToken, Vec<PendingDeposit>>) {
+        let oldest = Utc::now() - self.retention_period;
+        deposits.retain(|_, pending| {
+            pending.retain(|deposit| deposit.deposited_at > oldest);
+            !pending.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exposurelib::mailbox::MailboxAddress;
+    use exposurelib::primitives::{ComputationId, Seed, SystemRandom};
+    use exposurelib::testing::forward_params;
+
+    fn deposit(address: &MailboxAddress) -> Deposit {
+        let params = forward_params(ComputationId::from(3));
+        Deposit {
+            token: address.token(),
+            sealed: address.seal(&params, &SystemRandom::new()).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_requires_secret() {
+        let mailbox = Mailbox::new(MailboxConfig::default(), Duration::days(1));
+        let secure_random = SystemRandom::new();
+        let address = MailboxAddress::new(&Seed::new(&secure_random).unwrap()).unwrap();
+        let other = MailboxAddress::new(&Seed::new(&secure_random).unwrap()).unwrap();
+        mailbox.deposit(deposit(&address)).await.unwrap();
+
+        assert!(mailbox.fetch(&[other.secret()]).await.unwrap().is_empty());
+        let deposits = mailbox
+            .fetch(&[other.secret(), address.secret()])
+            .await
+            .unwrap();
+        assert_eq!(deposits.len(), 1);
+        assert!(address
+            .open(deposits.into_iter().next().unwrap().sealed)
+            .is_ok());
+        // fetching drains the mailbox
+        assert!(mailbox.fetch(&[address.secret()]).await.unwrap().is_empty());
+    }
+}
@@ -1,17 +1,43 @@
+mod admin;
+mod fake_cases;
+mod federation;
+mod gateway;
 mod handler;
+mod journal;
+mod legacy;
+mod mailbox;
+mod metrics;
+mod rate_limiter;
 mod state;
+mod store;
+mod verification;
+use admin::AdminHandler;
 use anyhow::Result;
 use exposurelib::args::{crate_authors, crate_description, crate_name, crate_version, Args};
 use exposurelib::config::DiagnosisServerConfig;
 use exposurelib::logger;
-use exposurelib::rpcs::DiagnosisServer;
+use exposurelib::config::FederationPeer;
+use exposurelib::primitives::Region;
+use exposurelib::rpcs::{Admin, DiagnosisServer, Federation};
+use fake_cases::FakeCases;
+use federation::{FederationHandler, Federator, ImportLimits};
 use futures::{future, prelude::*};
+use gateway::Gateway;
 use handler::ConnectionHandler;
+use journal::Journal;
+use legacy::{LegacyHandler, LegacySecrets};
+use mailbox::Mailbox;
+use metrics::Metrics;
+use rate_limiter::RateLimiters;
 use state::DiagnosisServerState;
+use std::collections::HashMap;
 use std::fs;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tarpc::server::{self, Channel, Incoming};
 use tarpc::tokio_serde::formats;
+use tokio::task;
+use verification::Verifier;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -27,26 +53,120 @@ async fn main() -> Result<()> {
 
     logger::setup_logger(&args.log_file_path, args.log_level, String::from("ds"));
 
-    let state = Arc::new(DiagnosisServerState::new(&config));
+    let store = store::open(&config.store)?;
+    journal::restore(config.journal.as_ref(), &*store, config.federation.region)?;
+    let journal = Arc::new(Journal::open(config.journal.as_ref())?);
+    let metrics = Arc::new(Metrics::new());
+    if let Some(metrics_endpoint) = config.metrics_endpoint {
+        let registry = metrics.registry().clone();
+        task::spawn(async move {
+            if let Err(e) = exposurelib::metrics::serve(metrics_endpoint, registry).await {
+                logger::error!("Metrics endpoint at {} failed: {:?}", metrics_endpoint, e);
+            }
+        });
+    }
+    let state = Arc::new(DiagnosisServerState::new(&config, Arc::clone(&store), journal, metrics)?);
+    if config.fake_cases.enabled {
+        let fake_cases = FakeCases::new(config.fake_cases, config.params, Arc::clone(&state));
+        task::spawn(fake_cases.run());
+    }
+    let rate_limiters = Arc::new(RateLimiters::new(config.rate_limits));
+    let verifier = Arc::new(Verifier::new(&config.verification, store)?);
+    let mailbox = Arc::new(Mailbox::new(
+        config.mailbox,
+        chrono::Duration::from(config.params.computation_period),
+    ));
+    if let Some(admin_endpoint) = config.admin_endpoint {
+        let state = Arc::clone(&state);
+        let max_frame_length = config.params.limits.max_frame_length;
+        task::spawn(async move {
+            if let Err(e) = serve_admin(admin_endpoint, max_frame_length, state).await {
+                logger::error!("Admin endpoint at {} failed: {:?}", admin_endpoint, e);
+            }
+        });
+    }
+    let import_limits = ImportLimits::new(config.params, config.rate_limits.per_computation);
+    if let Some(federation_endpoint) = config.federation.endpoint {
+        let peers = config
+            .federation
+            .peers
+            .iter()
+            .map(|peer| (peer.region, peer.clone()))
+            .collect();
+        let state = Arc::clone(&state);
+        task::spawn(async move {
+            if let Err(e) =
+                serve_federation(federation_endpoint, import_limits, peers, state).await
+            {
+                logger::error!(
+                    "Federation endpoint at {} failed: {:?}",
+                    federation_endpoint,
+                    e
+                );
+            }
+        });
+    }
+    for peer in config.federation.peers.iter() {
+        let federator = Federator::new(
+            peer.clone(),
+            &config.federation,
+            import_limits,
+            Arc::clone(&state),
+        );
+        task::spawn(federator.run());
+    }
+
+    let handler = ConnectionHandler::new(
+        config.endpoint,
+        config.params,
+        rate_limiters,
+        verifier,
+        mailbox,
+        state,
+    );
+
+    if let Some(http_endpoint) = config.http_endpoint {
+        let gateway = Gateway::new(handler.clone(), config.params.limits.max_frame_length);
+        task::spawn(async move {
+            if let Err(e) = gateway.serve(http_endpoint).await {
+                logger::error!("HTTP gateway at {} failed: {:?}", http_endpoint, e);
+            }
+        });
+    }
+
+    if let Some(legacy_endpoint) = config.legacy_endpoint {
+        let handler = handler.clone();
+        let secrets = if config.legacy_greylist_uploads {
+            logger::warn!("Version 1 greylist uploads are accepted without authorization");
+            Some(Arc::new(LegacySecrets::default()))
+        } else {
+            None
+        };
+        task::spawn(async move {
+            if let Err(e) = serve_legacy(legacy_endpoint, handler, secrets).await {
+                logger::error!("Version 1 endpoint at {} failed: {:?}", legacy_endpoint, e);
+            }
+        });
+    }
 
     logger::trace!("Diagnosis Server listening on {}", config.endpoint);
 
     let mut listener =
         tarpc::serde_transport::tcp::listen(&config.endpoint, formats::Bincode::default).await?;
-    listener.config_mut().max_frame_length(usize::MAX);
+    listener
+        .config_mut()
+        .max_frame_length(config.params.limits.max_frame_length);
     listener
         // ignore accept errors
         .filter_map(|r| future::ready(r.ok()))
         .map(server::BaseChannel::with_defaults)
-        // just one channel per *ip/port combo* (instead of per ip) in our simulation case
-        .max_channels_per_key(1, |t| t.as_ref().peer_addr().unwrap())
+        .max_channels_per_key(config.params.limits.max_channels_per_ip, |t| {
+            t.as_ref().peer_addr().unwrap().ip()
+        })
         // function serve() is generated by the service attribute
         // it takes as input any type implementing the generated service trait
         .map(|channel| {
-            let server = ConnectionHandler::new(
-                channel.as_ref().as_ref().peer_addr().unwrap(),
-                Arc::clone(&state),
-            );
+            let server = handler.for_peer(channel.as_ref().as_ref().peer_addr().unwrap());
             channel.requests().execute(server.serve())
         })
         // max 100 channels (i.e. clients)
@@ -56,3 +176,86 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+async fn serve_legacy(
+    endpoint: SocketAddr,
+    handler: ConnectionHandler,
+    secrets: Option<Arc<LegacySecrets>>,
+) -> Result<()> {
+    logger::trace!("Diagnosis Server serving version 1 on {}", endpoint);
+    let limits = handler.params().limits;
+    let mut listener =
+        tarpc::serde_transport::tcp::listen(&endpoint, formats::Bincode::default).await?;
+    listener.config_mut().max_frame_length(limits.max_frame_length);
+    listener
+        .filter_map(|r| future::ready(r.ok()))
+        .map(server::BaseChannel::with_defaults)
+        .max_channels_per_key(limits.max_channels_per_ip, |t| {
+            t.as_ref().peer_addr().unwrap().ip()
+        })
+        .map(|channel| {
+            let server = LegacyHandler::new(
+                handler.for_peer(channel.as_ref().as_ref().peer_addr().unwrap()),
+                secrets.clone(),
+            );
+            server.serve(channel)
+        })
+        .buffer_unordered(100)
+        .for_each(|_| async {})
+        .await;
+    Ok(())
+}
+
+async fn serve_admin(
+    endpoint: SocketAddr,
+    max_frame_length: usize,
+    state: Arc<DiagnosisServerState>,
+) -> Result<()> {
+    logger::trace!("Diagnosis Server serving admin RPCs on {}", endpoint);
+    let mut listener =
+        tarpc::serde_transport::tcp::listen(&endpoint, formats::Bincode::default).await?;
+    listener.config_mut().max_frame_length(max_frame_length);
+    listener
+        .filter_map(|r| future::ready(r.ok()))
+        .map(server::BaseChannel::with_defaults)
+        .map(|channel| {
+            let server =
+                AdminHandler::new(channel.as_ref().peer_addr().unwrap(), Arc::clone(&state));
+            channel.requests().execute(server.serve())
+        })
+        .buffer_unordered(10)
+        .for_each(|_| async {})
+        .await;
+    Ok(())
+}
+
+async fn serve_federation(
+    endpoint: SocketAddr,
+    limits: ImportLimits,
+    peers: HashMap<Region, FederationPeer>,
+    state: Arc<DiagnosisServerState>,
+) -> Result<()> {
+    logger::trace!("Diagnosis Server serving federation RPCs on {}", endpoint);
+    let peers = Arc::new(peers);
+    let mut listener =
+        tarpc::serde_transport::tcp::listen(&endpoint, formats::Bincode::default).await?;
+    listener
+        .config_mut()
+        .max_frame_length(limits.max_frame_length());
+    listener
+        .filter_map(|r| future::ready(r.ok()))
+        .map(server::BaseChannel::with_defaults)
+        .map(|channel| {
+            let server = FederationHandler::new(
+                channel.as_ref().peer_addr().unwrap(),
+                Arc::clone(&peers),
+                limits,
+                Arc::clone(&state),
+            );
+            channel.requests().execute(server.serve())
+        })
+        .buffer_unordered(10)
+        .for_each(|_| async {})
+        .await;
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use exposurelib::diagnosis_server_state::{Chunk, ListType};
+use exposurelib::metrics::{
+    self, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry,
+};
+use exposurelib::primitives::Region;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::Instant;
+
+/// Operational metrics of the diagnosis server, which are collected
+/// regardless of whether the metrics endpoint is enabled.
+pub struct Metrics {
+    registry: Registry,
+    uploads: IntCounterVec,
+    uploaded_keys: IntCounterVec,
+    revocations: IntCounter,
+    imported_chunks: IntCounterVec,
+    rejected_requests: IntCounterVec,
+    chunk_keys: Histogram,
+    current_chunk_keys: IntGauge,
+    active_computations: IntGauge,
+    download_requests: IntCounterVec,
+    download_bytes: IntCounter,
+    lock_wait: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("diagnosisserver")), None)
+            .expect("Creating the metrics registry failed");
+        Self {
+            uploads: metrics::register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "uploads_total",
+                        "Accepted uploads by list, including cover uploads",
+                    ),
+                    &["list"],
+                )
+                .unwrap(),
+            ),
+            uploaded_keys: metrics::register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "uploaded_keys_total",
+                        "Keys of accepted uploads by list, including cover uploads",
+                    ),
+                    &["list"],
+                )
+                .unwrap(),
+            ),
+            revocations: metrics::register(
+                &registry,
+                IntCounter::new("revocations_total", "Revoked computations").unwrap(),
+            ),
+            imported_chunks: metrics::register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "imported_chunks_total",
+                        "Non-empty chunks imported from federation peers by region",
+                    ),
+                    &["region"],
+                )
+                .unwrap(),
+            ),
+            rejected_requests: metrics::register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("rejected_requests_total", "Rejected requests by RPC"),
+                    &["rpc"],
+                )
+                .unwrap(),
+            ),
+            chunk_keys: metrics::register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new("chunk_keys", "Keys per done chunk")
+                        .buckets(vec![0.0, 10.0, 100.0, 1_000.0, 10_000.0, 100_000.0]),
+                )
+                .unwrap(),
+            ),
+            current_chunk_keys: metrics::register(
+                &registry,
+                IntGauge::new("current_chunk_keys", "Keys in the current chunk").unwrap(),
+            ),
+            active_computations: metrics::register(
+                &registry,
+                IntGauge::new(
+                    "active_computations",
+                    "Computations whose closure is not yet published",
+                )
+                .unwrap(),
+            ),
+            download_requests: metrics::register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("download_requests_total", "Download requests by RPC"),
+                    &["rpc"],
+                )
+                .unwrap(),
+            ),
+            download_bytes: metrics::register(
+                &registry,
+                IntCounter::new("download_bytes_total", "Bytes of downloaded chunks").unwrap(),
+            ),
+            lock_wait: metrics::register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("lock_wait_seconds", "Time spent waiting for a lock"),
+                    &["lock"],
+                )
+                .unwrap(),
+            ),
+            registry,
+        }
+    }
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+    pub fn uploaded(&self, list: ListType, keys: usize) {
+        let list = match list {
+            ListType::Blacklist => "blacklist",
+            ListType::Greylist => "greylist",
+        };
+        self.uploads.with_label_values(&[list]).inc();
+        self.uploaded_keys
+            .with_label_values(&[list])
+            .inc_by(keys as u64);
+    }
+    pub fn revoked(&self) {
+        self.revocations.inc();
+    }
+    pub fn imported(&self, region: Region) {
+        self.imported_chunks
+            .with_label_values(&[&u8::from(region).to_string()])
+            .inc();
+    }
+    pub fn rejected(&self, rpc: &str) {
+        self.rejected_requests.with_label_values(&[rpc]).inc();
+    }
+    pub fn current_chunk_changed(&self, current_chunk: &Chunk) {
+        self.current_chunk_keys
+            .set(current_chunk.diagnosis_keys() as i64);
+    }
+    pub fn chunk_done(&self, done_chunk: &Chunk) {
+        self.chunk_keys.observe(done_chunk.diagnosis_keys() as f64);
+    }
+    pub fn set_active_computations(&self, count: usize) {
+        self.active_computations.set(count as i64);
+    }
+    pub fn downloaded(&self, rpc: &str, bytes: usize) {
+        self.download_requests.with_label_values(&[rpc]).inc();
+        self.download_bytes.inc_by(bytes as u64);
+    }
+    /// Locks the mutex and records how long that took.
+    pub async fn lock<'a, T>(&self, lock: &str, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        let start = Instant::now();
+        let guard = mutex.lock().await;
+        self.lock_wait
+            .with_label_values(&[lock])
+            .observe(start.elapsed().as_secs_f64());
+        guard
+    }
+}
+
+// unlike Chunk::info() this does not serialize the chunk
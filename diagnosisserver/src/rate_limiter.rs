@@ -0,0 +1,144 @@
+use exposurelib::config::{RateLimits, TokenBucketConfig};
+use exposurelib::error::RequestError;
+use exposurelib::primitives::ComputationId;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+pub struct RateLimiters {
+    per_peer: Mutex<RateLimiter<IpAddr>>,
+    per_computation: Mutex<RateLimiter<ComputationId>>,
+    queries: Mutex<RateLimiter<IpAddr>>,
+}
+
+impl RateLimiters {
+    pub fn new(rate_limits: RateLimits) -> Self {
+        Self {
+            per_peer: Mutex::new(RateLimiter::new(rate_limits.per_peer)),
+            per_computation: Mutex::new(RateLimiter::new(rate_limits.per_computation)),
+            queries: Mutex::new(RateLimiter::new(rate_limits.queries)),
+        }
+    }
+    /// Peers are told apart by IP address only as a client can pick any port.
+    pub async fn check_peer(&self, peer_addr: SocketAddr) -> Result<(), RequestError> {
+        self.per_peer
+            .lock()
+            .await
+            .check(peer_addr.ip(), Instant::now())
+    }
+    pub async fn check_computation(
+        &self,
+        computation_id: ComputationId,
+    ) -> Result<(), RequestError> {
+        self.per_computation
+            .lock()
+            .await
+            .check(computation_id, Instant::now())
+    }
+    /// Queries have a bucket of their own such that they never delay uploads.
+    pub async fn check_query(&self, peer_addr: SocketAddr) -> Result<(), RequestError> {
+        self.queries
+            .lock()
+            .await
+            .check(peer_addr.ip(), Instant::now())
+    }
+}
+
+struct RateLimiter<K> {
+    config: TokenBucketConfig,
+    buckets: HashMap<K, TokenBucket>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    // full buckets carry no information and are dropped once this many keys are tracked
+    const PRUNE_THRESHOLD: usize = 1024;
+
+    fn new(config: TokenBucketConfig) -> Self {
+        Self {
+            config,
+            buckets: HashMap::new(),
+        }
+    }
+    fn check(&mut self, key: K, now: Instant) -> Result<(), RequestError> {
+        if self.buckets.len() >= Self::PRUNE_THRESHOLD {
+            let config = self.config;
+            self.buckets
+                .retain(|_, bucket| !bucket.is_full(&config, now));
+        }
+        let config = self.config;
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(&config, now))
+            .try_acquire(&config, now)
+            .map_err(|retry_after| RequestError::RateLimited { retry_after })
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &TokenBucketConfig, now: Instant) -> Self {
+        Self {
+            tokens: config.capacity as f64,
+            last_refill: now,
+        }
+    }
+    fn refill(&mut self, config: &TokenBucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let refilled = elapsed.as_secs_f64() / config.refill_period.as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(config.capacity as f64);
+        self.last_refill = now;
+    }
+    fn is_full(&mut self, config: &TokenBucketConfig, now: Instant) -> bool {
+        self.refill(config, now);
+        self.tokens >= config.capacity as f64
+    }
+    /// Takes one token or returns the time until the next token becomes available.
+    fn try_acquire(&mut self, config: &TokenBucketConfig, now: Instant) -> Result<(), Duration> {
+        self.refill(config, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(config.refill_period.mul_f64(1.0 - self.tokens))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let config = TokenBucketConfig {
+            capacity: 2,
+            refill_period: Duration::from_secs(10),
+        };
+        let mut limiter = RateLimiter::new(config);
+        let start = Instant::now();
+        assert!(limiter.check(0, start).is_ok());
+        assert!(limiter.check(0, start).is_ok());
+        assert_eq!(
+            limiter.check(0, start),
+            Err(RequestError::RateLimited {
+                retry_after: Duration::from_secs(10)
+            })
+        );
+        // other keys have their own bucket
+        assert!(limiter.check(1, start).is_ok());
+        assert!(limiter.check(0, start + Duration::from_secs(5)).is_err());
+        assert!(limiter.check(0, start + Duration::from_secs(10)).is_ok());
+        assert!(limiter.check(0, start + Duration::from_secs(10)).is_err());
+        // refills never exceed the capacity
+        let later = start + Duration::from_secs(1000);
+        assert!(limiter.check(0, later).is_ok());
+        assert!(limiter.check(0, later).is_ok());
+        assert!(limiter.check(0, later).is_err());
+    }
+}
@@ -1,47 +1,279 @@
+use crate::journal::Journal;
+use crate::metrics::Metrics;
+use crate::store::ChunkStore;
+use anyhow::{Context, Result};
 use chrono::prelude::*;
 use chrono::Duration;
-use exposurelib::diagnosis_server_state::{Chunk, ListType};
+use exposurelib::chunk_encoding::{ChunkEncoding, EncodedChunk};
+use exposurelib::config::Pagination;
+use exposurelib::diagnosis_server_state::{Chunk, ChunkId, ChunkInfo, ComputationPhase, ListType};
+use exposurelib::error::RequestError;
 use exposurelib::logger;
-use exposurelib::primitives::ComputationId;
-use exposurelib::rpcs::{BlacklistUploadParams, DownloadParams, GreylistUploadParams};
+use exposurelib::primitives::{ComputationId, Region};
+use exposurelib::rpcs::{
+    v1, BlacklistUploadParams, BlacklistUploadResponse, ChunkPage, ComputationInfo,
+    ComputationKeys, ConsistencyParams, DownloadParams, GreylistUploadParams, RetentionStatus,
+    RevokeParams,
+};
 use exposurelib::time::TimeInterval;
+use exposurelib::transparency::{
+    ConsistencyProof, InclusionProof, SignedTreeHead, TransparencyLog, TreeHash,
+};
+use exposurelib::verification::GreylistSecret;
 use exposurelib::{
     config::DiagnosisServerConfig,
     primitives::{TemporaryExposureKey, Validity},
 };
-use std::collections::{HashSet, VecDeque};
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::iter::IntoIterator;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex, Notify};
 use tokio::task;
 use tokio::time;
 
 pub struct DiagnosisServerState {
     current_chunk: Arc<Mutex<Chunk>>,
+    /// Counts the changes of the current chunk, the ones up to `synced` are on disk
+    changes: AtomicU64,
+    synced: Mutex<u64>,
     done_chunks: Arc<Mutex<Chunks>>,
+    latest_done_chunk: watch::Receiver<Option<ChunkId>>,
+    subscription_period: std::time::Duration,
+    pagination: Pagination,
     computation_id_seed: Mutex<u32>,
+    region: Region,
+    signing_key: Option<Arc<Ed25519KeyPair>>,
+    /// Latest chunk imported from each federation peer
+    imported: Mutex<HashMap<Region, ChunkId>>,
+    chunk_period: Duration,
+    computation_period: Duration,
+    retention_period: Duration,
+    secure_random: SystemRandom,
+    computations: Arc<Mutex<HashMap<ComputationId, ComputationRecord>>>,
+    /// Computations started by cover uploads, which are neither published
+    /// nor persisted
+    cover_computations: Mutex<HashMap<ComputationId, ComputationRecord>>,
+    store: Arc<dyn ChunkStore>,
+    journal: Arc<Journal>,
+    metrics: Arc<Metrics>,
+    rotate_now: Arc<Notify>,
+}
+
+/// What the diagnosis server remembers of a computation to authorize greylist uploads.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ComputationRecord {
+    started_at: DateTime<Utc>,
+    greylist_secret: GreylistSecret,
+    closure_published: bool,
+    revoked: bool,
+}
+
+impl ComputationRecord {
+    pub fn new(started_at: DateTime<Utc>, greylist_secret: GreylistSecret) -> Self {
+        Self {
+            started_at,
+            greylist_secret,
+            closure_published: false,
+            revoked: false,
+        }
+    }
+    /// Rebuilds a record from the journal, which lacks the greylist secret,
+    /// hence a fresh one nobody knows takes its place.
+    pub fn restore(
+        started_at: DateTime<Utc>,
+        closure_published: bool,
+        revoked: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            started_at,
+            greylist_secret: GreylistSecret::new(&SystemRandom::new())?,
+            closure_published,
+            revoked,
+        })
+    }
+    fn closes_at(&self, computation_period: Duration) -> DateTime<Utc> {
+        self.started_at + computation_period
+    }
+    /// Revocations are only accepted until the closure is published, as
+    /// clients evaluate the computation once they learn about its closure.
+    fn authorize_revocation(
+        &self,
+        data: &RevokeParams,
+        computation_period: Duration,
+    ) -> Result<(), RequestError> {
+        if !self
+            .greylist_secret
+            .verify_revocation(data.computation_id, &data.authorization)
+        {
+            return Err(RequestError::InvalidRevocationAuthorization);
+        }
+        if self.revoked {
+            return Err(RequestError::ComputationRevoked {
+                computation_id: data.computation_id,
+            });
+        }
+        if self.phase(computation_period, Utc::now()) == ComputationPhase::Closed {
+            return Err(RequestError::ComputationClosed {
+                closed_at: self.closes_at(computation_period),
+            });
+        }
+        Ok(())
+    }
+    /// Only the origin of a computation may greylist and only during the computation period.
+    fn authorize_greylist_upload(
+        &self,
+        data: &GreylistUploadParams,
+        computation_period: Duration,
+    ) -> Result<(), RequestError> {
+        if self.revoked {
+            return Err(RequestError::ComputationRevoked {
+                computation_id: data.computation_id,
+            });
+        }
+        if self.phase(computation_period, Utc::now()) != ComputationPhase::Open {
+            return Err(RequestError::ComputationClosed {
+                closed_at: self.closes_at(computation_period),
+            });
+        }
+        if !self.greylist_secret.verify(
+            data.computation_id,
+            &data.diagnosis_keys,
+            &data.authorization,
+        ) {
+            return Err(RequestError::InvalidGreylistAuthorization);
+        }
+        Ok(())
+    }
+    fn phase(&self, computation_period: Duration, now: DateTime<Utc>) -> ComputationPhase {
+        if self.closure_published {
+            ComputationPhase::Closed
+        } else if now < self.closes_at(computation_period) {
+            ComputationPhase::Open
+        } else {
+            ComputationPhase::Closing
+        }
+    }
 }
 
 impl DiagnosisServerState {
-    pub fn new(config: &DiagnosisServerConfig) -> Self {
+    /// Restores the chunks and computations kept in `store` by a previous run.
+    pub fn new(
+        config: &DiagnosisServerConfig,
+        store: Arc<dyn ChunkStore>,
+        journal: Arc<Journal>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
         let chunk_period = Duration::from(config.params.chunk_period);
         let retention_period = config
             .params
             .infection_period
             .as_duration(config.params.tek_rolling_period);
-        let done_chunks = Chunks::new(retention_period);
-        let current_chunk = Chunk::new(TimeInterval::with_alignment(chunk_period));
+        let signing_key = match &config.signing_key {
+            Some(signing_key) => Some(Arc::new(signing_key.key_pair()?)),
+            None => {
+                logger::warn!("Chunks and tree heads are handed out unsigned");
+                None
+            }
+        };
+        let mut done_chunks = Chunks::new(retention_period);
+        done_chunks.restore_log(store.leaves().context("Error restoring transparency log")?);
+        let mut chunks = store.chunks().context("Error restoring chunks")?;
+        let current_chunk = match chunks.pop() {
+            Some(latest) if latest.covers().contains(&Utc::now()) => latest,
+            // the chunk which was current before the restart is done by now
+            Some(latest) => {
+                let next_chunk = Chunk::new(
+                    latest.id().next(),
+                    TimeInterval::with_alignment(chunk_period),
+                );
+                chunks.push(latest);
+                next_chunk
+            }
+            None => Chunk::new(
+                ChunkId::default(),
+                TimeInterval::with_alignment(chunk_period),
+            ),
+        };
+        for chunk in chunks {
+            Self::publish(&mut done_chunks, chunk, &*store)?;
+        }
+        done_chunks.sign_tree_head(signing_key.as_deref());
+        log_store_error(store.put_chunk(&current_chunk));
+        journal.started(&current_chunk);
+        let computations = store
+            .computations()
+            .context("Error restoring computations")?;
+        let computation_id_seed = store
+            .computation_id_seed()
+            .context("Error restoring computation id seed")?;
+        let imported = store
+            .imported()
+            .context("Error restoring imported chunk ids")?;
+        if !done_chunks.inner.is_empty() || !computations.is_empty() {
+            logger::info!(
+                "Restored {} done chunks and {} computations, continuing with chunk {:?}",
+                done_chunks.inner.len(),
+                computations.len(),
+                current_chunk.id()
+            );
+        }
+        metrics.current_chunk_changed(&current_chunk);
+        metrics.set_active_computations(active_computations(&computations));
+        let (latest_done_chunk_tx, latest_done_chunk) =
+            watch::channel(done_chunks.inner.front().map(Chunk::id));
         let diagnosis_server_state = Self {
             done_chunks: Arc::new(Mutex::new(done_chunks)),
             current_chunk: Arc::new(Mutex::new(current_chunk)),
-            computation_id_seed: Mutex::new(0),
+            changes: AtomicU64::new(0),
+            synced: Mutex::new(0),
+            latest_done_chunk,
+            subscription_period: std::time::Duration::from(config.params.refresh_period),
+            pagination: config.pagination,
+            computation_id_seed: Mutex::new(computation_id_seed),
+            region: config.federation.region,
+            signing_key,
+            imported: Mutex::new(imported),
+            chunk_period,
+            computation_period: Duration::from(config.params.computation_period),
+            retention_period,
+            secure_random: SystemRandom::new(),
+            computations: Arc::new(Mutex::new(computations)),
+            cover_computations: Mutex::new(HashMap::new()),
+            store,
+            journal,
+            metrics,
+            rotate_now: Arc::new(Notify::new()),
         };
-        diagnosis_server_state.update();
-        diagnosis_server_state
+        diagnosis_server_state.update(latest_done_chunk_tx);
+        Ok(diagnosis_server_state)
+    }
+    /// A fresh state without journal which keeps its store in memory.
+    #[cfg(test)]
+    pub fn in_memory(config: &DiagnosisServerConfig) -> Self {
+        Self::new(
+            config,
+            Arc::new(crate::store::InMemoryStore::default()),
+            Arc::new(Journal::disabled()),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap()
     }
-    fn update(&self) -> () {
+    fn update(&self, latest_done_chunk: watch::Sender<Option<ChunkId>>) -> () {
         let done_chunks = Arc::clone(&self.done_chunks);
         let current_chunk = Arc::clone(&self.current_chunk);
+        let computations = Arc::clone(&self.computations);
+        let store = Arc::clone(&self.store);
+        let journal = Arc::clone(&self.journal);
+        let metrics = Arc::clone(&self.metrics);
+        let rotate_now = Arc::clone(&self.rotate_now);
+        let signing_key = self.signing_key.clone();
+        let chunk_period = self.chunk_period;
+        let computation_period = self.computation_period;
         task::spawn(async move {
             loop {
                 // new scope is important to release the lock before sleep
@@ -54,28 +286,152 @@ impl DiagnosisServerState {
                         .unwrap_or_else(|_| std::time::Duration::from_millis(0))
                 };
                 logger::debug!("Sleeping for {:?} before advancing next chunk", sleep);
-                time::sleep(sleep).await;
-                let mut current_chunk = current_chunk.lock().await;
-                let mut done_chunks = done_chunks.lock().await;
-                let next_chunk = current_chunk.next_chunk();
+                tokio::select! {
+                    _ = time::sleep(sleep) => {}
+                    _ = rotate_now.notified() => {
+                        logger::info!("Rotating current chunk ahead of time as requested");
+                    }
+                }
+                let mut current_chunk = metrics.lock("current_chunk", &current_chunk).await;
+                {
+                    let mut computations = metrics.lock("computations", &computations).await;
+                    let closed = Self::close_computations(
+                        &mut computations,
+                        computation_period,
+                        &mut current_chunk,
+                    );
+                    metrics.set_active_computations(active_computations(&computations));
+                    log_store_error(
+                        store_blocking(&store, move |store| {
+                            closed.iter().try_for_each(|(computation_id, record)| {
+                                store.put_computation(*computation_id, record)
+                            })
+                        })
+                        .await,
+                    );
+                }
+                let mut done_chunks = metrics.lock("done_chunks", &done_chunks).await;
+                let next_chunk = Self::next_chunk(&mut current_chunk, chunk_period);
                 logger::debug!(
                     "Replacing current chunk with validity {:?} with next chunk with validity {:?}",
                     current_chunk.covers(),
                     next_chunk.covers()
                 );
+                journal.rotated(&current_chunk, &next_chunk).await;
+                metrics.current_chunk_changed(&next_chunk);
+                let stored_next_chunk = next_chunk.clone();
                 let current_chunk = std::mem::replace(&mut *current_chunk, next_chunk);
-                done_chunks.add_done_chunk(current_chunk);
+                let current_chunk_id = current_chunk.id();
+                metrics.chunk_done(&current_chunk);
+                // publishes like `publish()` but stores off the tokio workers
+                let leaf = done_chunks.log(&current_chunk);
+                let stored = store_blocking(&store, move |store| {
+                    log_store_error(store.put_chunk(&stored_next_chunk));
+                    log_store_error(store.put_chunk(&current_chunk));
+                    Self::persist_leaf(store, current_chunk.id(), leaf).map(|_| current_chunk)
+                })
+                .await;
+                let current_chunk = match stored {
+                    Ok(current_chunk) => current_chunk,
+                    Err(e) => {
+                        // a server unable to keep its log must not sign further tree heads
+                        logger::error!("Stopping the diagnosis server: {:?}", e);
+                        std::process::exit(1);
+                    }
+                };
+                let pruned = done_chunks.add_done_chunk(current_chunk);
+                log_store_error(
+                    store_blocking(&store, move |store| {
+                        for pruned in pruned {
+                            log_store_error(store.remove_chunk(pruned.id()));
+                        }
+                        Ok(())
+                    })
+                    .await,
+                );
+                done_chunks.sign_tree_head(signing_key.as_deref());
+                // wakes up all subscribers waiting for the chunk just done
+                let _ = latest_done_chunk.send(Some(current_chunk_id));
             }
         });
     }
-    pub async fn add_to_blacklist(&self, data: BlacklistUploadParams) -> ComputationId {
-        let mut current_chunk = self.current_chunk.lock().await;
-        let computation_id = self.next_computation_id().await;
+    /// Returns the chunk which replaces the current one, ending the current
+    /// one now if it is rotated ahead of time. Either way the next chunk
+    /// covers the present and ends aligned to the chunk period.
+    fn next_chunk(current_chunk: &mut Chunk, chunk_period: Duration) -> Chunk {
+        let now = Utc::now();
+        let aligned = TimeInterval::with_alignment(chunk_period);
+        if current_chunk.covers().contains(&now) {
+            // the current chunk must not end up empty
+            let end = now.max(*current_chunk.covers().from_including() + Duration::milliseconds(1));
+            current_chunk.end_at(end);
+            let mut to_excluding = *aligned.to_excluding();
+            if to_excluding <= end {
+                to_excluding = to_excluding + chunk_period;
+            }
+            Chunk::new(
+                current_chunk.id().next(),
+                TimeInterval::with_bounds(end, to_excluding),
+            )
+        } else {
+            Chunk::new(current_chunk.id().next(), aligned)
+        }
+    }
+    /// `peer` is `None` for uploads the server creates itself.
+    pub async fn add_to_blacklist(
+        &self,
+        data: BlacklistUploadParams,
+        peer: Option<SocketAddr>,
+    ) -> Result<BlacklistUploadResponse, RequestError> {
+        let mut current_chunk = self
+            .metrics
+            .lock("current_chunk", &self.current_chunk)
+            .await;
+        let computation_id = self.next_computation_id().await?;
+        let greylist_secret =
+            GreylistSecret::new(&self.secure_random).expect("Generating a greylist secret failed");
+        let now = Utc::now();
+        {
+            let mut computations = self.metrics.lock("computations", &self.computations).await;
+            // records are kept beyond the computation period to tell late uploads apart
+            let mut expired = Vec::new();
+            computations.retain(|computation_id, record| {
+                let retained = record.started_at + self.retention_period > now;
+                if !retained {
+                    expired.push(*computation_id);
+                }
+                retained
+            });
+            let record = ComputationRecord::new(now, greylist_secret.clone());
+            let stored = record.clone();
+            log_store_error(
+                store_blocking(&self.store, move |store| {
+                    for computation_id in expired {
+                        log_store_error(store.remove_computation(computation_id));
+                    }
+                    store.put_computation(computation_id, &stored)
+                })
+                .await,
+            );
+            computations.insert(computation_id, record);
+            self.metrics
+                .set_active_computations(active_computations(&computations));
+        }
+        self.journal.computation_started(computation_id, now).await;
         // deduplication not strictly necessary here but let's make it more robust..
-        let done_chunks = self.done_chunks.lock().await;
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
         let diagnosis_keys_refs = &data.diagnosis_keys.iter().collect();
         let (deduplicated, duplicates) =
             done_chunks.deduplicate(ListType::Blacklist, computation_id, &diagnosis_keys_refs);
+        self.journal
+            .uploaded(
+                ListType::Blacklist,
+                peer,
+                computation_id,
+                current_chunk.id(),
+                &deduplicated,
+            )
+            .await;
         if !duplicates.is_empty() {
             logger::info!(
                 "Not adding to blacklist with {:?} the following duplicate DKs: {:?}",
@@ -90,17 +446,52 @@ impl DiagnosisServerState {
                 deduplicated
             );
             // deduplication for current chunk not necessary due to set usage
+            let added = deduplicated.len();
             current_chunk.insert(ListType::Blacklist, computation_id, deduplicated);
+            self.metrics.current_chunk_changed(&current_chunk);
+            self.cap_chunk(&current_chunk, added);
+        }
+        // the computation record is synced along
+        let change = self.chunk_changed();
+        drop(done_chunks);
+        drop(current_chunk);
+        self.sync(change).await;
+        // fake cases are not counted as uploads, cover uploads are
+        if peer.is_some() {
+            self.metrics
+                .uploaded(ListType::Blacklist, data.diagnosis_keys.len());
         }
-        computation_id
+        Ok(BlacklistUploadResponse {
+            computation_id,
+            greylist_secret,
+        })
     }
-    pub async fn add_to_greylist(&self, data: GreylistUploadParams) -> () {
-        let mut current_chunk = self.current_chunk.lock().await;
+    pub async fn add_to_greylist(
+        &self,
+        data: GreylistUploadParams,
+        peer: Option<SocketAddr>,
+    ) -> Result<(), RequestError> {
         let computation_id = data.computation_id;
-        let done_chunks = self.done_chunks.lock().await;
+        // authorizing while holding the lock ensures that no greylist upload
+        // ends up in a chunk after the one closing the computation
+        let mut current_chunk = self
+            .metrics
+            .lock("current_chunk", &self.current_chunk)
+            .await;
+        self.authorize_greylist_upload(&data).await?;
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
         let diagnosis_keys_refs = &data.diagnosis_keys.iter().collect();
         let (deduplicated, duplicates) =
             done_chunks.deduplicate(ListType::Greylist, computation_id, &diagnosis_keys_refs);
+        self.journal
+            .uploaded(
+                ListType::Greylist,
+                peer,
+                computation_id,
+                current_chunk.id(),
+                &deduplicated,
+            )
+            .await;
         if !duplicates.is_empty() {
             logger::info!(
                 "Not adding to greylist with {:?} the following duplicate DKs: {:?}",
@@ -115,24 +506,656 @@ impl DiagnosisServerState {
                 deduplicated
             );
             // deduplication for current chunk not necessary due to set usage
+            let added = deduplicated.len();
             current_chunk.insert(ListType::Greylist, computation_id, deduplicated);
+            self.metrics.current_chunk_changed(&current_chunk);
+            self.cap_chunk(&current_chunk, added);
+            let change = self.chunk_changed();
+            drop(done_chunks);
+            drop(current_chunk);
+            self.sync(change).await;
+        }
+        if peer.is_some() {
+            self.metrics
+                .uploaded(ListType::Greylist, data.diagnosis_keys.len());
+        }
+        Ok(())
+    }
+    /// Publishes the revocation with the current chunk. Keys of the computation
+    /// in done chunks stay, as clients already downloaded them anyway.
+    /// Checks whether the computation can be revoked without revoking it,
+    /// such that the verification token is only redeemed for revocations
+    /// which are going to succeed.
+    pub async fn authorize_revocation(&self, data: &RevokeParams) -> Result<(), RequestError> {
+        let computations = self.metrics.lock("computations", &self.computations).await;
+        computations
+            .get(&data.computation_id)
+            .ok_or(RequestError::UnknownComputation {
+                computation_id: data.computation_id,
+            })?
+            .authorize_revocation(data, self.computation_period)
+    }
+    pub async fn revoke(
+        &self,
+        data: RevokeParams,
+        peer: Option<SocketAddr>,
+    ) -> Result<(), RequestError> {
+        let computation_id = data.computation_id;
+        let mut current_chunk = self
+            .metrics
+            .lock("current_chunk", &self.current_chunk)
+            .await;
+        {
+            let mut computations = self.metrics.lock("computations", &self.computations).await;
+            let record = computations
+                .get_mut(&computation_id)
+                .ok_or(RequestError::UnknownComputation { computation_id })?;
+            record.authorize_revocation(&data, self.computation_period)?;
+            record.revoked = true;
+            let stored = record.clone();
+            log_store_error(
+                store_blocking(&self.store, move |store| {
+                    store.put_computation(computation_id, &stored)
+                })
+                .await,
+            );
+        }
+        logger::info!("Revoking {:?}", computation_id);
+        self.journal
+            .revoked(peer, computation_id, current_chunk.id())
+            .await;
+        current_chunk.revoke(computation_id);
+        let change = self.chunk_changed();
+        drop(current_chunk);
+        self.sync(change).await;
+        self.metrics.revoked();
+        Ok(())
+    }
+    /// Answers a cover blacklist upload like a genuine one but publishes
+    /// nothing. It goes through the same locks, journal and store syncs, and
+    /// uses up a computation id like a genuine upload, as handing out the id
+    /// of the next genuine upload would tell both apart once that one shows
+    /// up in a chunk.
+    pub async fn cover_blacklist(
+        &self,
+        data: &BlacklistUploadParams,
+    ) -> Result<BlacklistUploadResponse, RequestError> {
+        let current_chunk = self
+            .metrics
+            .lock("current_chunk", &self.current_chunk)
+            .await;
+        let computation_id = self.next_computation_id().await?;
+        let greylist_secret =
+            GreylistSecret::new(&self.secure_random).expect("Generating a greylist secret failed");
+        let now = Utc::now();
+        drop(self.metrics.lock("computations", &self.computations).await);
+        {
+            let mut cover_computations = self.cover_computations.lock().await;
+            cover_computations.retain(|_, record| record.started_at + self.retention_period > now);
+            cover_computations.insert(
+                computation_id,
+                ComputationRecord::new(now, greylist_secret.clone()),
+            );
+        }
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        let diagnosis_keys_refs = &data.diagnosis_keys.iter().collect();
+        done_chunks.deduplicate(ListType::Blacklist, computation_id, diagnosis_keys_refs);
+        // the computation start and the upload
+        self.journal.cover(2).await;
+        let change = self.chunk_changed();
+        drop(done_chunks);
+        drop(current_chunk);
+        self.sync(change).await;
+        self.metrics
+            .uploaded(ListType::Blacklist, data.diagnosis_keys.len());
+        Ok(BlacklistUploadResponse {
+            computation_id,
+            greylist_secret,
+        })
+    }
+    /// Tells whether the computation was started by a cover upload.
+    pub async fn is_cover_computation(&self, computation_id: ComputationId) -> bool {
+        self.cover_computations
+            .lock()
+            .await
+            .contains_key(&computation_id)
+    }
+    /// Answers a cover greylist upload like a genuine one but publishes
+    /// nothing, it is authorized by the cover computation it continues.
+    pub async fn cover_greylist(&self, data: &GreylistUploadParams) -> Result<(), RequestError> {
+        let current_chunk = self
+            .metrics
+            .lock("current_chunk", &self.current_chunk)
+            .await;
+        drop(self.metrics.lock("computations", &self.computations).await);
+        self.cover_computations
+            .lock()
+            .await
+            .get(&data.computation_id)
+            .ok_or(RequestError::UnknownComputation {
+                computation_id: data.computation_id,
+            })?
+            .authorize_greylist_upload(data, self.computation_period)?;
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        let diagnosis_keys_refs = &data.diagnosis_keys.iter().collect();
+        done_chunks.deduplicate(ListType::Greylist, data.computation_id, diagnosis_keys_refs);
+        self.journal.cover(1).await;
+        let change = self.chunk_changed();
+        drop(done_chunks);
+        drop(current_chunk);
+        self.sync(change).await;
+        self.metrics
+            .uploaded(ListType::Greylist, data.diagnosis_keys.len());
+        Ok(())
+    }
+    async fn authorize_greylist_upload(
+        &self,
+        data: &GreylistUploadParams,
+    ) -> Result<(), RequestError> {
+        let computations = self.metrics.lock("computations", &self.computations).await;
+        computations
+            .get(&data.computation_id)
+            .ok_or(RequestError::UnknownComputation {
+                computation_id: data.computation_id,
+            })?
+            .authorize_greylist_upload(data, self.computation_period)
+    }
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+    pub async fn request_chunks(&self, data: DownloadParams) -> ChunkPage {
+        logger::debug!("Client requests chunks after {:?}", data.after);
+        let page = self.page(data.after, true, data.encoding, None).await;
+        self.metrics.downloaded("download", page_length(&page));
+        page
+    }
+    /// Chunks are optionally restricted to the computations of a region.
+    async fn page(
+        &self,
+        after: Option<ChunkId>,
+        prove: bool,
+        encoding: ChunkEncoding,
+        restricted_to: Option<Region>,
+    ) -> ChunkPage {
+        let mut done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        let mut page = ChunkPage::default();
+        if prove {
+            page.tree_head = Some(done_chunks.tree_head.clone());
+        }
+        let mut page_length = 0;
+        let mut last = None;
+        let chunk_ids: Vec<ChunkId> = done_chunks.get_chunks(after).map(Chunk::id).collect();
+        for chunk_id in chunk_ids {
+            if page.chunks.len() >= self.pagination.page_size {
+                page.continuation = last;
+                break;
+            }
+            let encoded = done_chunks.encoded(
+                chunk_id,
+                EncodedKey {
+                    encoding,
+                    restricted_to,
+                },
+                self.signing_key.as_deref(),
+            );
+            // a page always contains at least one chunk to make progress
+            if !page.chunks.is_empty()
+                && page_length + encoded.len() > self.pagination.max_page_length
+            {
+                page.continuation = last;
+                break;
+            }
+            page_length += encoded.len();
+            last = Some(chunk_id);
+            page.chunks.push(encoded);
+            if prove {
+                page.inclusion_proofs.push(
+                    done_chunks
+                        .inclusion_proof(chunk_id)
+                        .expect("Done chunks are logged"),
+                );
+            }
+        }
+        page
+    }
+    /// Waits until chunks after `after` are done but at most for the subscription period.
+    pub async fn subscribe_chunks(&self, data: DownloadParams) -> ChunkPage {
+        let mut latest_done_chunk = self.latest_done_chunk.clone();
+        let newer_chunk_done = async {
+            while *latest_done_chunk.borrow() <= data.after {
+                if latest_done_chunk.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        if time::timeout(self.subscription_period, newer_chunk_done)
+            .await
+            .is_err()
+        {
+            logger::debug!("Subscription after {:?} timed out", data.after);
+        }
+        let page = self.page(data.after, true, data.encoding, None).await;
+        self.metrics.downloaded("subscribe", page_length(&page));
+        page
+    }
+    /// Done chunks after `after` restricted to the computations of this region.
+    pub async fn federation_pull(&self, after: Option<ChunkId>) -> ChunkPage {
+        // restricted chunks are not part of the transparency log
+        self.page(after, false, ChunkEncoding::default(), Some(self.region))
+            .await
+    }
+    pub async fn tree_head(&self) -> SignedTreeHead {
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        done_chunks.tree_head.clone()
+    }
+    pub async fn consistency(
+        &self,
+        data: ConsistencyParams,
+    ) -> Result<ConsistencyProof, RequestError> {
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        done_chunks
+            .log
+            .consistency_proof(data.old_size, data.new_size)
+            .ok_or(RequestError::InvalidTreeSize {
+                old_size: data.old_size,
+                new_size: data.new_size,
+                tree_size: done_chunks.log.size(),
+            })
+    }
+    pub fn region(&self) -> Region {
+        self.region
+    }
+    /// Receiver of the id of the latest done chunk.
+    pub fn latest_done_chunk(&self) -> watch::Receiver<Option<ChunkId>> {
+        self.latest_done_chunk.clone()
+    }
+    pub async fn imported_up_to(&self, region: Region) -> Option<ChunkId> {
+        self.imported.lock().await.get(&region).copied()
+    }
+    /// Merges the done chunks of a federation peer into the current chunk.
+    /// Chunks imported before are skipped, the remaining ones are
+    /// deduplicated against the done chunks like uploads, hence chunks pulled
+    /// and pushed alike or pulled again after a restart are merged only once.
+    pub async fn import(&self, region: Region, chunks: Vec<Chunk>) -> Result<(), RequestError> {
+        for chunk in chunks.iter() {
+            if let Some(computation_id) = chunk
+                .data()
+                .keys()
+                .chain(chunk.closed())
+                .chain(chunk.revoked())
+                .find(|computation_id| {
+                    computation_id.region() != region || computation_id.region() == self.region
+                })
+            {
+                return Err(RequestError::ForeignComputation {
+                    computation_id: *computation_id,
+                    region,
+                });
+            }
+        }
+        let mut current_chunk = self
+            .metrics
+            .lock("current_chunk", &self.current_chunk)
+            .await;
+        let mut imported = self.imported.lock().await;
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        let mut added = 0;
+        for chunk in chunks {
+            let peer_chunk = chunk.id();
+            // None compares smaller than any chunk id
+            if Some(peer_chunk) <= imported.get(&region).copied() {
+                logger::debug!(
+                    "Skipping chunk {:?} of {:?} which was imported before",
+                    peer_chunk,
+                    region
+                );
+                continue;
+            }
+            imported.insert(region, peer_chunk);
+            let (data, closed, revoked) = chunk.into_parts();
+            if data.is_empty() && closed.is_empty() && revoked.is_empty() {
+                continue;
+            }
+            logger::info!(
+                "Importing chunk {:?} of {:?} into chunk {:?}",
+                peer_chunk,
+                region,
+                current_chunk.id()
+            );
+            self.journal
+                .imported(region, peer_chunk, current_chunk.id())
+                .await;
+            for (computation_id, computation) in data.iter() {
+                for (list, diagnosis_keys) in [
+                    (ListType::Blacklist, computation.blacklist()),
+                    (ListType::Greylist, computation.greylist()),
+                ]
+                .iter()
+                {
+                    let diagnosis_keys_refs = diagnosis_keys.iter().collect();
+                    let (deduplicated, _) =
+                        done_chunks.deduplicate(*list, *computation_id, &diagnosis_keys_refs);
+                    if deduplicated.is_empty() {
+                        continue;
+                    }
+                    self.journal
+                        .uploaded(
+                            *list,
+                            None,
+                            *computation_id,
+                            current_chunk.id(),
+                            &deduplicated,
+                        )
+                        .await;
+                    added += deduplicated.len();
+                    current_chunk.insert(*list, *computation_id, deduplicated);
+                }
+            }
+            for computation_id in closed {
+                if !done_chunks.published(|chunk| chunk.closed(), computation_id) {
+                    current_chunk.close(computation_id);
+                }
+            }
+            for computation_id in revoked {
+                if !done_chunks.published(|chunk| chunk.revoked(), computation_id)
+                    && !current_chunk.revoked().contains(&computation_id)
+                {
+                    self.journal
+                        .revoked(None, computation_id, current_chunk.id())
+                        .await;
+                    current_chunk.revoke(computation_id);
+                }
+            }
+            self.metrics.imported(region);
+        }
+        self.metrics.current_chunk_changed(&current_chunk);
+        self.cap_chunk(&current_chunk, added);
+        let change = self.chunk_changed();
+        let peer_chunk = imported.get(&region).copied();
+        drop(done_chunks);
+        drop(imported);
+        drop(current_chunk);
+        self.sync(change).await;
+        // only after the imported data so that it is pulled again if lost
+        if let Some(peer_chunk) = peer_chunk {
+            log_store_error(
+                store_blocking(&self.store, move |store| {
+                    store.put_imported(region, peer_chunk)
+                })
+                .await,
+            );
+        }
+        Ok(())
+    }
+    pub async fn request_chunks_from(&self, from: DateTime<Utc>) -> Vec<v1::Chunk> {
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        logger::debug!("Version 1 client requests chunks from {}", from);
+        let chunks: Vec<v1::Chunk> = done_chunks
+            .get_chunks_from(&from)
+            .map(v1::Chunk::from)
+            .collect();
+        self.metrics.downloaded(
+            "v1_download",
+            bincode::serialized_size(&chunks).unwrap() as usize,
+        );
+        chunks
+    }
+    pub async fn request_index(&self) -> Vec<ChunkInfo> {
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        self.metrics.downloaded("index", 0);
+        done_chunks.into_iter().rev().map(Chunk::info).collect()
+    }
+    /// Done chunks with the oldest first followed by the current chunk.
+    pub async fn admin_chunks(&self) -> Vec<ChunkInfo> {
+        let current_chunk = self
+            .metrics
+            .lock("current_chunk", &self.current_chunk)
+            .await;
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        done_chunks
+            .into_iter()
+            .rev()
+            .chain(std::iter::once(&*current_chunk))
+            .map(Chunk::info)
+            .collect()
+    }
+    pub async fn admin_computations(&self) -> Vec<ComputationInfo> {
+        let current_chunk = self
+            .metrics
+            .lock("current_chunk", &self.current_chunk)
+            .await;
+        let computations = self.metrics.lock("computations", &self.computations).await;
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        let now = Utc::now();
+        let mut infos: Vec<ComputationInfo> = computations
+            .iter()
+            .map(|(computation_id, record)| {
+                let (blacklisted, greylisted) = done_chunks
+                    .into_iter()
+                    .chain(std::iter::once(&*current_chunk))
+                    .filter_map(|chunk| chunk.data().get(computation_id))
+                    .fold((0, 0), |(blacklisted, greylisted), computation| {
+                        (
+                            blacklisted + computation.blacklist().len(),
+                            greylisted + computation.greylist().len(),
+                        )
+                    });
+                ComputationInfo {
+                    computation_id: *computation_id,
+                    started_at: record.started_at,
+                    phase: record.phase(self.computation_period, now),
+                    blacklisted,
+                    greylisted,
+                    revoked: record.revoked,
+                }
+            })
+            .collect();
+        infos.sort_by_key(|info| info.started_at);
+        infos
+    }
+    pub async fn admin_computation_keys(
+        &self,
+        computation_id: ComputationId,
+    ) -> Result<ComputationKeys, RequestError> {
+        let current_chunk = self
+            .metrics
+            .lock("current_chunk", &self.current_chunk)
+            .await;
+        let computations = self.metrics.lock("computations", &self.computations).await;
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        let mut keys = ComputationKeys {
+            computation_id,
+            blacklist: HashSet::new(),
+            greylist: HashSet::new(),
+        };
+        let mut found = computations.contains_key(&computation_id);
+        for computation in done_chunks
+            .into_iter()
+            .chain(std::iter::once(&*current_chunk))
+            .filter_map(|chunk| chunk.data().get(&computation_id))
+        {
+            found = true;
+            keys.blacklist.extend(computation.blacklist());
+            keys.greylist.extend(computation.greylist());
+        }
+        if !found {
+            return Err(RequestError::UnknownComputation { computation_id });
+        }
+        Ok(keys)
+    }
+    /// Wakes up the rotation ahead of time and waits until it is done.
+    pub async fn admin_rotate(&self) -> ChunkId {
+        let mut latest_done_chunk = self.latest_done_chunk.clone();
+        latest_done_chunk.borrow_and_update();
+        self.rotate_now.notify_one();
+        let _ = latest_done_chunk.changed().await;
+        let done_chunk = *latest_done_chunk.borrow();
+        done_chunk.expect("A rotation always publishes a done chunk")
+    }
+    pub async fn admin_retention(&self) -> RetentionStatus {
+        let computations = self.metrics.lock("computations", &self.computations).await;
+        let done_chunks = self.metrics.lock("done_chunks", &self.done_chunks).await;
+        let oldest_chunk = done_chunks.inner.back();
+        RetentionStatus {
+            retention_period: self
+                .retention_period
+                .to_std()
+                .expect("Retention period is positive"),
+            done_chunks: done_chunks.inner.len(),
+            oldest_chunk: oldest_chunk.map(Chunk::info),
+            oldest_chunk_expires_at: oldest_chunk
+                .map(|chunk| *chunk.covers().to_excluding() + self.retention_period),
+            computations: computations.len(),
+        }
+    }
+    /// Appends the chunk to the transparency log unless a previous run did
+    /// already and adds it to the done chunks.
+    /// Fails unless the leaf is on disk, as a tree head handed out over a
+    /// leaf which is lost on restart looks like a split view to clients.
+    fn publish(done_chunks: &mut Chunks, chunk: Chunk, store: &dyn ChunkStore) -> Result<()> {
+        let leaf = done_chunks.log(&chunk);
+        Self::persist_leaf(store, chunk.id(), leaf)?;
+        for pruned in done_chunks.add_done_chunk(chunk) {
+            log_store_error(store.remove_chunk(pruned.id()));
         }
+        Ok(())
     }
-    pub async fn request_chunks(&self, data: DownloadParams) -> Vec<Chunk> {
-        let done_chunks = self.done_chunks.lock().await;
-        logger::debug!("Client requests chunks from {}", data.from);
-        done_chunks.get_chunks(&data.from)
+    /// Writes and flushes the leaf if the chunk was newly logged.
+    fn persist_leaf(
+        store: &dyn ChunkStore,
+        chunk_id: ChunkId,
+        leaf: Option<TreeHash>,
+    ) -> Result<()> {
+        if let Some(leaf) = leaf {
+            store
+                .put_leaf(chunk_id, &leaf)
+                .and_then(|_| store.flush())
+                .context(format!(
+                    "Error persisting transparency log leaf of {:?}",
+                    chunk_id
+                ))?;
+        }
+        Ok(())
     }
-    async fn next_computation_id(&self) -> ComputationId {
+    /// Publishes the closure of all computations whose period ended with the given chunk.
+    /// Returns the records of the closed computations to be stored.
+    fn close_computations(
+        computations: &mut HashMap<ComputationId, ComputationRecord>,
+        computation_period: Duration,
+        chunk: &mut Chunk,
+    ) -> Vec<(ComputationId, ComputationRecord)> {
+        let now = Utc::now();
+        let mut closed = Vec::new();
+        for (computation_id, record) in computations.iter_mut() {
+            if record.phase(computation_period, now) == ComputationPhase::Closing {
+                logger::info!(
+                    "Closing computation with {:?} with chunk {:?}",
+                    computation_id,
+                    chunk.id()
+                );
+                chunk.close(*computation_id);
+                record.closure_published = true;
+                closed.push((*computation_id, record.clone()));
+            }
+        }
+        closed
+    }
+    async fn next_computation_id(&self) -> Result<ComputationId, RequestError> {
         let mut computation_id_seed = self.computation_id_seed.lock().await;
         let current = *computation_id_seed;
-        logger::debug!(
-            "Advancing computation id from {} to {}",
-            current,
-            current + 1
+        let computation_id = ComputationId::new(self.region, current)
+            .ok_or(RequestError::ComputationIdsExhausted)?;
+        let next = current + 1;
+        logger::debug!("Advancing computation id from {} to {}", current, next);
+        // an id handed out must never be handed out again after a restart
+        store_blocking(&self.store, move |store| {
+            store.put_computation_id_seed(next)
+        })
+        .await
+        .map_err(|e| {
+            logger::error!("Error persisting computation id seed: {:?}", e);
+            RequestError::StoreUnavailable
+        })?;
+        *computation_id_seed = next;
+        Ok(computation_id)
+    }
+    /// Wakes up the rotation ahead of time once the current chunk reaches
+    /// `max_chunk_keys` diagnosis keys, as a page holds at least one chunk
+    /// and a larger one might not fit into a frame at all.
+    /// Must be called while holding the current chunk after adding `added`
+    /// keys to it, hence only the upload reaching the limit wakes it up.
+    fn cap_chunk(&self, current_chunk: &Chunk, added: usize) {
+        let diagnosis_keys = current_chunk.diagnosis_keys();
+        let max_chunk_keys = self.pagination.max_chunk_keys;
+        if diagnosis_keys >= max_chunk_keys && diagnosis_keys - added < max_chunk_keys {
+            logger::info!(
+                "Rotating {:?} ahead of time as it holds {} diagnosis keys",
+                current_chunk.id(),
+                diagnosis_keys
+            );
+            self.rotate_now.notify_one();
+        }
+    }
+    /// Must be called while holding the current chunk after changing it.
+    fn chunk_changed(&self) -> u64 {
+        self.changes.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    /// Returns once the current chunk including `change` is on disk.
+    /// Writing and flushing run off the tokio workers without holding the
+    /// current chunk, and changes made meanwhile are written together by the
+    /// next sync, hence a burst of uploads stores the chunk only a few times.
+    async fn sync(&self, change: u64) {
+        let mut synced = self.synced.lock().await;
+        if *synced >= change {
+            return;
+        }
+        let (chunk, changes) = {
+            let current_chunk = self
+                .metrics
+                .lock("current_chunk", &self.current_chunk)
+                .await;
+            (current_chunk.clone(), self.changes.load(Ordering::SeqCst))
+        };
+        log_store_error(
+            store_blocking(&self.store, move |store| {
+                store.put_chunk(&chunk).and_then(|_| store.flush())
+            })
+            .await,
         );
-        *computation_id_seed += 1;
-        ComputationId::from(current)
+        *synced = changes;
+    }
+}
+
+fn active_computations(computations: &HashMap<ComputationId, ComputationRecord>) -> usize {
+    computations
+        .values()
+        .filter(|record| !record.closure_published)
+        .count()
+}
+
+fn page_length(page: &ChunkPage) -> usize {
+    page.chunks.iter().map(EncodedChunk::len).sum()
+}
+
+/// Runs the store operation off the tokio workers, as sled writes and
+/// flushes block.
+async fn store_blocking<T, F>(store: &Arc<dyn ChunkStore>, operation: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn ChunkStore) -> Result<T> + Send + 'static,
+{
+    let store = Arc::clone(store);
+    task::spawn_blocking(move || operation(&*store))
+        .await
+        .context("Error running store operation")?
+}
+
+/// Failing to persist must not fail the request, the state in memory stays authoritative.
+/// Only computation ids and verification codes, which must never be reused, and the leaves
+/// of the transparency log, which must never change, are the exception.
+fn log_store_error(result: Result<()>) {
+    if let Err(e) = result {
+        logger::error!("Error persisting diagnosis server state: {:?}", e);
     }
 }
 
@@ -140,43 +1163,137 @@ impl DiagnosisServerState {
 struct Chunks {
     retention_period: Duration,
     inner: VecDeque<Chunk>,
+    log: TransparencyLog,
+    /// Leaf index of every logged chunk, including the pruned ones
+    leaf_indices: HashMap<ChunkId, u64>,
+    tree_head: SignedTreeHead,
+    /// Done chunks never change, hence each is encoded and signed only once
+    /// per encoding instead of on every download
+    encoded: HashMap<(ChunkId, EncodedKey), EncodedChunk>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct EncodedKey {
+    encoding: ChunkEncoding,
+    restricted_to: Option<Region>,
 }
 
 impl Chunks {
     fn new(retention_period: Duration) -> Self {
+        let log = TransparencyLog::default();
+        let tree_head = SignedTreeHead::new(0, log.root_hash(0).unwrap(), None);
         Self {
             retention_period,
             inner: VecDeque::new(),
+            log,
+            leaf_indices: HashMap::new(),
+            tree_head,
+            encoded: HashMap::new(),
+        }
+    }
+    fn restore_log(&mut self, leaves: Vec<(ChunkId, TreeHash)>) {
+        for (chunk_id, leaf) in leaves {
+            let leaf_index = self.log.append(leaf);
+            self.leaf_indices.insert(chunk_id, leaf_index);
         }
     }
-    fn add_done_chunk(&mut self, chunk: Chunk) -> () {
+    /// Returns the new leaf unless the chunk is logged already.
+    fn log(&mut self, chunk: &Chunk) -> Option<TreeHash> {
+        if self.leaf_indices.contains_key(&chunk.id()) {
+            return None;
+        }
+        let leaf = TreeHash::leaf(chunk);
+        let leaf_index = self.log.append(leaf);
+        self.leaf_indices.insert(chunk.id(), leaf_index);
+        Some(leaf)
+    }
+    fn sign_tree_head(&mut self, key_pair: Option<&Ed25519KeyPair>) {
+        let tree_size = self.log.size();
+        let root_hash = self.log.root_hash(tree_size).unwrap();
+        self.tree_head = SignedTreeHead::new(tree_size, root_hash, key_pair);
+    }
+    /// Proves the inclusion in the tree of the current tree head.
+    fn inclusion_proof(&self, chunk_id: ChunkId) -> Option<InclusionProof> {
+        let leaf_index = *self.leaf_indices.get(&chunk_id)?;
+        self.log
+            .inclusion_proof(leaf_index, self.tree_head.tree_size)
+    }
+    /// Returns the chunks pruned due to exceeding the retention period.
+    fn add_done_chunk(&mut self, chunk: Chunk) -> Vec<Chunk> {
         self.inner.push_front(chunk);
-        if let Some(chunk) = self.inner.back() {
-            if *chunk.covers().to_excluding() <= Utc::now() - self.retention_period {
-                logger::info!(
-                    "Pruning oldest chunk with validity {:?} due to it exceeding the retention period of {:?}",
-                    chunk.covers(),
-                    self.retention_period
-                );
-                self.inner.pop_back();
+        let mut pruned = Vec::new();
+        // restored chunks may have expired several at once while the server was down
+        while let Some(chunk) = self.inner.back() {
+            if *chunk.covers().to_excluding() > Utc::now() - self.retention_period {
+                break;
             }
+            logger::info!(
+                "Pruning oldest chunk with validity {:?} due to it exceeding the retention period of {:?}",
+                chunk.covers(),
+                self.retention_period
+            );
+            pruned.extend(self.inner.pop_back());
+        }
+        if !pruned.is_empty() {
+            let inner = &self.inner;
+            self.encoded.retain(|(chunk_id, _), _| {
+                inner.back().is_some_and(|oldest| *chunk_id >= oldest.id())
+            });
         }
+        pruned
     }
-    fn get_chunks(&self, from: &DateTime<Utc>) -> Vec<Chunk> {
-        match self.inner.front() {
-            Some(newest) => {
-                if from >= newest.covers().from_including() {
-                    Vec::new()
-                } else {
-                    self.inner
-                        .iter()
-                        .take_while(|chunk| !chunk.covers().contains(from))
-                        .cloned()
-                        .collect()
+    /// Returns all chunks with an id greater than `after` with the oldest first.
+    fn get_chunks(&self, after: Option<ChunkId>) -> impl Iterator<Item = &Chunk> {
+        let newer = self
+            .inner
+            .iter()
+            // None compares smaller than any chunk id
+            .take_while(|chunk| Some(chunk.id()) > after)
+            .count();
+        self.inner.range(..newer).rev()
+    }
+    /// Returns the encoded form of a done chunk, encoding it on first use.
+    fn encoded(
+        &mut self,
+        chunk_id: ChunkId,
+        key: EncodedKey,
+        key_pair: Option<&Ed25519KeyPair>,
+    ) -> EncodedChunk {
+        let inner = &self.inner;
+        self.encoded
+            .entry((chunk_id, key))
+            .or_insert_with(|| {
+                let chunk = inner
+                    .iter()
+                    .find(|chunk| chunk.id() == chunk_id)
+                    .expect("Only done chunks are encoded");
+                let encoded = match key.restricted_to {
+                    Some(region) => {
+                        EncodedChunk::encode(&chunk.restricted_to(region), key.encoding)
+                    }
+                    None => EncodedChunk::encode(chunk, key.encoding),
+                };
+                match key_pair {
+                    Some(key_pair) => encoded.sign(key_pair),
+                    None => encoded,
                 }
-            }
-            None => Vec::new(),
-        }
+            })
+            .clone()
+    }
+    /// Returns all chunks starting after `from` with the newest first.
+    fn get_chunks_from<'a>(&'a self, from: &'a DateTime<Utc>) -> impl Iterator<Item = &'a Chunk> {
+        self.inner
+            .iter()
+            .take_while(move |chunk| from < chunk.covers().from_including())
+    }
+    /// Whether a done chunk already published the closure or revocation.
+    fn published(
+        &self,
+        published: impl Fn(&Chunk) -> &HashSet<ComputationId>,
+        computation_id: ComputationId,
+    ) -> bool {
+        self.into_iter()
+            .any(|chunk| published(chunk).contains(&computation_id))
     }
     fn deduplicate<'a>(
         &'a self,
@@ -222,3 +1339,260 @@ impl<'a> IntoIterator for &'a Chunks {
         self.inner.iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exposurelib::chunk_encoding::Compression;
+    use exposurelib::primitives::TekRollingPeriod;
+    use exposurelib::time::ExposureTime;
+
+    #[test]
+    fn test_get_chunks() {
+        let mut chunks = Chunks::new(Duration::days(14));
+        let mut chunk = Chunk::new(
+            ChunkId::default(),
+            TimeInterval::with_alignment(Duration::minutes(1)),
+        );
+        for _ in 0..5 {
+            let next_chunk = chunk.next_chunk();
+            chunks.add_done_chunk(chunk);
+            chunk = next_chunk;
+        }
+        let ids = |after: Option<u64>| -> Vec<u64> {
+            chunks
+                .get_chunks(after.map(ChunkId::from))
+                .map(|chunk| u64::from(chunk.id()))
+                .collect()
+        };
+        assert_eq!(ids(None), vec![0, 1, 2, 3, 4]);
+        assert_eq!(ids(Some(2)), vec![3, 4]);
+        assert!(ids(Some(4)).is_empty());
+    }
+
+    #[test]
+    fn test_encoded_chunks() {
+        let mut chunks = Chunks::new(Duration::days(14));
+        let chunk = Chunk::new(
+            ChunkId::default(),
+            TimeInterval::with_duration(Utc::now() - Duration::minutes(2), Duration::minutes(1)),
+        );
+        let chunk_id = chunk.id();
+        chunks.add_done_chunk(chunk);
+        let key = |compression| EncodedKey {
+            encoding: ChunkEncoding {
+                compression,
+                ..ChunkEncoding::default()
+            },
+            restricted_to: None,
+        };
+        let gzip = chunks.encoded(chunk_id, key(Compression::Gzip), None);
+        chunks.encoded(chunk_id, key(Compression::None), None);
+        assert_eq!(chunks.encoded.len(), 2);
+        // encoded once per encoding
+        assert_eq!(
+            bincode::serialize(&chunks.encoded(chunk_id, key(Compression::Gzip), None)).unwrap(),
+            bincode::serialize(&gzip).unwrap()
+        );
+        assert_eq!(chunks.encoded.len(), 2);
+
+        // pruned together with the chunk
+        chunks.retention_period = Duration::zero();
+        let next_chunk = Chunk::new(
+            ChunkId::from(1),
+            TimeInterval::with_alignment(Duration::minutes(1)),
+        );
+        assert_eq!(chunks.add_done_chunk(next_chunk).len(), 1);
+        assert!(chunks.encoded.is_empty());
+    }
+
+    #[test]
+    fn test_computation_lifecycle() {
+        let computation_period = Duration::seconds(20);
+        let started_at = Utc::now();
+        let mut computations = HashMap::new();
+        computations.insert(
+            ComputationId::from(0),
+            ComputationRecord::new(
+                started_at,
+                GreylistSecret::new(&SystemRandom::new()).unwrap(),
+            ),
+        );
+        let record = &computations[&ComputationId::from(0)];
+        assert_eq!(
+            record.phase(computation_period, started_at),
+            ComputationPhase::Open
+        );
+        assert_eq!(
+            record.phase(computation_period, started_at + computation_period),
+            ComputationPhase::Closing
+        );
+
+        let mut chunk = Chunk::new(
+            ChunkId::default(),
+            TimeInterval::with_alignment(Duration::minutes(1)),
+        );
+        assert!(DiagnosisServerState::close_computations(
+            &mut computations,
+            computation_period,
+            &mut chunk,
+        )
+        .is_empty());
+        assert!(chunk.closed().is_empty());
+        let closed = DiagnosisServerState::close_computations(
+            &mut computations,
+            Duration::zero(),
+            &mut chunk,
+        );
+        assert!(chunk.closed().contains(&ComputationId::from(0)));
+        // the closure is stored to survive a restart
+        assert_eq!(closed.len(), 1);
+        assert!(closed[0].1.closure_published);
+        let record = &computations[&ComputationId::from(0)];
+        assert_eq!(
+            record.phase(computation_period, started_at),
+            ComputationPhase::Closed
+        );
+    }
+
+    fn config() -> DiagnosisServerConfig {
+        DiagnosisServerConfig::new(
+            "127.0.0.1:0".parse().unwrap(),
+            exposurelib::config::SystemParams::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_forced_rotation() {
+        let state = DiagnosisServerState::in_memory(&config());
+        let first = state.current_chunk.lock().await.id();
+        for rotation in 1..=2u64 {
+            let done_chunk = state.admin_rotate().await;
+            assert_eq!(u64::from(done_chunk), u64::from(first) + rotation - 1);
+            let current_chunk = state.current_chunk.lock().await;
+            assert!(current_chunk.covers().contains(&Utc::now()));
+            // the done chunk ends where the current one starts
+            let done_chunks = state.done_chunks.lock().await;
+            let done = done_chunks.inner.front().unwrap();
+            assert_eq!(done.id(), done_chunk);
+            assert_eq!(
+                done.covers().to_excluding(),
+                current_chunk.covers().from_including()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunk_cap() {
+        let mut config = config();
+        config.pagination.max_chunk_keys = 3;
+        let state = DiagnosisServerState::in_memory(&config);
+        let first = state.current_chunk.lock().await.id();
+        let mut latest_done_chunk = state.latest_done_chunk.clone();
+        latest_done_chunk.borrow_and_update();
+        let upload = BlacklistUploadParams {
+            diagnosis_keys: diagnosis_keys(),
+            verification_token: None,
+        };
+        state.add_to_blacklist(upload, None).await.unwrap();
+        // the chunk reaching the limit is done long before its period ends
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            latest_done_chunk.changed(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(*latest_done_chunk.borrow(), Some(first));
+        assert!(state.current_chunk.lock().await.data().is_empty());
+    }
+
+    fn diagnosis_keys() -> HashSet<Validity<TemporaryExposureKey>> {
+        let secure_random = SystemRandom::new();
+        (0..3)
+            .map(|i| {
+                Validity::new(
+                    ExposureTime::from(2_700_000 + i * 144),
+                    TekRollingPeriod::default(),
+                    TemporaryExposureKey::new(&secure_random).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_revocation_after_closure() {
+        let state = DiagnosisServerState::in_memory(&config());
+        let upload = BlacklistUploadParams {
+            diagnosis_keys: diagnosis_keys(),
+            verification_token: None,
+        };
+        let response = state.add_to_blacklist(upload, None).await.unwrap();
+        let computation_id = response.computation_id;
+        let revocation = || RevokeParams::new(computation_id, None, &response.greylist_secret);
+        state.authorize_revocation(&revocation()).await.unwrap();
+
+        // clients evaluate the computation once its closure is published
+        state
+            .computations
+            .lock()
+            .await
+            .get_mut(&computation_id)
+            .unwrap()
+            .closure_published = true;
+        assert!(matches!(
+            state.authorize_revocation(&revocation()).await,
+            Err(RequestError::ComputationClosed { .. })
+        ));
+        assert!(matches!(
+            state.revoke(revocation(), None).await,
+            Err(RequestError::ComputationClosed { .. })
+        ));
+        assert!(state.current_chunk.lock().await.revoked().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cover_uploads() {
+        let state = DiagnosisServerState::in_memory(&config());
+        let upload = || BlacklistUploadParams {
+            diagnosis_keys: diagnosis_keys(),
+            verification_token: None,
+        };
+        let cover = state.cover_blacklist(&upload()).await.unwrap();
+        assert!(state.is_cover_computation(cover.computation_id).await);
+        let greylist_upload = GreylistUploadParams::new(
+            cover.computation_id,
+            diagnosis_keys(),
+            &cover.greylist_secret,
+        );
+        state.cover_greylist(&greylist_upload).await.unwrap();
+        assert!(state.current_chunk.lock().await.data().is_empty());
+        assert!(state.computations.lock().await.is_empty());
+        // cover greylist uploads are authorized like genuine ones
+        let forged = GreylistUploadParams::new(
+            cover.computation_id,
+            diagnosis_keys(),
+            &GreylistSecret::new(&SystemRandom::new()).unwrap(),
+        );
+        assert_eq!(
+            state.cover_greylist(&forged).await,
+            Err(RequestError::InvalidGreylistAuthorization)
+        );
+
+        // cover uploads use up their computation id
+        let genuine = state.add_to_blacklist(upload(), None).await.unwrap();
+        assert_ne!(genuine.computation_id, cover.computation_id);
+        assert!(!state.is_cover_computation(genuine.computation_id).await);
+        // the metrics count cover uploads like genuine ones
+        let uploads: f64 = state
+            .metrics()
+            .registry()
+            .gather()
+            .iter()
+            .filter(|family| family.get_name() == "diagnosisserver_uploads_total")
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().get_value())
+            .sum();
+        assert_eq!(uploads, 2.0);
+    }
+}
@@ -0,0 +1,427 @@
+use crate::state::ComputationRecord;
+use anyhow::{Context, Result};
+use exposurelib::config::StoreConfig;
+use exposurelib::diagnosis_server_state::{Chunk, ChunkId};
+use exposurelib::logger;
+use exposurelib::primitives::{ComputationId, Region};
+use exposurelib::transparency::TreeHash;
+use exposurelib::verification::VerificationCode;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Durable part of the diagnosis server's state which is restored on startup.
+/// The current chunk is stored like done ones and replaced on every change.
+/// Writes may be buffered, `flush()` makes them durable.
+pub trait ChunkStore: Send + Sync {
+    /// Inserts the chunk or replaces the one with the same id.
+    fn put_chunk(&self, chunk: &Chunk) -> Result<()>;
+    fn remove_chunk(&self, chunk_id: ChunkId) -> Result<()>;
+    /// Returns all chunks with the oldest first.
+    fn chunks(&self) -> Result<Vec<Chunk>>;
+    fn put_computation(
+        &self,
+        computation_id: ComputationId,
+        record: &ComputationRecord,
+    ) -> Result<()>;
+    fn remove_computation(&self, computation_id: ComputationId) -> Result<()>;
+    fn computations(&self) -> Result<HashMap<ComputationId, ComputationRecord>>;
+    fn put_computation_id_seed(&self, seed: u32) -> Result<()>;
+    /// Returns zero if no computation was started yet.
+    fn computation_id_seed(&self) -> Result<u32>;
+    /// Leaves of the transparency log are kept beyond the retention period.
+    fn put_leaf(&self, chunk_id: ChunkId, leaf: &TreeHash) -> Result<()>;
+    /// Returns all leaves with the oldest first.
+    fn leaves(&self) -> Result<Vec<(ChunkId, TreeHash)>>;
+    /// Latest chunk imported from the federation peer of `region`.
+    fn put_imported(&self, region: Region, chunk_id: ChunkId) -> Result<()>;
+    fn imported(&self) -> Result<HashMap<Region, ChunkId>>;
+    /// Codes once redeemed are never accepted again, not even after a restart.
+    fn put_redeemed_code(&self, code: &VerificationCode) -> Result<()>;
+    fn redeemed_codes(&self) -> Result<HashSet<VerificationCode>>;
+    /// Blocks until all previous writes are on disk.
+    fn flush(&self) -> Result<()>;
+}
+
+pub fn open(config: &StoreConfig) -> Result<Arc<dyn ChunkStore>> {
+    match config {
+        StoreConfig::InMemory => {
+            logger::warn!("Chunks are kept in memory only and lost on restart");
+            Ok(Arc::new(InMemoryStore::default()))
+        }
+        StoreConfig::OnDisk { path } => {
+            logger::info!("Chunks are stored in {:?}", path);
+            Ok(Arc::new(SledStore::open(path)?))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryStore {
+    chunks: Mutex<BTreeMap<ChunkId, Chunk>>,
+    computations: Mutex<HashMap<ComputationId, ComputationRecord>>,
+    computation_id_seed: Mutex<u32>,
+    leaves: Mutex<BTreeMap<ChunkId, TreeHash>>,
+    imported: Mutex<HashMap<Region, ChunkId>>,
+    redeemed_codes: Mutex<HashSet<VerificationCode>>,
+}
+
+impl ChunkStore for InMemoryStore {
+    fn put_chunk(&self, chunk: &Chunk) -> Result<()> {
+        self.chunks
+            .lock()
+            .unwrap()
+            .insert(chunk.id(), chunk.clone());
+        Ok(())
+    }
+    fn remove_chunk(&self, chunk_id: ChunkId) -> Result<()> {
+        self.chunks.lock().unwrap().remove(&chunk_id);
+        Ok(())
+    }
+    fn chunks(&self) -> Result<Vec<Chunk>> {
+        Ok(self.chunks.lock().unwrap().values().cloned().collect())
+    }
+    fn put_computation(
+        &self,
+        computation_id: ComputationId,
+        record: &ComputationRecord,
+    ) -> Result<()> {
+        self.computations
+            .lock()
+            .unwrap()
+            .insert(computation_id, record.clone());
+        Ok(())
+    }
+    fn remove_computation(&self, computation_id: ComputationId) -> Result<()> {
+        self.computations.lock().unwrap().remove(&computation_id);
+        Ok(())
+    }
+    fn computations(&self) -> Result<HashMap<ComputationId, ComputationRecord>> {
+        Ok(self.computations.lock().unwrap().clone())
+    }
+    fn put_computation_id_seed(&self, seed: u32) -> Result<()> {
+        *self.computation_id_seed.lock().unwrap() = seed;
+        Ok(())
+    }
+    fn computation_id_seed(&self) -> Result<u32> {
+        Ok(*self.computation_id_seed.lock().unwrap())
+    }
+    fn put_leaf(&self, chunk_id: ChunkId, leaf: &TreeHash) -> Result<()> {
+        self.leaves.lock().unwrap().insert(chunk_id, *leaf);
+        Ok(())
+    }
+    fn leaves(&self) -> Result<Vec<(ChunkId, TreeHash)>> {
+        Ok(self
+            .leaves
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(chunk_id, leaf)| (*chunk_id, *leaf))
+            .collect())
+    }
+    fn put_imported(&self, region: Region, chunk_id: ChunkId) -> Result<()> {
+        self.imported.lock().unwrap().insert(region, chunk_id);
+        Ok(())
+    }
+    fn imported(&self) -> Result<HashMap<Region, ChunkId>> {
+        Ok(self.imported.lock().unwrap().clone())
+    }
+    fn put_redeemed_code(&self, code: &VerificationCode) -> Result<()> {
+        self.redeemed_codes.lock().unwrap().insert(code.clone());
+        Ok(())
+    }
+    fn redeemed_codes(&self) -> Result<HashSet<VerificationCode>> {
+        Ok(self.redeemed_codes.lock().unwrap().clone())
+    }
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps chunks, computations, leaves, imported chunk ids and redeemed codes
+/// in separate trees of a sled database with big endian ids as keys, such
+/// that iterating yields the oldest first.
+/// Besides explicit flushes sled flushes every half second on its own.
+pub struct SledStore {
+    db: sled::Db,
+    chunks: sled::Tree,
+    computations: sled::Tree,
+    leaves: sled::Tree,
+    imported: sled::Tree,
+    redeemed_codes: sled::Tree,
+}
+
+impl SledStore {
+    const CHUNKS: &'static str = "chunks";
+    const COMPUTATIONS: &'static str = "computations";
+    const COMPUTATION_ID_SEED: &'static str = "computation_id_seed";
+    const LEAVES: &'static str = "leaves";
+    const IMPORTED: &'static str = "imported";
+    const REDEEMED_CODES: &'static str = "redeemed_codes";
+
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::with_db(sled::open(path).context(format!("Error opening chunk store at {:?}", path))?)
+    }
+    fn with_db(db: sled::Db) -> Result<Self> {
+        Ok(Self {
+            chunks: db.open_tree(Self::CHUNKS)?,
+            computations: db.open_tree(Self::COMPUTATIONS)?,
+            leaves: db.open_tree(Self::LEAVES)?,
+            imported: db.open_tree(Self::IMPORTED)?,
+            redeemed_codes: db.open_tree(Self::REDEEMED_CODES)?,
+            db,
+        })
+    }
+}
+
+impl ChunkStore for SledStore {
+    fn put_chunk(&self, chunk: &Chunk) -> Result<()> {
+        self.chunks.insert(
+            u64::from(chunk.id()).to_be_bytes(),
+            bincode::serialize(chunk)?,
+        )?;
+        Ok(())
+    }
+    fn remove_chunk(&self, chunk_id: ChunkId) -> Result<()> {
+        self.chunks.remove(u64::from(chunk_id).to_be_bytes())?;
+        Ok(())
+    }
+    fn chunks(&self) -> Result<Vec<Chunk>> {
+        self.chunks
+            .iter()
+            .values()
+            .map(|chunk| -> Result<Chunk> {
+                bincode::deserialize(&chunk?).context("Error decoding stored chunk")
+            })
+            .collect()
+    }
+    fn put_computation(
+        &self,
+        computation_id: ComputationId,
+        record: &ComputationRecord,
+    ) -> Result<()> {
+        self.computations.insert(
+            u32::from(computation_id).to_be_bytes(),
+            bincode::serialize(record)?,
+        )?;
+        Ok(())
+    }
+    fn remove_computation(&self, computation_id: ComputationId) -> Result<()> {
+        self.computations
+            .remove(u32::from(computation_id).to_be_bytes())?;
+        Ok(())
+    }
+    fn computations(&self) -> Result<HashMap<ComputationId, ComputationRecord>> {
+        self.computations
+            .iter()
+            .map(|entry| {
+                let (computation_id, record) = entry?;
+                let computation_id = u32::from_be_bytes(
+                    computation_id
+                        .as_ref()
+                        .try_into()
+                        .context("Invalid stored computation id")?,
+                );
+                Ok((
+                    ComputationId::from(computation_id),
+                    bincode::deserialize(&record).context("Error decoding stored computation")?,
+                ))
+            })
+            .collect()
+    }
+    fn put_computation_id_seed(&self, seed: u32) -> Result<()> {
+        self.db
+            .insert(Self::COMPUTATION_ID_SEED, &seed.to_be_bytes())?;
+        Ok(())
+    }
+    fn computation_id_seed(&self) -> Result<u32> {
+        match self.db.get(Self::COMPUTATION_ID_SEED)? {
+            Some(seed) => Ok(u32::from_be_bytes(
+                seed.as_ref()
+                    .try_into()
+                    .context("Invalid stored computation id seed")?,
+            )),
+            None => Ok(0),
+        }
+    }
+    fn put_leaf(&self, chunk_id: ChunkId, leaf: &TreeHash) -> Result<()> {
+        self.leaves
+            .insert(u64::from(chunk_id).to_be_bytes(), bincode::serialize(leaf)?)?;
+        Ok(())
+    }
+    fn leaves(&self) -> Result<Vec<(ChunkId, TreeHash)>> {
+        self.leaves
+            .iter()
+            .map(|entry| {
+                let (chunk_id, leaf) = entry?;
+                let chunk_id = u64::from_be_bytes(
+                    chunk_id
+                        .as_ref()
+                        .try_into()
+                        .context("Invalid stored chunk id")?,
+                );
+                Ok((
+                    ChunkId::from(chunk_id),
+                    bincode::deserialize(&leaf).context("Error decoding stored leaf")?,
+                ))
+            })
+            .collect()
+    }
+    fn put_imported(&self, region: Region, chunk_id: ChunkId) -> Result<()> {
+        self.imported
+            .insert([u8::from(region)], &u64::from(chunk_id).to_be_bytes())?;
+        Ok(())
+    }
+    fn imported(&self) -> Result<HashMap<Region, ChunkId>> {
+        self.imported
+            .iter()
+            .map(|entry| {
+                let (region, chunk_id) = entry?;
+                let region = match region.as_ref() {
+                    [region] => Region::from(*region),
+                    _ => anyhow::bail!("Invalid stored region"),
+                };
+                let chunk_id = u64::from_be_bytes(
+                    chunk_id
+                        .as_ref()
+                        .try_into()
+                        .context("Invalid stored chunk id")?,
+                );
+                Ok((region, ChunkId::from(chunk_id)))
+            })
+            .collect()
+    }
+    fn put_redeemed_code(&self, code: &VerificationCode) -> Result<()> {
+        self.redeemed_codes
+            .insert(bincode::serialize(code)?, &[])?;
+        Ok(())
+    }
+    fn redeemed_codes(&self) -> Result<HashSet<VerificationCode>> {
+        self.redeemed_codes
+            .iter()
+            .keys()
+            .map(|code| -> Result<VerificationCode> {
+                bincode::deserialize(&code?).context("Error decoding stored verification code")
+            })
+            .collect()
+    }
+    fn flush(&self) -> Result<()> {
+        self.db.flush().context("Error flushing chunk store")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+    use chrono::Duration;
+    use exposurelib::time::TimeInterval;
+    use exposurelib::verification::GreylistSecret;
+    use ring::rand::SystemRandom;
+
+    fn check_store(store: &dyn ChunkStore) {
+        assert!(store.chunks().unwrap().is_empty());
+        assert_eq!(store.computation_id_seed().unwrap(), 0);
+
+        let mut chunk = Chunk::new(
+            ChunkId::default(),
+            TimeInterval::with_alignment(Duration::minutes(1)),
+        );
+        let mut ids = Vec::new();
+        // beyond 255 to check that big endian keys keep the order
+        for _ in 0..300 {
+            let next_chunk = chunk.next_chunk();
+            store.put_chunk(&chunk).unwrap();
+            ids.push(u64::from(chunk.id()));
+            chunk = next_chunk;
+        }
+        chunk.close(ComputationId::from(7));
+        store.put_chunk(&chunk).unwrap();
+        let mut closed = chunk.clone();
+        closed.close(ComputationId::from(8));
+        // replaces the previous version of the same chunk
+        store.put_chunk(&closed).unwrap();
+        ids.push(u64::from(chunk.id()));
+        store.remove_chunk(ChunkId::from(0)).unwrap();
+        ids.remove(0);
+        let chunks = store.chunks().unwrap();
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| u64::from(chunk.id()))
+                .collect::<Vec<_>>(),
+            ids
+        );
+        assert_eq!(chunks.last().unwrap().closed(), closed.closed());
+
+        let record = ComputationRecord::new(
+            Utc::now(),
+            GreylistSecret::new(&SystemRandom::new()).unwrap(),
+        );
+        store
+            .put_computation(ComputationId::from(3), &record)
+            .unwrap();
+        store
+            .put_computation(ComputationId::from(4), &record)
+            .unwrap();
+        store.remove_computation(ComputationId::from(4)).unwrap();
+        let computations = store.computations().unwrap();
+        assert_eq!(computations.len(), 1);
+        assert!(computations.contains_key(&ComputationId::from(3)));
+
+        store.put_computation_id_seed(5).unwrap();
+        assert_eq!(store.computation_id_seed().unwrap(), 5);
+
+        assert!(store.leaves().unwrap().is_empty());
+        // leaves outlive the pruned chunk 0
+        for chunk_id in [1u64, 0, 2].iter() {
+            let mut leaf_chunk = chunk.clone();
+            leaf_chunk.close(ComputationId::from(*chunk_id as u32));
+            store
+                .put_leaf(ChunkId::from(*chunk_id), &TreeHash::leaf(&leaf_chunk))
+                .unwrap();
+        }
+        let leaves = store.leaves().unwrap();
+        assert_eq!(
+            leaves
+                .iter()
+                .map(|(chunk_id, _)| u64::from(*chunk_id))
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_ne!(leaves[0].1, leaves[1].1);
+
+        assert!(store.imported().unwrap().is_empty());
+        store
+            .put_imported(Region::from(1), ChunkId::from(4))
+            .unwrap();
+        store
+            .put_imported(Region::from(2), ChunkId::from(9))
+            .unwrap();
+        // replaces the previous high-water mark of the region
+        store
+            .put_imported(Region::from(1), ChunkId::from(6))
+            .unwrap();
+        let imported = store.imported().unwrap();
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[&Region::from(1)], ChunkId::from(6));
+        assert_eq!(imported[&Region::from(2)], ChunkId::from(9));
+
+        assert!(store.redeemed_codes().unwrap().is_empty());
+        let code = VerificationCode::new(&SystemRandom::new()).unwrap();
+        store.put_redeemed_code(&code).unwrap();
+        store.flush().unwrap();
+        let redeemed_codes = store.redeemed_codes().unwrap();
+        assert_eq!(redeemed_codes.len(), 1);
+        assert!(redeemed_codes.contains(&code));
+    }
+
+    #[test]
+    fn test_chunk_stores() {
+        check_store(&InMemoryStore::default());
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_store(&SledStore::with_db(db).unwrap());
+    }
+}
@@ -0,0 +1,311 @@
+use crate::store::ChunkStore;
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use chrono::Duration;
+use exposurelib::config::VerificationConfig;
+use exposurelib::error::RequestError;
+use exposurelib::logger;
+use exposurelib::primitives::{ComputationId, TemporaryExposureKey, Validity};
+use exposurelib::rpcs::VerifyParams;
+use exposurelib::verification::{KeyCommitment, TestType, VerificationCode, VerificationToken};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task;
+
+/// Redeems verification codes for tokens and checks the tokens of blacklist uploads.
+pub struct Verifier {
+    required: bool,
+    token_validity: Duration,
+    // only tokens issued during this run of the diagnosis server are accepted
+    key: hmac::Key,
+    // tokens of the cover traffic are issued with this key and thus never accepted
+    cover_key: hmac::Key,
+    secure_random: SystemRandom,
+    // all configured codes, whether redeemed or not
+    issued: HashSet<VerificationCode>,
+    codes: Mutex<HashMap<VerificationCode, TestType>>,
+    store: Arc<dyn ChunkStore>,
+    // nonces of redeemed but not yet expired tokens
+    redeemed_tokens: Mutex<HashMap<[u8; 16], DateTime<Utc>>>,
+}
+
+impl Verifier {
+    /// Codes redeemed in a previous run, as kept in `store`, are not issued again.
+    pub fn new(config: &VerificationConfig, store: Arc<dyn ChunkStore>) -> Result<Self> {
+        let redeemed_codes = store
+            .redeemed_codes()
+            .context("Error restoring redeemed verification codes")?;
+        let codes: HashMap<VerificationCode, TestType> = config
+            .codes
+            .iter()
+            .filter(|(code, _)| !redeemed_codes.contains(code))
+            .map(|(code, test_type)| (code.clone(), *test_type))
+            .collect();
+        let secure_random = SystemRandom::new();
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &secure_random)
+            .expect("Generating the verification key failed");
+        let cover_key = hmac::Key::generate(hmac::HMAC_SHA256, &secure_random)
+            .expect("Generating the cover verification key failed");
+        if config.required {
+            logger::info!(
+                "Blacklist uploads require verification, {} of {} codes are left",
+                codes.len(),
+                config.codes.len()
+            );
+        } else {
+            logger::warn!("Blacklist uploads do not require verification");
+        }
+        Ok(Self {
+            required: config.required,
+            token_validity: Duration::from_std(config.token_validity).unwrap(),
+            key,
+            cover_key,
+            secure_random,
+            issued: config.codes.keys().cloned().collect(),
+            codes: Mutex::new(codes),
+            store,
+            redeemed_tokens: Mutex::new(HashMap::new()),
+        })
+    }
+    /// Redeems the one-time code for a token bound to the committed keys.
+    /// Codes which were never issued are answered with a cover token, as
+    /// cover verifications send random codes which the diagnosis server must
+    /// answer like genuine ones. Guessed codes are thus answered alike.
+    pub async fn redeem_code(
+        &self,
+        params: VerifyParams,
+    ) -> Result<VerificationToken, RequestError> {
+        let mut codes = self.codes.lock().await;
+        let test_type = match codes.get(&params.code) {
+            Some(test_type) => *test_type,
+            None if self.issued.contains(&params.code) => {
+                return Err(RequestError::InvalidVerificationCode)
+            }
+            None => return Ok(self.cover_token(params.key_commitment)),
+        };
+        // the code stays redeemable unless its redemption is on disk
+        let store = Arc::clone(&self.store);
+        let code = params.code.clone();
+        task::spawn_blocking(move || store.put_redeemed_code(&code).and_then(|_| store.flush()))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result)
+            .map_err(|e| {
+                logger::error!("Error persisting redeemed verification code: {:?}", e);
+                RequestError::StoreUnavailable
+            })?;
+        codes.remove(&params.code);
+        logger::info!("Redeemed {:?} for a {:?} test", params.code, test_type);
+        Ok(VerificationToken::issue(
+            &self.key,
+            test_type,
+            params.key_commitment,
+            Utc::now() + self.token_validity,
+            &self.secure_random,
+        )
+        .expect("Issuing a verification token failed"))
+    }
+    /// Answers a cover verification with a token looking like a genuine one.
+    fn cover_token(&self, key_commitment: KeyCommitment) -> VerificationToken {
+        VerificationToken::issue(
+            &self.cover_key,
+            TestType::Confirmed,
+            key_commitment,
+            Utc::now() + self.token_validity,
+            &self.secure_random,
+        )
+        .expect("Issuing a verification token failed")
+    }
+    /// Checks the token of a blacklist upload and marks it as redeemed,
+    /// `release_token()` makes it usable again if the upload fails.
+    pub async fn redeem_token(
+        &self,
+        token: Option<&VerificationToken>,
+        diagnosis_keys: &HashSet<Validity<TemporaryExposureKey>>,
+    ) -> Result<(), RequestError> {
+        self.redeem(token, false, KeyCommitment::new(diagnosis_keys), false)
+            .await
+    }
+    /// Tells cover blacklist uploads apart by their token, which takes as
+    /// long as checking a genuine token's signature.
+    pub fn is_cover_token(&self, token: Option<&VerificationToken>) -> bool {
+        token.is_some_and(|token| token.verify(&self.cover_key))
+    }
+    /// Checks the cover token of a cover blacklist upload as thoroughly as
+    /// `redeem_token()` checks a genuine one.
+    pub async fn redeem_cover_token(
+        &self,
+        token: Option<&VerificationToken>,
+        diagnosis_keys: &HashSet<Validity<TemporaryExposureKey>>,
+    ) -> Result<(), RequestError> {
+        self.redeem(token, false, KeyCommitment::new(diagnosis_keys), true)
+            .await
+    }
+    /// Checks the token of a revocation and marks it as redeemed.
+    pub async fn redeem_revocation_token(
+        &self,
+        token: Option<&VerificationToken>,
+        computation_id: ComputationId,
+    ) -> Result<(), RequestError> {
+        self.redeem(
+            token,
+            true,
+            KeyCommitment::revocation(computation_id),
+            false,
+        )
+        .await
+    }
+    /// Lets the token be redeemed again, e.g., as its upload was not stored.
+    pub async fn release_token(&self, token: Option<&VerificationToken>) {
+        if let Some(token) = token {
+            self.redeemed_tokens.lock().await.remove(&token.nonce());
+        }
+    }
+    async fn redeem(
+        &self,
+        token: Option<&VerificationToken>,
+        revocation: bool,
+        key_commitment: KeyCommitment,
+        cover: bool,
+    ) -> Result<(), RequestError> {
+        let token = match token {
+            Some(token) => token,
+            None if self.required => return Err(RequestError::VerificationRequired),
+            None => return Ok(()),
+        };
+        let key = if cover { &self.cover_key } else { &self.key };
+        if !token.verify(key) {
+            return Err(RequestError::InvalidVerificationToken);
+        }
+        let now = Utc::now();
+        if token.expires_at() <= now {
+            return Err(RequestError::VerificationTokenExpired {
+                expired_at: token.expires_at(),
+            });
+        }
+        // a revised test must not authorize an upload and vice versa
+        if (token.test_type() == TestType::Revoked) != revocation {
+            return Err(RequestError::VerificationTestTypeMismatch);
+        }
+        if token.key_commitment() != key_commitment {
+            return Err(RequestError::KeyCommitmentMismatch);
+        }
+        let mut redeemed_tokens = self.redeemed_tokens.lock().await;
+        // expired tokens are rejected anyway, hence there is no need to remember them
+        redeemed_tokens.retain(|_, expires_at| *expires_at > now);
+        // cover tokens may be sent again, the clients never do so anyway
+        if cover {
+            return Ok(());
+        }
+        if redeemed_tokens
+            .insert(token.nonce(), token.expires_at())
+            .is_some()
+        {
+            return Err(RequestError::InvalidVerificationToken);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::InMemoryStore;
+
+    #[tokio::test]
+    async fn test_codes_stay_redeemed() {
+        let secure_random = SystemRandom::new();
+        let code = VerificationCode::new(&secure_random).unwrap();
+        let mut config = VerificationConfig::default();
+        config.codes.insert(code.clone(), TestType::Confirmed);
+        let store: Arc<dyn ChunkStore> = Arc::new(InMemoryStore::default());
+        let params = || VerifyParams {
+            code: code.clone(),
+            key_commitment: KeyCommitment::new(&HashSet::new()),
+        };
+        let verifier = Verifier::new(&config, Arc::clone(&store)).unwrap();
+        assert!(verifier.redeem_code(params()).await.is_ok());
+        assert_eq!(
+            verifier.redeem_code(params()).await.unwrap_err(),
+            RequestError::InvalidVerificationCode
+        );
+        // a restarted diagnosis server does not accept the code either
+        let verifier = Verifier::new(&config, store).unwrap();
+        assert_eq!(
+            verifier.redeem_code(params()).await.unwrap_err(),
+            RequestError::InvalidVerificationCode
+        );
+    }
+
+    #[tokio::test]
+    async fn test_released_token() {
+        let secure_random = SystemRandom::new();
+        let code = VerificationCode::new(&secure_random).unwrap();
+        let mut config = VerificationConfig {
+            required: true,
+            ..VerificationConfig::default()
+        };
+        config.codes.insert(code.clone(), TestType::Confirmed);
+        let verifier = Verifier::new(&config, Arc::new(InMemoryStore::default())).unwrap();
+        let diagnosis_keys = HashSet::new();
+        assert_eq!(
+            verifier.redeem_token(None, &diagnosis_keys).await,
+            Err(RequestError::VerificationRequired)
+        );
+        let token = verifier
+            .redeem_code(VerifyParams {
+                code,
+                key_commitment: KeyCommitment::new(&diagnosis_keys),
+            })
+            .await
+            .unwrap();
+        assert!(verifier
+            .redeem_token(Some(&token), &diagnosis_keys)
+            .await
+            .is_ok());
+        assert_eq!(
+            verifier.redeem_token(Some(&token), &diagnosis_keys).await,
+            Err(RequestError::InvalidVerificationToken)
+        );
+        // the upload failed, hence the token may be used once more
+        verifier.release_token(Some(&token)).await;
+        assert!(verifier
+            .redeem_token(Some(&token), &diagnosis_keys)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cover_token() {
+        let verifier = Verifier::new(
+            &VerificationConfig::default(),
+            Arc::new(InMemoryStore::default()),
+        )
+        .unwrap();
+        let diagnosis_keys = HashSet::new();
+        // codes which were never issued are answered with a cover token
+        let token = verifier
+            .redeem_code(VerifyParams {
+                code: VerificationCode::new(&SystemRandom::new()).unwrap(),
+                key_commitment: KeyCommitment::new(&diagnosis_keys),
+            })
+            .await
+            .unwrap();
+        assert!(verifier.is_cover_token(Some(&token)));
+        for _ in 0..2 {
+            assert!(verifier
+                .redeem_cover_token(Some(&token), &diagnosis_keys)
+                .await
+                .is_ok());
+        }
+        // cover tokens never authorize a genuine upload
+        assert!(!verifier.is_cover_token(None));
+        assert_eq!(
+            verifier.redeem_token(Some(&token), &diagnosis_keys).await,
+            Err(RequestError::InvalidVerificationToken)
+        );
+    }
+}
@@ -0,0 +1,384 @@
+use crate::diagnosis_server_state::{Chunk, ChunkId, ComputationState};
+use crate::error::ExposurelibError;
+use crate::primitives::{ComputationId, Key, TemporaryExposureKey, Validity};
+use crate::signing::{PublicKey, Signature};
+use crate::time::{ExposureTime, TimeInterval};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use ring::signature::Ed25519KeyPair;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Read, Write};
+
+/// Layout of a chunk's payload.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChunkFormat {
+    /// The chunk as is, serialized with Bincode.
+    Bincode,
+    /// Computations sorted by id with delta encoded validities and
+    /// concatenated keys which compresses considerably better.
+    Columnar,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChunkEncoding {
+    pub format: ChunkFormat,
+    pub compression: Compression,
+}
+
+impl ChunkEncoding {
+    pub fn plain() -> Self {
+        Self {
+            format: ChunkFormat::Bincode,
+            compression: Compression::None,
+        }
+    }
+}
+
+impl std::default::Default for ChunkEncoding {
+    fn default() -> Self {
+        Self {
+            format: ChunkFormat::Columnar,
+            compression: Compression::Gzip,
+        }
+    }
+}
+
+/// A chunk as it is sent over the wire.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EncodedChunk {
+    encoding: ChunkEncoding,
+    payload: Vec<u8>,
+    /// Set by diagnosis servers with a signing key
+    signature: Option<Signature>,
+}
+
+impl EncodedChunk {
+    pub fn encode(chunk: &Chunk, encoding: ChunkEncoding) -> Self {
+        let serialized = match encoding.format {
+            ChunkFormat::Bincode => bincode::serialize(chunk),
+            ChunkFormat::Columnar => bincode::serialize(&ColumnarChunk::from(chunk)),
+        }
+        .expect("Serializing a chunk cannot fail");
+        let payload = match encoding.compression {
+            Compression::None => serialized,
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(&serialized)
+                    .and_then(|_| encoder.finish())
+                    .expect("Compressing into memory cannot fail")
+            }
+        };
+        Self {
+            encoding,
+            payload,
+            signature: None,
+        }
+    }
+    /// Signs the encoding and the payload as they are sent, hence the
+    /// signature holds regardless of how the chunk is encoded.
+    pub fn sign(mut self, key_pair: &Ed25519KeyPair) -> Self {
+        self.signature = Some(Signature::sign(key_pair, &self.signed_message()));
+        self
+    }
+    /// Fails for unsigned chunks as well.
+    pub fn verify(&self, public_key: &PublicKey) -> Result<(), ExposurelibError> {
+        let signature = self
+            .signature
+            .as_ref()
+            .ok_or_else(|| ExposurelibError::SignatureError(String::from("Chunk is unsigned")))?;
+        public_key.verify(&self.signed_message(), signature)
+    }
+    fn signed_message(&self) -> Vec<u8> {
+        let mut message =
+            bincode::serialize(&self.encoding).expect("Serializing an encoding cannot fail");
+        message.extend_from_slice(&self.payload);
+        message
+    }
+    /// Decodes the chunk while refusing to decompress more than
+    /// `max_decoded_length` bytes.
+    pub fn decode(&self, max_decoded_length: usize) -> Result<Chunk, ExposurelibError> {
+        let decompressed;
+        let serialized = match self.encoding.compression {
+            Compression::None => &self.payload[..],
+            Compression::Gzip => {
+                let mut buffer = Vec::new();
+                GzDecoder::new(&self.payload[..])
+                    .take(max_decoded_length as u64 + 1)
+                    .read_to_end(&mut buffer)
+                    .map_err(|e| ExposurelibError::ChunkDecodingError(e.to_string()))?;
+                decompressed = buffer;
+                &decompressed[..]
+            }
+        };
+        if serialized.len() > max_decoded_length {
+            return Err(ExposurelibError::ChunkDecodingError(format!(
+                "Decoded chunk exceeds {} bytes",
+                max_decoded_length
+            )));
+        }
+        let bincode_error = |e: bincode::Error| ExposurelibError::ChunkDecodingError(e.to_string());
+        match self.encoding.format {
+            ChunkFormat::Bincode => bincode::deserialize(serialized).map_err(bincode_error),
+            ChunkFormat::Columnar => bincode::deserialize::<ColumnarChunk>(serialized)
+                .map_err(bincode_error)?
+                .try_into(),
+        }
+    }
+    pub fn encoding(&self) -> ChunkEncoding {
+        self.encoding
+    }
+    pub fn len(&self) -> usize {
+        self.payload.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+}
+
+impl fmt::Debug for EncodedChunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncodedChunk")
+            .field("encoding", &self.encoding)
+            .field("len", &self.payload.len())
+            .field("signature", &self.signature)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ColumnarChunk {
+    id: ChunkId,
+    covers: TimeInterval,
+    computation_ids: Vec<ComputationId>,
+    /// Blacklist and greylist length of each computation in turn
+    list_lengths: Vec<u32>,
+    /// Difference of each key's valid_from to the previous key's
+    valid_from_deltas: Vec<u32>,
+    /// All keys' bytes back to back
+    keys: Vec<u8>,
+    closed: Vec<ComputationId>,
+    revoked: Vec<ComputationId>,
+}
+
+impl ColumnarChunk {
+    fn push_list(&mut self, list: &HashSet<Validity<TemporaryExposureKey>>, previous: &mut u32) {
+        let mut list: Vec<_> = list.iter().collect();
+        list.sort_by(|a, b| {
+            (u32::from(a.valid_from()), a.keyring().get())
+                .cmp(&(u32::from(b.valid_from()), b.keyring().get()))
+        });
+        self.list_lengths.push(list.len() as u32);
+        for validity in list {
+            let valid_from = u32::from(validity.valid_from());
+            self.valid_from_deltas
+                .push(valid_from.wrapping_sub(*previous));
+            *previous = valid_from;
+            self.keys.extend_from_slice(validity.keyring().get());
+        }
+    }
+}
+
+impl From<&Chunk> for ColumnarChunk {
+    fn from(chunk: &Chunk) -> Self {
+        let mut computation_ids: Vec<_> = chunk.data().keys().copied().collect();
+        computation_ids.sort();
+        let mut closed: Vec<_> = chunk.closed().iter().copied().collect();
+        closed.sort();
+        let mut revoked: Vec<_> = chunk.revoked().iter().copied().collect();
+        revoked.sort();
+        let mut columnar = Self {
+            id: chunk.id(),
+            covers: chunk.covers().clone(),
+            computation_ids: Vec::new(),
+            list_lengths: Vec::new(),
+            valid_from_deltas: Vec::new(),
+            keys: Vec::new(),
+            closed,
+            revoked,
+        };
+        let mut previous = 0;
+        for computation_id in computation_ids.iter() {
+            let computation = &chunk.data()[computation_id];
+            columnar.push_list(computation.blacklist(), &mut previous);
+            columnar.push_list(computation.greylist(), &mut previous);
+        }
+        columnar.computation_ids = computation_ids;
+        columnar
+    }
+}
+
+impl std::convert::TryFrom<ColumnarChunk> for Chunk {
+    type Error = ExposurelibError;
+
+    fn try_from(columnar: ColumnarChunk) -> Result<Self, Self::Error> {
+        let key_count = columnar.valid_from_deltas.len();
+        if columnar.list_lengths.len() != 2 * columnar.computation_ids.len()
+            || columnar
+                .list_lengths
+                .iter()
+                .map(|l| *l as usize)
+                .sum::<usize>()
+                != key_count
+            || columnar.keys.len() != key_count * TemporaryExposureKey::KEY_LEN
+        {
+            return Err(ExposurelibError::ChunkDecodingError(String::from(
+                "Columns of columnar chunk differ in length",
+            )));
+        }
+        let mut deltas = columnar.valid_from_deltas.iter();
+        let mut keys = columnar.keys.chunks_exact(TemporaryExposureKey::KEY_LEN);
+        let mut previous: u32 = 0;
+        let mut next_list = |length: u32| -> HashSet<Validity<TemporaryExposureKey>> {
+            (0..length)
+                .map(|_| {
+                    previous = previous.wrapping_add(*deltas.next().unwrap());
+                    let key: [u8; TemporaryExposureKey::KEY_LEN] =
+                        keys.next().unwrap().try_into().unwrap();
+                    Validity::with_valid_from(
+                        ExposureTime::from(previous),
+                        TemporaryExposureKey::from(key),
+                    )
+                })
+                .collect()
+        };
+        let mut data = HashMap::new();
+        for (computation_id, lengths) in columnar
+            .computation_ids
+            .iter()
+            .zip(columnar.list_lengths.chunks_exact(2))
+        {
+            let blacklist = next_list(lengths[0]);
+            let greylist = next_list(lengths[1]);
+            data.insert(
+                *computation_id,
+                ComputationState::from_lists(blacklist, greylist),
+            );
+        }
+        Ok(Chunk::from_data(
+            columnar.id,
+            columnar.covers,
+            data,
+            columnar.closed.into_iter().collect(),
+            columnar.revoked.into_iter().collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnosis_server_state::ListType;
+    use crate::primitives::TekRollingPeriod;
+    use crate::signing::SigningKey;
+    use chrono::{Duration, Utc};
+    use ring::rand::SystemRandom;
+
+    fn sample_chunk() -> Chunk {
+        let secure_random = SystemRandom::new();
+        let tekrp = TekRollingPeriod::default();
+        let mut chunk = Chunk::new(
+            ChunkId::from(7),
+            TimeInterval::with_duration(Utc::now(), Duration::hours(1)),
+        );
+        for computation in 0..3u32 {
+            let validities: Vec<_> = (0..14u32)
+                .map(|day| {
+                    Validity::new(
+                        ExposureTime::from(2_700_000 + day * u32::from(tekrp)),
+                        tekrp,
+                        TemporaryExposureKey::new(&secure_random).unwrap(),
+                    )
+                })
+                .collect();
+            let (blacklist, greylist) = validities.split_at(10 - computation as usize);
+            chunk.insert(
+                ListType::Blacklist,
+                ComputationId::from(computation),
+                blacklist.iter().collect(),
+            );
+            chunk.insert(
+                ListType::Greylist,
+                ComputationId::from(computation),
+                greylist.iter().collect(),
+            );
+        }
+        chunk.close(ComputationId::from(42));
+        chunk.revoke(ComputationId::from(41));
+        chunk
+    }
+
+    fn assert_same_chunk(a: &Chunk, b: &Chunk) {
+        assert_eq!(a.id(), b.id());
+        assert_eq!(
+            bincode::serialize(a.covers()).unwrap(),
+            bincode::serialize(b.covers()).unwrap()
+        );
+        assert_eq!(a.closed(), b.closed());
+        assert_eq!(a.revoked(), b.revoked());
+        assert_eq!(a.data().len(), b.data().len());
+        for (computation_id, computation) in a.data() {
+            let other = &b.data()[computation_id];
+            assert_eq!(computation.blacklist(), other.blacklist());
+            assert_eq!(computation.greylist(), other.greylist());
+        }
+    }
+
+    #[test]
+    fn test_chunk_encoding_round_trip() {
+        let chunk = sample_chunk();
+        for format in [ChunkFormat::Bincode, ChunkFormat::Columnar].iter() {
+            for compression in [Compression::None, Compression::Gzip].iter() {
+                let encoding = ChunkEncoding {
+                    format: *format,
+                    compression: *compression,
+                };
+                let encoded = EncodedChunk::encode(&chunk, encoding);
+                assert_same_chunk(&chunk, &encoded.decode(1 << 20).unwrap());
+            }
+        }
+        let plain = EncodedChunk::encode(&chunk, ChunkEncoding::plain());
+        let columnar = EncodedChunk::encode(&chunk, ChunkEncoding::default());
+        assert!(columnar.len() < plain.len());
+    }
+
+    #[test]
+    fn test_chunk_signature() {
+        let signing_key = SigningKey::generate(&SystemRandom::new()).unwrap();
+        let key_pair = signing_key.key_pair().unwrap();
+        let public_key = signing_key.public_key().unwrap();
+        let chunk = sample_chunk();
+        let unsigned = EncodedChunk::encode(&chunk, ChunkEncoding::default());
+        assert!(unsigned.verify(&public_key).is_err());
+        let signed = unsigned.sign(&key_pair);
+        assert!(signed.verify(&public_key).is_ok());
+        let mut tampered = signed.clone();
+        tampered.payload[0] ^= 1;
+        assert!(tampered.verify(&public_key).is_err());
+        let mut reencoded = signed;
+        reencoded.encoding = ChunkEncoding::plain();
+        assert!(reencoded.verify(&public_key).is_err());
+    }
+
+    #[test]
+    fn test_chunk_decoding_limits() {
+        let chunk = sample_chunk();
+        let encoded = EncodedChunk::encode(&chunk, ChunkEncoding::default());
+        assert!(encoded.decode(16).is_err());
+        let truncated = EncodedChunk {
+            encoding: ChunkEncoding::default(),
+            payload: encoded.payload[..encoded.len() / 2].to_vec(),
+            signature: None,
+        };
+        assert!(truncated.decode(1 << 20).is_err());
+    }
+}
@@ -2,7 +2,7 @@ use crate::config::Intensity;
 use crate::error::ExposurelibError;
 use crate::primitives::{
     AssociatedEncryptedMetadata, ExposureKeyring, InfectionPeriod, RollingProximityIdentifier,
-    TekKeyring, TekRollingPeriod, TemporaryExposureKey, Validity,
+    Seed, TekKeyring, TekRollingPeriod, TemporaryExposureKey, Validity,
 };
 use crate::time::{ExposureTime, ExposureTimeSet};
 use chrono::prelude::*;
@@ -122,6 +122,7 @@ impl BluetoothLayer {
         let mut high_risk = ExposureTimeSet::new();
         let mut low_risk = ExposureTimeSet::new();
         let mut socket_addr = None;
+        let mut seed = None;
 
         for encounters_at_exposure_time in encounters_at_tekrp_multiple.iter() {
             let (exposure_time, traced_contacts) = encounters_at_exposure_time;
@@ -145,6 +146,7 @@ impl BluetoothLayer {
                     } else {
                         socket_addr = Some(metadata.connection_identifier());
                     }
+                    seed = Some(metadata.seed());
                 }
             }
         }
@@ -152,6 +154,7 @@ impl BluetoothLayer {
         if socket_addr.is_some() {
             Some(Match::new(
                 socket_addr.unwrap(),
+                seed.unwrap(),
                 Validity::<TemporaryExposureKey>::from(with),
                 high_risk,
                 low_risk,
@@ -168,6 +171,7 @@ impl BluetoothLayer {
 #[derive(Debug, Eq)]
 pub struct Match {
     socket_addr: SocketAddr,
+    seed: Seed,
     tek: Validity<TemporaryExposureKey>,
     high_risk: ExposureTimeSet,
     low_risk: ExposureTimeSet,
@@ -176,12 +180,14 @@ pub struct Match {
 impl Match {
     pub fn new(
         socket_addr: SocketAddr,
+        seed: Seed,
         tek: Validity<TemporaryExposureKey>,
         high_risk: ExposureTimeSet,
         low_risk: ExposureTimeSet,
     ) -> Self {
         Self {
             socket_addr,
+            seed,
             tek,
             high_risk,
             low_risk,
@@ -190,6 +196,10 @@ impl Match {
     pub fn connection_identifier(&self) -> SocketAddr {
         self.socket_addr
     }
+    /// Seed of the matched TEKRP which addresses the holder's mailbox.
+    pub fn seed(&self) -> &Seed {
+        &self.seed
+    }
     pub fn tek(&self) -> &Validity<TemporaryExposureKey> {
         &self.tek
     }
@@ -1,26 +1,403 @@
+use crate::chunk_encoding::ChunkEncoding;
 use crate::client_state::ClientState;
 use crate::primitives::*;
+use crate::signing::{PublicKey, SigningKey};
+use crate::verification::{TestType, VerificationCode};
 use chrono::prelude::*;
 use chrono::Duration;
 use serde::{Deserialize, Serialize};
 use std::cmp;
+use std::collections::HashMap;
 use std::fmt;
 use std::hash;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DiagnosisServerConfig {
     pub endpoint: SocketAddr,
+    /// Optionally serves the RPCs additionally via HTTP with JSON or Bincode payloads.
+    #[serde(default)]
+    pub http_endpoint: Option<SocketAddr>,
+    /// Optionally serves protocol version 1 clients which predate the handshake
+    /// and thus cannot be served at `endpoint`.
+    #[serde(default)]
+    pub legacy_endpoint: Option<SocketAddr>,
+    /// Accepts greylist uploads at `legacy_endpoint` for the computations
+    /// started there without authorization, which version 1 origins cannot
+    /// provide. Otherwise their blacklist uploads are rejected right away.
+    #[serde(default)]
+    pub legacy_greylist_uploads: bool,
+    /// Optionally serves the admin RPCs, must only be reachable by operators.
+    #[serde(default)]
+    pub admin_endpoint: Option<SocketAddr>,
+    /// Optionally serves metrics in the Prometheus text format at `/metrics`.
+    #[serde(default)]
+    pub metrics_endpoint: Option<SocketAddr>,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
+    pub pagination: Pagination,
+    #[serde(default)]
+    pub verification: VerificationConfig,
+    #[serde(default)]
+    pub mailbox: MailboxConfig,
+    #[serde(default)]
+    pub fake_cases: FakeCasesConfig,
+    #[serde(default)]
+    pub store: StoreConfig,
+    #[serde(default)]
+    pub journal: Option<JournalConfig>,
+    #[serde(default)]
+    pub federation: FederationConfig,
+    /// Optionally signs every chunk handed out to clients.
+    #[serde(default)]
+    pub signing_key: Option<SigningKey>,
     #[serde(flatten)]
     pub params: SystemParams,
 }
 
 impl DiagnosisServerConfig {
     pub fn new(endpoint: SocketAddr, params: SystemParams) -> Self {
-        Self { endpoint, params }
+        Self {
+            endpoint,
+            http_endpoint: None,
+            legacy_endpoint: None,
+            legacy_greylist_uploads: false,
+            admin_endpoint: None,
+            metrics_endpoint: None,
+            rate_limits: RateLimits::default(),
+            pagination: Pagination::default(),
+            verification: VerificationConfig::default(),
+            mailbox: MailboxConfig::default(),
+            fake_cases: FakeCasesConfig::default(),
+            store: StoreConfig::default(),
+            journal: None,
+            federation: FederationConfig::default(),
+            signing_key: None,
+            params,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RelayConfig {
+    pub endpoint: SocketAddr,
+    #[serde(default)]
+    pub mixing: MixingConfig,
+    #[serde(flatten)]
+    pub params: SystemParams,
+}
+
+impl RelayConfig {
+    pub fn new(endpoint: SocketAddr, params: SystemParams) -> Self {
+        Self {
+            endpoint,
+            mixing: MixingConfig::default(),
+            params,
+        }
     }
 }
 
+/// A relay collects forwards until either `batch_size` forwards are pending
+/// or the oldest one waited for `batch_timeout` and then delivers all
+/// pending forwards in random order.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct MixingConfig {
+    pub batch_size: usize,
+    pub batch_timeout: std::time::Duration,
+    /// A failed delivery is retried every `retry_interval` until it failed
+    /// this many times.
+    #[serde(default = "MixingConfig::default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "MixingConfig::default_retry_interval")]
+    pub retry_interval: std::time::Duration,
+}
+
+impl MixingConfig {
+    fn default_max_attempts() -> u32 {
+        3
+    }
+    fn default_retry_interval() -> std::time::Duration {
+        std::time::Duration::from_secs(30)
+    }
+}
+
+impl std::default::Default for MixingConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 4,
+            batch_timeout: std::time::Duration::from_secs(5),
+            max_attempts: Self::default_max_attempts(),
+            retry_interval: Self::default_retry_interval(),
+        }
+    }
+}
+
+/// Undelivered forwards are retried every `retry_interval` until their
+/// computation is closed.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct ForwardQueueConfig {
+    pub retry_interval: std::time::Duration,
+    /// An attempt still running after this long is retried later
+    #[serde(default = "ForwardQueueConfig::default_attempt_timeout")]
+    pub attempt_timeout: std::time::Duration,
+}
+
+impl ForwardQueueConfig {
+    fn default_attempt_timeout() -> std::time::Duration {
+        std::time::Duration::from_secs(30)
+    }
+}
+
+impl std::default::Default for ForwardQueueConfig {
+    fn default() -> Self {
+        Self {
+            retry_interval: std::time::Duration::from_secs(10),
+            attempt_timeout: Self::default_attempt_timeout(),
+        }
+    }
+}
+
+/// Where the diagnosis server keeps its chunks and computations.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum StoreConfig {
+    /// Everything is lost on restart
+    #[default]
+    InMemory,
+    /// Restored on startup from the database at `path`
+    OnDisk { path: PathBuf },
+}
+
+/// Append-only journal at `path` of all uploads and chunk rotations.
+/// With `anonymise_peers` only the network prefix of a peer's address is kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub anonymise_peers: bool,
+}
+
+/// Diagnosis servers of different regions exchange the chunk data of their
+/// own computations with their `peers`, which reach each other's federation
+/// service at `endpoint`. Chunks are pushed right after they are done and
+/// pulled every `pull_period` to catch up after outages.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FederationConfig {
+    pub region: Region,
+    pub endpoint: Option<SocketAddr>,
+    pub peers: Vec<FederationPeer>,
+    pub pull_period: std::time::Duration,
+}
+
+impl std::default::Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            region: Region::default(),
+            endpoint: None,
+            peers: Vec::new(),
+            pull_period: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FederationPeer {
+    pub region: Region,
+    /// Requests of the peer are only accepted from the IP address of its endpoint.
+    pub endpoint: SocketAddr,
+    /// Pins the key the peer signs its chunks with, chunks pulled from or
+    /// pushed by the peer must carry a valid signature.
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
+}
+
+/// Fake cases, i.e., blacklists of random keys, are published as a Poisson
+/// process with `mean_interval` such that the size of the published lists
+/// does not reveal the real case count.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct FakeCasesConfig {
+    pub enabled: bool,
+    pub mean_interval: std::time::Duration,
+}
+
+impl std::default::Default for FakeCasesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mean_interval: std::time::Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Greylist keys are uploaded in one request per computation after a delay
+/// drawn uniformly from [0, `max_delay`] but before the computation closes.
+/// A `max_delay` of zero uploads each key right away.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+pub struct GreylistBatchingConfig {
+    pub max_delay: std::time::Duration,
+}
+
+/// The revised test result is handed out `after` the blacklist upload,
+/// which is revoked with the `code`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevocationConfig {
+    pub code: VerificationCode,
+    pub after: std::time::Duration,
+}
+
+/// Cover requests are sent as a Poisson process, i.e., with exponentially
+/// distributed pauses of `mean_interval` on average.
+/// Fake forwards are sent to a random one of the `peers`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoverTrafficConfig {
+    pub enabled: bool,
+    pub mean_interval: std::time::Duration,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl std::default::Default for CoverTrafficConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mean_interval: std::time::Duration::from_secs(60),
+            peers: Vec::new(),
+        }
+    }
+}
+
+/// Clients serve the latest tree head of the transparency log they observed
+/// at `endpoint` and compare it with the one of a random one of the `peers`
+/// every `period`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GossipConfig {
+    pub endpoint: Option<SocketAddr>,
+    pub peers: Vec<SocketAddr>,
+    pub period: std::time::Duration,
+}
+
+impl std::default::Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            peers: Vec::new(),
+            period: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VerificationConfig {
+    /// Rejects blacklist uploads without a valid verification token,
+    /// off unless the health authority issues codes
+    pub required: bool,
+    /// Time span within which an issued token must be used
+    pub token_validity: std::time::Duration,
+    /// One-time codes handed out to positively tested participants
+    pub codes: HashMap<VerificationCode, TestType>,
+}
+
+impl std::default::Default for VerificationConfig {
+    fn default() -> Self {
+        Self {
+            required: false,
+            token_validity: std::time::Duration::from_secs(15 * 60),
+            codes: HashMap::new(),
+        }
+    }
+}
+
+/// Deposits are kept until fetched or for at most one computation period.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct MailboxConfig {
+    /// Maximum number of pending deposits per rendezvous token
+    pub max_deposits_per_token: usize,
+}
+
+impl std::default::Default for MailboxConfig {
+    fn default() -> Self {
+        Self {
+            max_deposits_per_token: 64,
+        }
+    }
+}
+
+/// Bounds a single page of downloaded chunks.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Pagination {
+    /// Maximum number of chunks per page
+    pub page_size: usize,
+    /// Maximum encoded length of all chunks of a page in bytes,
+    /// a page contains at least one chunk regardless
+    pub max_page_length: usize,
+    /// Number of diagnosis keys after which the current chunk is rotated
+    /// ahead of time, which keeps a single chunk below `max_page_length`
+    /// and the maximum frame length
+    #[serde(default = "Pagination::default_max_chunk_keys")]
+    pub max_chunk_keys: usize,
+}
+
+impl Pagination {
+    fn default_max_chunk_keys() -> usize {
+        50_000
+    }
+}
+
+impl std::default::Default for Pagination {
+    fn default() -> Self {
+        Self {
+            page_size: 16,
+            max_page_length: 4 * 1024 * 1024,
+            max_chunk_keys: Self::default_max_chunk_keys(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct RateLimits {
+    /// Applies to all uploads, deposits and revocations of a single peer IP address.
+    pub per_peer: TokenBucketConfig,
+    /// Applies to all greylist uploads of a single computation.
+    pub per_computation: TokenBucketConfig,
+    /// Applies to all mailbox fetches, tree heads and consistency proofs of a
+    /// single peer IP address, which must not drain the bucket of its uploads.
+    #[serde(default = "RateLimits::default_queries")]
+    pub queries: TokenBucketConfig,
+}
+
+impl RateLimits {
+    fn default_queries() -> TokenBucketConfig {
+        TokenBucketConfig {
+            capacity: 10,
+            refill_period: std::time::Duration::from_secs(6),
+        }
+    }
+}
+
+impl std::default::Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            per_peer: TokenBucketConfig {
+                capacity: 10,
+                refill_period: std::time::Duration::from_secs(6),
+            },
+            per_computation: TokenBucketConfig {
+                capacity: 50,
+                refill_period: std::time::Duration::from_secs(1),
+            },
+            queries: Self::default_queries(),
+        }
+    }
+}
+
+/// A bucket holds at most `capacity` tokens and regains one token per `refill_period`.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct TokenBucketConfig {
+    pub capacity: u32,
+    pub refill_period: std::time::Duration,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Participant {
     pub name: String,
@@ -28,6 +405,9 @@ pub struct Participant {
     pub positively_tested: bool,
     #[serde(default = "Participant::default_to_be_warned")]
     pub to_be_warned: bool,
+    /// The positive test turns out to be false and the upload is revoked
+    #[serde(default)]
+    pub revoked: bool,
 }
 
 impl Participant {
@@ -36,6 +416,7 @@ impl Participant {
             name: name.into(),
             positively_tested,
             to_be_warned: Self::default_to_be_warned(),
+            revoked: false,
         }
     }
     fn default_positively_tested() -> bool {
@@ -50,6 +431,9 @@ impl Participant {
     pub fn to_be_warned(&self) -> bool {
         self.to_be_warned
     }
+    pub fn revoked(&self) -> bool {
+        self.revoked
+    }
     pub fn set_to_be_warned(&mut self) -> () {
         self.to_be_warned = true;
     }
@@ -137,6 +521,45 @@ pub struct SystemParams {
     pub chunk_period: ChunkPeriod,
     pub refresh_period: RefreshPeriod,
     pub computation_period: ComputationPeriod,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+impl SystemParams {
+    /// Upper bound of diagnosis keys per upload, i.e. one per TEKRP of the infection period.
+    pub fn max_diagnosis_keys(&self) -> usize {
+        usize::from(self.infection_period)
+    }
+}
+
+/// Bounds enforced by all servers on incoming frames and requests.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+pub struct Limits {
+    /// Maximum length of a single transport frame in bytes.
+    pub max_frame_length: usize,
+    /// Maximum number of shared encounter times within a forward request.
+    pub max_encounter_times: usize,
+    /// Maximum number of concurrent connections from a single IP address.
+    /// Leaves room for clients behind a shared NAT by default.
+    #[serde(default = "Limits::default_max_channels_per_ip")]
+    pub max_channels_per_ip: u32,
+}
+
+impl Limits {
+    fn default_max_channels_per_ip() -> u32 {
+        64
+    }
+}
+
+impl std::default::Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_length: 16 * 1024 * 1024,
+            // one encounter time per EN interval of the default TEKRP
+            max_encounter_times: 144,
+            max_channels_per_ip: Self::default_max_channels_per_ip(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Copy, Clone)]
@@ -204,6 +627,36 @@ pub struct ClientConfig {
     pub diagnosis_server_endpoint: SocketAddr,
     #[serde(flatten)]
     pub params: SystemParams,
+    /// Encoding the diagnosis server is asked to use for downloaded chunks
+    #[serde(default)]
+    pub chunk_encoding: ChunkEncoding,
+    /// Handed out by the health authority if positively tested
+    #[serde(default)]
+    pub verification_code: Option<VerificationCode>,
+    /// Routes forwards through this relay instead of connecting to successors directly
+    #[serde(default)]
+    pub relay: Option<SocketAddr>,
+    /// Retrying of forwards whose delivery failed
+    #[serde(default)]
+    pub forward_queue: ForwardQueueConfig,
+    /// Fake requests hiding whether the participant takes part in a computation
+    #[serde(default)]
+    pub cover_traffic: CoverTrafficConfig,
+    /// Delays and batches the greylist uploads of the own computation
+    #[serde(default)]
+    pub greylist_batching: GreylistBatchingConfig,
+    /// Handed out by the health authority if a positive test turns out to be false
+    #[serde(default)]
+    pub revocation: Option<RevocationConfig>,
+    /// Optionally serves metrics in the Prometheus text format at `/metrics`
+    #[serde(default)]
+    pub metrics_endpoint: Option<SocketAddr>,
+    /// Downloaded chunks are rejected unless signed with the matching key
+    #[serde(default)]
+    pub diagnosis_server_public_key: Option<PublicKey>,
+    /// Compares tree heads of the transparency log with other participants
+    #[serde(default)]
+    pub gossip: GossipConfig,
     pub state: ClientState,
 }
 
@@ -220,6 +673,16 @@ impl ClientConfig {
             client_endpoint,
             diagnosis_server_endpoint,
             params,
+            chunk_encoding: ChunkEncoding::default(),
+            verification_code: None,
+            relay: None,
+            forward_queue: ForwardQueueConfig::default(),
+            cover_traffic: CoverTrafficConfig::default(),
+            greylist_batching: GreylistBatchingConfig::default(),
+            revocation: None,
+            metrics_endpoint: None,
+            diagnosis_server_public_key: None,
+            gossip: GossipConfig::default(),
             state,
         }
     }
@@ -1,7 +1,9 @@
-use crate::primitives::{ComputationId, TemporaryExposureKey, Validity};
+use crate::primitives::{ComputationId, Region, TemporaryExposureKey, Validity};
 use crate::time::TimeInterval;
+use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ListType {
@@ -9,25 +11,118 @@ pub enum ListType {
     Greylist,
 }
 
+/// Lifecycle of a computation on the diagnosis server.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ComputationPhase {
+    /// Greylist uploads are accepted until the computation period ends.
+    Open,
+    /// The computation period ended but the chunk closing it is not yet done.
+    Closing,
+    /// A done chunk announced the closure to the clients.
+    Closed,
+}
+
+/// Chunk ids increase monotonically with each published chunk.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChunkId(u64);
+
+impl ChunkId {
+    pub fn next(&self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl From<u64> for ChunkId {
+    fn from(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+impl From<ChunkId> for u64 {
+    fn from(chunk_id: ChunkId) -> Self {
+        chunk_id.0
+    }
+}
+
+impl fmt::Debug for ChunkId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChunkId({})", self.0)
+    }
+}
+
+/// Entry of the chunk index which lists all available chunks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    pub id: ChunkId,
+    pub covers: TimeInterval,
+    /// Size of the Bincode encoded chunk in bytes
+    pub size: u64,
+    pub diagnosis_keys: usize,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Chunk {
+    id: ChunkId,
     covers: TimeInterval,
     data: HashMap<ComputationId, ComputationState>,
+    /// Computations closed with this chunk, i.e., neither this
+    /// nor any later chunk contains diagnosis keys of them
+    closed: HashSet<ComputationId>,
+    /// Computations whose blacklist upload was revoked, e.g., due to a false
+    /// positive test, hence clients retract everything derived from them
+    revoked: HashSet<ComputationId>,
 }
 
 impl Chunk {
-    pub fn new(covers: TimeInterval) -> Self {
+    pub fn new(id: ChunkId, covers: TimeInterval) -> Self {
         Self {
+            id,
             covers,
             data: HashMap::new(),
+            closed: HashSet::new(),
+            revoked: HashSet::new(),
         }
     }
-    pub fn next_chunk(&self) -> Self {
+    pub(crate) fn from_data(
+        id: ChunkId,
+        covers: TimeInterval,
+        data: HashMap<ComputationId, ComputationState>,
+        closed: HashSet<ComputationId>,
+        revoked: HashSet<ComputationId>,
+    ) -> Self {
         Self {
-            covers: self.covers.next_interval(),
-            data: HashMap::new(),
+            id,
+            covers,
+            data,
+            closed,
+            revoked,
         }
     }
+    /// Cuts the interval short, e.g., when the chunk is done ahead of time.
+    pub fn end_at(&mut self, to_excluding: DateTime<Utc>) {
+        self.covers = TimeInterval::with_bounds(*self.covers.from_including(), to_excluding);
+    }
+    pub fn next_chunk(&self) -> Self {
+        Self::new(self.id.next(), self.covers.next_interval())
+    }
+    pub fn id(&self) -> ChunkId {
+        self.id
+    }
+    pub fn info(&self) -> ChunkInfo {
+        ChunkInfo {
+            id: self.id,
+            covers: self.covers.clone(),
+            size: bincode::serialized_size(self).unwrap(),
+            diagnosis_keys: self.diagnosis_keys(),
+        }
+    }
+    /// Number of diagnosis keys of all computations and both lists.
+    pub fn diagnosis_keys(&self) -> usize {
+        self.data
+            .values()
+            .map(|computation| computation.blacklist().len() + computation.greylist().len())
+            .sum()
+    }
     pub fn insert(
         &mut self,
         list: ListType,
@@ -40,15 +135,52 @@ impl Chunk {
             .or_insert(ComputationState::new());
         computation.insert(list, data);
     }
+    pub fn close(&mut self, computation_id: ComputationId) {
+        self.closed.insert(computation_id);
+    }
+    pub fn revoke(&mut self, computation_id: ComputationId) {
+        self.revoked.insert(computation_id);
+    }
     pub fn covers(&self) -> &TimeInterval {
         &self.covers
     }
+    pub fn closed(&self) -> &HashSet<ComputationId> {
+        &self.closed
+    }
+    pub fn revoked(&self) -> &HashSet<ComputationId> {
+        &self.revoked
+    }
     pub fn data(&self) -> &HashMap<ComputationId, ComputationState> {
         &self.data
     }
+    /// Copy keeping only the computations of the given region.
+    pub fn restricted_to(&self, region: Region) -> Self {
+        let of_region = |computation_id: &&ComputationId| computation_id.region() == region;
+        Self {
+            id: self.id,
+            covers: self.covers.clone(),
+            data: self
+                .data
+                .iter()
+                .filter(|(computation_id, _)| of_region(computation_id))
+                .map(|(computation_id, computation)| (*computation_id, computation.clone()))
+                .collect(),
+            closed: self.closed.iter().filter(of_region).copied().collect(),
+            revoked: self.revoked.iter().filter(of_region).copied().collect(),
+        }
+    }
     pub fn to_data(self) -> HashMap<ComputationId, ComputationState> {
         self.data
     }
+    pub fn into_parts(
+        self,
+    ) -> (
+        HashMap<ComputationId, ComputationState>,
+        HashSet<ComputationId>,
+        HashSet<ComputationId>,
+    ) {
+        (self.data, self.closed, self.revoked)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -64,6 +196,15 @@ impl ComputationState {
             greylist: HashSet::new(),
         }
     }
+    pub(crate) fn from_lists(
+        blacklist: HashSet<Validity<TemporaryExposureKey>>,
+        greylist: HashSet<Validity<TemporaryExposureKey>>,
+    ) -> Self {
+        Self {
+            blacklist,
+            greylist,
+        }
+    }
     pub fn insert(&mut self, list: ListType, data: HashSet<&Validity<TemporaryExposureKey>>) -> () {
         match list {
             ListType::Blacklist => {
@@ -1,3 +1,9 @@
+use crate::primitives::{ComputationId, Region, RendezvousToken};
+use crate::rpcs::ProtocolVersion;
+use crate::time::ExposureTime;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -7,4 +13,143 @@ pub enum ExposurelibError {
 
     #[error("HKDF error")]
     KeyDerivationError,
+
+    #[error("Chunk decoding error: {0}")]
+    ChunkDecodingError(String),
+
+    #[error("Signature error: {0}")]
+    SignatureError(String),
+
+    #[error("Mailbox deposit could not be sealed or opened")]
+    SealingError,
+
+    #[error("Journal error: {0}")]
+    JournalError(String),
+
+    #[error("Metrics error: {0}")]
+    MetricsError(String),
+}
+
+/// Typed rejection of an RPC request which is sent back to the caller.
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestError {
+    #[error("Request contains no diagnosis keys")]
+    NoDiagnosisKeys,
+
+    #[error("Request contains {count} diagnosis keys exceeding the maximum of {max}")]
+    TooManyDiagnosisKeys { count: usize, max: usize },
+
+    #[error("Validity starting at {valid_from:?} is not aligned to the TEK rolling period")]
+    MisalignedValidity { valid_from: ExposureTime },
+
+    #[error("Validities from {earliest:?} to {latest:?} exceed the infection period")]
+    ValiditySpanTooLarge {
+        earliest: ExposureTime,
+        latest: ExposureTime,
+    },
+
+    #[error("Request contains no shared encounter times")]
+    NoEncounterTimes,
+
+    #[error("Request contains {count} encounter times exceeding the maximum of {max}")]
+    TooManyEncounterTimes { count: usize, max: usize },
+
+    #[error("Encounter time {at:?} is not within the validity starting at {valid_from:?}")]
+    EncounterTimeOutOfValidity {
+        at: ExposureTime,
+        valid_from: ExposureTime,
+    },
+
+    #[error("Rate limit exceeded, retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+
+    #[error("All computation ids are exhausted")]
+    ComputationIdsExhausted,
+
+    #[error("Blacklist uploads require a verification token")]
+    VerificationRequired,
+
+    #[error("Verification code is unknown or already redeemed")]
+    InvalidVerificationCode,
+
+    #[error("Verification token is forged or already redeemed")]
+    InvalidVerificationToken,
+
+    #[error("Verification token expired at {expired_at}")]
+    VerificationTokenExpired { expired_at: DateTime<Utc> },
+
+    #[error("Verification token was issued for other diagnosis keys")]
+    KeyCommitmentMismatch,
+
+    #[error("Computation with {computation_id:?} is unknown")]
+    UnknownComputation { computation_id: ComputationId },
+
+    #[error("Computation closed at {closed_at}")]
+    ComputationClosed { closed_at: DateTime<Utc> },
+
+    #[error("Greylist upload is not authorized by the computation's origin")]
+    InvalidGreylistAuthorization,
+
+    #[error("Computation with {computation_id:?} is revoked")]
+    ComputationRevoked { computation_id: ComputationId },
+
+    #[error("Revocation is not authorized by the computation's origin")]
+    InvalidRevocationAuthorization,
+
+    #[error("Verification token is not valid for this kind of request")]
+    VerificationTestTypeMismatch,
+
+    #[error("No consistency proof from tree size {old_size} to {new_size} with {tree_size} chunks logged")]
+    InvalidTreeSize {
+        old_size: u64,
+        new_size: u64,
+        tree_size: u64,
+    },
+
+    #[error("Chunk is invalid: {reason}")]
+    InvalidChunk { reason: String },
+
+    #[error("{region:?} is not a federation peer")]
+    UnknownRegion { region: Region },
+
+    #[error("Computation with {computation_id:?} does not belong to {region:?}")]
+    ForeignComputation {
+        computation_id: ComputationId,
+        region: Region,
+    },
+
+    #[error("Request fetches {count} mailboxes exceeding the maximum of {max}")]
+    TooManyMailboxes { count: usize, max: usize },
+
+    #[error("Mailbox secret is invalid")]
+    InvalidMailboxSecret,
+
+    #[error("Mailbox of {token:?} is full")]
+    MailboxFull { token: RendezvousToken },
+
+    #[error("Protocol version {version:?} is not within the supported {min_version:?} to {max_version:?}")]
+    UnsupportedProtocolVersion {
+        version: ProtocolVersion,
+        min_version: ProtocolVersion,
+        max_version: ProtocolVersion,
+    },
+
+    #[error("Version 1 origins cannot authorize greylist uploads of their computation")]
+    UnauthorizableLegacyUpload,
+
+    #[error("Diagnosis server failed to persist the request, retry later")]
+    StoreUnavailable,
+
+    #[error("Relay cannot accept forwards, retry later")]
+    RelayUnavailable,
+}
+
+impl RequestError {
+    /// Transient rejections are worth retrying the same request later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. } | Self::StoreUnavailable | Self::RelayUnavailable
+        )
+    }
 }
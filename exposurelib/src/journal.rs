@@ -0,0 +1,421 @@
+//! Append-only journal of the mutations of the diagnosis server state.
+//! Each line holds a record as JSON preceded by a SHA-256 checksum over the
+//! checksum of the previous line and the JSON, hence altering, dropping or
+//! reordering lines breaks the chain of all following lines.
+
+use crate::diagnosis_server_state::{Chunk, ChunkId, ListType};
+use crate::error::ExposurelibError;
+use crate::logger;
+use crate::primitives::{ComputationId, Region, TemporaryExposureKey, Validity};
+use crate::time::TimeInterval;
+use chrono::prelude::*;
+use ring::digest::{Context, SHA256, SHA256_OUTPUT_LEN};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+type Checksum = [u8; SHA256_OUTPUT_LEN];
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalRecord {
+    pub at: DateTime<Utc>,
+    pub entry: JournalEntry,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum JournalEntry {
+    /// The server (re)started with the given current chunk.
+    Started {
+        current_chunk: ChunkId,
+        covers: TimeInterval,
+    },
+    /// Keys added to a chunk, without the duplicates of earlier chunks.
+    /// Uploads the server creates itself have no peer.
+    Uploaded {
+        list: ListType,
+        peer: Option<IpAddr>,
+        computation_id: ComputationId,
+        chunk_id: ChunkId,
+        diagnosis_keys: HashSet<Validity<TemporaryExposureKey>>,
+    },
+    /// The current chunk is done, closes the given computations and is
+    /// followed by the next chunk.
+    Rotated {
+        done_chunk: ChunkId,
+        closed: HashSet<ComputationId>,
+        next_chunk: ChunkId,
+        covers: TimeInterval,
+    },
+    /// A chunk of a federation peer is merged into the given chunk, its keys
+    /// and revocations follow as entries without peer.
+    Imported {
+        region: Region,
+        peer_chunk: ChunkId,
+        chunk_id: ChunkId,
+    },
+    /// The origin of the computation revoked it, which is published with the
+    /// given chunk.
+    Revoked {
+        peer: Option<IpAddr>,
+        computation_id: ComputationId,
+        chunk_id: ChunkId,
+    },
+    /// A blacklist upload started the computation. Its greylist secret is
+    /// kept in the store only, as anyone reading it could greylist or revoke
+    /// the computation.
+    ComputationStarted {
+        computation_id: ComputationId,
+        started_at: DateTime<Utc>,
+    },
+}
+
+/// Appends records to the journal at `path`, continuing the checksum chain
+/// of the records written by previous runs.
+pub struct JournalWriter {
+    path: PathBuf,
+    file: File,
+    checksum: Checksum,
+}
+
+impl JournalWriter {
+    /// Verifies the existing journal and cuts off a torn last line, e.g.,
+    /// one left by a crash during a write.
+    pub fn open(path: &Path) -> Result<Self, ExposurelibError> {
+        let checksum = if path.exists() {
+            let journal = read(path)?;
+            let content = fs::read(path).map_err(|e| journal_error(path, e))?;
+            if journal.valid_length < content.len() {
+                logger::warn!(
+                    "Cutting off torn last line of journal {:?} at byte {}",
+                    path,
+                    journal.valid_length
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)
+                    .and_then(|file| file.set_len(journal.valid_length as u64))
+                    .map_err(|e| journal_error(path, e))?;
+            }
+            journal.checksum
+        } else {
+            [0; SHA256_OUTPUT_LEN]
+        };
+        // the journal reveals the uploaders and their uploads, hence it is
+        // created readable by its owner only
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options.open(path).map_err(|e| journal_error(path, e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            checksum,
+        })
+    }
+    /// Returns only after the record is on disk.
+    pub fn append(&mut self, entry: JournalEntry) -> Result<(), ExposurelibError> {
+        let record = JournalRecord {
+            at: Utc::now(),
+            entry,
+        };
+        let json = serde_json::to_string(&record).unwrap();
+        let checksum = chain(&self.checksum, &json);
+        writeln!(self.file, "{} {}", hex::encode(checksum), json)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| journal_error(&self.path, e))?;
+        self.checksum = checksum;
+        Ok(())
+    }
+    /// Syncs the file like `append()` does but without writing a record.
+    pub fn sync(&mut self) -> Result<(), ExposurelibError> {
+        self.file
+            .sync_data()
+            .map_err(|e| journal_error(&self.path, e))
+    }
+}
+
+/// Records of a journal whose checksum chain is intact.
+pub struct Journal {
+    pub records: Vec<JournalRecord>,
+    /// Checksum of the last record.
+    pub checksum: Checksum,
+    /// Length in bytes of the intact lines, i.e., without a torn last line.
+    pub valid_length: usize,
+}
+
+/// Reads and verifies the journal at `path`.
+/// A last line without line break is skipped as it was torn by a crash.
+pub fn read(path: &Path) -> Result<Journal, ExposurelibError> {
+    let content = fs::read_to_string(path).map_err(|e| journal_error(path, e))?;
+    let mut journal = Journal {
+        records: Vec::new(),
+        checksum: [0; SHA256_OUTPUT_LEN],
+        valid_length: 0,
+    };
+    for (number, line) in content.split_inclusive('\n').enumerate() {
+        let line_error = |reason: &str| {
+            ExposurelibError::JournalError(format!("{:?} line {}: {}", path, number + 1, reason))
+        };
+        let line = match line.strip_suffix('\n') {
+            Some(line) => line,
+            None => {
+                logger::warn!(
+                    "Skipping torn last line {} of journal {:?}",
+                    number + 1,
+                    path
+                );
+                break;
+            }
+        };
+        let (checksum, json) = line
+            .split_once(' ')
+            .ok_or_else(|| line_error("missing checksum"))?;
+        let expected = chain(&journal.checksum, json);
+        if hex::decode(checksum).ok().as_deref() != Some(&expected[..]) {
+            return Err(line_error("checksum mismatch"));
+        }
+        let record = serde_json::from_str(json)
+            .map_err(|e| line_error(&format!("invalid record: {}", e)))?;
+        journal.records.push(record);
+        journal.checksum = expected;
+        journal.valid_length += line.len() + 1;
+    }
+    Ok(journal)
+}
+
+/// State of the diagnosis server rebuilt from its journal.
+#[derive(Default)]
+pub struct Replay {
+    /// All chunks the journal saw, including the ones pruned since.
+    pub chunks: BTreeMap<ChunkId, Chunk>,
+    pub computations: BTreeMap<ComputationId, ReplayedComputation>,
+    /// Latest chunk imported from each federation peer.
+    pub imported: HashMap<Region, ChunkId>,
+}
+
+pub struct ReplayedComputation {
+    pub started_at: DateTime<Utc>,
+    pub closure_published: bool,
+    pub revoked: bool,
+}
+
+/// Rebuilds the chunks, computations and imports the journal saw.
+pub fn replay(records: &[JournalRecord]) -> Result<Replay, ExposurelibError> {
+    let mut replay = Replay::default();
+    let chunks = &mut replay.chunks;
+    let unknown_chunk = |chunk_id: ChunkId| {
+        ExposurelibError::JournalError(format!("Record refers to unknown {:?}", chunk_id))
+    };
+    for record in records {
+        match &record.entry {
+            JournalEntry::Started {
+                current_chunk,
+                covers,
+            } => {
+                chunks
+                    .entry(*current_chunk)
+                    .or_insert_with(|| Chunk::new(*current_chunk, covers.clone()));
+            }
+            JournalEntry::Uploaded {
+                list,
+                computation_id,
+                chunk_id,
+                diagnosis_keys,
+                ..
+            } => {
+                chunks
+                    .get_mut(chunk_id)
+                    .ok_or_else(|| unknown_chunk(*chunk_id))?
+                    .insert(*list, *computation_id, diagnosis_keys.iter().collect());
+            }
+            JournalEntry::Rotated {
+                done_chunk,
+                closed,
+                next_chunk,
+                covers,
+            } => {
+                let done_chunk = chunks
+                    .get_mut(done_chunk)
+                    .ok_or_else(|| unknown_chunk(*done_chunk))?;
+                for computation_id in closed {
+                    done_chunk.close(*computation_id);
+                    if let Some(computation) = replay.computations.get_mut(computation_id) {
+                        computation.closure_published = true;
+                    }
+                }
+                // a chunk rotated ahead of time ends where the next one starts
+                if covers.from_including() < done_chunk.covers().to_excluding() {
+                    done_chunk.end_at(*covers.from_including());
+                }
+                chunks.insert(*next_chunk, Chunk::new(*next_chunk, covers.clone()));
+            }
+            JournalEntry::Imported {
+                region, peer_chunk, ..
+            } => {
+                replay.imported.insert(*region, *peer_chunk);
+            }
+            JournalEntry::Revoked {
+                computation_id,
+                chunk_id,
+                ..
+            } => {
+                chunks
+                    .get_mut(chunk_id)
+                    .ok_or_else(|| unknown_chunk(*chunk_id))?
+                    .revoke(*computation_id);
+                if let Some(computation) = replay.computations.get_mut(computation_id) {
+                    computation.revoked = true;
+                }
+            }
+            JournalEntry::ComputationStarted {
+                computation_id,
+                started_at,
+            } => {
+                replay.computations.insert(
+                    *computation_id,
+                    ReplayedComputation {
+                        started_at: *started_at,
+                        closure_published: false,
+                        revoked: false,
+                    },
+                );
+            }
+        }
+    }
+    Ok(replay)
+}
+
+/// Keeps the network prefix only, i.e., a /24 of IPv4 and a /48 of IPv6
+/// addresses.
+pub fn anonymise(peer: IpAddr) -> IpAddr {
+    match peer {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut segments = ip.segments();
+            segments[3..].iter_mut().for_each(|segment| *segment = 0);
+            IpAddr::from(segments)
+        }
+    }
+}
+
+fn chain(previous: &Checksum, json: &str) -> Checksum {
+    let mut context = Context::new(&SHA256);
+    context.update(previous);
+    context.update(json.as_bytes());
+    let mut checksum = [0; SHA256_OUTPUT_LEN];
+    checksum.copy_from_slice(context.finish().as_ref());
+    checksum
+}
+
+fn journal_error(path: &Path, error: std::io::Error) -> ExposurelibError {
+    ExposurelibError::JournalError(format!("{:?}: {}", path, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::TekRollingPeriod;
+    use crate::time::ExposureTime;
+    use chrono::Duration;
+    use ring::rand::SystemRandom;
+
+    #[test]
+    fn test_journal() {
+        let path = std::env::temp_dir().join(format!("journal-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let covers = TimeInterval::with_alignment(Duration::minutes(1));
+        let mut diagnosis_keys = HashSet::new();
+        diagnosis_keys.insert(Validity::new(
+            ExposureTime::from(0),
+            TekRollingPeriod::default(),
+            TemporaryExposureKey::new(&SystemRandom::new()).unwrap(),
+        ));
+        let mut closed = HashSet::new();
+        closed.insert(ComputationId::from(3));
+        let mut writer = JournalWriter::open(&path).unwrap();
+        writer
+            .append(JournalEntry::Started {
+                current_chunk: ChunkId::from(0),
+                covers: covers.clone(),
+            })
+            .unwrap();
+        writer
+            .append(JournalEntry::ComputationStarted {
+                computation_id: ComputationId::from(3),
+                started_at: Utc::now(),
+            })
+            .unwrap();
+        writer
+            .append(JournalEntry::Uploaded {
+                list: ListType::Blacklist,
+                peer: Some(anonymise("10.1.2.3".parse().unwrap())),
+                computation_id: ComputationId::from(3),
+                chunk_id: ChunkId::from(0),
+                diagnosis_keys: diagnosis_keys.clone(),
+            })
+            .unwrap();
+        writer
+            .append(JournalEntry::Revoked {
+                peer: None,
+                computation_id: ComputationId::from(3),
+                chunk_id: ChunkId::from(0),
+            })
+            .unwrap();
+        drop(writer);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // a restart continues the chain and cuts off the torn line
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "deadbeef {{").unwrap();
+        let mut writer = JournalWriter::open(&path).unwrap();
+        writer
+            .append(JournalEntry::Rotated {
+                done_chunk: ChunkId::from(0),
+                closed,
+                next_chunk: ChunkId::from(1),
+                covers: covers.next_interval(),
+            })
+            .unwrap();
+
+        let journal = read(&path).unwrap();
+        assert_eq!(journal.records.len(), 5);
+        match &journal.records[2].entry {
+            JournalEntry::Uploaded { peer, .. } => {
+                assert_eq!(*peer, Some("10.1.2.0".parse().unwrap()))
+            }
+            entry => panic!("Unexpected entry {:?}", entry),
+        }
+        let replay = replay(&journal.records).unwrap();
+        let chunks = &replay.chunks;
+        assert_eq!(chunks.len(), 2);
+        let chunk = &chunks[&ChunkId::from(0)];
+        assert!(chunk.closed().contains(&ComputationId::from(3)));
+        assert!(chunk.revoked().contains(&ComputationId::from(3)));
+        assert_eq!(
+            chunk.data()[&ComputationId::from(3)].blacklist(),
+            &diagnosis_keys
+        );
+        let computation = &replay.computations[&ComputationId::from(3)];
+        assert!(computation.closure_published);
+        assert!(computation.revoked);
+
+        // altering a record breaks the chain
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replacen("\"Started\"", "\"Startet\"", 1)).unwrap();
+        assert!(read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
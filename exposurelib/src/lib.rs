@@ -1,9 +1,18 @@
 pub mod args;
-pub mod config;
-pub mod logger;
-pub mod error;
+pub mod chunk_encoding;
 pub mod client_state;
+pub mod config;
 pub mod diagnosis_server_state;
-pub mod rpcs;
+pub mod error;
+pub mod journal;
+pub mod logger;
+pub mod mailbox;
+pub mod metrics;
 pub mod primitives;
+pub mod rpcs;
+pub mod signing;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod time;
+pub mod transparency;
+pub mod verification;
@@ -0,0 +1,140 @@
+//! Store-and-forward of forwards via the diagnosis server for successors
+//! whose listener is offline: The sender derives the rendezvous token and
+//! the key of the mailbox from the seed in the metadata of the successor's
+//! broadcast it matched and deposits the sealed forward which the successor
+//! fetches during its next refresh. Unlike the TEK the seed is never
+//! uploaded, hence neither the diagnosis server nor anyone downloading
+//! chunks can open or fetch the deposits.
+
+use crate::error::ExposurelibError;
+use crate::primitives::{Key, MailboxKey, MailboxSecret, RendezvousToken, Seed};
+use crate::rpcs::ForwardParams;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::rand::SecureRandom;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Secret, rendezvous token and key of the mailbox of a seed's holder.
+#[derive(Clone, Debug)]
+pub struct MailboxAddress {
+    secret: MailboxSecret,
+    token: RendezvousToken,
+    key: MailboxKey,
+}
+
+impl MailboxAddress {
+    pub fn new(sd: &Seed) -> Result<Self, ExposurelibError> {
+        let secret = MailboxSecret::new(sd)?;
+        Ok(Self {
+            secret,
+            token: RendezvousToken::new(&secret)?,
+            key: MailboxKey::new(sd)?,
+        })
+    }
+    /// Authorizes fetching the deposits of the mailbox.
+    pub fn secret(&self) -> MailboxSecret {
+        self.secret
+    }
+    pub fn token(&self) -> RendezvousToken {
+        self.token
+    }
+    /// Encrypts the forward with the token as associated data.
+    pub fn seal(
+        &self,
+        params: &ForwardParams,
+        secure_random: &dyn SecureRandom,
+    ) -> Result<SealedForward, ExposurelibError> {
+        let mut nonce = [0; aead::NONCE_LEN];
+        secure_random
+            .fill(&mut nonce)
+            .map_err(|_| ExposurelibError::RandomKeyGenerationError)?;
+        let mut ciphertext = bincode::serialize(params).unwrap();
+        self.aead_key()?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(self.token.get()),
+                &mut ciphertext,
+            )
+            .map_err(|_| ExposurelibError::SealingError)?;
+        Ok(SealedForward { nonce, ciphertext })
+    }
+    pub fn open(&self, sealed: SealedForward) -> Result<ForwardParams, ExposurelibError> {
+        let mut ciphertext = sealed.ciphertext;
+        let plaintext = self
+            .aead_key()?
+            .open_in_place(
+                Nonce::assume_unique_for_key(sealed.nonce),
+                Aad::from(self.token.get()),
+                &mut ciphertext,
+            )
+            .map_err(|_| ExposurelibError::SealingError)?;
+        bincode::deserialize(plaintext).map_err(|_| ExposurelibError::SealingError)
+    }
+    fn aead_key(&self) -> Result<LessSafeKey, ExposurelibError> {
+        UnboundKey::new(&aead::CHACHA20_POLY1305, self.key.get())
+            .map(LessSafeKey::new)
+            .map_err(|_| ExposurelibError::SealingError)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedForward {
+    nonce: [u8; aead::NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+impl fmt::Debug for SealedForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SealedForward")
+            .field("len", &self.ciphertext.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{ComputationId, SystemRandom};
+    use crate::testing::forward_params;
+
+    #[test]
+    fn test_mailbox_round_trip() {
+        let secure_random = SystemRandom::new();
+        let successor_seed = Seed::new(&secure_random).unwrap();
+        let sender = MailboxAddress::new(&successor_seed).unwrap();
+        let successor = MailboxAddress::new(&successor_seed).unwrap();
+        assert_eq!(sender.token(), successor.token());
+        // the diagnosis server derives the token from the secret sent by the fetcher
+        assert_eq!(
+            RendezvousToken::new(&successor.secret()).unwrap(),
+            sender.token()
+        );
+
+        let params = forward_params(ComputationId::from(3));
+        let sealed = sender.seal(&params, &secure_random).unwrap();
+        let opened = successor.open(sealed).unwrap();
+        assert_eq!(opened.computation_id, params.computation_id);
+        assert_eq!(
+            opened.shared_encounter_times,
+            params.shared_encounter_times
+        );
+    }
+
+    #[test]
+    fn test_mailbox_wrong_key() {
+        let secure_random = SystemRandom::new();
+        let address = MailboxAddress::new(&Seed::new(&secure_random).unwrap()).unwrap();
+        let sealed = address
+            .seal(&forward_params(ComputationId::from(3)), &secure_random)
+            .unwrap();
+
+        let other_address = MailboxAddress::new(&Seed::new(&secure_random).unwrap()).unwrap();
+        assert_ne!(other_address.token(), address.token());
+        assert_ne!(other_address.secret(), address.secret());
+        assert!(other_address.open(sealed.clone()).is_err());
+
+        let mut tampered = sealed;
+        tampered.ciphertext[0] ^= 1;
+        assert!(address.open(tampered).is_err());
+    }
+}
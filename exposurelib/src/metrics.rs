@@ -0,0 +1,90 @@
+//! Exposes the metrics of a registry in the Prometheus text format at
+//! `GET /metrics` of an HTTP endpoint.
+
+use crate::error::ExposurelibError;
+use crate::logger;
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{Encoder, TextEncoder};
+pub use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+pub async fn serve(endpoint: SocketAddr, registry: Registry) -> Result<(), ExposurelibError> {
+    let make_service = make_service_fn(move |_connection| {
+        let registry = registry.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let registry = registry.clone();
+                async move { Ok::<_, Infallible>(respond(&registry, request)) }
+            }))
+        }
+    });
+    logger::info!("Metrics endpoint listening on {}", endpoint);
+    Server::try_bind(&endpoint)
+        .map_err(|e| ExposurelibError::MetricsError(format!("binding {}: {}", endpoint, e)))?
+        .serve(make_service)
+        .await
+        .map_err(|e| ExposurelibError::MetricsError(format!("serving {}: {}", endpoint, e)))
+}
+
+/// Registers the collector, which must not clash with one registered before.
+pub fn register<C: prometheus::core::Collector + Clone + 'static>(
+    registry: &Registry,
+    collector: C,
+) -> C {
+    registry
+        .register(Box::new(collector.clone()))
+        .expect("Registering a metric failed");
+    collector
+}
+
+fn respond(registry: &Registry, request: Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    if (request.method(), request.uri().path()) != (&Method::GET, "/metrics") {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&registry.gather(), &mut buffer) {
+        logger::error!("Error encoding metrics: {}", e);
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return response;
+    }
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(encoder.format_type()).unwrap(),
+    );
+    *response.body_mut() = Body::from(buffer);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_respond() {
+        let registry = Registry::new();
+        let uploads = register(
+            &registry,
+            IntCounterVec::new(Opts::new("uploads_total", "Uploads"), &["list"]).unwrap(),
+        );
+        uploads.with_label_values(&["blacklist"]).inc();
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = respond(&registry, request);
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = Request::get("/other").body(Body::empty()).unwrap();
+        assert_eq!(respond(&registry, request).status(), StatusCode::NOT_FOUND);
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains("uploads_total{list=\"blacklist\"} 1"));
+    }
+}
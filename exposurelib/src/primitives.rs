@@ -28,20 +28,70 @@ pub struct KeyUpload {
     // NOTE: omitting EPK in the prototype
 }
 
-#[derive(Serialize, Deserialize, Default, Copy, Clone, PartialEq, Eq, Hash)]
+/// Diagnosis servers of a federation assign computation ids each within
+/// their own region, hence the ids do not collide.
+#[derive(
+    Serialize, Deserialize, Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Region(u8);
+
+impl From<u8> for Region {
+    fn from(int: u8) -> Self {
+        Region(int)
+    }
+}
+
+impl From<Region> for u8 {
+    fn from(region: Region) -> Self {
+        region.0
+    }
+}
+
+/// The most significant byte holds the region of the diagnosis server which
+/// assigned the id, the remaining bits count within that region.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ComputationId {
     id: u32,
 }
 
+impl ComputationId {
+    const LOCAL_BITS: u32 = 24;
+
+    /// Returns `None` if `local` exceeds the ids available to a region.
+    pub fn new(region: Region, local: u32) -> Option<Self> {
+        if local >> Self::LOCAL_BITS != 0 {
+            return None;
+        }
+        Some(Self {
+            id: u32::from(region.0) << Self::LOCAL_BITS | local,
+        })
+    }
+    pub fn region(&self) -> Region {
+        Region((self.id >> Self::LOCAL_BITS) as u8)
+    }
+    pub fn local(&self) -> u32 {
+        self.id & ((1 << Self::LOCAL_BITS) - 1)
+    }
+}
+
 impl From<u32> for ComputationId {
     fn from(int: u32) -> Self {
         ComputationId { id: int }
     }
 }
 
+impl From<ComputationId> for u32 {
+    fn from(computation_id: ComputationId) -> Self {
+        computation_id.id
+    }
+}
+
 impl fmt::Debug for ComputationId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ComputationId({})", self.id)
+        match u8::from(self.region()) {
+            0 => write!(f, "ComputationId({})", self.id),
+            region => write!(f, "ComputationId({}@{})", self.local(), region),
+        }
     }
 }
 
@@ -58,6 +108,13 @@ impl<Keyring> Validity<Keyring> {
             keyring,
         }
     }
+    /// Unlike new() this does not align `valid_from` to a TEKRP multiple.
+    pub(crate) fn with_valid_from(valid_from: ExposureTime, keyring: Keyring) -> Self {
+        Self {
+            valid_from,
+            keyring,
+        }
+    }
     pub fn keyring(&self) -> &Keyring {
         &self.keyring
     }
@@ -174,6 +231,9 @@ pub struct SdKeyring {
 }
 
 impl SdKeyring {
+    pub fn sd(&self) -> &Seed {
+        &self.sd
+    }
     // pub fn epk(&self, tek: &TemporaryExposureKey, pk: PublicKey) -> EncryptedPublicKey {
     // }
 }
@@ -310,6 +370,12 @@ impl TemporaryExposureKey {
 
 impl RandomKey for TemporaryExposureKey {}
 
+impl From<[u8; TemporaryExposureKey::KEY_LEN]> for TemporaryExposureKey {
+    fn from(key: [u8; TemporaryExposureKey::KEY_LEN]) -> Self {
+        Self { key }
+    }
+}
+
 impl Key for TemporaryExposureKey {
     const KEY_LEN: usize = 16;
 
@@ -465,6 +531,101 @@ impl HKDFDerivedKey for PublicKeySymmetricKey {
     const INFO: &'static str = "EN-PKSK";
 }
 
+/// Proves to the diagnosis server that the fetcher of a mailbox holds the
+/// seed it is derived from, the server only learns it while fetching.
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxSecret {
+    key: [u8; Self::KEY_LEN],
+}
+
+impl MailboxSecret {
+    pub fn new(sd: &Seed) -> Result<Self, ExposurelibError> {
+        Self::derive(sd).map(|key| Self {
+            key: key.try_into().unwrap(),
+        })
+    }
+}
+
+impl Key for MailboxSecret {
+    const KEY_LEN: usize = 16;
+
+    fn get(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl HKDFDerivedKey for MailboxSecret {
+    const INFO: &'static str = "SSEV-MBS";
+}
+
+impl fmt::Debug for MailboxSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MailboxSecret(..)")
+    }
+}
+
+/// Addresses mailbox deposits to the holder of a seed.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RendezvousToken {
+    key: [u8; Self::KEY_LEN],
+}
+
+impl RendezvousToken {
+    pub fn new(secret: &MailboxSecret) -> Result<Self, ExposurelibError> {
+        Self::derive(secret).map(|key| Self {
+            key: key.try_into().unwrap(),
+        })
+    }
+}
+
+impl Key for RendezvousToken {
+    const KEY_LEN: usize = 16;
+
+    fn get(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl HKDFDerivedKey for RendezvousToken {
+    const INFO: &'static str = "SSEV-RVT";
+}
+
+impl fmt::Debug for RendezvousToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RVT(")?;
+        for byte in self.key.iter().take(3) {
+            write!(f, "{:02X} ", byte)?;
+        }
+        write!(f, "{:02X}..)", self.key[3])
+    }
+}
+
+/// Encrypts mailbox deposits to the holder of a seed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxKey {
+    key: [u8; Self::KEY_LEN],
+}
+
+impl MailboxKey {
+    pub fn new(sd: &Seed) -> Result<Self, ExposurelibError> {
+        Self::derive(sd).map(|key| Self {
+            key: key.try_into().unwrap(),
+        })
+    }
+}
+
+impl Key for MailboxKey {
+    const KEY_LEN: usize = 32;
+
+    fn get(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl HKDFDerivedKey for MailboxKey {
+    const INFO: &'static str = "SSEV-MBK";
+}
+
 struct Wrapper<T>(T);
 
 impl ring::hkdf::KeyType for Wrapper<usize> {
@@ -517,15 +678,21 @@ pub struct Metadata {
     intensity: Intensity,
     // actually seed instead of connection identifier
     connection_identifier: SocketAddr,
+    // only learned by observers of the broadcast as it is never uploaded
+    seed: Seed,
 }
 
 impl Metadata {
-    pub fn new(intensity: Intensity, connection_identifier: SocketAddr) -> Self {
+    pub fn new(intensity: Intensity, connection_identifier: SocketAddr, seed: Seed) -> Self {
         Self {
             intensity,
             connection_identifier,
+            seed,
         }
     }
+    pub fn seed(&self) -> Seed {
+        self.seed
+    }
     pub fn intensity(&self) -> Intensity {
         self.intensity
     }
@@ -562,6 +729,20 @@ impl AssociatedEncryptedMetadata {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_computation_id_region() {
+        let computation_id = ComputationId::new(Region::from(3), 42).unwrap();
+        assert_eq!(computation_id.region(), Region::from(3));
+        assert_eq!(computation_id.local(), 42);
+        assert_eq!(format!("{:?}", computation_id), "ComputationId(42@3)");
+        // ids of the default region stay as before
+        assert_eq!(
+            ComputationId::new(Region::default(), 42),
+            Some(ComputationId::from(42))
+        );
+        assert!(ComputationId::new(Region::from(3), 1 << 24).is_none());
+    }
+
     #[test]
     fn test_tek_rolling_period() {
         let tekrp = TekRollingPeriod::default();
@@ -1,36 +1,469 @@
-use crate::primitives::{ComputationId, TekRollingPeriod, TemporaryExposureKey, Validity};
+use crate::chunk_encoding::{ChunkEncoding, EncodedChunk};
+use crate::config::SystemParams;
+use crate::diagnosis_server_state::{ChunkId, ChunkInfo, ComputationPhase};
+use crate::error::{ExposurelibError, RequestError};
+use crate::mailbox::SealedForward;
+use crate::primitives::{
+    ComputationId, MailboxSecret, Region, RendezvousToken, TekRollingPeriod,
+    TemporaryExposureKey, Validity,
+};
+use crate::time::ExposureTime;
 use crate::time::ExposureTimeSet;
-use crate::{diagnosis_server_state::Chunk, time::ExposureTime};
+use crate::transparency::{ConsistencyProof, InclusionProof, SignedTreeHead};
+use crate::verification::{
+    GreylistAuthorization, GreylistSecret, KeyCommitment, VerificationCode, VerificationToken,
+};
 use chrono::prelude::*;
+use ring::rand::SecureRandom;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+
+pub mod v1;
+
+/// Version of the RPC protocol spoken by this build.
+///
+/// Requests are Bincode encoded enums whose variant is the index of the RPC
+/// in its service trait, hence new RPCs must be appended to the traits.
+/// Breaking changes of existing RPCs or their params bump the version and
+/// keep serving the previous version via the `v1`, ... modules.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion(2);
+/// Oldest protocol version which is still served.
+pub const MIN_PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion(1);
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion(u16);
+
+impl From<u16> for ProtocolVersion {
+    fn from(version: u16) -> Self {
+        Self(version)
+    }
+}
+
+/// Optional capabilities of a peer as bit flags.
+/// Unknown flags of newer peers are simply dropped while negotiating.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Features(u64);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Chunks are served in other than the plain Bincode encoding.
+    pub const CHUNK_ENCODINGS: Self = Self(1);
+    /// Downloads are answered in pages with a continuation token.
+    pub const PAGINATION: Self = Self(1 << 1);
+    /// The subscribe() RPC is available.
+    pub const SUBSCRIPTIONS: Self = Self(1 << 2);
+    /// The index() RPC is available.
+    pub const INDEX: Self = Self(1 << 3);
+    /// The deposit() and fetch() RPCs are available.
+    pub const MAILBOX: Self = Self(1 << 4);
+    /// Uploads with a cover token and greylist uploads for the computations
+    /// they started are answered but dropped.
+    pub const COVER_TRAFFIC: Self = Self(1 << 5);
+    /// The revoke() RPC is available and chunks carry revocations.
+    pub const REVOCATION: Self = Self(1 << 6);
+    /// Pages carry the tree head of the transparency log with inclusion
+    /// proofs and the tree_head() and consistency() RPCs are available.
+    pub const TRANSPARENCY: Self = Self(1 << 7);
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitAnd for Features {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl fmt::Debug for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Features({:#b})", self.0)
+    }
+}
+
+/// Exchanged by the handshake() RPC of all services.
+/// The caller sends its supported versions and features and gets back
+/// the negotiated ones.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: ProtocolVersion,
+    pub min_version: ProtocolVersion,
+    pub features: Features,
+}
+
+impl Handshake {
+    pub fn new(features: Features) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features,
+        }
+    }
+    /// Picks the newest version both sides support and the common features.
+    pub fn negotiate(&self, peer: &Handshake) -> Result<Handshake, RequestError> {
+        let version = self.version.min(peer.version);
+        if version < self.min_version || version < peer.min_version {
+            return Err(RequestError::UnsupportedProtocolVersion {
+                version: peer.version,
+                min_version: self.min_version,
+                max_version: self.version,
+            });
+        }
+        Ok(Handshake {
+            version,
+            min_version: version,
+            features: self.features & peer.features,
+        })
+    }
+}
 
 #[tarpc::service]
 pub trait DiagnosisServer {
-    async fn blacklist_upload(params: BlacklistUploadParams) -> ComputationId;
-    async fn greylist_upload(params: GreylistUploadParams) -> ();
-    async fn download(params: DownloadParams) -> Vec<Chunk>;
+    async fn blacklist_upload(
+        params: BlacklistUploadParams,
+    ) -> Result<BlacklistUploadResponse, RequestError>;
+    async fn greylist_upload(params: GreylistUploadParams) -> Result<(), RequestError>;
+    async fn download(params: DownloadParams) -> ChunkPage;
+    /// Like download() but waits until at least one newer chunk is published
+    /// or the refresh period elapsed, whichever comes first.
+    async fn subscribe(params: DownloadParams) -> ChunkPage;
+    async fn index() -> Vec<ChunkInfo>;
+    async fn handshake(params: Handshake) -> Result<Handshake, RequestError>;
+    /// Redeems a one-time verification code for a token authorizing a blacklist upload.
+    async fn verify(params: VerifyParams) -> Result<VerificationToken, RequestError>;
+    /// Deposits a sealed forward for a successor whose listener is offline.
+    async fn deposit(params: Deposit) -> Result<(), RequestError>;
+    /// Hands out and removes all deposits addressed to the tokens derived from
+    /// the secrets, i.e., only the holder of a mailbox's seed can fetch them.
+    async fn fetch(params: FetchParams) -> Result<Vec<Deposit>, RequestError>;
+    /// Revokes the blacklist upload of a computation, e.g., after a false
+    /// positive test, which is published with the next chunk.
+    async fn revoke(params: RevokeParams) -> Result<(), RequestError>;
+    /// Latest tree head of the transparency log over all published chunks,
+    /// rate limited like consistency() as both are answered to anyone.
+    async fn tree_head() -> Result<SignedTreeHead, RequestError>;
+    async fn consistency(params: ConsistencyParams) -> Result<ConsistencyProof, RequestError>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConsistencyParams {
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Deposit {
+    pub token: RendezvousToken,
+    pub sealed: SealedForward,
+}
+
+/// Fetches the mailboxes of all of a participant's TEKRPs at once. Separate
+/// requests would not keep the diagnosis server from linking them anyway,
+/// as they arrive from the same peer during the same refresh.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FetchParams {
+    pub secrets: Vec<MailboxSecret>,
+}
+
+impl FetchParams {
+    /// A participant holds one mailbox per TEKRP of the infection period.
+    pub fn validate(&self, params: &SystemParams) -> Result<(), RequestError> {
+        let count = self.secrets.len();
+        let max = params.max_diagnosis_keys();
+        if count > max {
+            return Err(RequestError::TooManyMailboxes { count, max });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyParams {
+    pub code: VerificationCode,
+    pub key_commitment: KeyCommitment,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BlacklistUploadParams {
     pub diagnosis_keys: HashSet<Validity<TemporaryExposureKey>>,
+    pub verification_token: Option<VerificationToken>,
+}
+
+impl BlacklistUploadParams {
+    pub fn validate(&self, params: &SystemParams) -> Result<(), RequestError> {
+        validate_diagnosis_keys(&self.diagnosis_keys, params)
+    }
+}
+
+/// The secret is only known to the origin and authorizes its greylist uploads.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlacklistUploadResponse {
+    pub computation_id: ComputationId,
+    pub greylist_secret: GreylistSecret,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GreylistUploadParams {
     pub computation_id: ComputationId,
     pub diagnosis_keys: HashSet<Validity<TemporaryExposureKey>>,
+    pub authorization: GreylistAuthorization,
+}
+
+impl GreylistUploadParams {
+    pub fn new(
+        computation_id: ComputationId,
+        diagnosis_keys: HashSet<Validity<TemporaryExposureKey>>,
+        greylist_secret: &GreylistSecret,
+    ) -> Self {
+        Self {
+            computation_id,
+            authorization: greylist_secret.authorize(computation_id, &diagnosis_keys),
+            diagnosis_keys,
+        }
+    }
+    pub fn validate(&self, params: &SystemParams) -> Result<(), RequestError> {
+        validate_diagnosis_keys(&self.diagnosis_keys, params)
+    }
+}
+
+/// Like GAEN's revised reports the health authority authorizes a revocation
+/// with a verification code of `TestType::Revoked` whose token commits to
+/// `KeyCommitment::revocation(computation_id)`. The authorization proves
+/// that the request comes from the computation's origin.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevokeParams {
+    pub computation_id: ComputationId,
+    pub verification_token: Option<VerificationToken>,
+    pub authorization: GreylistAuthorization,
+}
+
+impl RevokeParams {
+    pub fn new(
+        computation_id: ComputationId,
+        verification_token: Option<VerificationToken>,
+        greylist_secret: &GreylistSecret,
+    ) -> Self {
+        Self {
+            computation_id,
+            verification_token,
+            authorization: greylist_secret.authorize_revocation(computation_id),
+        }
+    }
+}
+
+/// Checks the diagnosis keys of a single upload, also applied to the lists
+/// imported from federation peers.
+pub fn validate_diagnosis_keys(
+    diagnosis_keys: &HashSet<Validity<TemporaryExposureKey>>,
+    params: &SystemParams,
+) -> Result<(), RequestError> {
+    let max = params.max_diagnosis_keys();
+    if diagnosis_keys.is_empty() {
+        return Err(RequestError::NoDiagnosisKeys);
+    }
+    if diagnosis_keys.len() > max {
+        return Err(RequestError::TooManyDiagnosisKeys {
+            count: diagnosis_keys.len(),
+            max,
+        });
+    }
+    let tekrp = params.tek_rolling_period;
+    for diagnosis_key in diagnosis_keys {
+        validate_alignment(diagnosis_key.valid_from(), tekrp)?;
+    }
+    let earliest = diagnosis_keys
+        .iter()
+        .map(|dk| dk.valid_from())
+        .min()
+        .unwrap();
+    let latest = diagnosis_keys
+        .iter()
+        .map(|dk| dk.valid_from())
+        .max()
+        .unwrap();
+    if u32::from(latest - earliest) >= u32::from(tekrp) * max as u32 {
+        return Err(RequestError::ValiditySpanTooLarge { earliest, latest });
+    }
+    Ok(())
+}
+
+/// Tops up the diagnosis keys with random decoys to the maximum count such
+/// that it does not tell for how long the uploader has been active.
+/// Decoys fill the free TEKRPs before the latest key.
+pub fn pad_diagnosis_keys(
+    diagnosis_keys: &mut HashSet<Validity<TemporaryExposureKey>>,
+    params: &SystemParams,
+    secure_random: &dyn SecureRandom,
+) -> Result<(), ExposurelibError> {
+    let tekrp = params.tek_rolling_period;
+    let latest = match diagnosis_keys.iter().map(|dk| dk.valid_from()).max() {
+        Some(latest) => u32::from(latest),
+        None => return Ok(()),
+    };
+    let taken: HashSet<ExposureTime> = diagnosis_keys.iter().map(|dk| dk.valid_from()).collect();
+    let slots = (0..params.max_diagnosis_keys() as u32)
+        .filter_map(|i| latest.checked_sub(i * u32::from(tekrp)))
+        .map(ExposureTime::from);
+    for valid_from in slots {
+        if diagnosis_keys.len() >= params.max_diagnosis_keys() {
+            break;
+        }
+        if !taken.contains(&valid_from) {
+            diagnosis_keys.insert(Validity::new(
+                valid_from,
+                tekrp,
+                TemporaryExposureKey::new(secure_random)?,
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_alignment(
+    valid_from: ExposureTime,
+    tekrp: TekRollingPeriod,
+) -> Result<(), RequestError> {
+    if valid_from.floor_tekrp_multiple(tekrp) != valid_from {
+        return Err(RequestError::MisalignedValidity { valid_from });
+    }
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DownloadParams {
-    pub from: DateTime<Utc>,
+    /// Only chunks with a greater id are downloaded, `None` downloads all chunks.
+    pub after: Option<ChunkId>,
+    /// How the server encodes the returned chunks.
+    pub encoding: ChunkEncoding,
+}
+
+/// Chunks after the requested id with the oldest first.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChunkPage {
+    pub chunks: Vec<EncodedChunk>,
+    /// Set if more chunks are available which are downloaded by passing
+    /// the token as `after` again, `None` once the page is the last one.
+    pub continuation: Option<ChunkId>,
+    /// Tree head the inclusion proofs refer to, set by diagnosis servers
+    /// supporting the transparency feature.
+    pub tree_head: Option<SignedTreeHead>,
+    /// Inclusion proof of each chunk in the same order.
+    pub inclusion_proofs: Vec<InclusionProof>,
 }
 
 #[tarpc::service]
 pub trait Forwarder {
-    async fn forward(params: ForwardParams) -> ();
+    /// Protocol version 1 forward() which cannot express rejections. It stays
+    /// first as participants reach each other on fixed endpoints and thus
+    /// listeners serve both versions.
+    async fn legacy_forward(params: v1::ForwardParams);
+    async fn handshake(params: Handshake) -> Result<Handshake, RequestError>;
+    async fn forward(params: ForwardParams) -> Result<(), RequestError>;
+}
+
+/// Mix node between participants which hides who forwards to whom.
+#[tarpc::service]
+pub trait Relay {
+    /// Accepts the forward for a delayed delivery in a shuffled batch.
+    async fn relay(params: RelayParams) -> Result<(), RequestError>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RelayParams {
+    /// Listener of the successor
+    pub destination: SocketAddr,
+    pub forward: ForwardParams,
+}
+
+/// Inspection and maintenance of a running diagnosis server. It is served on
+/// a separate endpoint which must only be reachable by operators.
+#[tarpc::service]
+pub trait Admin {
+    /// Done chunks with the oldest first followed by the current chunk.
+    async fn chunks() -> Vec<ChunkInfo>;
+    /// Retained computations with the oldest first.
+    async fn computations() -> Vec<ComputationInfo>;
+    async fn computation_keys(
+        computation_id: ComputationId,
+    ) -> Result<ComputationKeys, RequestError>;
+    /// Publishes the current chunk before its interval ends and returns its id.
+    async fn rotate() -> ChunkId;
+    async fn retention() -> RetentionStatus;
+    async fn tree_head() -> SignedTreeHead;
+    async fn consistency(params: ConsistencyParams) -> Result<ConsistencyProof, RequestError>;
+}
+
+/// Participants compare the tree heads of the transparency log they observed
+/// to detect a diagnosis server showing them different chunks.
+#[tarpc::service]
+pub trait Gossip {
+    /// Sends the latest tree head the caller observed and returns the callee's.
+    async fn exchange(tree_head: Option<SignedTreeHead>) -> Option<SignedTreeHead>;
+}
+
+/// Exchange of chunk data between the diagnosis servers of a federation.
+/// It is served on a separate endpoint which must only be reachable by peers.
+#[tarpc::service]
+pub trait Federation {
+    /// Done chunks after `after` restricted to the computations of the
+    /// serving region, i.e., imported data is never passed on.
+    async fn pull(params: PullParams) -> Result<ChunkPage, RequestError>;
+    /// Done chunks of the pushing region with the oldest first.
+    async fn push(params: PushParams) -> Result<(), RequestError>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PullParams {
+    pub region: Region,
+    pub after: Option<ChunkId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PushParams {
+    pub region: Region,
+    pub chunks: Vec<EncodedChunk>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ComputationInfo {
+    pub computation_id: ComputationId,
+    pub started_at: DateTime<Utc>,
+    pub phase: ComputationPhase,
+    /// Number of keys over all retained chunks
+    pub blacklisted: usize,
+    pub greylisted: usize,
+    pub revoked: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ComputationKeys {
+    pub computation_id: ComputationId,
+    pub blacklist: HashSet<Validity<TemporaryExposureKey>>,
+    pub greylist: HashSet<Validity<TemporaryExposureKey>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetentionStatus {
+    pub retention_period: std::time::Duration,
+    pub done_chunks: usize,
+    pub oldest_chunk: Option<ChunkInfo>,
+    /// When the oldest chunk exceeds the retention period and is pruned
+    /// with the next rotation
+    pub oldest_chunk_expires_at: Option<DateTime<Utc>>,
+    pub computations: usize,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -92,6 +525,30 @@ impl ForwardParams {
     pub fn shared_encounter_times(&self) -> &ExposureTimeSet {
         &self.shared_encounter_times
     }
+    pub fn validate(&self, params: &SystemParams) -> Result<(), RequestError> {
+        let tekrp = params.tek_rolling_period;
+        let valid_from = self.info.valid_from();
+        validate_alignment(valid_from, tekrp)?;
+        let count = self.shared_encounter_times.len();
+        if count == 0 {
+            return Err(RequestError::NoEncounterTimes);
+        }
+        if count > params.limits.max_encounter_times {
+            return Err(RequestError::TooManyEncounterTimes {
+                count,
+                max: params.limits.max_encounter_times,
+            });
+        }
+        for at in self.shared_encounter_times.iter() {
+            if at.floor_tekrp_multiple(tekrp) != valid_from {
+                return Err(RequestError::EncounterTimeOutOfValidity {
+                    at: *at,
+                    valid_from,
+                });
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -125,3 +582,164 @@ impl OriginInfo {
         Self { tek: origin_tek }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::SystemRandom;
+
+    fn diagnosis_key(en_interval_number: u32) -> Validity<TemporaryExposureKey> {
+        Validity::new(
+            ExposureTime::from(en_interval_number),
+            TekRollingPeriod::default(),
+            TemporaryExposureKey::new(&SystemRandom::new()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_diagnosis_key_padding() {
+        let params = SystemParams::default();
+        let tekrp = u32::from(params.tek_rolling_period);
+        let secure_random = SystemRandom::new();
+
+        let real: HashSet<_> = (20..23).map(|i| diagnosis_key(i * tekrp)).collect();
+        let mut padded = real.clone();
+        pad_diagnosis_keys(&mut padded, &params, &secure_random).unwrap();
+        assert_eq!(padded.len(), params.max_diagnosis_keys());
+        assert!(real.is_subset(&padded));
+        let upload = BlacklistUploadParams {
+            diagnosis_keys: padded,
+            verification_token: None,
+        };
+        assert_eq!(upload.validate(&params), Ok(()));
+
+        // decoys never precede the first TEKRP
+        let mut padded: HashSet<_> = (0..2).map(|i| diagnosis_key(i * tekrp)).collect();
+        pad_diagnosis_keys(&mut padded, &params, &secure_random).unwrap();
+        assert_eq!(padded.len(), 2);
+
+        let mut padded = HashSet::new();
+        pad_diagnosis_keys(&mut padded, &params, &secure_random).unwrap();
+        assert!(padded.is_empty());
+    }
+
+    #[test]
+    fn test_diagnosis_key_validation() {
+        let params = SystemParams::default();
+        let tekrp = u32::from(params.tek_rolling_period);
+
+        let upload = BlacklistUploadParams {
+            diagnosis_keys: HashSet::new(),
+            verification_token: None,
+        };
+        assert_eq!(upload.validate(&params), Err(RequestError::NoDiagnosisKeys));
+
+        let upload = BlacklistUploadParams {
+            diagnosis_keys: (0..14).map(|i| diagnosis_key(i * tekrp)).collect(),
+            verification_token: None,
+        };
+        assert_eq!(upload.validate(&params), Ok(()));
+
+        let upload = BlacklistUploadParams {
+            diagnosis_keys: (0..15).map(|i| diagnosis_key(i * tekrp)).collect(),
+            verification_token: None,
+        };
+        assert_eq!(
+            upload.validate(&params),
+            Err(RequestError::TooManyDiagnosisKeys { count: 15, max: 14 })
+        );
+
+        let upload = BlacklistUploadParams {
+            diagnosis_keys: vec![diagnosis_key(0), diagnosis_key(14 * tekrp)]
+                .into_iter()
+                .collect(),
+            verification_token: None,
+        };
+        assert_eq!(
+            upload.validate(&params),
+            Err(RequestError::ValiditySpanTooLarge {
+                earliest: ExposureTime::from(0),
+                latest: ExposureTime::from(14 * tekrp),
+            })
+        );
+    }
+
+    #[test]
+    fn test_handshake_negotiation() {
+        let server = Handshake::new(Features::PAGINATION | Features::SUBSCRIPTIONS);
+        let client = Handshake::new(Features::PAGINATION | Features(1 << 42));
+        let negotiated = server.negotiate(&client).unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.features, Features::PAGINATION);
+
+        let newer_client = Handshake {
+            version: ProtocolVersion(PROTOCOL_VERSION.0 + 1),
+            min_version: PROTOCOL_VERSION,
+            features: Features::NONE,
+        };
+        assert_eq!(
+            server.negotiate(&newer_client).unwrap().version,
+            PROTOCOL_VERSION
+        );
+
+        let future_client = Handshake {
+            version: ProtocolVersion(PROTOCOL_VERSION.0 + 2),
+            min_version: ProtocolVersion(PROTOCOL_VERSION.0 + 1),
+            features: Features::NONE,
+        };
+        assert!(server.negotiate(&future_client).is_err());
+
+        let ancient_client = Handshake {
+            version: ProtocolVersion(0),
+            min_version: ProtocolVersion(0),
+            features: Features::NONE,
+        };
+        assert!(server.negotiate(&ancient_client).is_err());
+    }
+
+    #[test]
+    fn test_forward_params_validation() {
+        let params = SystemParams::default();
+        let tekrp = params.tek_rolling_period;
+        let valid_from = ExposureTime::from(u32::from(tekrp));
+        let tek = TemporaryExposureKey::new(&SystemRandom::new()).unwrap();
+
+        let forward = ForwardParams::new(
+            ComputationId::from(0),
+            valid_from,
+            tekrp,
+            tek,
+            ExposureTimeSet::new(),
+        );
+        assert_eq!(
+            forward.validate(&params),
+            Err(RequestError::NoEncounterTimes)
+        );
+
+        let within = valid_from + ExposureTime::from(10);
+        let forward = ForwardParams::new(
+            ComputationId::from(0),
+            valid_from,
+            tekrp,
+            tek,
+            vec![within].into_iter().collect(),
+        );
+        assert_eq!(forward.validate(&params), Ok(()));
+
+        let outside = valid_from + tekrp;
+        let forward = ForwardParams::new(
+            ComputationId::from(0),
+            valid_from,
+            tekrp,
+            tek,
+            vec![within, outside].into_iter().collect(),
+        );
+        assert_eq!(
+            forward.validate(&params),
+            Err(RequestError::EncounterTimeOutOfValidity {
+                at: outside,
+                valid_from,
+            })
+        );
+    }
+}
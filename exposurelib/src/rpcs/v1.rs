@@ -0,0 +1,226 @@
+//! Frozen wire format of protocol version 1 which predates the handshake.
+//!
+//! Its types must never change, hence they are copies of the current types
+//! as of version 1 rather than the current types themselves, and conversions
+//! to and from the current types live next to them instead. Only the
+//! primitive newtypes for ids, times and keys are shared.
+
+use super::{BlacklistUploadParams, GreylistUploadParams};
+use crate::diagnosis_server_state;
+use crate::primitives::{self, ComputationId, TemporaryExposureKey};
+use crate::time::{self, ExposureTime, ExposureTimeSet};
+use crate::verification::GreylistAuthorization;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Served at the diagnosis server's `legacy_endpoint` only, since version 1
+/// clients do not know the handshake and thus cannot be told apart from
+/// current ones on the main endpoint. Rejected requests close the connection,
+/// as version 1 cannot express rejections.
+#[tarpc::service]
+pub trait DiagnosisServer {
+    async fn blacklist_upload(params: BlacklistUpload) -> ComputationId;
+    async fn greylist_upload(params: GreylistUpload);
+    async fn download(params: DownloadParams) -> Vec<Chunk>;
+}
+
+/// Served by current listeners via `Forwarder::legacy_forward()`.
+#[tarpc::service]
+pub trait Forwarder {
+    async fn forward(params: ForwardParams);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DiagnosisKey {
+    valid_from: ExposureTime,
+    tek: TemporaryExposureKey,
+}
+
+impl From<DiagnosisKey> for primitives::Validity<TemporaryExposureKey> {
+    fn from(key: DiagnosisKey) -> Self {
+        Self::with_valid_from(key.valid_from, key.tek)
+    }
+}
+
+impl From<&primitives::Validity<TemporaryExposureKey>> for DiagnosisKey {
+    fn from(key: &primitives::Validity<TemporaryExposureKey>) -> Self {
+        Self {
+            valid_from: key.valid_from(),
+            tek: *key.keyring(),
+        }
+    }
+}
+
+fn diagnosis_keys(
+    keys: HashSet<DiagnosisKey>,
+) -> HashSet<primitives::Validity<TemporaryExposureKey>> {
+    keys.into_iter().map(primitives::Validity::from).collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlacklistUpload {
+    pub diagnosis_keys: HashSet<DiagnosisKey>,
+}
+
+impl From<BlacklistUpload> for BlacklistUploadParams {
+    fn from(params: BlacklistUpload) -> Self {
+        Self {
+            diagnosis_keys: diagnosis_keys(params.diagnosis_keys),
+            // version 1 predates verification, hence such uploads are only
+            // accepted if the diagnosis server does not require verification
+            verification_token: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GreylistUpload {
+    pub computation_id: ComputationId,
+    pub diagnosis_keys: HashSet<DiagnosisKey>,
+}
+
+impl From<GreylistUpload> for GreylistUploadParams {
+    fn from(params: GreylistUpload) -> Self {
+        Self {
+            computation_id: params.computation_id,
+            diagnosis_keys: diagnosis_keys(params.diagnosis_keys),
+            // version 1 origins never learn a greylist secret, the diagnosis
+            // server may authorize the upload on their behalf
+            authorization: GreylistAuthorization::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadParams {
+    /// Chunks covering this point in time and all newer ones are downloaded.
+    pub from: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeInterval {
+    from_including: DateTime<Utc>,
+    to_excluding: DateTime<Utc>,
+}
+
+impl From<&time::TimeInterval> for TimeInterval {
+    fn from(interval: &time::TimeInterval) -> Self {
+        Self {
+            from_including: *interval.from_including(),
+            to_excluding: *interval.to_excluding(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ComputationState {
+    blacklist: HashSet<DiagnosisKey>,
+    greylist: HashSet<DiagnosisKey>,
+}
+
+impl From<&diagnosis_server_state::ComputationState> for ComputationState {
+    fn from(state: &diagnosis_server_state::ComputationState) -> Self {
+        Self {
+            blacklist: state.blacklist().iter().map(DiagnosisKey::from).collect(),
+            greylist: state.greylist().iter().map(DiagnosisKey::from).collect(),
+        }
+    }
+}
+
+/// A chunk without id, newest chunks are sent first.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chunk {
+    covers: TimeInterval,
+    data: HashMap<ComputationId, ComputationState>,
+}
+
+impl From<&diagnosis_server_state::Chunk> for Chunk {
+    fn from(chunk: &diagnosis_server_state::Chunk) -> Self {
+        Self {
+            covers: TimeInterval::from(chunk.covers()),
+            data: chunk
+                .data()
+                .iter()
+                .map(|(computation_id, state)| (*computation_id, ComputationState::from(state)))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ForwardParams {
+    computation_id: ComputationId,
+    valid_from: ExposureTime,
+    predecessor_tek: TemporaryExposureKey,
+    origin_tek: TemporaryExposureKey,
+    shared_encounter_times: ExposureTimeSet,
+}
+
+impl From<super::ForwardParams> for ForwardParams {
+    fn from(params: super::ForwardParams) -> Self {
+        Self {
+            computation_id: params.computation_id,
+            valid_from: params.info.valid_from(),
+            predecessor_tek: params.info.keyring().predecessor.tek,
+            origin_tek: params.info.keyring().origin.tek,
+            shared_encounter_times: params.shared_encounter_times,
+        }
+    }
+}
+
+impl From<ForwardParams> for super::ForwardParams {
+    fn from(params: ForwardParams) -> Self {
+        Self {
+            computation_id: params.computation_id,
+            info: primitives::Validity::with_valid_from(
+                params.valid_from,
+                super::ForwardInfo {
+                    predecessor: super::PredecessorInfo {
+                        tek: params.predecessor_tek,
+                    },
+                    origin: super::OriginInfo {
+                        tek: params.origin_tek,
+                    },
+                },
+            ),
+            shared_encounter_times: params.shared_encounter_times,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{SystemRandom, TekRollingPeriod};
+
+    /// The copies serialize like the current types did in version 1.
+    #[test]
+    fn test_wire_format() {
+        let tek = TemporaryExposureKey::new(&SystemRandom::new()).unwrap();
+        let params = super::super::ForwardParams::new(
+            ComputationId::from(7),
+            ExposureTime::from(144),
+            TekRollingPeriod::default(),
+            tek,
+            vec![ExposureTime::from(150)].into_iter().collect(),
+        );
+        let legacy = ForwardParams::from(params.clone());
+        assert_eq!(
+            bincode::serialize(&params).unwrap(),
+            bincode::serialize(&legacy).unwrap()
+        );
+        let restored = super::super::ForwardParams::from(legacy);
+        assert_eq!(
+            bincode::serialize(&params).unwrap(),
+            bincode::serialize(&restored).unwrap()
+        );
+
+        let key =
+            primitives::Validity::new(ExposureTime::from(144), TekRollingPeriod::default(), tek);
+        assert_eq!(
+            bincode::serialize(&key).unwrap(),
+            bincode::serialize(&DiagnosisKey::from(&key)).unwrap()
+        );
+    }
+}
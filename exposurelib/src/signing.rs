@@ -0,0 +1,150 @@
+//! The diagnosis server signs each chunk it hands out with Ed25519 and
+//! clients verify the signature against the server's public key pinned in
+//! their config, hence they do not have to trust the transport.
+
+use crate::error::ExposurelibError;
+use ring::rand::SecureRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// PKCS#8 document of an Ed25519 key pair, hex encoded in configs.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SigningKey(Vec<u8>);
+
+impl SigningKey {
+    pub fn generate(secure_random: &dyn SecureRandom) -> Result<Self, ExposurelibError> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(secure_random)
+            .map_err(|_| ExposurelibError::RandomKeyGenerationError)?;
+        Ok(Self(pkcs8.as_ref().to_vec()))
+    }
+    pub fn key_pair(&self) -> Result<Ed25519KeyPair, ExposurelibError> {
+        Ed25519KeyPair::from_pkcs8(&self.0)
+            .map_err(|e| ExposurelibError::SignatureError(format!("Invalid signing key: {}", e)))
+    }
+    pub fn public_key(&self) -> Result<PublicKey, ExposurelibError> {
+        PublicKey::try_from(self.key_pair()?.public_key().as_ref())
+    }
+}
+
+impl TryFrom<String> for SigningKey {
+    type Error = String;
+
+    fn try_from(hex: String) -> Result<Self, Self::Error> {
+        let signing_key = Self(hex::decode(&hex).map_err(|e| e.to_string())?);
+        signing_key.key_pair().map_err(|e| e.to_string())?;
+        Ok(signing_key)
+    }
+}
+
+impl From<SigningKey> for String {
+    fn from(signing_key: SigningKey) -> Self {
+        hex::encode(signing_key.0)
+    }
+}
+
+// keeps the private key out of logs
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SigningKey(..)")
+    }
+}
+
+/// Ed25519 public key, hex encoded in configs.
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> Result<(), ExposurelibError> {
+        UnparsedPublicKey::new(&signature::ED25519, &self.0)
+            .verify(message, &signature.0)
+            .map_err(|_| ExposurelibError::SignatureError(String::from("Signature mismatch")))
+    }
+}
+
+impl TryFrom<&[u8]> for PublicKey {
+    type Error = ExposurelibError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut public_key = [0; 32];
+        if bytes.len() != public_key.len() {
+            return Err(ExposurelibError::SignatureError(format!(
+                "Public key has {} bytes instead of {}",
+                bytes.len(),
+                public_key.len()
+            )));
+        }
+        public_key.copy_from_slice(bytes);
+        Ok(Self(public_key))
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = String;
+
+    fn try_from(hex: String) -> Result<Self, Self::Error> {
+        let bytes = hex::decode(&hex).map_err(|e| e.to_string())?;
+        Self::try_from(&bytes[..]).map_err(|e| e.to_string())
+    }
+}
+
+impl From<PublicKey> for String {
+    fn from(public_key: PublicKey) -> Self {
+        hex::encode(public_key.0)
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", hex::encode(self.0))
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature(Vec<u8>);
+
+impl Signature {
+    pub fn sign(key_pair: &Ed25519KeyPair, message: &[u8]) -> Self {
+        Self(key_pair.sign(message).as_ref().to_vec())
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Signature(")?;
+        for byte in self.0.iter().take(4) {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signing_key = SigningKey::generate(&SystemRandom::new()).unwrap();
+        // keys survive the round trip through a config
+        let signing_key: SigningKey =
+            serde_json::from_str(&serde_json::to_string(&signing_key).unwrap()).unwrap();
+        let public_key: PublicKey = serde_json::from_str(
+            &serde_json::to_string(&signing_key.public_key().unwrap()).unwrap(),
+        )
+        .unwrap();
+        let signature = Signature::sign(&signing_key.key_pair().unwrap(), b"chunk");
+        assert!(public_key.verify(b"chunk", &signature).is_ok());
+        assert!(public_key.verify(b"other chunk", &signature).is_err());
+        let other_key = SigningKey::generate(&SystemRandom::new()).unwrap();
+        assert!(other_key
+            .public_key()
+            .unwrap()
+            .verify(b"chunk", &signature)
+            .is_err());
+    }
+}
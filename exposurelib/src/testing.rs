@@ -0,0 +1,20 @@
+//! Fixtures shared by the tests of all crates, which enable them via the
+//! `testing` feature.
+
+use crate::primitives::{ComputationId, SystemRandom, TekRollingPeriod, TemporaryExposureKey};
+use crate::rpcs::ForwardParams;
+use crate::time::{ExposureTime, ExposureTimeSet};
+
+/// Forward of a fresh TEK which shares a single encounter at its start.
+pub fn forward_params(computation_id: ComputationId) -> ForwardParams {
+    let valid_from = ExposureTime::from(2_700_000);
+    let mut shared_encounter_times = ExposureTimeSet::new();
+    shared_encounter_times.insert(valid_from);
+    ForwardParams::new(
+        computation_id,
+        valid_from,
+        TekRollingPeriod::default(),
+        TemporaryExposureKey::new(&SystemRandom::new()).unwrap(),
+        shared_encounter_times,
+    )
+}
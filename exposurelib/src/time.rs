@@ -1,6 +1,7 @@
 use crate::primitives::TekRollingPeriod;
 use chrono::prelude::*;
 use chrono::Duration;
+use ring::rand::SecureRandom;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
 use std::fmt;
@@ -175,6 +176,21 @@ impl TimeInterval {
     }
 }
 
+/// Exponentially distributed pause with the given mean between two events of
+/// a Poisson process.
+pub fn poisson_interval(
+    mean: std::time::Duration,
+    secure_random: &dyn SecureRandom,
+) -> std::time::Duration {
+    let mut random = [0u8; 8];
+    secure_random
+        .fill(&mut random)
+        .expect("Generating randomness failed");
+    // uniformly distributed in [0, 1)
+    let uniform = (u64::from_le_bytes(random) >> 11) as f64 / (1u64 << 53) as f64;
+    mean.mul_f64(-(1.0 - uniform).ln())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
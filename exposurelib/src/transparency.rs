@@ -0,0 +1,462 @@
+//! Transparency log over the published chunks modelled on Certificate
+//! Transparency (RFC 6962): The diagnosis server appends each done chunk to
+//! a Merkle tree and signs its tree heads. Inclusion proofs show that a
+//! downloaded chunk is part of the tree and consistency proofs that a tree
+//! only grew by appending, hence a server showing different participants
+//! different chunks is caught once they compare their tree heads.
+
+use crate::diagnosis_server_state::Chunk;
+use crate::error::ExposurelibError;
+use crate::primitives::Key;
+use crate::signing::{PublicKey, Signature};
+use chrono::prelude::*;
+use ring::digest::{self, SHA256, SHA256_OUTPUT_LEN};
+use ring::signature::Ed25519KeyPair;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// SHA-256 hash of a leaf or an inner node, hex encoded in yaml.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TreeHash([u8; SHA256_OUTPUT_LEN]);
+
+impl TreeHash {
+    /// Domain separation prevents passing off an inner node as a leaf.
+    const LEAF_PREFIX: u8 = 0;
+    const NODE_PREFIX: u8 = 1;
+
+    fn digest(parts: &[&[u8]]) -> Self {
+        let mut context = digest::Context::new(&SHA256);
+        for part in parts {
+            context.update(part);
+        }
+        let mut hash = [0; SHA256_OUTPUT_LEN];
+        hash.copy_from_slice(context.finish().as_ref());
+        Self(hash)
+    }
+    fn empty() -> Self {
+        Self::digest(&[])
+    }
+    fn node(left: &Self, right: &Self) -> Self {
+        Self::digest(&[&[Self::NODE_PREFIX], &left.0, &right.0])
+    }
+    /// Hashes the content of the chunk in a canonical order, hence the hash
+    /// does not depend on how the chunk was encoded for the download.
+    pub fn leaf(chunk: &Chunk) -> Self {
+        let mut leaf = Vec::new();
+        leaf.extend_from_slice(&u64::from(chunk.id()).to_le_bytes());
+        for bound in [
+            chunk.covers().from_including(),
+            chunk.covers().to_excluding(),
+        ]
+        .iter()
+        {
+            leaf.extend_from_slice(&bound.timestamp().to_le_bytes());
+            leaf.extend_from_slice(&bound.timestamp_subsec_nanos().to_le_bytes());
+        }
+        let mut computations: Vec<_> = chunk.data().iter().collect();
+        computations.sort_by_key(|(computation_id, _)| **computation_id);
+        leaf.extend_from_slice(&(computations.len() as u64).to_le_bytes());
+        for (computation_id, computation) in computations {
+            leaf.extend_from_slice(&u32::from(*computation_id).to_le_bytes());
+            for list in [computation.blacklist(), computation.greylist()].iter() {
+                let mut keys: Vec<_> = list
+                    .iter()
+                    .map(|validity| (u32::from(validity.valid_from()), validity.keyring().get()))
+                    .collect();
+                keys.sort();
+                leaf.extend_from_slice(&(keys.len() as u64).to_le_bytes());
+                for (valid_from, key) in keys {
+                    leaf.extend_from_slice(&valid_from.to_le_bytes());
+                    leaf.extend_from_slice(key);
+                }
+            }
+        }
+        for computation_ids in [chunk.closed(), chunk.revoked()].iter() {
+            let mut computation_ids: Vec<u32> =
+                computation_ids.iter().copied().map(u32::from).collect();
+            computation_ids.sort_unstable();
+            leaf.extend_from_slice(&(computation_ids.len() as u64).to_le_bytes());
+            for computation_id in computation_ids {
+                leaf.extend_from_slice(&computation_id.to_le_bytes());
+            }
+        }
+        Self::digest(&[&[Self::LEAF_PREFIX], &leaf])
+    }
+}
+
+impl TryFrom<String> for TreeHash {
+    type Error = String;
+
+    fn try_from(hex: String) -> Result<Self, Self::Error> {
+        let bytes = hex::decode(&hex).map_err(|e| e.to_string())?;
+        let mut hash = [0; SHA256_OUTPUT_LEN];
+        if bytes.len() != hash.len() {
+            return Err(format!("Tree hash has {} bytes", bytes.len()));
+        }
+        hash.copy_from_slice(&bytes);
+        Ok(Self(hash))
+    }
+}
+
+impl From<TreeHash> for String {
+    fn from(hash: TreeHash) -> Self {
+        hex::encode(hash.0)
+    }
+}
+
+impl fmt::Debug for TreeHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TreeHash(")?;
+        for byte in &self.0[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "..)")
+    }
+}
+
+/// Root of the tree over the first `tree_size` chunks, signed by diagnosis
+/// servers with a signing key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignedTreeHead {
+    pub tree_size: u64,
+    pub root_hash: TreeHash,
+    pub timestamp: DateTime<Utc>,
+    pub signature: Option<Signature>,
+}
+
+impl SignedTreeHead {
+    pub fn new(tree_size: u64, root_hash: TreeHash, key_pair: Option<&Ed25519KeyPair>) -> Self {
+        let mut tree_head = Self {
+            tree_size,
+            root_hash,
+            timestamp: Utc::now(),
+            signature: None,
+        };
+        tree_head.signature =
+            key_pair.map(|key_pair| Signature::sign(key_pair, &tree_head.signed_message()));
+        tree_head
+    }
+    /// Fails for unsigned tree heads as well.
+    pub fn verify(&self, public_key: &PublicKey) -> Result<(), ExposurelibError> {
+        let signature = self.signature.as_ref().ok_or_else(|| {
+            ExposurelibError::SignatureError(String::from("Tree head is unsigned"))
+        })?;
+        public_key.verify(&self.signed_message(), signature)
+    }
+    fn signed_message(&self) -> Vec<u8> {
+        bincode::serialize(&(self.tree_size, self.root_hash, self.timestamp))
+            .expect("Serializing a tree head cannot fail")
+    }
+    /// Two heads of the same size with different roots prove a split view.
+    pub fn conflicts_with(&self, other: &Self) -> bool {
+        self.tree_size == other.tree_size && self.root_hash != other.root_hash
+    }
+}
+
+/// Audit path from a leaf to the root of the tree of the given size.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    pub path: Vec<TreeHash>,
+}
+
+impl InclusionProof {
+    pub fn verify(&self, leaf: &TreeHash, root_hash: &TreeHash) -> bool {
+        if self.leaf_index >= self.tree_size {
+            return false;
+        }
+        let (mut index, mut last) = (self.leaf_index, self.tree_size - 1);
+        let mut hash = *leaf;
+        for sibling in self.path.iter() {
+            if last == 0 {
+                return false;
+            }
+            if index % 2 == 1 || index == last {
+                hash = TreeHash::node(sibling, &hash);
+                while index % 2 == 0 && index != 0 {
+                    index >>= 1;
+                    last >>= 1;
+                }
+            } else {
+                hash = TreeHash::node(&hash, sibling);
+            }
+            index >>= 1;
+            last >>= 1;
+        }
+        last == 0 && hash == *root_hash
+    }
+}
+
+/// Proves that the tree of `new_size` contains the tree of `old_size` as
+/// its prefix.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConsistencyProof {
+    pub old_size: u64,
+    pub new_size: u64,
+    pub path: Vec<TreeHash>,
+}
+
+impl ConsistencyProof {
+    pub fn verify(&self, old_root: &TreeHash, new_root: &TreeHash) -> bool {
+        if self.old_size > self.new_size {
+            return false;
+        }
+        // the empty tree is a prefix of any tree
+        if self.old_size == 0 {
+            return self.path.is_empty();
+        }
+        if self.old_size == self.new_size {
+            return self.path.is_empty() && old_root == new_root;
+        }
+        let mut path = self.path.iter();
+        let (mut index, mut last) = (self.old_size - 1, self.new_size - 1);
+        // the old root is omitted from the path if it is a node of the new tree
+        let first = if self.old_size.is_power_of_two() {
+            old_root
+        } else {
+            match path.next() {
+                Some(first) => first,
+                None => return false,
+            }
+        };
+        while index % 2 == 1 {
+            index >>= 1;
+            last >>= 1;
+        }
+        let (mut old_hash, mut new_hash) = (*first, *first);
+        for sibling in path {
+            if last == 0 {
+                return false;
+            }
+            if index % 2 == 1 || index == last {
+                old_hash = TreeHash::node(sibling, &old_hash);
+                new_hash = TreeHash::node(sibling, &new_hash);
+                while index % 2 == 0 && index != 0 {
+                    index >>= 1;
+                    last >>= 1;
+                }
+            } else {
+                new_hash = TreeHash::node(&new_hash, sibling);
+            }
+            index >>= 1;
+            last >>= 1;
+        }
+        last == 0 && old_hash == *old_root && new_hash == *new_root
+    }
+}
+
+/// Append-only Merkle tree over the leaf hashes of all chunks published.
+/// The roots of all complete subtrees are kept, hence roots and proofs are
+/// composed of O(log n) of them instead of rehashing the leaves.
+#[derive(Debug, Default)]
+pub struct TransparencyLog {
+    // levels[h][i] is the root over the leaves [i * 2^h, (i + 1) * 2^h)
+    levels: Vec<Vec<TreeHash>>,
+}
+
+impl TransparencyLog {
+    pub fn with_leaves(leaves: Vec<TreeHash>) -> Self {
+        let mut log = Self::default();
+        for leaf in leaves {
+            log.append(leaf);
+        }
+        log
+    }
+    /// Returns the index of the appended leaf.
+    pub fn append(&mut self, leaf: TreeHash) -> u64 {
+        let mut hash = leaf;
+        for level in 0.. {
+            if self.levels.len() == level {
+                self.levels.push(Vec::new());
+            }
+            let nodes = &mut self.levels[level];
+            nodes.push(hash);
+            // an even count completes the subtree one level up
+            if nodes.len() % 2 == 1 {
+                break;
+            }
+            hash = TreeHash::node(&nodes[nodes.len() - 2], &nodes[nodes.len() - 1]);
+        }
+        self.size() - 1
+    }
+    pub fn size(&self) -> u64 {
+        self.leaves().len() as u64
+    }
+    pub fn root_hash(&self, tree_size: u64) -> Option<TreeHash> {
+        if tree_size > self.size() {
+            return None;
+        }
+        Some(self.root(0, tree_size as usize))
+    }
+    pub fn inclusion_proof(&self, leaf_index: u64, tree_size: u64) -> Option<InclusionProof> {
+        if leaf_index >= tree_size || tree_size > self.size() {
+            return None;
+        }
+        let mut path = Vec::new();
+        self.audit_path(leaf_index as usize, 0, tree_size as usize, &mut path);
+        Some(InclusionProof {
+            leaf_index,
+            tree_size,
+            path,
+        })
+    }
+    pub fn consistency_proof(&self, old_size: u64, new_size: u64) -> Option<ConsistencyProof> {
+        if old_size > new_size || new_size > self.size() {
+            return None;
+        }
+        let mut path = Vec::new();
+        if old_size > 0 {
+            self.subproof(old_size as usize, 0, new_size as usize, true, &mut path);
+        }
+        Some(ConsistencyProof {
+            old_size,
+            new_size,
+            path,
+        })
+    }
+    fn leaves(&self) -> &[TreeHash] {
+        self.levels.first().map_or(&[], Vec::as_slice)
+    }
+    /// Root over the leaves `[start, end)`. The recursion below only splits
+    /// off subtrees whose size is a power of two and divides their start,
+    /// which are exactly the kept ones.
+    fn root(&self, start: usize, end: usize) -> TreeHash {
+        match end - start {
+            0 => TreeHash::empty(),
+            n if n.is_power_of_two() && start.is_multiple_of(n) => {
+                self.levels[n.trailing_zeros() as usize][start / n]
+            }
+            n => {
+                let k = split(n);
+                TreeHash::node(&self.root(start, start + k), &self.root(start + k, end))
+            }
+        }
+    }
+    fn audit_path(&self, index: usize, start: usize, end: usize, path: &mut Vec<TreeHash>) {
+        if end - start <= 1 {
+            return;
+        }
+        let k = split(end - start);
+        if index < start + k {
+            self.audit_path(index, start, start + k, path);
+            path.push(self.root(start + k, end));
+        } else {
+            self.audit_path(index, start + k, end, path);
+            path.push(self.root(start, start + k));
+        }
+    }
+    fn subproof(
+        &self,
+        old_size: usize,
+        start: usize,
+        end: usize,
+        complete: bool,
+        path: &mut Vec<TreeHash>,
+    ) {
+        if old_size == end - start {
+            if !complete {
+                path.push(self.root(start, end));
+            }
+            return;
+        }
+        let k = split(end - start);
+        if old_size <= k {
+            self.subproof(old_size, start, start + k, complete, path);
+            path.push(self.root(start + k, end));
+        } else {
+            self.subproof(old_size - k, start + k, end, false, path);
+            path.push(self.root(start, start + k));
+        }
+    }
+}
+
+/// Largest power of two smaller than `n`, which must be greater than one.
+fn split(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(size: u64) -> TransparencyLog {
+        TransparencyLog::with_leaves(
+            (0..size)
+                .map(|leaf| TreeHash::digest(&[&leaf.to_le_bytes()]))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split(2), 1);
+        assert_eq!(split(3), 2);
+        assert_eq!(split(4), 2);
+        assert_eq!(split(5), 4);
+        assert_eq!(split(8), 4);
+        assert_eq!(split(9), 8);
+    }
+
+    #[test]
+    fn test_inclusion_proofs() {
+        let log = log(13);
+        for tree_size in 1..=log.size() {
+            let root_hash = log.root_hash(tree_size).unwrap();
+            for leaf_index in 0..tree_size {
+                let leaf = log.leaves()[leaf_index as usize];
+                let proof = log.inclusion_proof(leaf_index, tree_size).unwrap();
+                assert!(proof.verify(&leaf, &root_hash));
+                let other_leaf = log.leaves()[((leaf_index + 1) % log.size()) as usize];
+                assert!(!proof.verify(&other_leaf, &root_hash));
+            }
+        }
+        assert!(log.inclusion_proof(3, 3).is_none());
+    }
+
+    #[test]
+    fn test_consistency_proofs() {
+        let log = log(13);
+        for new_size in 0..=log.size() {
+            let new_root = log.root_hash(new_size).unwrap();
+            for old_size in 0..=new_size {
+                let old_root = log.root_hash(old_size).unwrap();
+                let proof = log.consistency_proof(old_size, new_size).unwrap();
+                assert!(proof.verify(&old_root, &new_root));
+                if 0 < old_size && old_size < new_size {
+                    // a rewritten history does not verify
+                    let forked = TreeHash::digest(&[b"fork"]);
+                    assert!(!proof.verify(&forked, &new_root));
+                    assert!(!proof.verify(&old_root, &forked));
+                }
+            }
+        }
+        assert!(log.consistency_proof(5, 4).is_none());
+    }
+
+    /// Root as defined by RFC 6962, recomputed from all leaves.
+    fn naive_root(leaves: &[TreeHash]) -> TreeHash {
+        match leaves.len() {
+            0 => TreeHash::empty(),
+            1 => leaves[0],
+            n => {
+                let k = split(n);
+                TreeHash::node(&naive_root(&leaves[..k]), &naive_root(&leaves[k..]))
+            }
+        }
+    }
+
+    #[test]
+    fn test_kept_subtrees() {
+        let log = log(37);
+        for tree_size in 0..=log.size() {
+            assert_eq!(
+                log.root_hash(tree_size).unwrap(),
+                naive_root(&log.leaves()[..tree_size as usize])
+            );
+        }
+        assert!(log.root_hash(38).is_none());
+    }
+}
@@ -0,0 +1,328 @@
+//! Stand-in for a health authority's verification server modelled on GAEN:
+//! The health authority hands out a one-time verification code to each
+//! positively tested participant who redeems it for a signed verification
+//! token which in turn authorizes exactly one blacklist upload.
+
+use crate::error::ExposurelibError;
+use crate::primitives::{ComputationId, Key, TemporaryExposureKey, Validity};
+use chrono::prelude::*;
+use ring::rand::SecureRandom;
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct VerificationCode(String);
+
+impl VerificationCode {
+    const LEN: usize = 16;
+
+    pub fn new(secure_random: &dyn SecureRandom) -> Result<Self, ExposurelibError> {
+        let mut code = String::with_capacity(Self::LEN);
+        let mut byte = [0u8; 1];
+        while code.len() < Self::LEN {
+            secure_random
+                .fill(&mut byte)
+                .map_err(|_| ExposurelibError::RandomKeyGenerationError)?;
+            // rejecting bytes above the largest multiple of ten avoids a bias
+            if byte[0] < 250 {
+                code.push(char::from(b'0' + byte[0] % 10));
+            }
+        }
+        Ok(Self(code))
+    }
+}
+
+impl From<String> for VerificationCode {
+    fn from(code: String) -> Self {
+        Self(code)
+    }
+}
+
+impl fmt::Debug for VerificationCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VerificationCode({})", self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TestType {
+    Confirmed,
+    Likely,
+    /// Revision of an earlier positive test, authorizes revoking its upload
+    /// rather than uploading keys.
+    Revoked,
+}
+
+/// Binds a verification token to the diagnosis keys of the upload without
+/// revealing them while redeeming the verification code.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct KeyCommitment([u8; 32]);
+
+impl KeyCommitment {
+    pub fn new(diagnosis_keys: &HashSet<Validity<TemporaryExposureKey>>) -> Self {
+        let mut diagnosis_keys: Vec<_> = diagnosis_keys
+            .iter()
+            .map(|validity| (u32::from(validity.valid_from()), validity.keyring().get()))
+            .collect();
+        diagnosis_keys.sort();
+        let mut context = digest::Context::new(&digest::SHA256);
+        for (valid_from, key) in diagnosis_keys {
+            context.update(&valid_from.to_le_bytes());
+            context.update(key);
+        }
+        let mut commitment = [0; 32];
+        commitment.copy_from_slice(context.finish().as_ref());
+        Self(commitment)
+    }
+    /// Binds a verification token to the revocation of a computation.
+    pub fn revocation(computation_id: ComputationId) -> Self {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(b"revoke");
+        context.update(&u32::from(computation_id).to_le_bytes());
+        let mut commitment = [0; 32];
+        commitment.copy_from_slice(context.finish().as_ref());
+        Self(commitment)
+    }
+}
+
+impl fmt::Debug for KeyCommitment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyCommitment(")?;
+        for byte in &self.0[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "..)")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VerificationToken {
+    test_type: TestType,
+    key_commitment: KeyCommitment,
+    expires_at: DateTime<Utc>,
+    nonce: [u8; 16],
+    mac: Vec<u8>,
+}
+
+impl VerificationToken {
+    pub fn issue(
+        key: &hmac::Key,
+        test_type: TestType,
+        key_commitment: KeyCommitment,
+        expires_at: DateTime<Utc>,
+        secure_random: &dyn SecureRandom,
+    ) -> Result<Self, ExposurelibError> {
+        let mut nonce = [0; 16];
+        secure_random
+            .fill(&mut nonce)
+            .map_err(|_| ExposurelibError::RandomKeyGenerationError)?;
+        let mut token = Self {
+            test_type,
+            key_commitment,
+            expires_at,
+            nonce,
+            mac: Vec::new(),
+        };
+        token.mac = hmac::sign(key, &token.signed_bytes()).as_ref().to_vec();
+        Ok(token)
+    }
+    /// Checks that the token was issued with the given key and is unaltered.
+    pub fn verify(&self, key: &hmac::Key) -> bool {
+        hmac::verify(key, &self.signed_bytes(), &self.mac).is_ok()
+    }
+    pub fn test_type(&self) -> TestType {
+        self.test_type
+    }
+    pub fn key_commitment(&self) -> KeyCommitment {
+        self.key_commitment
+    }
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+    /// Unique per token and thus identifies redeemed tokens.
+    pub fn nonce(&self) -> [u8; 16] {
+        self.nonce
+    }
+    fn signed_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&(
+            self.test_type,
+            self.key_commitment,
+            self.expires_at.timestamp(),
+            self.expires_at.timestamp_subsec_nanos(),
+            self.nonce,
+        ))
+        .unwrap()
+    }
+}
+
+/// Shared by the diagnosis server and the origin of a computation
+/// to authorize the origin's greylist uploads.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GreylistSecret([u8; 32]);
+
+impl GreylistSecret {
+    pub fn new(secure_random: &dyn SecureRandom) -> Result<Self, ExposurelibError> {
+        let mut secret = [0; 32];
+        secure_random
+            .fill(&mut secret)
+            .map_err(|_| ExposurelibError::RandomKeyGenerationError)?;
+        Ok(Self(secret))
+    }
+    /// HMAC-SHA256 over the little endian computation id followed by the
+    /// key commitment of the uploaded diagnosis keys.
+    pub fn authorize(
+        &self,
+        computation_id: ComputationId,
+        diagnosis_keys: &HashSet<Validity<TemporaryExposureKey>>,
+    ) -> GreylistAuthorization {
+        let tag = hmac::sign(&self.key(), &Self::message(computation_id, diagnosis_keys));
+        GreylistAuthorization(tag.as_ref().to_vec())
+    }
+    pub fn verify(
+        &self,
+        computation_id: ComputationId,
+        diagnosis_keys: &HashSet<Validity<TemporaryExposureKey>>,
+        authorization: &GreylistAuthorization,
+    ) -> bool {
+        hmac::verify(
+            &self.key(),
+            &Self::message(computation_id, diagnosis_keys),
+            &authorization.0,
+        )
+        .is_ok()
+    }
+    /// HMAC-SHA256 over a revocation label followed by the little endian
+    /// computation id, hence it cannot be mistaken for a greylist upload.
+    pub fn authorize_revocation(&self, computation_id: ComputationId) -> GreylistAuthorization {
+        let tag = hmac::sign(&self.key(), &Self::revocation_message(computation_id));
+        GreylistAuthorization(tag.as_ref().to_vec())
+    }
+    pub fn verify_revocation(
+        &self,
+        computation_id: ComputationId,
+        authorization: &GreylistAuthorization,
+    ) -> bool {
+        hmac::verify(
+            &self.key(),
+            &Self::revocation_message(computation_id),
+            &authorization.0,
+        )
+        .is_ok()
+    }
+    fn key(&self) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, &self.0)
+    }
+    fn message(
+        computation_id: ComputationId,
+        diagnosis_keys: &HashSet<Validity<TemporaryExposureKey>>,
+    ) -> Vec<u8> {
+        let mut message = u32::from(computation_id).to_le_bytes().to_vec();
+        message.extend_from_slice(&KeyCommitment::new(diagnosis_keys).0);
+        message
+    }
+    fn revocation_message(computation_id: ComputationId) -> Vec<u8> {
+        let mut message = b"revoke".to_vec();
+        message.extend_from_slice(&u32::from(computation_id).to_le_bytes());
+        message
+    }
+}
+
+impl fmt::Debug for GreylistSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GreylistSecret(..)")
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct GreylistAuthorization(Vec<u8>);
+
+impl From<Vec<u8>> for GreylistAuthorization {
+    fn from(tag: Vec<u8>) -> Self {
+        Self(tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{SystemRandom, TekRollingPeriod};
+    use crate::time::ExposureTime;
+
+    #[test]
+    fn test_verification_token() {
+        let secure_random = SystemRandom::new();
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &secure_random).unwrap();
+        let diagnosis_keys: HashSet<_> = (0..3u32)
+            .map(|i| {
+                Validity::new(
+                    ExposureTime::from(i * 144),
+                    TekRollingPeriod::default(),
+                    TemporaryExposureKey::new(&secure_random).unwrap(),
+                )
+            })
+            .collect();
+        let key_commitment = KeyCommitment::new(&diagnosis_keys);
+        // the commitment does not depend on the iteration order
+        let mut reversed: Vec<_> = diagnosis_keys.iter().cloned().collect();
+        reversed.reverse();
+        let mut reinserted = HashSet::with_capacity(1024);
+        reinserted.extend(reversed);
+        assert_eq!(key_commitment, KeyCommitment::new(&reinserted));
+
+        let token = VerificationToken::issue(
+            &key,
+            TestType::Confirmed,
+            key_commitment,
+            Utc::now(),
+            &secure_random,
+        )
+        .unwrap();
+        assert!(token.verify(&key));
+
+        let other_key = hmac::Key::generate(hmac::HMAC_SHA256, &secure_random).unwrap();
+        assert!(!token.verify(&other_key));
+
+        let mut tampered = token.clone();
+        tampered.test_type = TestType::Likely;
+        assert!(!tampered.verify(&key));
+
+        let code = VerificationCode::new(&secure_random).unwrap();
+        assert_eq!(code.0.len(), VerificationCode::LEN);
+        assert!(code.0.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_greylist_authorization() {
+        let secure_random = SystemRandom::new();
+        let secret = GreylistSecret::new(&secure_random).unwrap();
+        let diagnosis_keys: HashSet<_> = vec![Validity::new(
+            ExposureTime::from(144),
+            TekRollingPeriod::default(),
+            TemporaryExposureKey::new(&secure_random).unwrap(),
+        )]
+        .into_iter()
+        .collect();
+        let computation_id = ComputationId::from(1);
+        let authorization = secret.authorize(computation_id, &diagnosis_keys);
+        assert!(secret.verify(computation_id, &diagnosis_keys, &authorization));
+        assert!(!secret.verify(ComputationId::from(2), &diagnosis_keys, &authorization));
+        assert!(!secret.verify(computation_id, &HashSet::new(), &authorization));
+        let other_secret = GreylistSecret::new(&secure_random).unwrap();
+        assert!(!other_secret.verify(computation_id, &diagnosis_keys, &authorization));
+        assert!(!secret.verify(
+            computation_id,
+            &diagnosis_keys,
+            &GreylistAuthorization::default()
+        ));
+
+        let revocation = secret.authorize_revocation(computation_id);
+        assert!(secret.verify_revocation(computation_id, &revocation));
+        assert!(!secret.verify_revocation(ComputationId::from(2), &revocation));
+        assert!(!other_secret.verify_revocation(computation_id, &revocation));
+        // neither authorization passes for the other
+        assert!(!secret.verify_revocation(computation_id, &authorization));
+        assert!(!secret.verify(computation_id, &HashSet::new(), &revocation));
+    }
+}
@@ -0,0 +1,53 @@
+use exposurelib::config::SystemParams;
+use exposurelib::error::RequestError;
+use exposurelib::logger;
+use exposurelib::rpcs::{Relay, RelayParams};
+use std::net::SocketAddr;
+use tarpc::context::Context;
+use tokio::sync::mpsc;
+
+#[derive(Clone)]
+pub struct RelayHandler {
+    peer_addr: SocketAddr,
+    params: SystemParams,
+    mixer: mpsc::Sender<RelayParams>,
+}
+
+impl RelayHandler {
+    pub fn new(
+        peer_addr: SocketAddr,
+        params: SystemParams,
+        mixer: mpsc::Sender<RelayParams>,
+    ) -> Self {
+        Self {
+            peer_addr,
+            params,
+            mixer,
+        }
+    }
+}
+
+#[tarpc::server]
+impl Relay for RelayHandler {
+    async fn relay(self, context: Context, params: RelayParams) -> Result<(), RequestError> {
+        logger::trace!(
+            "New relay() RPC from {:?} with context {:?} and params {:?}",
+            self.peer_addr,
+            context,
+            params
+        );
+        // invalid forwards are rejected right away as the sender learns
+        // nothing about the delivery later on
+        if let Err(e) = params.forward.validate(&self.params) {
+            logger::warn!("Rejecting relay() RPC from {:?}: {}", self.peer_addr, e);
+            return Err(e);
+        }
+        self.mixer.send(params).await.map_err(|_| {
+            logger::error!(
+                "Mixer stopped, rejecting relay() RPC from {:?}",
+                self.peer_addr
+            );
+            RequestError::RelayUnavailable
+        })
+    }
+}
@@ -0,0 +1,67 @@
+mod handler;
+mod mixer;
+use anyhow::Result;
+use exposurelib::args::{crate_authors, crate_description, crate_name, crate_version, Args};
+use exposurelib::config::RelayConfig;
+use exposurelib::logger;
+use exposurelib::rpcs::{Relay, RelayParams};
+use futures::{future, prelude::*};
+use handler::RelayHandler;
+use mixer::Mixer;
+use std::fs;
+use tarpc::server::{self, Channel, Incoming};
+use tarpc::tokio_serde::formats;
+use tokio::sync::mpsc;
+use tokio::task;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::new(
+        crate_name!(),
+        crate_version!(),
+        crate_authors!(),
+        crate_description!(),
+    );
+
+    let config = fs::read_to_string(&args.config_file_path)?;
+    let config: RelayConfig = serde_yaml::from_str(&config)?;
+
+    logger::setup_logger(&args.log_file_path, args.log_level, String::from("relay"));
+
+    let (mixer_tx, mixer_rx) = mpsc::channel::<RelayParams>(100);
+    let mixer = Mixer::new(
+        config.mixing,
+        config.params.limits.max_frame_length,
+        mixer_rx,
+    );
+    task::spawn(mixer.run());
+
+    logger::trace!("Relay listening on {}", config.endpoint);
+
+    let mut listener =
+        tarpc::serde_transport::tcp::listen(&config.endpoint, formats::Bincode::default).await?;
+    listener
+        .config_mut()
+        .max_frame_length(config.params.limits.max_frame_length);
+    listener
+        // ignore accept errors
+        .filter_map(|r| future::ready(r.ok()))
+        .map(server::BaseChannel::with_defaults)
+        .max_channels_per_key(config.params.limits.max_channels_per_ip, |t| {
+            t.as_ref().peer_addr().unwrap().ip()
+        })
+        .map(|channel| {
+            let server = RelayHandler::new(
+                channel.as_ref().as_ref().peer_addr().unwrap(),
+                config.params,
+                mixer_tx.clone(),
+            );
+            channel.requests().execute(server.serve())
+        })
+        // max 100 channels (i.e. clients)
+        .buffer_unordered(100)
+        .for_each(|_| async {})
+        .await;
+
+    Ok(())
+}
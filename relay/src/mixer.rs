@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+use exposurelib::config::MixingConfig;
+use exposurelib::logger;
+use exposurelib::rpcs::{ForwarderClient, RelayParams};
+use ring::rand::{SecureRandom, SystemRandom};
+use tarpc::{client, context, tokio_serde::formats};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{self, Instant};
+
+/// Batches, shuffles and delivers the relayed forwards.
+pub struct Mixer {
+    config: MixingConfig,
+    max_frame_length: usize,
+    secure_random: SystemRandom,
+    requests: mpsc::Receiver<RelayParams>,
+}
+
+impl Mixer {
+    pub fn new(
+        config: MixingConfig,
+        max_frame_length: usize,
+        requests: mpsc::Receiver<RelayParams>,
+    ) -> Self {
+        Self {
+            config,
+            max_frame_length,
+            secure_random: SystemRandom::new(),
+            requests,
+        }
+    }
+    pub async fn run(mut self) -> ! {
+        loop {
+            let mut batch = self.next_batch().await;
+            self.shuffle(&mut batch);
+            logger::info!("Delivering batch of {} forwards", batch.len());
+            for params in batch {
+                let config = self.config;
+                let max_frame_length = self.max_frame_length;
+                task::spawn(Self::deliver_retrying(params, config, max_frame_length));
+            }
+        }
+    }
+    /// Waits for the first forward and collects further ones until the batch
+    /// is full or the first one waited for the batch timeout.
+    async fn next_batch(&mut self) -> Vec<RelayParams> {
+        let first = self.requests.recv().await.unwrap();
+        let deadline = Instant::now() + self.config.batch_timeout;
+        let mut batch = vec![first];
+        while batch.len() < self.config.batch_size {
+            match time::timeout_at(deadline, self.requests.recv()).await {
+                Ok(params) => batch.push(params.unwrap()),
+                Err(_) => {
+                    logger::debug!("Batch timeout elapsed with {} forwards", batch.len());
+                    break;
+                }
+            }
+        }
+        batch
+    }
+    fn shuffle(&self, batch: &mut [RelayParams]) {
+        batch.sort_by_cached_key(|_| {
+            let mut key = [0u8; 8];
+            self.secure_random
+                .fill(&mut key)
+                .expect("Generating randomness failed");
+            u64::from_le_bytes(key)
+        });
+    }
+    /// Retries failed deliveries as the sender cannot tell that they failed.
+    async fn deliver_retrying(params: RelayParams, config: MixingConfig, max_frame_length: usize) {
+        let destination = params.destination;
+        for attempt in 1..=config.max_attempts {
+            match Self::deliver(&params, max_frame_length).await {
+                Ok(()) => return,
+                Err(e) if attempt < config.max_attempts => {
+                    logger::warn!(
+                        "Attempt {} to deliver forward to {:?} failed, retrying in {:?}: {:?}",
+                        attempt,
+                        destination,
+                        config.retry_interval,
+                        e
+                    );
+                    time::sleep(config.retry_interval).await;
+                }
+                Err(e) => logger::error!(
+                    "Dropping forward to {:?} after {} failed attempts: {:?}",
+                    destination,
+                    attempt,
+                    e
+                ),
+            }
+        }
+    }
+    async fn deliver(params: &RelayParams, max_frame_length: usize) -> Result<()> {
+        let mut transport =
+            tarpc::serde_transport::tcp::connect(&params.destination, formats::Bincode::default);
+        transport.config_mut().max_frame_length(max_frame_length);
+        let transport = transport.await.context(format!(
+            "Error creating TCP Bincode connect with client at {:?}",
+            params.destination
+        ))?;
+        let client = ForwarderClient::new(client::Config::default(), transport)
+            .spawn()
+            .context("Error spawning forwarder client")?;
+        // understood by listeners of all protocol versions, rejections cannot
+        // be reported back to the sender anyway
+        client
+            .legacy_forward(context::current(), params.forward.clone().into())
+            .await
+            .context("Error while delivering forward")?;
+        logger::debug!("Delivered forward to {:?}", params.destination);
+        Ok(())
+    }
+}
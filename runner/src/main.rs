@@ -53,6 +53,8 @@ fn main() -> Result<()> {
 
     let mut subprocesses = spawn_diagnosis_server(&args, subprocess_channels.clone())
         .context("Error launching diagnosis server")?;
+    subprocesses +=
+        spawn_relay(&args, subprocess_channels.clone()).context("Error launching relay")?;
     // let's wait a bit until the diagnosis server is up and running
     thread::sleep(Duration::from_secs(2));
     subprocesses +=
@@ -118,6 +120,31 @@ fn spawn_diagnosis_server(args: &Args, channels: SubprocessChannels) -> Result<u
     Ok(1)
 }
 
+/// The relay is optional and only spawned if the configurator generated its config.
+fn spawn_relay(args: &Args, channels: SubprocessChannels) -> Result<usize> {
+    let mut config_path = args.config_files_path.clone();
+    config_path.push("relay");
+    config_path.set_extension("yaml");
+    if !config_path.exists() {
+        return Ok(0);
+    }
+    let mut log_path = args.log_files_path.clone();
+    log_path.push("relay");
+    log_path.set_extension("log");
+    monitor_subprocess(
+        args,
+        String::from("relay"),
+        Command::new("target/release/relay")
+            .arg(format!("--config={}", config_path.to_str().unwrap()))
+            .arg(format!("--log={}", log_path.to_str().unwrap()))
+            .arg(format!("-{}", args.log_level))
+            .stderr(Stdio::piped())
+            .spawn()?,
+        channels.clone(),
+    );
+    Ok(1)
+}
+
 fn spawn_clients(args: &Args, channels: SubprocessChannels) -> Result<usize> {
     let mut config_path = args.config_files_path.clone();
     config_path.push("clients");